@@ -2,61 +2,121 @@
  * Hold the Structs for programs as well as intermediate forms of programs
  * 
  */
+use std::collections::HashMap;
 use std::vec::Vec;
+use crate::software::diagnostic::Span;
 
 pub struct Program {
     pub instructions: Vec<u32>,
-    pub data: Vec<u32>
+    pub data: Vec<u8>,          // static data image, loaded byte for byte at arch::STATIC_DATA
+    pub symbols: HashMap<String, u32>   // label -> address, empty for hand built programs
 }
 impl Program {
     pub fn new() -> Self {
         Program {
             instructions: Vec::new(),
-            data: Vec::new()
+            data: Vec::new(),
+            symbols: HashMap::new()
         }
     }
 }
 
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for Program {
     fn clone(&self) -> Self {
-        Self { instructions: self.instructions.clone(), data: self.data.clone() }
+        Self { instructions: self.instructions.clone(), data: self.data.clone(), symbols: self.symbols.clone() }
     }
 }
 
+// A parsed program whose label operands have not been resolved yet
 pub struct Protogram {
-    text: Vec<Instruction>, 
-    data: Vec<StaticData>
+    pub text: Vec<Instruction>, 
+    pub data: Vec<StaticData>
 }
 
 pub enum Instruction {
-    RInstruction, IInstruction, JInstruction
+    R(RInstruction), I(IInstruction), J(JInstruction), Pseudo(PseudoInstruction)
+}
+
+impl Instruction {
+    // labels that point at this instruction
+    pub fn labels(&self) -> &Vec<Label> {
+        match self {
+            Instruction::R(r) => &r.labels,
+            Instruction::I(i) => &i.labels,
+            Instruction::J(j) => &j.labels,
+            Instruction::Pseudo(p) => &p.labels
+        }
+    }
+
+    // where the instruction was written in the source
+    pub fn span(&self) -> Span {
+        match self {
+            Instruction::R(r) => r.span,
+            Instruction::I(i) => i.span,
+            Instruction::J(j) => j.span,
+            Instruction::Pseudo(p) => p.span
+        }
+    }
+}
+
+pub struct Label {
+    pub name: String,
+    pub span: Span
 }
 
 pub struct RInstruction {
-    label: String,
-    opcode: u8,
-    rs: u8,
-    rt: u8,
-    rd: u8,
-    shamt: u8,
-    func: u8
+    pub labels: Vec<Label>,
+    pub span: Span,
+    pub opcode: u8,
+    pub rs: u8,
+    pub rt: u8,
+    pub rd: u8,
+    pub shamt: u8,
+    pub func: u8
 }
 pub struct IInstruction {
-    label: String,
-    opcode: u8,
-    rs: u8,
-    rt: u8,
-    immediate: i16,
-    lbl_0p: String
+    pub labels: Vec<Label>,
+    pub span: Span,
+    pub opcode: u8,
+    pub rs: u8,
+    pub rt: u8,
+    pub immediate: i16,
+    pub lbl_op: String,         // label operand, empty if the immediate is used as is
+    pub lbl_use: LabelUse       // which part of the label's address becomes the immediate
+}
+
+pub enum LabelUse {
+    Branch,     // word offset from the next instruction
+    Upper,      // upper 16 bits of the address
+    Lower       // lower 16 bits of the address
 }
 pub struct JInstruction {
-    label: String,
-    opcode: u8,
-    address: u32,
-    lbl_op: String
+    pub labels: Vec<Label>,
+    pub span: Span,
+    pub opcode: u8,
+    pub address: u32,
+    pub lbl_op: String          // jump target, empty if the address is used as is
+}
+
+// Assembler convenience instruction, rewritten into real instructions before encoding
+pub struct PseudoInstruction {
+    pub labels: Vec<Label>,
+    pub span: Span,
+    pub mnemonic: String,
+    pub registers: Vec<u8>,     // register operands in source order
+    pub immediate: i32,
+    pub lbl_op: String
 }
 
 pub struct StaticData {
-    label: String,
-    value: Vec<i32>
-}
\ No newline at end of file
+    pub labels: Vec<Label>,
+    pub span: Span,
+    pub align: u32,             // byte boundary the value starts on, padding with zeros
+    pub value: Vec<u8>          // big endian bytes, as the CPU reads them
+}
@@ -0,0 +1,98 @@
+use std::fmt::Write;
+use crate::hardware::cpu::{CPU, MemoryAccess};
+use crate::hardware::mmu::Access;
+use super::expression::Expression;
+
+/*
+ * Breakpoints and watchpoints, numbered together as gdb does. They are checked after every
+ * step: a breakpoint when the PC reaches its address, a value watchpoint when its expression
+ * changes and an access watchpoint when a load or store touches its range. One that triggers
+ * only stops the run if its condition holds and its ignore count has run out, and every time
+ * the condition holds counts as a hit
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Address(u32),
+    Change { expression: Expression, value: Option<i64> },     // None while it can't be read
+    Access { start: u32, length: u32, read: bool, write: bool }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub kind: Kind,
+    pub condition: Option<Expression>,
+    pub hits: u64,
+    pub ignore: u64,        // triggers to let pass before stopping again
+    pub access: Option<MemoryAccess>   // the load or store that last set off an access watchpoint
+}
+
+impl Breakpoint {
+    pub fn new(id: u32, kind: Kind) -> Self {
+        Breakpoint { id, kind, condition: None, hits: 0, ignore: 0, access: None }
+    }
+
+    // After a step, what to say if the breakpoint stops the run
+    pub fn check(&mut self, cpu: &CPU) -> Option<String> {
+        let detail = self.triggered(cpu)?;
+        if let Some(condition) = &self.condition {
+            match condition.evaluate(cpu) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(error) => return Some(format!("Error in testing condition for breakpoint {}: {error}\n", self.id))
+            }
+        }
+        self.hits += 1;
+        if self.ignore > 0 {
+            self.ignore -= 1;
+            return None;
+        }
+        Some(detail)
+    }
+
+    fn triggered(&mut self, cpu: &CPU) -> Option<String> {
+        match &mut self.kind {
+            Kind::Address(address) => (*address == cpu.program_counter).then(|| format!("Breakpoint {}, ", self.id)),
+            Kind::Change { expression, value } => {
+                let now = expression.evaluate(cpu).ok();
+                if now == *value {
+                    return None;
+                }
+                let show = |value: Option<i64>| value.map_or(String::from("<unreadable>"), |v| v.to_string());
+                let text = format!("Watchpoint {}: {expression}\n\nOld value = {}\nNew value = {}\n", self.id, show(*value), show(now));
+                *value = now;
+                Some(text)
+            }
+            Kind::Access { start, length, read, write } => {
+                let end = *start as u64 + *length as u64;
+                let mut text = String::new();
+                let accesses = cpu.accesses.iter().flatten()
+                    .filter(|a| if a.access == Access::Store { *write } else { *read })
+                    .filter(|a| (a.address as u64) < end && a.address as u64 + a.size as u64 > *start as u64);
+                for access in accesses {
+                    self.access = Some(*access);
+                    let what = if access.access == Access::Store { "write" } else { "read" };
+                    writeln!(text, "Watchpoint {}: {what} of {:#x} at {:#010x}", self.id, access.value, access.address).unwrap();
+                }
+                (!text.is_empty()).then_some(text)
+            }
+        }
+    }
+
+    // Type and what it watches, for info breakpoints
+    pub fn describe(&self) -> (&'static str, String) {
+        match &self.kind {
+            Kind::Address(address) => ("breakpoint", format!("{address:#010x}")),
+            Kind::Change { expression, .. } => ("watchpoint", expression.to_string()),
+            Kind::Access { start, length, read, write } => {
+                let kind = match (read, write) {
+                    (true, true) => "acc watchpoint",
+                    (true, false) => "read watchpoint",
+                    _ => "write watchpoint"
+                };
+                (kind, format!("{length} bytes at {start:#010x}"))
+            }
+        }
+    }
+}
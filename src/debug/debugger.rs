@@ -0,0 +1,892 @@
+use std::fmt::Write;
+use crate::hardware::arch::{self, Fields, Fault};
+use crate::hardware::bus::Bus;
+use crate::hardware::cpu::CPU;
+use crate::hardware::mmu::Access;
+use crate::hardware::snapshot::Snapshot;
+use crate::software::disassemble;
+use super::breakpoint::{Breakpoint, Kind};
+use super::expression::Expression;
+use super::history::{self, History};
+use super::line::LineEditor;
+
+/*
+ * Command line debugger driving a CPU one step at a time. Commands take addresses and
+ * values as expressions and give back the text to show, so the same commands work from
+ * the REPL and from a test. An empty line repeats the last command if it only moves or
+ * looks, as in gdb, never one that changes breakpoints, state or files. Every step
+ * is recorded, so the program can also be run backwards to an earlier cycle or to the
+ * last place a breakpoint or watchpoint would have stopped it
+ */
+
+const SP: usize = 29;
+const RA: usize = 31;
+
+// Commands an empty line repeats, besides the x/ family
+const REPEATABLE: [&str; 17] = [
+    "step", "s", "stepi", "si", "next", "n", "finish", "continue", "c",
+    "reverse-step", "rs", "reverse-stepi", "rsi", "reverse-continue", "rc", "disas", "disassemble"
+];
+
+// Why a run handed control back
+#[derive(Debug)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(Vec<u32>, String),   // the breakpoints and watchpoints that stopped it and what they say
+    Exited(i32),
+    Fault(Fault)
+}
+
+pub struct Debugger {
+    pub cpu: CPU,
+    pub breakpoints: Vec<Breakpoint>,
+    pub history: History,
+    next_id: u32,
+    last: Option<String>,
+    ended: Option<String>       // how the program ended, nothing runs after that
+}
+
+impl Debugger {
+    pub fn new(mut cpu: CPU) -> Self {
+        cpu.debug_mode = false;
+        Debugger { cpu, breakpoints: Vec::new(), history: History::default(), next_id: 1, last: None, ended: None }
+    }
+
+    // Read and run commands until quit or the end of input, giving the program's exit code
+    // if it got to the end
+    pub fn repl(&mut self) -> Option<i32> {
+        let mut editor = LineEditor::new();
+        println!("{}", self.location(self.cpu.program_counter));
+        while let Some(line) = editor.read_line("(mips) ") {
+            match line.trim() {
+                "quit" | "q" => break,
+                "history" => editor.history.iter().enumerate().for_each(|(i, line)| println!("{:>4}  {line}", i + 1)),
+                _ => match self.command(&line) {
+                    Ok(output) => print!("{output}"),
+                    Err(error) => println!("{error}")
+                }
+            }
+        }
+        self.exit_code()
+    }
+
+    // Exit code of a program that has run to its end
+    pub fn exit_code(&self) -> Option<i32> {
+        self.ended.as_ref()?;
+        self.cpu.exit_code().or(Some(0))
+    }
+
+    // Run one command line, an empty one repeating the last
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let line = match line.trim() {
+            "" => self.last.clone().ok_or("no command to repeat")?,
+            line => line.to_string()
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arguments = &words[1..];
+        let repeatable = REPEATABLE.contains(&words[0]) || words[0].starts_with('x');
+        self.last = repeatable.then(|| line.clone());
+
+        match words[0] {
+            "break" | "b" => self.add_breakpoint(rest(&line)),
+            "watch" => self.watch(rest(&line)),
+            "rwatch" => self.watch_accesses(rest(&line), true, false),
+            "wwatch" => self.watch_accesses(rest(&line), false, true),
+            "awatch" => self.watch_accesses(rest(&line), true, true),
+            "condition" => self.condition(arguments),
+            "ignore" => self.ignore(arguments),
+            "delete" | "d" => self.delete(arguments),
+            "step" | "s" | "stepi" | "si" => {
+                let mut left = count(arguments)?;
+                self.resume(|_| { left -= 1; left == 0 })
+            }
+            "next" | "n" => self.next(),
+            "finish" => self.finish(),
+            "continue" | "c" => self.resume(|_| false),
+            "reverse-step" | "rs" | "reverse-stepi" | "rsi" => self.reverse_step(count(arguments)?),
+            "reverse-continue" | "rc" => self.reverse_continue(),
+            "record" => match arguments {
+                ["goto", "begin" | "start"] => self.goto(self.history.earliest().unwrap_or(self.cpu.retired)),
+                ["goto", cycle] => self.goto(cycle.parse().map_err(|_| format!("not a cycle: {cycle}"))?),
+                _ => Err(String::from("record goto <cycle|begin>"))
+            },
+            "save" => match rest(&line) {
+                "" => Err(String::from("save FILE")),
+                path => Snapshot::of(&self.cpu).save(path)
+                    .map(|_| format!("Saved cycle {} to {path}.\n", self.cpu.retired))
+                    .map_err(|e| format!("could not write {path}: {e}"))
+            },
+            "restore" => match rest(&line) {
+                "" => Err(String::from("restore FILE")),
+                path => self.restore(path)
+            },
+            "regs" => Ok(self.registers()),
+            "disas" | "disassemble" => self.disassemble(arguments),
+            "set" => self.set(arguments),
+            "info" => match arguments.first() {
+                Some(&"frame") => Ok(self.frame()),
+                Some(&"breakpoints") => Ok(self.list_breakpoints()),
+                Some(&"registers") => Ok(self.registers()),
+                Some(&"record") => Ok(self.record()),
+                _ => Err(String::from("info frame, info breakpoints, info registers or info record"))
+            },
+            word if word.starts_with("x") => self.examine(word, rest(&line)),
+            word => Err(format!("unknown command: {word}"))
+        }
+    }
+
+    // Run until stop says so, a breakpoint or watchpoint stops it or the program ends. The
+    // instruction at the PC runs first, so a run can leave the breakpoint it stopped at
+    pub fn run(&mut self, mut stop: impl FnMut(&CPU) -> bool) -> Stop {
+        let watching = self.breakpoints.iter().any(|b| matches!(b.kind, Kind::Access { .. }));
+        let result = loop {
+            self.cpu.accesses = watching.then(Vec::new);
+            match self.history.step(&mut self.cpu) {
+                Ok(Some(code)) => break Stop::Exited(code),
+                Err(fault) => break Stop::Fault(fault),
+                Ok(None) => ()
+            }
+            // every one is checked, so watched values and hit counts keep up
+            let said: Vec<(u32, String)> = self.breakpoints.iter_mut().filter_map(|b| Some((b.id, b.check(&self.cpu)?))).collect();
+            if !said.is_empty() {
+                let (ids, said) = said.into_iter().unzip();
+                break Stop::Breakpoint(ids, said);
+            }
+            if stop(&self.cpu) {
+                break Stop::Stepped;
+            }
+        };
+        self.cpu.accesses = None;
+        result
+    }
+
+    // run, describing where it stopped
+    fn resume(&mut self, stop: impl FnMut(&CPU) -> bool) -> Result<String, String> {
+        if let Some(ended) = &self.ended {
+            return Err(format!("The program is not being run, it {ended}."));
+        }
+        Ok(match self.run(stop) {
+            Stop::Stepped => format!("{}\n", self.location(self.cpu.program_counter)),
+            Stop::Breakpoint(_, said) => format!("{said}{}\n", self.location(self.cpu.program_counter)),
+            Stop::Exited(code) => self.end(format!("exited with code {code}")),
+            Stop::Fault(fault) => self.end(format!("stopped: {fault}"))
+        })
+    }
+
+    // Go back count steps, or as far as the history goes
+    fn reverse_step(&mut self, count: u64) -> Result<String, String> {
+        history::reversible(&self.cpu)?;
+        let mut text = String::new();
+        for taken in 0..count {
+            // with the records used up, a checkpoint still reaches the cycle before
+            let retired = self.cpu.retired;
+            let back = self.history.back(&mut self.cpu) || retired > 0 && self.history.seek(&mut self.cpu, retired - 1).is_ok();
+            if !back {
+                if taken == 0 {
+                    return Err(String::from("No more reverse-execution history."));
+                }
+                text += "\nNo more reverse-execution history.\n";
+                break;
+            }
+        }
+        self.rewound();
+        Ok(text + &format!("{}\n", self.location(self.cpu.program_counter)))
+    }
+
+    // Go back to the last place a breakpoint or watchpoint would have stopped the program.
+    // The history is run forward again an interval at a time, newest first, with copies of
+    // the breakpoints looking for the last that would have stopped it
+    fn reverse_continue(&mut self) -> Result<String, String> {
+        history::reversible(&self.cpu)?;
+        let earliest = self.history.earliest().ok_or("No more reverse-execution history.")?;
+        let watching = self.breakpoints.iter().any(|b| matches!(b.kind, Kind::Access { .. }));
+        let mut limit = self.cpu.retired.saturating_sub(1);     // not where it is now
+        while limit > earliest {
+            let start = limit.saturating_sub(self.history.interval).max(earliest);
+            self.history.seek(&mut self.cpu, start)?;
+            let mut copies = self.breakpoints.clone();
+            for copy in &mut copies {
+                copy.ignore = 0;
+                if let Kind::Change { expression, value } = &mut copy.kind {
+                    *value = expression.evaluate(&self.cpu).ok();
+                }
+            }
+            let mut last = None;
+            while self.cpu.retired < limit {
+                self.cpu.accesses = watching.then(Vec::new);
+                if !matches!(self.history.step(&mut self.cpu), Ok(None)) {
+                    break;
+                }
+                let said: Vec<(u32, String)> = copies.iter_mut().filter_map(|b| Some((b.id, b.check(&self.cpu)?))).collect();
+                if !said.is_empty() {
+                    last = Some((self.cpu.retired, said));
+                }
+            }
+            self.cpu.accesses = None;
+            if let Some((retired, said)) = last {
+                self.history.seek(&mut self.cpu, retired)?;
+                self.rewound();
+                for (id, _) in &said {
+                    self.breakpoints.iter_mut().filter(|b| b.id == *id).for_each(|b| b.hits += 1);
+                }
+                let said: String = said.into_iter().map(|(_, text)| text).collect();
+                return Ok(format!("{said}{}\n", self.location(self.cpu.program_counter)));
+            }
+            limit = start;
+        }
+        self.history.seek(&mut self.cpu, earliest)?;
+        self.rewound();
+        Ok(format!("\nNo more reverse-execution history.\n{}\n", self.location(self.cpu.program_counter)))
+    }
+
+    // record goto cycle, back through the history or forward by running without stopping
+    fn goto(&mut self, cycle: u64) -> Result<String, String> {
+        if cycle <= self.cpu.retired {
+            self.history.seek(&mut self.cpu, cycle)?;
+        } else if let Some(ended) = &self.ended {
+            return Err(format!("The program is not being run, it {ended}."));
+        }
+        while self.cpu.retired < cycle {
+            match self.history.step(&mut self.cpu) {
+                Ok(None) => (),
+                Ok(Some(code)) => return Ok(self.end(format!("exited with code {code}"))),
+                Err(fault) => return Ok(self.end(format!("stopped: {fault}")))
+            }
+        }
+        self.rewound();
+        Ok(format!("{}\n", self.location(self.cpu.program_counter)))
+    }
+
+    // After moving through the history the program can run on, and watched values are
+    // the ones now
+    fn rewound(&mut self) {
+        self.ended = None;
+        for breakpoint in &mut self.breakpoints {
+            if let Kind::Change { expression, value } = &mut breakpoint.kind {
+                *value = expression.evaluate(&self.cpu).ok();
+            }
+        }
+    }
+
+    // restore FILE, a snapshot taking the place of the running program. The history before
+    // it no longer applies
+    fn restore(&mut self, path: &str) -> Result<String, String> {
+        Snapshot::load(path)?.restore(&mut self.cpu)?;
+        self.history = History::new(self.history.budget, self.history.interval);
+        self.rewound();
+        Ok(format!("Restored cycle {} from {path}.\n{}\n", self.cpu.retired, self.location(self.cpu.program_counter)))
+    }
+
+    fn record(&self) -> String {
+        let (records, checkpoints, used) = self.history.usage();
+        let mut text = match self.history.earliest() {
+            Some(earliest) => format!("Recorded from cycle {earliest}, now at cycle {}.\n", self.cpu.retired),
+            None => String::from("Nothing recorded yet.\n")
+        };
+        writeln!(text, "Undo records {records}, checkpoints {checkpoints}, memory {} KiB of {} KiB.", used.div_ceil(1024), self.history.budget / 1024).unwrap();
+        text
+    }
+
+    fn end(&mut self, how: String) -> String {
+        let text = format!("Program {how}\n");
+        self.ended = Some(how);
+        text
+    }
+
+    // Step over calls, running until the call returns to the next instruction
+    fn next(&mut self) -> Result<String, String> {
+        let pc = self.cpu.program_counter;
+        let word = self.read_word(pc).ok_or_else(|| format!("Cannot access memory at {pc:#010x}"))?;
+        if !is_call(word) {
+            return self.resume(|_| true);
+        }
+        let sp = self.cpu.registers[SP] as u32;
+        // a recursive call comes back to the same address deeper in the stack
+        self.resume(|cpu| cpu.program_counter == pc.wrapping_add(4) && cpu.registers[SP] as u32 >= sp)
+    }
+
+    // Run until the function returns to the address in $ra
+    fn finish(&mut self) -> Result<String, String> {
+        let (ra, sp) = (self.cpu.registers[RA] as u32, self.cpu.registers[SP] as u32);
+        if ra == 0 {
+            return Err(String::from("\"finish\" not meaningful in the outermost frame."));
+        }
+        let mut text = format!("Run till exit from {}\n", self.location(self.cpu.program_counter));
+        text += &self.resume(|cpu| cpu.program_counter == ra && cpu.registers[SP] as u32 >= sp)?;
+        Ok(text)
+    }
+
+    // break [location] [if condition]
+    fn add_breakpoint(&mut self, arguments: &str) -> Result<String, String> {
+        let (location, condition) = match (arguments.strip_prefix("if "), arguments.split_once(" if ")) {
+            (Some(condition), _) => ("", Some(condition)),
+            (None, Some((location, condition))) => (location.trim(), Some(condition)),
+            (None, None) => (arguments, None)
+        };
+        let address = match location {
+            "" => self.cpu.program_counter,
+            location => self.evaluate(location)?
+        };
+        let condition = condition.map(Expression::parse).transpose()?;
+        let id = self.add(Kind::Address(address));
+        self.breakpoints.last_mut().unwrap().condition = condition;
+        Ok(format!("Breakpoint {id} at {address:#010x}{}\n", self.symbol(address)))
+    }
+
+    // watch expression, stopping when its value changes
+    fn watch(&mut self, arguments: &str) -> Result<String, String> {
+        let expression = Expression::parse(arguments)?;
+        let value = expression.evaluate(&self.cpu).ok();
+        let text = format!("Watchpoint {}: {expression}\n", self.next_id);
+        self.add(Kind::Change { expression, value });
+        Ok(text)
+    }
+
+    // rwatch, wwatch or awatch address [, length], 4 bytes unless a length is given
+    fn watch_accesses(&mut self, arguments: &str, read: bool, write: bool) -> Result<String, String> {
+        let (address, length) = match arguments.rsplit_once(',') {
+            Some((address, length)) => (address, self.evaluate(length)?),
+            None => (arguments, 4)
+        };
+        let start = self.evaluate(address)?;
+        if length == 0 {
+            return Err(String::from("nothing to watch in 0 bytes"));
+        }
+        let id = self.add(Kind::Access { start, length, read, write });
+        let (kind, what) = self.breakpoints.last().unwrap().describe();
+        Ok(format!("{}{} {id}: {what}\n", kind[..1].to_uppercase(), &kind[1..]))
+    }
+
+    // A breakpoint or watchpoint numbered after the last, giving its number
+    pub fn add(&mut self, kind: Kind) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint::new(id, kind));
+        id
+    }
+
+    fn breakpoint(&mut self, argument: Option<&&str>) -> Result<&mut Breakpoint, String> {
+        let argument = argument.ok_or("which breakpoint?")?;
+        let id: u32 = argument.parse().map_err(|_| format!("not a breakpoint number: {argument}"))?;
+        self.breakpoints.iter_mut().find(|b| b.id == id).ok_or_else(|| format!("No breakpoint number {id}."))
+    }
+
+    // condition n [expression], without one the breakpoint stops unconditionally
+    fn condition(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let condition = match arguments.get(1..).filter(|rest| !rest.is_empty()) {
+            Some(rest) => Some(Expression::parse(&rest.join(" "))?),
+            None => None
+        };
+        let breakpoint = self.breakpoint(arguments.first())?;
+        let text = match &condition {
+            Some(_) => String::new(),
+            None => format!("Breakpoint {} now unconditional.\n", breakpoint.id)
+        };
+        breakpoint.condition = condition;
+        Ok(text)
+    }
+
+    // ignore n count, letting the next count triggers pass
+    fn ignore(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let count = arguments.get(1).ok_or("ignore <breakpoint> <count>")?;
+        let count: u64 = count.parse().map_err(|_| format!("not a count: {count}"))?;
+        let breakpoint = self.breakpoint(arguments.first())?;
+        breakpoint.ignore = count;
+        Ok(match count {
+            0 => format!("Will stop next time breakpoint {} is reached.\n", breakpoint.id),
+            count => format!("Will ignore next {count} crossings of breakpoint {}.\n", breakpoint.id)
+        })
+    }
+
+    // delete with no number deletes them all
+    fn delete(&mut self, arguments: &[&str]) -> Result<String, String> {
+        if arguments.is_empty() {
+            self.breakpoints.clear();
+            return Ok(String::new());
+        }
+        for argument in arguments {
+            let id: u32 = argument.parse().map_err(|_| format!("not a breakpoint number: {argument}"))?;
+            let before = self.breakpoints.len();
+            self.breakpoints.retain(|b| b.id != id);
+            if self.breakpoints.len() == before {
+                return Err(format!("No breakpoint number {id}."));
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return String::from("No breakpoints.\n");
+        }
+        let mut text = String::from("Num  Type              What\n");
+        for breakpoint in &self.breakpoints {
+            let (kind, mut what) = breakpoint.describe();
+            if let Kind::Address(address) = breakpoint.kind {
+                what += &self.symbol(address);
+            }
+            writeln!(text, "{:<4} {kind:<17} {what}", breakpoint.id).unwrap();
+            if let Some(condition) = &breakpoint.condition {
+                writeln!(text, "        stop only if {condition}").unwrap();
+            }
+            if breakpoint.hits > 0 {
+                writeln!(text, "        breakpoint already hit {} time{}", breakpoint.hits, if breakpoint.hits == 1 { "" } else { "s" }).unwrap();
+            }
+            if breakpoint.ignore > 0 {
+                writeln!(text, "        Will ignore next {} crossings of breakpoint.", breakpoint.ignore).unwrap();
+            }
+        }
+        text
+    }
+
+    fn registers(&self) -> String {
+        let mut text = String::new();
+        for row in 0..8 {
+            let line: Vec<String> = (0..4).map(|column| {
+                let n = row * 4 + column;
+                format!("{:<5}{:#010x}", arch::REGISTER_NAMES[n], self.cpu.registers[n])
+            }).collect();
+            writeln!(text, "{}", line.join("  ")).unwrap();
+        }
+        writeln!(text, "{:<5}{:#010x}  {:<5}{:#010x}  {:<5}{:#010x}", "pc", self.cpu.program_counter, "hi", self.cpu.hi, "lo", self.cpu.lo).unwrap();
+        text
+    }
+
+    // x/<n><b|h|w> address, words unless a unit is given
+    fn examine(&self, format: &str, address: &str) -> Result<String, String> {
+        let spec = format.strip_prefix("x").unwrap().trim_start_matches('/');
+        let digits = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let count: u32 = if digits == 0 { 1 } else { spec[..digits].parse().map_err(|_| format!("bad count in {format}"))? };
+        let (size, per_line) = match &spec[digits..] {
+            "" | "w" => (4, 4),
+            "h" => (2, 8),
+            "b" => (1, 8),
+            unit => return Err(format!("unknown unit {unit}, expected b, h or w"))
+        };
+        if address.is_empty() {
+            return Err(String::from("x needs an address"));
+        }
+        let start = self.evaluate(address)?;
+
+        let mut text = String::new();
+        for line in 0..count.div_ceil(per_line) {
+            let address = start.wrapping_add(line * per_line * size);
+            write!(text, "{address:#010x}{}:", self.symbol(address)).unwrap();
+            for i in 0..per_line.min(count - line * per_line) {
+                let address = address.wrapping_add(i * size);
+                let value = self.read(address, size).ok_or_else(|| format!("{text}\nCannot access memory at {address:#010x}"))?;
+                write!(text, "  {value:#0width$x}", width = 2 + 2 * size as usize).unwrap();
+            }
+            text.push('\n');
+        }
+        Ok(text)
+    }
+
+    // set reg <register> <value> or set mem <address> <value>, a word
+    fn set(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [what, target, value @ ..] = arguments else { return Err(String::from("set reg <register> <value> or set mem <address> <value>")) };
+        let value = self.evaluate(&value.join(" "))?;
+        match *what {
+            "reg" => match target.trim_start_matches('$') {
+                "pc" => self.cpu.program_counter = value,
+                "hi" => self.cpu.hi = value as i32,
+                "lo" => self.cpu.lo = value as i32,
+                name => {
+                    let n = arch::register_number(name).ok_or_else(|| format!("no register {target}"))?;
+                    if n != 0 {
+                        self.cpu.registers[n as usize] = value as i32;
+                    }
+                }
+            },
+            "mem" => {
+                let address = self.evaluate(target)?;
+                self.cpu.physical(address, Access::Store)
+                    .and_then(|physical| self.cpu.write_word_to_mem(physical, value))
+                    .ok_or_else(|| format!("Cannot access memory at {address:#010x}"))?;
+            }
+            _ => return Err(String::from("set reg or set mem"))
+        }
+        Ok(String::new())
+    }
+
+    // disas [address [count]], 8 instructions from the PC unless told otherwise
+    fn disassemble(&self, arguments: &[&str]) -> Result<String, String> {
+        let start = match arguments.first() {
+            Some(text) => self.evaluate(text)?,
+            None => self.cpu.program_counter
+        } & !3;
+        let count = match arguments.get(1) {
+            Some(count) => count.parse::<u32>().map_err(|_| format!("not a count: {count}"))?,
+            None => 8
+        };
+
+        let mut text = String::new();
+        for address in (0..count).map(|i| start.wrapping_add(4 * i)) {
+            let Some(word) = self.read_word(address) else { break };
+            let marker = if address == self.cpu.program_counter { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.iter().any(|b| b.kind == Kind::Address(address)) { '*' } else { ' ' };
+            writeln!(text, "{marker}{breakpoint}{address:#010x}{:<14}  {}",
+                self.symbol(address), disassemble::disassemble(word, address, &self.cpu.symbols)).unwrap();
+        }
+        Ok(text)
+    }
+
+    fn frame(&self) -> String {
+        let cpu = &self.cpu;
+        let (sp, fp, ra) = (cpu.registers[SP] as u32, cpu.registers[30] as u32, cpu.registers[RA] as u32);
+        let mut text = format!("Stack frame at sp {sp:#010x}, fp {fp:#010x}:\n");
+        writeln!(text, " pc = {:#010x}{}", cpu.program_counter, self.symbol(cpu.program_counter)).unwrap();
+        if ra != 0 {
+            writeln!(text, " called from ra = {ra:#010x}{}", self.symbol(ra)).unwrap();
+        }
+        text
+    }
+
+    // Where the program is and the instruction there
+    fn location(&self, address: u32) -> String {
+        let text = self.read_word(address)
+            .map(|word| disassemble::disassemble(word, address, &self.cpu.symbols))
+            .unwrap_or_else(|| String::from("<unreadable>"));
+        format!("{address:#010x}{}  {text}", self.symbol(address))
+    }
+
+    // " <label+offset>" for the nearest label at or before address in the same segment,
+    // nothing without one
+    fn symbol(&self, address: u32) -> String {
+        let nearest = self.cpu.symbols.iter()
+            .filter(|(_, &at)| at <= address && self.cpu.in_text(at) == self.cpu.in_text(address))
+            .max_by_key(|(label, &at)| (at, std::cmp::Reverse(label.as_str())));
+        match nearest {
+            Some((label, &at)) if at == address => format!(" <{label}>"),
+            Some((label, &at)) => format!(" <{label}+{}>", address - at),
+            None => String::new()
+        }
+    }
+
+    // An expression as a 32 bit address or value
+    pub fn evaluate(&self, text: &str) -> Result<u32, String> {
+        Expression::parse(text)?.evaluate(&self.cpu).map(|value| value as u32)
+    }
+
+    fn read_word(&self, address: u32) -> Option<u32> {
+        self.read(address, 4)
+    }
+
+    // Read at a virtual address without side effects
+    fn read(&self, address: u32, size: u32) -> Option<u32> {
+        let physical = self.cpu.physical(address, Access::Load)?;
+        self.cpu.bus.peek(physical, size)
+    }
+}
+
+// How many steps a step command asks for, 1 unless given
+fn count(arguments: &[&str]) -> Result<u64, String> {
+    match arguments.first() {
+        Some(count) => count.parse::<u64>().ok().filter(|&count| count > 0).ok_or(format!("not a count: {count}")),
+        None => Ok(1)
+    }
+}
+
+// The line after its command word
+fn rest(line: &str) -> &str {
+    line.trim().split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim())
+}
+
+// jal, jalr, bltzal and bgezal, which come back to the next instruction
+fn is_call(word: u32) -> bool {
+    let fields = Fields::extract(word);
+    match fields.opcode {
+        0x03 => true,
+        0x00 => fields.func == 0x09,
+        0x01 => fields.rt == 0x10 || fields.rt == 0x11,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::hardware::bus::Permissions;
+    use crate::hardware::cache::Hierarchy;
+    use crate::hardware::mmu::{Mmu, TlbEntry};
+    use crate::hardware::predictor::{self, BranchUnit};
+    use crate::hardware::syscall::{Capture, Syscalls};
+    use crate::hardware::timer::{self, Timer};
+    use crate::hardware::uart::{self, Streams, Uart};
+
+    const SOURCE: &str = "
+        .data
+        values: .word 7, 8
+        .text
+        main: li $sp, 0x7ff0
+        li $a0, 3
+        jal triple
+        addu $s0, $v0, $zero
+        loop: addiu $s1, $s1, 1
+        bne $s1, $a0, loop
+        halt
+        triple: addu $v0, $a0, $a0
+        addu $v0, $v0, $a0
+        jr $ra";
+
+    fn debugger() -> Debugger {
+        Debugger::new(CPU::assembled(SOURCE, |_, _| ()))
+    }
+
+    #[test]
+    fn breakpoints_stop_runs_and_enter_repeats() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.command("break loop").unwrap(), "Breakpoint 1 at 0x00000050 <loop>\n");
+        assert_eq!(debugger.command("c").unwrap(), "Breakpoint 1, 0x00000050 <loop>  addiu $s1, $s1, 1\n");
+        assert_eq!(debugger.cpu.registers[16], 9);
+        assert_eq!(debugger.command("").unwrap(), "Breakpoint 1, 0x00000050 <loop>  addiu $s1, $s1, 1\n");
+        assert_eq!(debugger.cpu.registers[17], 1);
+
+        assert!(debugger.command("x/2w values").unwrap().starts_with("0x00001000 <values>:  0x00000007  0x00000008"));
+        assert!(debugger.command("regs").unwrap().contains("s1   0x00000001"));
+        assert_eq!(debugger.command("delete 2"), Err(String::from("No breakpoint number 2.")));
+        debugger.command("delete").unwrap();
+        assert_eq!(debugger.command("continue").unwrap(), "Program exited with code 0\n");
+        assert_eq!(debugger.exit_code(), Some(0));
+        assert!(debugger.command("step").is_err());
+    }
+
+    #[test]
+    fn enter_only_repeats_commands_that_move_or_look() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.command(""), Err(String::from("no command to repeat")));
+        assert_eq!(debugger.command("step 0"), Err(String::from("not a count: 0")));
+        assert_eq!(debugger.command("rs 0"), Err(String::from("not a count: 0")));
+
+        debugger.command("step").unwrap();
+        debugger.command("").unwrap();
+        assert_eq!(debugger.cpu.retired, 2);
+
+        debugger.command("b loop").unwrap();
+        debugger.command("b triple").unwrap();
+        debugger.command("delete 1").unwrap();
+        assert_eq!(debugger.command(""), Err(String::from("no command to repeat")));
+        assert_eq!(debugger.breakpoints.iter().map(|b| b.id).collect::<Vec<_>>(), [2]);
+        debugger.command("delete").unwrap();
+        debugger.command("b loop").unwrap();
+        assert!(debugger.command("").is_err());
+        assert_eq!(debugger.breakpoints.len(), 1);
+
+        debugger.command("set reg $s1 5").unwrap();
+        assert!(debugger.command("").is_err());
+        let examined = debugger.command("x/1w values").unwrap();
+        assert_eq!(debugger.command(""), Ok(examined));
+    }
+
+    #[test]
+    fn next_and_finish_treat_calls_as_one_step() {
+        let mut debugger = debugger();
+        debugger.command("step 2").unwrap();
+        assert_eq!(debugger.command("next").unwrap(), "0x0000004c <main+12>  addu $s0, $v0, $zero\n");
+        assert_eq!(debugger.cpu.registers[2], 9);
+
+        let mut debugger = self::debugger();
+        debugger.command("step 3").unwrap();
+        assert!(debugger.command("info frame").unwrap().contains("called from ra = 0x0000004c <main+12>"));
+        assert!(debugger.command("finish").unwrap().ends_with("0x0000004c <main+12>  addu $s0, $v0, $zero\n"));
+
+        debugger.command("set reg $s1 2").unwrap();
+        debugger.command("set mem values+4 -1").unwrap();
+        assert_eq!(debugger.command("x/1w 0x1004").unwrap(), "0x00001004 <values+4>:  0xffffffff\n");
+        debugger.command("b loop").unwrap();
+        let listing = debugger.command("disas $pc 2").unwrap();
+        assert_eq!(listing.lines().collect::<Vec<_>>(), [
+            "=> 0x0000004c <main+12>      addu $s0, $v0, $zero",
+            "  *0x00000050 <loop>         addiu $s1, $s1, 1"
+        ]);
+        assert_eq!(debugger.command("c").unwrap(), "Breakpoint 1, 0x00000050 <loop>  addiu $s1, $s1, 1\n");
+        assert_eq!(debugger.command("c").unwrap(), "Program exited with code 0\n");
+        assert_eq!(debugger.cpu.registers[17], 3);
+    }
+
+    #[test]
+    fn watchpoints_conditions_and_ignore_counts() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.command("break loop if $s1 == 2").unwrap(), "Breakpoint 1 at 0x00000050 <loop>\n");
+        assert!(debugger.command("c").unwrap().starts_with("Breakpoint 1, 0x00000050 <loop>"));
+        assert_eq!(debugger.cpu.registers[17], 2);
+        assert_eq!(debugger.command("condition 1").unwrap(), "Breakpoint 1 now unconditional.\n");
+
+        assert_eq!(debugger.command("watch $s1 * 10").unwrap(), "Watchpoint 2: $s1 * 10\n");
+        assert_eq!(debugger.command("ignore 1 5").unwrap(), "Will ignore next 5 crossings of breakpoint 1.\n");
+        assert_eq!(debugger.command("c").unwrap(),
+            "Watchpoint 2: $s1 * 10\n\nOld value = 20\nNew value = 30\n0x00000054 <loop+4>  bne $s1, $a0, loop\n");
+        let info = debugger.command("info breakpoints").unwrap();
+        assert!(info.contains("1    breakpoint        0x00000050 <loop>\n        breakpoint already hit 1 time\n"));
+        assert!(info.contains("2    watchpoint        $s1 * 10\n        breakpoint already hit 1 time\n"));
+
+        let mut debugger = self::debugger();
+        assert_eq!(debugger.command("awatch values + 4, 4").unwrap(), "Acc watchpoint 1: 4 bytes at 0x00001004\n");
+        debugger.command("wwatch values").unwrap();
+        debugger.command("set reg $t0 values").unwrap();
+        debugger.cpu.program_counter = debugger.cpu.symbols["main"];
+        debugger.command("set mem $pc 0xad090004").unwrap();     // sw $t1, 4($t0)
+        assert_eq!(debugger.command("step").unwrap(),
+            "Watchpoint 1: write of 0x0 at 0x00001004\n0x00000044 <main+4>  addiu $a0, $zero, 3\n");
+        assert_eq!(debugger.command("info breakpoints").unwrap().lines().nth(2).unwrap(), "        breakpoint already hit 1 time");
+    }
+
+    #[test]
+    fn watchpoints_see_what_syscalls_read_and_write() {
+        let source = "
+            .data
+            buffer: .space 8
+            .text
+            la $a0, buffer
+            li $a1, 8
+            li $v0, 8
+            syscall
+            li $v0, 4
+            syscall
+            halt";
+        let mut debugger = Debugger::new(CPU::assembled(source, |cpu, _| {
+            cpu.syscalls = Some(Syscalls::new(Box::new(io::Cursor::new("hi\n")), Box::new(Capture::default())));
+        }));
+        debugger.command("awatch buffer + 1, 1").unwrap();
+        assert_eq!(debugger.command("c").unwrap(),
+            "Watchpoint 1: write of 0x69 at 0x00001001\n0x00000054  addiu $v0, $zero, 4\n");
+        assert_eq!(debugger.command("c").unwrap(),
+            "Watchpoint 1: read of 0x69 at 0x00001001\n0x0000005c  halt\n");
+    }
+
+    #[test]
+    fn labels_ending_in_if_and_calls_at_the_top_of_memory() {
+        let source = "main: li $t0, 1\nendif: halt\ncallee: jr $ra";
+        let mut debugger = Debugger::new(CPU::assembled(source, |_, _| ()));
+        assert_eq!(debugger.command("break endif if $t0 == 1").unwrap(), "Breakpoint 1 at 0x00000044 <endif>\n");
+        assert!(debugger.command("c").unwrap().starts_with("Breakpoint 1, 0x00000044 <endif>"));
+
+        // a jalr in the last word of memory returns to address 0, with pages 0 and 1
+        // mapped onto themselves and the last page of kseg2 onto 0x7000
+        let mut debugger = Debugger::new(CPU::assembled(source, |cpu, _| {
+            let mut mmu = Mmu::new(2);
+            mmu.tlb[0] = TlbEntry { entry_hi: 0, entry_lo0: 0x7, entry_lo1: 0x47 };
+            mmu.tlb[1] = TlbEntry { entry_hi: 0xFFFF_E000, entry_lo0: 0, entry_lo1: 0x1C7 };
+            cpu.mmu = Some(mmu);
+        }));
+        debugger.command("set reg $t9 callee").unwrap();
+        debugger.command("set reg $pc 0xfffffffc").unwrap();
+        debugger.command("set mem $pc 0x0320f809").unwrap();
+        assert!(debugger.command("next").unwrap().starts_with("0x00000000"));
+    }
+
+    #[test]
+    fn reverse_execution_goes_back_to_breakpoints_watchpoints_and_cycles() {
+        let mut debugger = debugger();
+        debugger.command("break loop").unwrap();
+        for _ in 0..3 {
+            debugger.command("c").unwrap();
+        }
+        assert_eq!(debugger.cpu.registers[17], 2);
+        assert_eq!(debugger.command("rc").unwrap(), "Breakpoint 1, 0x00000050 <loop>  addiu $s1, $s1, 1\n");
+        assert_eq!((debugger.cpu.registers[17], debugger.cpu.retired, debugger.breakpoints[0].hits), (1, 9, 4));
+        assert_eq!(debugger.command("reverse-step").unwrap(), "0x00000054 <loop+4>  bne $s1, $a0, loop\n");
+        let record = debugger.command("info record").unwrap();
+        assert!(record.starts_with("Recorded from cycle 0, now at cycle 8.\nUndo records 8, checkpoints 1, memory "));
+
+        debugger.command("delete").unwrap();
+        assert_eq!(debugger.command("c").unwrap(), "Program exited with code 0\n");
+        assert_eq!(debugger.command("watch $s0").unwrap(), "Watchpoint 2: $s0\n");
+        assert_eq!(debugger.command("rc").unwrap(),
+            "Watchpoint 2: $s0\n\nOld value = 0\nNew value = 9\n0x00000050 <loop>  addiu $s1, $s1, 1\n");
+        assert_eq!(debugger.command("record goto 3").unwrap(), "0x0000005c <triple>  addu $v0, $a0, $a0\n");
+        assert_eq!(debugger.cpu.registers[31], 0x4c);
+        assert_eq!(debugger.command("rs 5").unwrap(), "\nNo more reverse-execution history.\n0x00000040 <main>  addiu $sp, $zero, 32752\n");
+        assert_eq!(debugger.command("rsi"), Err(String::from("No more reverse-execution history.")));
+        assert_eq!(debugger.command("record goto 100").unwrap(), "Program exited with code 0\n");
+        assert_eq!(debugger.cpu.registers[17], 3);
+    }
+
+    #[test]
+    fn save_and_restore_go_back_to_a_snapshot() {
+        let path = std::env::temp_dir().join(format!("debugger-{}.snap", std::process::id())).to_string_lossy().into_owned();
+        let mut debugger = debugger();
+        debugger.command("step 4").unwrap();
+        assert_eq!(debugger.command(&format!("save {path}")).unwrap(), format!("Saved cycle 4 to {path}.\n"));
+        debugger.command("continue").unwrap();
+        assert_eq!(debugger.cpu.registers[17], 3);
+
+        let restored = debugger.command(&format!("restore {path}")).unwrap();
+        assert!(restored.starts_with(&format!("Restored cycle 4 from {path}.\n")));
+        assert_eq!((debugger.cpu.registers[17], debugger.cpu.registers[2]), (0, 6));
+        assert_eq!(debugger.command("rsi"), Err(String::from("No more reverse-execution history.")));
+        assert_eq!(debugger.command("continue").unwrap(), "Program exited with code 0\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reverse_continue_winds_the_timer_back_too() {
+        // a periodic timer on HW1, taken three times
+        let source = "
+            lui $s0, 0xffff
+            li $t0, 10
+            sw $t0, 0x14($s0)
+            li $t0, 7
+            sw $t0, 0x10($s0)
+            li $t0, 0x801
+            mtc0 $t0, $12
+            li $t1, 3
+            spin: bne $s1, $t1, spin
+            halt
+            handler: addiu $s1, $s1, 1
+            li $k0, 1
+            sw $k0, 0x1c($s0)
+            eret";
+        let mut debugger = Debugger::new(CPU::assembled(source, |cpu, symbols| {
+            cpu.bus.map("timer", timer::BASE, Permissions::RW, Box::new(Timer::new())).unwrap();
+            cpu.interrupts.route("timer", 1).unwrap();
+            cpu.exception_handler = Some(symbols["handler"]);
+        }));
+        let timer = |debugger: &Debugger| {
+            Snapshot::of(&debugger.cpu).regions.into_iter().find(|image| image.name == "timer").unwrap().state
+        };
+        debugger.command("break handler").unwrap();
+        for _ in 0..3 {
+            debugger.command("c").unwrap();
+        }
+        let third = (Snapshot::of(&debugger.cpu), timer(&debugger), debugger.cpu.interrupts.log.len());
+        debugger.command("delete").unwrap();
+        assert_eq!(debugger.command("c").unwrap(), "Program exited with code 0\n");
+        assert_ne!(timer(&debugger), third.1);
+
+        debugger.command("break handler").unwrap();
+        assert!(debugger.command("rc").unwrap().starts_with("Breakpoint 2, "));
+        assert_eq!((timer(&debugger), debugger.cpu.interrupts.log.len()), (third.1, third.2));
+        assert!(Snapshot::of(&debugger.cpu) == third.0);
+    }
+
+    #[test]
+    fn nothing_runs_backwards_with_a_uart_mapped() {
+        let (streams, output) = Streams::buffer(b"");
+        let mut debugger = Debugger::new(CPU::assembled("lui $s0, 0xffff\nli $t0, 104\nsw $t0, 12($s0)\nhalt", |cpu, _| {
+            cpu.bus.map("uart", uart::MARS_BASE, Permissions::RW, Box::new(Uart::new(Box::new(streams)))).unwrap();
+        }));
+        debugger.command("step 3").unwrap();
+        let refused = Err(String::from("Reverse execution is not supported with uart mapped, what it sent can't be taken back."));
+        assert_eq!(debugger.command("rs"), refused);
+        assert_eq!(debugger.command("rc"), refused);
+        assert_eq!(debugger.command("record goto 1"), refused);
+        assert_eq!((debugger.cpu.retired, output.text()), (3, String::from("h")));
+    }
+    #[test]
+    fn nothing_runs_backwards_with_caches_or_a_predictor() {
+        let refused = Err(String::from("Reverse execution is not supported with caches or a branch predictor, they are not wound back."));
+        let mut cached = Debugger::new(CPU::assembled(SOURCE, |cpu, _| cpu.caches = Some(Hierarchy::default())));
+        let mut predicted = Debugger::new(CPU::assembled(SOURCE, |cpu, _| {
+            cpu.branches = Some(BranchUnit::new(predictor::by_name("2bit").unwrap(), None, 2));
+        }));
+        for debugger in [&mut cached, &mut predicted] {
+            debugger.command("step 3").unwrap();
+            assert_eq!(debugger.command("rs"), refused);
+            assert_eq!(debugger.command("rc"), refused);
+            assert_eq!(debugger.command("record goto 1"), refused);
+            assert_eq!(debugger.cpu.retired, 3);
+        }
+    }
+}
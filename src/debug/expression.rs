@@ -0,0 +1,293 @@
+use std::fmt;
+use crate::hardware::arch;
+use crate::hardware::bus::Bus;
+use crate::hardware::cpu::CPU;
+use crate::hardware::mmu::Access;
+
+/*
+ * Debugger expressions, C operators over 64 bit integers. Operands are numbers, registers
+ * ($t0, $8, $pc, $hi, $lo), labels, which stand for their address, and memory read at a
+ * virtual address as mem8[...], mem16[...] or mem32[...]. Registers are signed, memory is
+ * not, and comparisons and logical operators give 1 or 0
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Or, And, BitOr, BitXor, BitAnd,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    ShiftLeft, ShiftRight, Add, Subtract, Multiply, Divide, Remainder
+}
+
+impl Operator {
+    // binding power, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::BitOr => 3,
+            Operator::BitXor => 4,
+            Operator::BitAnd => 5,
+            Operator::Equal | Operator::NotEqual => 6,
+            Operator::Less | Operator::LessEqual | Operator::Greater | Operator::GreaterEqual => 7,
+            Operator::ShiftLeft | Operator::ShiftRight => 8,
+            Operator::Add | Operator::Subtract => 9,
+            Operator::Multiply | Operator::Divide | Operator::Remainder => 10
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(u32),
+    Pc,
+    Hi,
+    Lo,
+    Symbol(String),
+    Memory(u32, Box<Node>),     // width in bytes and the address
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Complement(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),           // a label or memN
+    Register(String),       // without the $
+    Operator(&'static str),
+    Open(char),
+    Close(char)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    text: String,
+    root: Node
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let root = parser.expression(0)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("unexpected {token:?} in {text}"));
+        }
+        Ok(Expression { text: text.trim().to_string(), root })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> Result<i64, String> {
+        evaluate(&self.root, cpu)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    const OPERATORS: [&str; 21] = ["||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+        "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "!", "~", "="];
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let word_end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+        let (token, length) = if c.is_ascii_digit() {
+            let word = &rest[..word_end];
+            let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse()
+            };
+            (Token::Number(number.map_err(|_| format!("bad number {word}"))?), word_end)
+        } else if c == '$' {
+            let end = rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).map_or(rest.len(), |end| end + 1);
+            (Token::Register(rest[1..end].to_string()), end)
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            (Token::Name(rest[..word_end].to_string()), word_end)
+        } else if c == '(' || c == '[' {
+            (Token::Open(c), 1)
+        } else if c == ')' || c == ']' {
+            (Token::Close(c), 1)
+        } else {
+            let operator = OPERATORS.iter().find(|op| rest.starts_with(**op)).ok_or_else(|| format!("unexpected '{c}' in {text}"))?;
+            if *operator == "=" {
+                return Err(String::from("use == to compare"));
+            }
+            (Token::Operator(operator), operator.len())
+        };
+        tokens.push(token);
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_operator(&self) -> Option<Operator> {
+        let Some(Token::Operator(text)) = self.tokens.get(self.position) else { return None };
+        Some(match *text {
+            "||" => Operator::Or,
+            "&&" => Operator::And,
+            "|" => Operator::BitOr,
+            "^" => Operator::BitXor,
+            "&" => Operator::BitAnd,
+            "==" => Operator::Equal,
+            "!=" => Operator::NotEqual,
+            "<" => Operator::Less,
+            "<=" => Operator::LessEqual,
+            ">" => Operator::Greater,
+            ">=" => Operator::GreaterEqual,
+            "<<" => Operator::ShiftLeft,
+            ">>" => Operator::ShiftRight,
+            "+" => Operator::Add,
+            "-" => Operator::Subtract,
+            "*" => Operator::Multiply,
+            "/" => Operator::Divide,
+            "%" => Operator::Remainder,
+            _ => return None
+        })
+    }
+
+    // Operators binding tighter than minimum, left to right
+    fn expression(&mut self, minimum: u8) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        while let Some(operator) = self.peek_operator().filter(|op| op.precedence() > minimum) {
+            self.position += 1;
+            let right = self.expression(operator.precedence())?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Operator("-")) => Ok(Node::Negate(Box::new(self.unary()?))),
+            Some(Token::Operator("!")) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::Operator("~")) => Ok(Node::Complement(Box::new(self.unary()?))),
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Register(name)) => match name.as_str() {
+                "pc" => Ok(Node::Pc),
+                "hi" => Ok(Node::Hi),
+                "lo" => Ok(Node::Lo),
+                _ => arch::register_number(&name).map(Node::Register).ok_or_else(|| format!("no register ${name}"))
+            },
+            Some(Token::Open('(')) => {
+                let inner = self.expression(0)?;
+                self.close(')')?;
+                Ok(inner)
+            }
+            Some(Token::Name(name)) => {
+                let width = match name.as_str() {
+                    "mem8" => 1,
+                    "mem16" => 2,
+                    "mem32" => 4,
+                    _ => return Ok(Node::Symbol(name))
+                };
+                if self.next() != Some(Token::Open('[')) {
+                    return Err(format!("{name} takes an address in brackets"));
+                }
+                let address = self.expression(0)?;
+                self.close(']')?;
+                Ok(Node::Memory(width, Box::new(address)))
+            }
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err(String::from("expression ends too soon"))
+        }
+    }
+
+    fn close(&mut self, bracket: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close(c)) if c == bracket => Ok(()),
+            _ => Err(format!("missing '{bracket}'"))
+        }
+    }
+}
+
+fn evaluate(node: &Node, cpu: &CPU) -> Result<i64, String> {
+    Ok(match node {
+        Node::Number(n) => *n,
+        Node::Register(n) => cpu.registers[*n as usize] as i64,
+        Node::Pc => cpu.program_counter as i64,
+        Node::Hi => cpu.hi as i64,
+        Node::Lo => cpu.lo as i64,
+        Node::Symbol(name) => *cpu.symbols.get(name).ok_or_else(|| format!("No symbol \"{name}\"."))? as i64,
+        Node::Memory(width, address) => {
+            let address = evaluate(address, cpu)? as u32;
+            cpu.physical(address, Access::Load)
+                .and_then(|physical| cpu.bus.peek(physical, *width))
+                .ok_or_else(|| format!("Cannot access memory at {address:#010x}"))? as i64
+        }
+        Node::Negate(inner) => evaluate(inner, cpu)?.wrapping_neg(),
+        Node::Not(inner) => (evaluate(inner, cpu)? == 0) as i64,
+        Node::Complement(inner) => !evaluate(inner, cpu)?,
+        Node::Binary(operator, left, right) => {
+            let left = evaluate(left, cpu)?;
+            // && and || don't look at the right side once the left decides
+            match (operator, left != 0) {
+                (Operator::And, false) => return Ok(0),
+                (Operator::Or, true) => return Ok(1),
+                _ => ()
+            }
+            let right = evaluate(right, cpu)?;
+            match operator {
+                Operator::Or | Operator::And => (right != 0) as i64,
+                Operator::BitOr => left | right,
+                Operator::BitXor => left ^ right,
+                Operator::BitAnd => left & right,
+                Operator::Equal => (left == right) as i64,
+                Operator::NotEqual => (left != right) as i64,
+                Operator::Less => (left < right) as i64,
+                Operator::LessEqual => (left <= right) as i64,
+                Operator::Greater => (left > right) as i64,
+                Operator::GreaterEqual => (left >= right) as i64,
+                Operator::ShiftLeft => left.wrapping_shl(right as u32),
+                Operator::ShiftRight => left.wrapping_shr(right as u32),
+                Operator::Add => left.wrapping_add(right),
+                Operator::Subtract => left.wrapping_sub(right),
+                Operator::Multiply => left.wrapping_mul(right),
+                Operator::Divide => left.checked_div(right).ok_or("Division by zero")?,
+                Operator::Remainder => left.checked_rem(right).ok_or("Division by zero")?
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_with_c_precedence_over_machine_state() {
+        let mut cpu = CPU::new();
+        cpu.debug_mode = false;
+        cpu.registers[8] = 5;
+        cpu.registers[29] = 0x7FF0;
+        cpu.registers[9] = -2;
+        cpu.write_word_to_mem(0x7FF4, 0x8000_0001);
+        cpu.symbols.insert(String::from("buffer"), 0x1000);
+        let value = |text: &str| Expression::parse(text).and_then(|e| e.evaluate(&cpu));
+
+        assert_eq!(value("$t0 == 5 && mem32[$sp+4] > 0"), Ok(1));
+        assert_eq!(value("1 + 2 * 3 << 1"), Ok(14));
+        assert_eq!(value("$t1 < 0 || 1 / 0"), Ok(1));
+        assert_eq!(value("mem16[$sp + 4] | mem8[0x7ff7]"), Ok(0x8001));
+        assert_eq!(value("-(buffer + 4) & 0xffff"), Ok(0xEFFC));
+        assert_eq!(value("!$zero + ~0 + $8 + $hi"), Ok(5));
+        assert_eq!(value("mem32[0x10000]"), Err(String::from("Cannot access memory at 0x00010000")));
+        assert!(value("$t0 = 5").is_err() && value("(1 + 2").is_err() && value("nowhere").is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use crate::hardware::arch::Fault;
+use crate::hardware::bus::Bus;
+use crate::hardware::cp0::ExceptionCode;
+use crate::hardware::cp1;
+use crate::hardware::mmu::Access;
+use super::breakpoint::Kind;
+use super::debugger::{Debugger, Stop};
+
+/*
+ * GDB remote serial protocol stub, so gdb can drive the simulator with target remote.
+ * Registers are numbered as gdb numbers them for MIPS, the 32 GPRs, then status, lo, hi,
+ * badvaddr, cause and pc, then the FPU registers, fcsr and fir, and a target description
+ * tells gdb so. Breakpoints and watchpoints set with Z packets are the debugger's own, and
+ * while the program runs the connection is looked at now and then for gdb's interrupt
+ */
+
+const REGISTERS: usize = 72;
+const PC: usize = 37;
+const POLL_EVERY: u64 = 4096;   // steps between looks for an interrupt
+const INTERRUPT: u8 = 0x03;
+
+// Signals as gdb numbers them, for stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 12;
+
+// A connection to gdb, which can be looked at for a byte while the program runs
+pub trait Transport: Read + Write {
+    fn poll(&mut self) -> io::Result<Option<u8>>;
+}
+
+impl Transport for TcpStream {
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+// Wait for gdb on a TCP port, host:port, or a Unix socket path, and serve it until it goes
+pub fn listen(debugger: &mut Debugger, address: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    if address.contains('/') {
+        let _ = std::fs::remove_file(address);
+        let listener = UnixListener::bind(address)?;
+        println!("Waiting for gdb on {address}");
+        let (connection, _) = listener.accept()?;
+        return Stub::new(debugger, connection).serve();
+    }
+    let address = if address.contains(':') { address.to_string() } else { format!("127.0.0.1:{address}") };
+    let listener = TcpListener::bind(&address)?;
+    println!("Waiting for gdb on {address}");
+    let (connection, _) = listener.accept()?;
+    connection.set_nodelay(true)?;
+    Stub::new(debugger, connection).serve()
+}
+
+pub struct Stub<'a, T: Transport> {
+    debugger: &'a mut Debugger,
+    connection: T,
+    acknowledge: bool,      // until gdb asks for no-ack mode
+    sent: Vec<u8>,          // the last packet, sent again if gdb asks
+    stopped: String         // the reply to ?
+}
+
+impl<'a, T: Transport> Stub<'a, T> {
+    pub fn new(debugger: &'a mut Debugger, connection: T) -> Self {
+        Stub { debugger, connection, acknowledge: true, sent: Vec::new(), stopped: format!("S{SIGTRAP:02x}") }
+    }
+
+    // Answer packets until gdb detaches, kills the program or hangs up
+    pub fn serve(&mut self) -> io::Result<()> {
+        match self.session() {
+            Err(e) if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::ConnectionReset) => Ok(()),
+            result => result
+        }
+    }
+
+    fn session(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match packet.as_slice() {
+                b"k" | b"vKill;1" => return Ok(()),
+                [b'D', ..] => return self.send(b"OK"),
+                _ => {
+                    let reply = self.reply(&packet);
+                    self.send(reply.as_bytes())?;
+                    if packet == b"QStartNoAckMode" {
+                        self.acknowledge = false;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, packet: &[u8]) -> String {
+        // X carries binary data, everything else is text
+        if let Some(rest) = packet.strip_prefix(b"X") {
+            return self.write_binary(rest).unwrap_or_else(|| String::from("E01"));
+        }
+        // an empty packet gets the empty reply, as for any command we don't know
+        let Some((&command, rest)) = packet.split_first() else { return String::new() };
+        let text = String::from_utf8_lossy(packet);
+        let arguments = &*String::from_utf8_lossy(rest);
+        let reply = match command {
+            b'?' => Some(self.stopped.clone()),
+            b'g' => Some((0..REGISTERS).map(|n| self.encode(self.register(n))).collect()),
+            b'G' => self.write_registers(arguments),
+            b'p' => usize::from_str_radix(arguments, 16).ok().filter(|&n| n < REGISTERS).map(|n| self.encode(self.register(n))),
+            b'P' => self.write_register(arguments),
+            b'm' => self.read_memory(arguments),
+            b'M' => self.write_memory(arguments),
+            b'c' | b's' => Some(self.resume(command == b's', arguments)),
+            b'Z' | b'z' => self.breakpoint(command == b'Z', arguments),
+            b'H' | b'T' => Some(String::from("OK")),
+            b'q' | b'Q' | b'v' => Some(self.query(&text)),
+            _ => Some(String::new())
+        };
+        reply.unwrap_or_else(|| String::from("E01"))
+    }
+
+    fn query(&self, text: &str) -> String {
+        if let Some(range) = text.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = range.split_once(',') else { return String::from("E01") };
+            let (Ok(offset), Ok(length)) = (usize::from_str_radix(offset, 16), usize::from_str_radix(length, 16)) else {
+                return String::from("E01");
+            };
+            let description = target_description();
+            let chunk = description.get(offset.min(description.len())..).unwrap_or("");
+            let more = chunk.len() > length;
+            return format!("{}{}", if more { 'm' } else { 'l' }, &chunk[..length.min(chunk.len())]);
+        }
+        match text.split(':').next().unwrap_or(text) {
+            "qSupported" => String::from("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+"),
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "qSymbol" => String::from("OK"),
+            _ => String::new()
+        }
+    }
+
+    // Register n in gdb's numbering
+    fn register(&self, n: usize) -> u32 {
+        let cpu = &self.debugger.cpu;
+        match n {
+            0..=31 => cpu.registers[n] as u32,
+            32 => cpu.cp0.status,
+            33 => cpu.lo as u32,
+            34 => cpu.hi as u32,
+            35 => cpu.cp0.bad_vaddr,
+            36 => cpu.cp0.cause,
+            PC => cpu.program_counter,
+            38..=69 => cpu.cp1.registers[n - 38],
+            70 => cpu.cp1.fcsr,
+            _ => cpu.cp1.read_control(cp1::FIR)
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) {
+        let cpu = &mut self.debugger.cpu;
+        match n {
+            1..=31 => cpu.registers[n] = value as i32,
+            32 => cpu.cp0.status = value,
+            33 => cpu.lo = value as i32,
+            34 => cpu.hi = value as i32,
+            35 => cpu.cp0.bad_vaddr = value,
+            36 => cpu.cp0.cause = value,
+            PC => cpu.program_counter = value,
+            38..=69 => cpu.cp1.registers[n - 38] = value,
+            70 => cpu.cp1.write_control(cp1::FCSR, value),
+            _ => ()     // $zero and fir can't be written
+        }
+    }
+
+    // A register as gdb expects it, in the target's byte order
+    fn encode(&self, value: u32) -> String {
+        hex(&self.debugger.cpu.bus.endian.to_bytes(value, 4))
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> Option<String> {
+        let bytes = unhex(arguments)?;
+        for (n, word) in bytes.chunks_exact(4).enumerate().take(REGISTERS) {
+            let value = self.debugger.cpu.bus.endian.from_bytes(word);
+            self.set_register(n, value);
+        }
+        Some(String::from("OK"))
+    }
+
+    // P n=value
+    fn write_register(&mut self, arguments: &str) -> Option<String> {
+        let (n, value) = arguments.split_once('=')?;
+        let n = usize::from_str_radix(n, 16).ok().filter(|&n| n < REGISTERS)?;
+        let bytes = unhex(value).filter(|bytes| bytes.len() == 4)?;
+        let value = self.debugger.cpu.bus.endian.from_bytes(&bytes);
+        self.set_register(n, value);
+        Some(String::from("OK"))
+    }
+
+    // m address,length, as much as can be read
+    fn read_memory(&self, arguments: &str) -> Option<String> {
+        let (address, length) = address_length(arguments)?;
+        let cpu = &self.debugger.cpu;
+        let bytes: Vec<u8> = (0..length)
+            .map_while(|i| {
+                let address = address.wrapping_add(i);
+                cpu.physical(address, Access::Load).and_then(|physical| cpu.bus.peek(physical, 1)).map(|byte| byte as u8)
+            })
+            .collect();
+        (!bytes.is_empty() || length == 0).then(|| hex(&bytes))
+    }
+
+    // M address,length:bytes in hex
+    fn write_memory(&mut self, arguments: &str) -> Option<String> {
+        let (range, data) = arguments.split_once(':')?;
+        let (address, length) = address_length(range)?;
+        let bytes = unhex(data).filter(|bytes| bytes.len() as u32 == length)?;
+        self.store(address, &bytes)
+    }
+
+    // X address,length:bytes escaped
+    fn write_binary(&mut self, arguments: &[u8]) -> Option<String> {
+        let colon = arguments.iter().position(|&b| b == b':')?;
+        let (address, length) = address_length(std::str::from_utf8(&arguments[..colon]).ok()?)?;
+        let mut bytes = Vec::new();
+        let mut data = arguments[colon + 1..].iter();
+        while let Some(&byte) = data.next() {
+            bytes.push(if byte == b'}' { data.next()? ^ 0x20 } else { byte });
+        }
+        (bytes.len() as u32 == length).then_some(())?;
+        self.store(address, &bytes)
+    }
+
+    fn store(&mut self, address: u32, bytes: &[u8]) -> Option<String> {
+        let cpu = &mut self.debugger.cpu;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let address = address.wrapping_add(i as u32);
+            cpu.physical(address, Access::Store).and_then(|physical| cpu.write_byte_to_mem(physical, byte))?;
+        }
+        Some(String::from("OK"))
+    }
+
+    // c [address] or s [address], running from address if there is one
+    fn resume(&mut self, step: bool, arguments: &str) -> String {
+        if let Ok(address) = u32::from_str_radix(arguments, 16) {
+            self.debugger.cpu.program_counter = address;
+        }
+        let connection = &mut self.connection;
+        let (mut steps, mut interrupted) = (0u64, false);
+        let stop = self.debugger.run(|_| {
+            steps += 1;
+            if !step && steps.is_multiple_of(POLL_EVERY) {
+                interrupted = matches!(connection.poll(), Ok(Some(INTERRUPT)));
+            }
+            step || interrupted
+        });
+        self.stopped = match stop {
+            Stop::Stepped if interrupted => format!("S{SIGINT:02x}"),
+            Stop::Stepped => format!("S{SIGTRAP:02x}"),
+            Stop::Breakpoint(ids, _) => {
+                let watched = self.debugger.breakpoints.iter()
+                    .filter(|b| ids.contains(&b.id))
+                    .find_map(|b| match (&b.kind, b.access) {
+                        (Kind::Access { read, write, .. }, Some(access)) => {
+                            let kind = if *read && *write { "awatch" } else if access.access == Access::Store { "watch" } else { "rwatch" };
+                            Some(format!("{kind}:{:x};", access.address))
+                        }
+                        _ => None
+                    });
+                format!("T{SIGTRAP:02x}{}", watched.unwrap_or_default())
+            }
+            Stop::Exited(code) => format!("W{:02x}", code as u8),
+            Stop::Fault(fault) => format!("S{:02x}", signal(&fault))
+        };
+        self.stopped.clone()
+    }
+
+    // Z type,address,kind inserts and z removes, 0 and 1 breakpoints, 2 write, 3 read and
+    // 4 access watchpoints
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> Option<String> {
+        let mut fields = arguments.split(',');
+        let kind = fields.next()?;
+        let address = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u32::from_str_radix(fields.next()?.split(';').next()?, 16).ok()?;
+        let kind = match kind {
+            "0" | "1" => Kind::Address(address),
+            "2" => Kind::Access { start: address, length, read: false, write: true },
+            "3" => Kind::Access { start: address, length, read: true, write: false },
+            "4" => Kind::Access { start: address, length, read: true, write: true },
+            _ => return Some(String::new())
+        };
+        let breakpoints = &mut self.debugger.breakpoints;
+        if !insert {
+            breakpoints.retain(|b| b.kind != kind);
+        } else if !breakpoints.iter().any(|b| b.kind == kind) {
+            self.debugger.add(kind);
+        }
+        Some(String::from("OK"))
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(b'-') => {
+                    self.connection.write_all(&self.sent)?;
+                    continue;
+                }
+                Some(_) => continue     // acknowledgements, and interrupts with nothing running
+            }
+            let mut packet = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte)
+                }
+            }
+            let (Some(high), Some(low)) = (self.byte()?, self.byte()?) else { return Ok(None) };
+            let checksum = std::str::from_utf8(&[high, low]).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            let good = checksum == Some(packet.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+            if self.acknowledge {
+                self.connection.write_all(if good { b"+" } else { b"-" })?;
+            }
+            if good || !self.acknowledge {
+                return Ok(Some(packet));
+            }
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.connection.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for &byte in data {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => packet.extend([b'}', byte ^ 0x20]),
+                _ => packet.push(byte)
+            }
+        }
+        let checksum = packet[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        write!(packet, "#{checksum:02x}")?;
+        self.connection.write_all(&packet)?;
+        self.connection.flush()?;
+        self.sent = packet;
+        Ok(())
+    }
+}
+
+// The signal gdb is told a fault stopped the program with
+fn signal(fault: &Fault) -> u8 {
+    match fault {
+        Fault::Exception(exception) => match exception.code {
+            ExceptionCode::AddressLoad | ExceptionCode::AddressStore | ExceptionCode::TlbLoad
+                | ExceptionCode::TlbStore | ExceptionCode::TlbModified => SIGSEGV,
+            ExceptionCode::InstructionBus | ExceptionCode::DataBus => SIGBUS,
+            ExceptionCode::ReservedInstruction | ExceptionCode::CoprocessorUnusable => SIGILL,
+            ExceptionCode::Overflow | ExceptionCode::FloatingPoint => SIGFPE,
+            _ => SIGTRAP
+        },
+        Fault::Syscall { .. } => SIGSYS
+    }
+}
+
+// The registers as gdb numbers them, in the features its MIPS support looks for
+fn target_description() -> String {
+    let mut cpu = String::new();
+    for n in 0..32 {
+        writeln!(cpu, r#"    <reg name="r{n}" bitsize="32" regnum="{n}"/>"#).unwrap();
+    }
+    let mut fpu = String::new();
+    for n in 0..32 {
+        writeln!(fpu, r#"    <reg name="f{n}" bitsize="32" type="ieee_single" regnum="{}"/>"#, 38 + n).unwrap();
+    }
+    format!(r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>mips</architecture>
+  <feature name="org.gnu.gdb.mips.cpu">
+{cpu}    <reg name="lo" bitsize="32" regnum="33"/>
+    <reg name="hi" bitsize="32" regnum="34"/>
+    <reg name="pc" bitsize="32" type="code_ptr" regnum="37"/>
+  </feature>
+  <feature name="org.gnu.gdb.mips.cp0">
+    <reg name="status" bitsize="32" regnum="32"/>
+    <reg name="badvaddr" bitsize="32" regnum="35"/>
+    <reg name="cause" bitsize="32" regnum="36"/>
+  </feature>
+  <feature name="org.gnu.gdb.mips.fpu">
+{fpu}    <reg name="fcsr" bitsize="32" group="float" regnum="70"/>
+    <reg name="fir" bitsize="32" group="float" regnum="71"/>
+  </feature>
+</target>
+"#)
+}
+
+// address,length in hex
+fn address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::hardware::cpu::CPU;
+
+    // Count to 5 in memory, then exit with 3
+    const SOURCE: &str = "
+        .data
+        value: .word 0
+        .text
+        main: la $s0, value
+        li $t0, 5
+        loop: addiu $t1, $t1, 1
+        sw $t1, 0($s0)
+        bne $t1, $t0, loop
+        li $a0, 3
+        li $v0, 17
+        syscall";
+
+    // The other end of the connection, as gdb would be
+    struct Client {
+        stream: TcpStream,
+        acknowledge: bool
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.stream, "${data}#{checksum:02x}").unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+            let checksum = String::from_utf8(vec![self.byte(), self.byte()]).unwrap();
+            assert_eq!(u8::from_str_radix(&checksum, 16), Ok(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))));
+            if self.acknowledge {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    // Serve a debugger over source on a thread, giving the client and what the session left in $t1
+    fn session(source: &'static str) -> (Client, thread::JoinHandle<i32>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut debugger = Debugger::new(CPU::assembled(source, |_, _| ()));
+            let (connection, _) = listener.accept().unwrap();
+            connection.set_nodelay(true).unwrap();
+            Stub::new(&mut debugger, connection).serve().unwrap();
+            debugger.cpu.registers[9]
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        (Client { stream, acknowledge: true }, server)
+    }
+
+    #[test]
+    fn gdb_sets_breakpoints_and_watchpoints_and_reads_state() {
+        let (mut gdb, server) = session(SOURCE);
+        assert!(gdb.request("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        assert_eq!(gdb.request("QStartNoAckMode"), "OK");
+        gdb.acknowledge = false;
+        assert_eq!(gdb.request("?"), "S05");
+        assert_eq!((gdb.request(""), gdb.request("\u{e9}t\u{e9}")), (String::new(), String::new()));
+        let mut description = String::new();
+        loop {
+            let chunk = gdb.request(&format!("qXfer:features:read:target.xml:{:x},800", description.len()));
+            description += &chunk[1..];
+            if chunk.starts_with('l') {
+                break;
+            }
+        }
+        assert_eq!(description, target_description());
+        assert!(description.contains(r#"<reg name="pc" bitsize="32" type="code_ptr" regnum="37"/>"#));
+
+        let registers = gdb.request("g");
+        assert_eq!((registers.len(), &registers[PC * 8..PC * 8 + 8]), (REGISTERS * 8, "00000040"));
+        assert_eq!(gdb.request("Z0,4c,4"), "OK");
+        assert_eq!(gdb.request("c"), "T05");
+        assert_eq!((gdb.request("p25"), gdb.request("p9")), (String::from("0000004c"), String::from("00000000")));
+
+        assert_eq!(gdb.request("z0,4c,4"), "OK");
+        assert_eq!(gdb.request("Z2,1000,4"), "OK");
+        assert_eq!(gdb.request("c"), "T05watch:1000;");
+        assert_eq!(gdb.request("m1000,4"), "00000001");
+        assert_eq!(gdb.request("M1000,4:00000063"), "OK");
+        assert_eq!(gdb.request("m1000,2"), "0000");
+        assert_eq!(gdb.request("P9=00000003"), "OK");
+        assert_eq!(gdb.request("c"), "T05watch:1000;");
+        assert_eq!(gdb.request("m1000,4"), "00000004");
+
+        assert_eq!(gdb.request("z2,1000,4"), "OK");
+        assert_eq!(gdb.request("s"), "S05");
+        assert_eq!(gdb.request("p25"), "0000004c");
+        assert_eq!(gdb.request("m10000000,4"), "E01");
+        assert_eq!(gdb.request("c"), "W03");
+        gdb.stream.write_all(b"$k#6b").unwrap();
+        assert_eq!(server.join().unwrap(), 5);
+    }
+
+    #[test]
+    fn an_interrupt_stops_a_running_program() {
+        let (mut gdb, server) = session("spin: b spin");
+        let checksum = b'c';
+        write!(gdb.stream, "$c#{checksum:02x}").unwrap();
+        thread::sleep(std::time::Duration::from_millis(20));
+        gdb.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(gdb.reply(), "S02");
+        assert_eq!(gdb.request("p25"), "00000040");
+        assert_eq!(gdb.request("D"), "OK");
+        server.join().unwrap();
+    }
+}
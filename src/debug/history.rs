@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+use crate::hardware::arch::Fault;
+use crate::hardware::cp0::Cp0;
+use crate::hardware::cp1::Cp1;
+use crate::hardware::cpu::CPU;
+use crate::hardware::mmu::TlbEntry;
+
+/*
+ * Execution history for running backwards. Every step leaves an undo record of what it
+ * changed: the registers it wrote, the device registers that moved, and the bytes it
+ * stored over with what they held before. Going back a step applies the newest record.
+ * Every so many steps a checkpoint of all registers, devices and memory is kept too.
+ * Records and checkpoints share a memory budget. Past it the records from before the
+ * newest checkpoint go first, since running forward from a checkpoint stands in for them,
+ * then the oldest checkpoints. A point the records no longer reach is found by restoring
+ * the checkpoint before it and running forward again. Points are counted in instructions
+ * retired, the cycles of the debug trace. Caches and predictors are not wound back, so a
+ * machine with either is not run backwards, as their stats and timing would not match
+ * running forward again. Nor is one with a device mapped that reaches outside the
+ * machine, like a UART: what it sent can't be taken back, and running forward again would
+ * send it twice
+ */
+
+pub const BUDGET: usize = 64 << 20;    // bytes
+pub const INTERVAL: u64 = 10_000;      // steps between checkpoints
+
+// Registers and counters, everything a step can change but memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    retired: u64,
+    cycles: u64,
+    pc: u32,
+    registers: Vec<i32>,
+    hi: i32,
+    lo: i32,
+    cp0: Cp0,
+    cp1: Cp1,
+    heap_end: u32,
+    tlb: Option<Vec<TlbEntry>>,
+    devices: Vec<Vec<u32>>, // Device::save of every region, in map order
+    interrupts: usize       // interrupts logged
+}
+
+impl State {
+    fn of(cpu: &CPU) -> Self {
+        State {
+            retired: cpu.retired,
+            cycles: cpu.cycles,
+            pc: cpu.program_counter,
+            registers: cpu.registers.clone(),
+            hi: cpu.hi,
+            lo: cpu.lo,
+            cp0: cpu.cp0,
+            cp1: cpu.cp1,
+            heap_end: cpu.heap_end,
+            tlb: cpu.mmu.as_ref().map(|mmu| mmu.tlb.clone()),
+            devices: cpu.bus.regions().iter().map(|region| region.device.save()).collect(),
+            interrupts: cpu.interrupts.log.len()
+        }
+    }
+
+    fn restore(&self, cpu: &mut CPU) {
+        cpu.retired = self.retired;
+        cpu.cycles = self.cycles;
+        cpu.program_counter = self.pc;
+        cpu.registers.clone_from(&self.registers);
+        (cpu.hi, cpu.lo) = (self.hi, self.lo);
+        (cpu.cp0, cpu.cp1) = (self.cp0, self.cp1);
+        cpu.heap_end = self.heap_end;
+        if let (Some(mmu), Some(tlb)) = (&mut cpu.mmu, &self.tlb) {
+            mmu.tlb.clone_from(tlb);
+        }
+        restore_devices(cpu, &self.devices);
+        cpu.interrupts.log.truncate(self.interrupts);
+        cpu.take_exit_code();
+    }
+}
+
+// The regions are the ones saved from, the bus isn't remapped while there is a history
+fn restore_devices(cpu: &mut CPU, devices: &[Vec<u32>]) {
+    for (region, words) in cpu.bus.regions_mut().iter_mut().zip(devices) {
+        region.device.restore(words).expect("a device takes back its own state");
+    }
+}
+
+// A step undone, what it changed as it was before. Count moves on every instruction, the
+// rest of CP0 and CP1 are kept only when the step changed them
+struct Undo {
+    retired: u64,
+    cycles: u64,
+    pc: u32,
+    count: u32,
+    registers: Vec<(u8, i32)>,
+    hi_lo: Option<(i32, i32)>,
+    coprocessors: Option<Box<(Cp0, Cp1)>>,
+    heap_end: Option<u32>,
+    tlb: Option<Vec<TlbEntry>>,
+    devices: Option<Vec<Vec<u32>>>,
+    interrupts: usize,
+    memory: Vec<(u32, u8)>      // physical bytes in the order they were written over
+}
+
+impl Undo {
+    fn between(before: State, after: &State, memory: Vec<(u32, u8)>) -> Self {
+        let registers = before.registers.iter().zip(&after.registers).enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(n, (&old, _))| (n as u8, old))
+            .collect();
+        let coprocessors = (Cp0 { count: after.cp0.count, ..before.cp0 } != after.cp0 || before.cp1 != after.cp1)
+            .then(|| Box::new((before.cp0, before.cp1)));
+        Undo {
+            retired: before.retired,
+            cycles: before.cycles,
+            pc: before.pc,
+            count: before.cp0.count,
+            registers,
+            hi_lo: ((before.hi, before.lo) != (after.hi, after.lo)).then_some((before.hi, before.lo)),
+            coprocessors,
+            heap_end: (before.heap_end != after.heap_end).then_some(before.heap_end),
+            tlb: before.tlb.filter(|tlb| Some(tlb) != after.tlb.as_ref()),
+            devices: (before.devices != after.devices).then_some(before.devices),
+            interrupts: before.interrupts,
+            memory
+        }
+    }
+
+    fn apply(&self, cpu: &mut CPU) {
+        cpu.retired = self.retired;
+        cpu.cycles = self.cycles;
+        cpu.program_counter = self.pc;
+        for &(n, value) in &self.registers {
+            cpu.registers[n as usize] = value;
+        }
+        if let Some((hi, lo)) = self.hi_lo {
+            (cpu.hi, cpu.lo) = (hi, lo);
+        }
+        match &self.coprocessors {
+            Some(coprocessors) => (cpu.cp0, cpu.cp1) = **coprocessors,
+            None => cpu.cp0.count = self.count
+        }
+        if let Some(heap_end) = self.heap_end {
+            cpu.heap_end = heap_end;
+        }
+        if let (Some(mmu), Some(tlb)) = (&mut cpu.mmu, &self.tlb) {
+            mmu.tlb.clone_from(tlb);
+        }
+        if let Some(devices) = &self.devices {
+            restore_devices(cpu, devices);
+        }
+        cpu.interrupts.log.truncate(self.interrupts);
+        // the first value a byte had is the one to go back to
+        for &(address, byte) in self.memory.iter().rev() {
+            cpu.bus.load(address, &[byte]);
+        }
+        cpu.take_exit_code();
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>()
+            + self.registers.capacity() * size_of::<(u8, i32)>()
+            + self.coprocessors.as_ref().map_or(0, |_| size_of::<(Cp0, Cp1)>())
+            + self.tlb.as_ref().map_or(0, |tlb| tlb.capacity() * size_of::<TlbEntry>())
+            + self.devices.as_ref().map_or(0, |devices| devices_size(devices))
+            + self.memory.capacity() * size_of::<(u32, u8)>()
+    }
+}
+
+// Everything needed to go back to a point without the records before it
+struct Checkpoint {
+    state: State,
+    memory: Vec<(u32, Vec<u8>)>     // contents of every memory on the bus, by where it starts
+}
+
+impl Checkpoint {
+    fn of(cpu: &CPU) -> Self {
+        let memory = cpu.bus.regions().iter()
+            .filter_map(|region| Some((region.start, region.device.bytes()?.to_vec())))
+            .collect();
+        Checkpoint { state: State::of(cpu), memory }
+    }
+
+    fn restore(&self, cpu: &mut CPU) {
+        self.state.restore(cpu);
+        for (start, bytes) in &self.memory {
+            cpu.bus.load(*start, bytes);
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.state.registers.len() * size_of::<i32>() + devices_size(&self.state.devices)
+            + self.state.tlb.as_ref().map_or(0, |tlb| tlb.len() * size_of::<TlbEntry>())
+            + self.memory.iter().map(|(_, bytes)| bytes.len()).sum::<usize>()
+    }
+}
+
+fn devices_size(devices: &[Vec<u32>]) -> usize {
+    devices.iter().map(|words| size_of::<Vec<u32>>() + words.capacity() * size_of::<u32>()).sum()
+}
+
+// Err saying why not when cpu can't be run backwards, because it has caches or a branch
+// predictor, or a device mapped on it reaches outside the machine
+pub fn reversible(cpu: &CPU) -> Result<(), String> {
+    if cpu.caches.is_some() || cpu.branches.is_some() {
+        return Err(String::from("Reverse execution is not supported with caches or a branch predictor, they are not wound back."));
+    }
+    match cpu.bus.regions().iter().find(|region| region.device.external()) {
+        Some(region) => Err(format!("Reverse execution is not supported with {} mapped, what it sent can't be taken back.", region.name)),
+        None => Ok(())
+    }
+}
+
+pub struct History {
+    pub budget: usize,
+    pub interval: u64,
+    undo: VecDeque<Undo>,           // oldest first, ending at the present
+    checkpoints: VecDeque<Checkpoint>,
+    used: usize
+}
+
+impl History {
+    pub fn new(budget: usize, interval: u64) -> Self {
+        History { budget, interval: interval.max(1), undo: VecDeque::new(), checkpoints: VecDeque::new(), used: 0 }
+    }
+
+    // cpu.step, keeping what it takes to undo it
+    pub fn step(&mut self, cpu: &mut CPU) -> Result<Option<i32>, Fault> {
+        let due = self.checkpoints.back().is_none_or(|c| cpu.retired >= c.state.retired + self.interval);
+        if due && cpu.exit_code().is_none() {
+            let checkpoint = Checkpoint::of(cpu);
+            self.used += checkpoint.size();
+            self.checkpoints.push_back(checkpoint);
+        }
+
+        let before = State::of(cpu);
+        cpu.overwritten = Some(Vec::new());
+        let result = cpu.step();
+        let memory = cpu.overwritten.take().unwrap_or_default();
+        let after = State::of(cpu);
+        // halting or stepping an ended program changes nothing
+        if before != after || !memory.is_empty() {
+            let undo = Undo::between(before, &after, memory);
+            self.used += undo.size();
+            self.undo.push_back(undo);
+            self.trim();
+        }
+        result
+    }
+
+    // Undo the newest step, false when there is none to undo or cpu isn't reversible
+    pub fn back(&mut self, cpu: &mut CPU) -> bool {
+        if reversible(cpu).is_err() {
+            return false;
+        }
+        let Some(undo) = self.undo.pop_back() else { return false };
+        undo.apply(cpu);
+        self.used -= undo.size();
+        true
+    }
+
+    // Go to the point where retired instructions had retired, back through the records or
+    // from a checkpoint, or forward by running
+    pub fn seek(&mut self, cpu: &mut CPU, retired: u64) -> Result<(), String> {
+        if retired < cpu.retired {
+            reversible(cpu)?;
+        }
+        if retired < cpu.retired && self.undo.front().is_none_or(|u| u.retired > retired) {
+            let Some(at) = self.checkpoints.iter().rposition(|c| c.state.retired <= retired) else {
+                return Err(match self.earliest() {
+                    Some(earliest) => format!("Cycle {retired} is no longer in the history, which goes back to cycle {earliest}."),
+                    None => String::from("No more reverse-execution history.")
+                });
+            };
+            // what came after the checkpoint is run again
+            for checkpoint in self.checkpoints.drain(at + 1..) {
+                self.used -= checkpoint.size();
+            }
+            for undo in self.undo.drain(..) {
+                self.used -= undo.size();
+            }
+            self.checkpoints[at].restore(cpu);
+        }
+        while cpu.retired > retired && self.back(cpu) {}
+        while cpu.retired < retired {
+            match self.step(cpu) {
+                Ok(None) => (),
+                Ok(Some(code)) => return Err(format!("The program exited with code {code} at cycle {}.", cpu.retired)),
+                Err(fault) => return Err(format!("The program stopped at cycle {}: {fault}", cpu.retired))
+            }
+        }
+        Ok(())
+    }
+
+    // Earliest point that can still be gone back to, None with no history
+    pub fn earliest(&self) -> Option<u64> {
+        let undo = self.undo.front().map(|u| u.retired);
+        let checkpoint = self.checkpoints.front().map(|c| c.state.retired);
+        undo.into_iter().chain(checkpoint).min()
+    }
+
+    // Undo records, checkpoints and the bytes they take
+    pub fn usage(&self) -> (usize, usize, usize) {
+        (self.undo.len(), self.checkpoints.len(), self.used)
+    }
+
+    // Drop records and checkpoints, oldest first, until within budget, keeping a checkpoint
+    fn trim(&mut self) {
+        while self.used > self.budget {
+            let newest = self.checkpoints.back().map_or(0, |c| c.state.retired);
+            if self.undo.front().is_some_and(|u| u.retired < newest) || self.checkpoints.len() == 1 {
+                let Some(undo) = self.undo.pop_front() else { break };
+                self.used -= undo.size();
+            } else if let Some(checkpoint) = self.checkpoints.pop_front() {
+                self.used -= checkpoint.size();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(BUDGET, INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fill a buffer with running sums, storing over each word twice
+    const SOURCE: &str = "
+        .data
+        sums: .space 64
+        .text
+        la $s0, sums
+        li $t0, 0
+        loop: addu $t1, $t1, $t0
+        sll $t2, $t0, 2
+        addu $t2, $s0, $t2
+        sw $t1, 0($t2)
+        sb $t0, 1($t2)
+        addiu $t0, $t0, 1
+        slti $t3, $t0, 16
+        bne $t3, $zero, loop
+        li $v0, 10
+        syscall";
+
+    fn cpu() -> CPU {
+        CPU::assembled(SOURCE, |_, _| ())
+    }
+
+    fn memory(cpu: &CPU) -> Vec<u8> {
+        cpu.bus.regions()[0].device.bytes().unwrap().to_vec()
+    }
+
+    #[test]
+    fn stepping_back_undoes_registers_memory_and_exits() {
+        let mut cpu = cpu();
+        let mut history = History::default();
+        let mut states = vec![(State::of(&cpu), memory(&cpu))];
+        loop {
+            let exited = history.step(&mut cpu).unwrap().is_some();
+            states.push((State::of(&cpu), memory(&cpu)));
+            if exited {
+                break;
+            }
+        }
+        assert_eq!(cpu.exit_code(), Some(0));
+
+        while history.back(&mut cpu) {
+            states.pop();
+            assert_eq!(&(State::of(&cpu), memory(&cpu)), states.last().unwrap());
+        }
+        assert_eq!((cpu.retired, cpu.exit_code(), states.len()), (0, None, 1));
+        assert_eq!(history.usage(), (0, 1, Checkpoint::of(&cpu).size()));
+    }
+
+    #[test]
+    fn seeking_past_the_records_restores_a_checkpoint_and_runs_forward() {
+        let mut cpu = cpu();
+        let mut history = History::new(Checkpoint::of(&cpu).size() * 2 + 4000, 20);
+        while history.step(&mut cpu).unwrap().is_none() {}
+        let (records, checkpoints, used) = history.usage();
+        assert!(used <= history.budget && checkpoints == 2 && records < 40 && history.undo[0].retired > history.earliest().unwrap());
+
+        // a point only a checkpoint reaches, then one further on
+        let earliest = history.earliest().unwrap();
+        let mut reference = self::cpu();
+        for target in [earliest + 3, earliest + 30] {
+            while reference.retired < target {
+                reference.step().unwrap();
+            }
+            history.seek(&mut cpu, target).unwrap();
+            assert_eq!((State::of(&cpu), memory(&cpu)), (State::of(&reference), memory(&reference)));
+        }
+        assert_eq!(history.seek(&mut cpu, earliest - 1).unwrap_err(),
+            format!("Cycle {} is no longer in the history, which goes back to cycle {earliest}.", earliest - 1));
+        assert!(history.seek(&mut cpu, 1000).unwrap_err().starts_with("The program exited with code 0"));
+    }
+}
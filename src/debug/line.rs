@@ -0,0 +1,109 @@
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+
+/*
+ * Line input with history. On a terminal the line is edited in raw mode, set with stty for
+ * the time the line takes, so up and down walk the history. Anywhere else lines are read
+ * as they come
+ */
+
+#[derive(Default)]
+pub struct LineEditor {
+    pub history: Vec<String>
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Next line without its newline, None at the end of input
+    pub fn read_line(&mut self, prompt: &str) -> Option<String> {
+        print!("{prompt}");
+        io::stdout().flush().ok()?;
+        let line = match raw_mode() {
+            Some(saved) => {
+                let line = self.edit(prompt);
+                stty(&[saved.as_str()]);
+                line
+            }
+            None => {
+                let mut line = String::new();
+                match io::stdin().lock().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string())
+                }
+            }
+        }?;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+
+    fn edit(&self, prompt: &str) -> Option<String> {
+        let mut stdout = io::stdout();
+        let mut bytes = io::stdin().lock().bytes().map_while(Result::ok);
+        let mut line = String::new();
+        let mut recalled = self.history.len();
+        loop {
+            match bytes.next()? {
+                b'\n' | b'\r' => {
+                    println!();
+                    return Some(line);
+                }
+                0x04 if line.is_empty() => {
+                    println!();
+                    return None;
+                }
+                0x7F | 0x08 if line.is_empty() => (),
+                0x7F | 0x08 => {
+                    line.pop();
+                    print!("\x08 \x08");
+                }
+                // arrow keys, up and down recall history, the rest are ignored
+                0x1B => {
+                    if bytes.next() != Some(b'[') {
+                        continue;
+                    }
+                    recalled = match bytes.next() {
+                        Some(b'A') => recalled.saturating_sub(1),
+                        Some(b'B') => (recalled + 1).min(self.history.len()),
+                        _ => continue
+                    };
+                    line = self.history.get(recalled).cloned().unwrap_or_default();
+                    print!("\r\x1B[K{prompt}{line}");
+                }
+                byte if byte >= 0x20 => {
+                    // the rest of a UTF-8 sequence follows its first byte
+                    let mut encoded = vec![byte];
+                    let continuation = if byte >= 0xF0 { 3 } else if byte >= 0xE0 { 2 } else if byte >= 0xC0 { 1 } else { 0 };
+                    encoded.extend(bytes.by_ref().take(continuation));
+                    let text = String::from_utf8_lossy(&encoded);
+                    print!("{text}");
+                    line.push_str(&text);
+                }
+                _ => ()
+            }
+            stdout.flush().ok()?;
+        }
+    }
+}
+
+// Put the terminal into raw mode, giving the settings to restore. None when stdin is not
+// a terminal or stty can't be run
+fn raw_mode() -> Option<String> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    let saved = Command::new("stty").arg("-g").stdin(Stdio::inherit()).output().ok()?;
+    if !saved.status.success() {
+        return None;
+    }
+    let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+    stty(&["-icanon", "-echo", "min", "1"]).then_some(saved)
+}
+
+fn stty(args: &[&str]) -> bool {
+    Command::new("stty").args(args).stdin(Stdio::inherit()).status().is_ok_and(|status| status.success())
+}
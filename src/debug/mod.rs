@@ -0,0 +1,6 @@
+pub mod breakpoint;
+pub mod debugger;
+pub mod expression;
+pub mod gdbstub;
+pub mod history;
+pub mod line;
@@ -1,6 +1,9 @@
 use crate::datatypes::Program;
+use super::isa;
+use std::{fmt, io};
+use super::cp0::Exception;
 
-/**
+/*
  * Architecture definitions, based on the MIPS ISA.
  * 
  * Doesn't implment sc or ll
@@ -9,6 +12,13 @@ use crate::datatypes::Program;
 
 // Number of Registers
 pub const REG_NUM: u32 = 32;    
+// ABI Register Names, indexed by register number
+pub const REGISTER_NAMES: [&str; REG_NUM as usize] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra"
+];
 // Memory Address Declarations
 pub const PC_START: u32 = 0x0040;        // PC Starting Address
 pub const STATIC_DATA: u32 = 0x1000;     // Static Data Space
@@ -18,9 +28,74 @@ pub const END_MEM: u32 = 0x8000;         // Last Valid Memory Address
 // Memory Size Declarations
 pub const MEM_SIZE: u32 = END_MEM + 1;        // Address Space in bytes
 
+// Instruction Field Declarations
+pub const JUMP_ADDRESS_MASK: u32 = 0x3FF_FFFF; // Width of the J-Type address field
+pub const HALT: u32 = 0xFFFF_FFFF;             // Stop sentinel, the CPU halts when it fetches this
+
+// Register name without the leading $, either a number 0-31 or an ABI name
+pub fn register_number(name: &str) -> Option<u32> {
+    if let Ok(n) = name.parse::<u32>() {
+        return if n < REG_NUM { Some(n) } else { None };
+    }
+    REGISTER_NAMES.iter().position(|abi| *abi == name).map(|n| n as u32)
+}
+
+// Floating point register name without the leading $, f0-f31
+pub fn fp_register_number(name: &str) -> Option<u32> {
+    let n = name.strip_prefix('f')?.parse::<u32>().ok()?;
+    if n < REG_NUM { Some(n) } else { None }
+}
+
+// The fields of an instruction word, every format's view at once
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fields {
+    pub opcode: u32,
+    pub rs: u32,
+    pub rt: u32,
+    pub rd: u32,
+    pub shamt: u32,
+    pub func: u32,
+    pub immediate: i16,
+    pub address: u32
+}
+
+impl Fields {
+    pub fn extract(instruction: u32) -> Self {
+        Fields {
+            opcode: instruction >> 26,                      // 31..26
+            rs: (instruction >> 21) & 0x1F,                 // 25..21
+            rt: (instruction >> 16) & 0x1F,                 // 20..16
+            rd: (instruction >> 11) & 0x1F,                 // 15..11
+            shamt: (instruction >> 6) & 0x1F,               // 10..6
+            func: instruction & 0x3F,                       // 5..0
+            immediate: (instruction & 0xFFFF) as i16,       // 16 bits
+            address: instruction & JUMP_ADDRESS_MASK        // 26 bits
+        }
+    }
+}
+
+// Why a run stopped before the program ended
+#[derive(Debug)]
+pub enum Fault {
+    Exception(Exception),                                   // raised with no handler installed
+    Syscall { service: u32, pc: u32, error: io::Error }     // a syscall service failed
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Exception(exception) => write!(f, "{exception}"),
+            Fault::Syscall { service, pc, error } => write!(f, "syscall {service} failed at pc {pc:#010x}: {error}")
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
 pub trait Computer {
     fn load_program(&mut self, program: Program);
-    fn start(&mut self);
+    // run until the program ends and return its exit code, 0 unless it exits through a syscall
+    fn start(&mut self) -> Result<i32, Fault>;
 }
 
 pub trait MipsIsa {
@@ -33,87 +108,106 @@ pub trait MipsIsa {
     fn or(&mut self, rs: u32, rt: u32, rd: u32);
     fn slt(&mut self, rs: u32, rt: u32, rd: u32);
     fn sltu(&mut self, rs: u32, rt: u32, rd: u32);
-    fn sll(&mut self, rs: u32, rd: u32, shamt: u32);
-    fn srl(&mut self, rs: u32, rd: u32, shamt: u32);
+    fn sll(&mut self, rt: u32, rd: u32, shamt: u32);
+    fn srl(&mut self, rt: u32, rd: u32, shamt: u32);
+    fn sra(&mut self, rt: u32, rd: u32, shamt: u32);
+    fn sllv(&mut self, rs: u32, rt: u32, rd: u32);
+    fn srlv(&mut self, rs: u32, rt: u32, rd: u32);
+    fn srav(&mut self, rs: u32, rt: u32, rd: u32);
     fn sub(&mut self, rs: u32, rt: u32, rd: u32);
     fn subu(&mut self, rs: u32, rt: u32, rd: u32);
+    fn xor(&mut self, rs: u32, rt: u32, rd: u32);
+    fn jalr(&mut self, rs: u32, rd: u32);
+    fn mult(&mut self, rs: u32, rt: u32);
+    fn multu(&mut self, rs: u32, rt: u32);
+    fn div(&mut self, rs: u32, rt: u32);
+    fn divu(&mut self, rs: u32, rt: u32);
+    fn mfhi(&mut self, rd: u32);
+    fn mflo(&mut self, rd: u32);
+    fn mthi(&mut self, rs: u32);
+    fn mtlo(&mut self, rs: u32);
+    fn syscall(&mut self);
+    fn brk(&mut self);      // break, a keyword in Rust
     // I-Instructions
     fn addi(&mut self, rs: u32, rt: u32, immediate: i16);
     fn addiu(&mut self, rs: u32, rt: u32, immediate: i16);
     fn andi(&mut self, rs: u32, rt: u32, immediate: i16);
     fn ori(&mut self, rs: u32, rt: u32, immediate: i16);
+    fn xori(&mut self, rs: u32, rt: u32, immediate: i16);
     fn beq(&mut self, rs: u32, rt: u32, immediate: i16);
     fn bne(&mut self, rs: u32, rt: u32, immediate: i16);
+    fn blez(&mut self, rs: u32, immediate: i16);
+    fn bgtz(&mut self, rs: u32, immediate: i16);
+    fn bltz(&mut self, rs: u32, immediate: i16);
+    fn bgez(&mut self, rs: u32, immediate: i16);
+    fn bltzal(&mut self, rs: u32, immediate: i16);
+    fn bgezal(&mut self, rs: u32, immediate: i16);
+    fn lb(&mut self, rs: u32, rt: u32, immediate: i16);
+    fn lh(&mut self, rs: u32, rt: u32, immediate: i16);
     fn lbu(&mut self, rs: u32, rt: u32, immediate: i16);
     fn lhu(&mut self, rs: u32, rt: u32, immediate: i16);
-    fn lui(&mut self, rs: u32, immediate: i16);
+    fn lui(&mut self, rt: u32, immediate: i16);
     fn lw(&mut self, rs: u32, rt: u32, immediate: i16);
     fn sb(&mut self, rs: u32, rt: u32, immediate: i16);
     fn sh(&mut self, rs: u32, rt: u32, immediate: i16);
     fn sw(&mut self, rs: u32, rt: u32, immediate: i16);
     fn slti(&mut self, rs: u32, rt: u32, immediate: i16);
-    fn sltiu(&mut self, rs: u32, rt: u32, immediate: u16);    
+    fn sltiu(&mut self, rs: u32, rt: u32, immediate: i16);    
     // J-Instructions
     fn j(&mut self, address: u32);
     fn jal(&mut self, address: u32);
+    // Coprocessor 0
+    fn mfc0(&mut self, rt: u32, rd: u32);
+    fn mtc0(&mut self, rt: u32, rd: u32);
+    fn eret(&mut self);
+    fn tlbr(&mut self);
+    fn tlbwi(&mut self);
+    fn tlbwr(&mut self);
+    fn tlbp(&mut self);
+    // Coprocessor 1
+    fn mfc1(&mut self, rt: u32, fs: u32);
+    fn mtc1(&mut self, rt: u32, fs: u32);
+    fn cfc1(&mut self, rt: u32, rd: u32);
+    fn ctc1(&mut self, rt: u32, rd: u32);
+    fn bc1f(&mut self, immediate: i16);
+    fn bc1t(&mut self, immediate: i16);
+    fn lwc1(&mut self, rs: u32, ft: u32, immediate: i16);
+    fn ldc1(&mut self, rs: u32, ft: u32, immediate: i16);
+    fn swc1(&mut self, rs: u32, ft: u32, immediate: i16);
+    fn sdc1(&mut self, rs: u32, ft: u32, immediate: i16);
+    fn add_s(&mut self, fs: u32, ft: u32, fd: u32);
+    fn add_d(&mut self, fs: u32, ft: u32, fd: u32);
+    fn sub_s(&mut self, fs: u32, ft: u32, fd: u32);
+    fn sub_d(&mut self, fs: u32, ft: u32, fd: u32);
+    fn mul_s(&mut self, fs: u32, ft: u32, fd: u32);
+    fn mul_d(&mut self, fs: u32, ft: u32, fd: u32);
+    fn div_s(&mut self, fs: u32, ft: u32, fd: u32);
+    fn div_d(&mut self, fs: u32, ft: u32, fd: u32);
+    fn sqrt_s(&mut self, fs: u32, fd: u32);
+    fn sqrt_d(&mut self, fs: u32, fd: u32);
+    fn abs_s(&mut self, fs: u32, fd: u32);
+    fn abs_d(&mut self, fs: u32, fd: u32);
+    fn mov_s(&mut self, fs: u32, fd: u32);
+    fn mov_d(&mut self, fs: u32, fd: u32);
+    fn neg_s(&mut self, fs: u32, fd: u32);
+    fn neg_d(&mut self, fs: u32, fd: u32);
+    fn cvt_s_d(&mut self, fs: u32, fd: u32);
+    fn cvt_s_w(&mut self, fs: u32, fd: u32);
+    fn cvt_d_s(&mut self, fs: u32, fd: u32);
+    fn cvt_d_w(&mut self, fs: u32, fd: u32);
+    fn cvt_w_s(&mut self, fs: u32, fd: u32);
+    fn cvt_w_d(&mut self, fs: u32, fd: u32);
+    fn c_eq_s(&mut self, fs: u32, ft: u32);
+    fn c_eq_d(&mut self, fs: u32, ft: u32);
+    fn c_lt_s(&mut self, fs: u32, ft: u32);
+    fn c_lt_d(&mut self, fs: u32, ft: u32);
+    fn c_le_s(&mut self, fs: u32, ft: u32);
+    fn c_le_d(&mut self, fs: u32, ft: u32);
 
-    // Execute command which also holds the 'decoding'
-    fn decode_execute(&mut self, instruction: u32) {
-        let opcode = instruction >> 26;        // 31..26
-        let rs: u32 = (instruction >> 21) & 0x1F;   // 25..21
-        let rt: u32 = (instruction >> 16) & 0x1F;   // 20..16
-        let rd: u32 = (instruction >> 11) & 0x1F;   // 15..11
-        let shamt: u32 = (instruction >> 6) & 0x1F; // 10..6
-        let func: u32 = instruction & 0x3F;         // 5..0
-
-        let immediate: i16 = (instruction & 0xFFFF) as i16; // 16 bits
-        let address: u32 = instruction & 0xFF_FFFF;         // 24 bits
-
-        match opcode {
-            // R-Type
-            0x0 => 
-                match func {
-                    0x20 => Self::add(self, rs, rt, rd),
-                    0x21 => Self::addu(self, rs, rt, rd),
-                    0x24 => Self::and(self, rs, rt, rd),
-                    0x8 => Self::jr(self, rs),
-                    0x27 => Self::nor(self, rs, rt, rd),
-                    0x25 => Self::or(self, rs, rt, rd),
-                    0x2a => Self::slt(self, rs, rt, rd),
-                    0x2b => Self::sltu(self, rs, rt, rd),
-                    0x0 => Self::sll(self, rs, rd, shamt),
-                    0x2 => Self::srl(self, rs, rd, shamt),
-                    0x22 => Self::sub(self, rs, rt, rd),
-                    0x23 => Self::subu(self, rs, rt, rd),
-                    _ => ()
-                },
-            
-            // J-Type
-            0x2 => Self::j(self, address),
-            0x3 => Self::jal(self, address),
-
-            // I-Type
-            0x8 => Self::addi(self, rs, rt, immediate),
-            0x9 => Self::addiu(self, rs, rt, immediate),
-            0xc => Self::andi(self, rs, rt, immediate),
-            0x4 => Self::beq(self, rs, rt, immediate),
-            0x5 => Self::bne(self, rs, rt, immediate),
-            0xf => Self::lui(self, rs, immediate),
-            0x23 => Self::lw(self, rs, rt, immediate),
-            0xd => Self::ori(self, rs, rt, immediate),
-            0xa => Self::slti(self, rs, rt, immediate),
-            0xb => Self::sltiu(self, rs, rt, immediate as u16),
-            0x2b => Self::sw(self, rs, rt, immediate), 
-            0x24 => Self::lbu(self, rs, rt, immediate),
-            0x25 => Self::lhu(self, rs, rt, immediate),
-            0x28 => Self::sb(self, rs, rt, immediate),
-            0x29 => Self::sh(self, rs, rt, immediate),
-            _ => ()
-        }
+    // Execute command, decoding through the instruction table.
+    // false if the word is not an instruction
+    fn decode_execute(&mut self, instruction: u32) -> bool {
+        isa::dispatch(self, instruction)
     }
 
 }
-
-
-
-
@@ -0,0 +1,377 @@
+use std::any::Any;
+use std::fmt;
+use super::mmu::Access;
+
+/*
+ * The physical address space. Regions of RAM, ROM and devices are mapped onto address
+ * ranges with permissions, and every load, store and fetch goes through the bus with its
+ * width. Accesses have to be naturally aligned and inside one region. Memories keep bytes
+ * and assemble them in the bus's byte order, devices see whole values
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big, Little
+}
+
+impl Endian {
+    pub fn to_bytes(self, value: u32, width: u32) -> Vec<u8> {
+        let bytes = match self {
+            Endian::Big => value.to_be_bytes(),
+            Endian::Little => value.to_le_bytes()
+        };
+        match self {
+            Endian::Big => bytes[4 - width as usize..].to_vec(),
+            Endian::Little => bytes[..width as usize].to_vec()
+        }
+    }
+
+    pub fn from_bytes(self, bytes: &[u8]) -> u32 {
+        let fold = |value: u32, &byte: &u8| (value << 8) | byte as u32;
+        match self {
+            Endian::Big => bytes.iter().fold(0, fold),
+            Endian::Little => bytes.iter().rev().fold(0, fold)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Permissions {
+    pub const RWX: Permissions = Permissions { read: true, write: true, execute: true };
+    pub const RX: Permissions = Permissions { read: true, write: false, execute: true };
+    pub const RW: Permissions = Permissions { read: true, write: true, execute: false };
+
+    fn allow(&self, access: Access) -> bool {
+        match access {
+            Access::Fetch => self.execute,
+            Access::Load => self.read,
+            Access::Store => self.write
+        }
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(f, "{}{}{}", flag(self.read, 'r'), flag(self.write, 'w'), flag(self.execute, 'x'))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    Unmapped,       // no region covers the access
+    Protected,      // the region doesn't allow it
+    Device          // the device has nothing there
+}
+
+// Something mapped on the bus, addressed by offset into its region
+pub trait Device: Any {
+    fn size(&self) -> u32;
+    fn read(&mut self, offset: u32, width: u32, endian: Endian) -> Option<u32>;
+    fn write(&mut self, offset: u32, width: u32, value: u32, endian: Endian) -> Option<()>;
+
+    // read without side effects, for debuggers. Devices whose reads change state see nothing
+    fn peek(&self, _offset: u32, _width: u32, _endian: Endian) -> Option<u32> {
+        None
+    }
+
+    // contents of a memory, for loaders and snapshots
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn bytes_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // advance a cycle, for devices that do things on their own
+    fn tick(&mut self) {}
+
+    // the CPU's cycle count once an instruction is done, stalls included, for devices
+    // keeping time by it rather than by ticks
+    fn clock(&mut self, _cycles: u64) {}
+
+    // whether the device is asking for an interrupt
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    // whether what it does reaches outside the machine, as a UART's output does, so it can
+    // neither be taken back nor done over
+    fn external(&self) -> bool {
+        false
+    }
+
+    // registers and other state beyond bytes(), as words for snapshots, and putting them back
+    fn save(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        words.is_empty().then_some(())
+    }
+}
+
+// RAM, or ROM when mapped without write permission
+pub struct Memory {
+    bytes: Vec<u8>
+}
+
+impl Memory {
+    pub fn new(size: u32) -> Self {
+        Memory { bytes: vec![0; size as usize] }
+    }
+
+    pub fn with_contents(bytes: Vec<u8>) -> Self {
+        Memory { bytes }
+    }
+}
+
+impl Device for Memory {
+    fn size(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    fn read(&mut self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        self.peek(offset, width, endian)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32, endian: Endian) -> Option<()> {
+        let start = offset as usize;
+        self.bytes.get_mut(start..start + width as usize)?.copy_from_slice(&endian.to_bytes(value, width));
+        Some(())
+    }
+
+    fn peek(&self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        let start = offset as usize;
+        self.bytes.get(start..start + width as usize).map(|bytes| endian.from_bytes(bytes))
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(&self.bytes)
+    }
+
+    fn bytes_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.bytes)
+    }
+}
+
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    pub permissions: Permissions,
+    pub device: Box<dyn Device>
+}
+
+impl Region {
+    // one past the last byte, 1 << 32 for a region running to the top of the address space
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.device.size() as u64
+    }
+
+    fn covers(&self, address: u32, width: u32) -> bool {
+        address >= self.start && address as u64 + width as u64 <= self.end()
+    }
+}
+
+// Accesses of width bytes at a physical address
+pub trait Bus {
+    fn read(&mut self, address: u32, width: u32, access: Access) -> Result<u32, BusError>;
+    fn write(&mut self, address: u32, width: u32, value: u32) -> Result<(), BusError>;
+    fn peek(&self, address: u32, width: u32) -> Option<u32>;
+}
+
+pub struct RegionMap {
+    pub endian: Endian,
+    regions: Vec<Region>
+}
+
+impl RegionMap {
+    pub fn new(endian: Endian) -> Self {
+        RegionMap { endian, regions: Vec::new() }
+    }
+
+    // Map a device at start, unless it would overlap a region already there or take its
+    // name, regions being looked up by name
+    pub fn map(&mut self, name: &str, start: u32, permissions: Permissions, device: Box<dyn Device>) -> Result<(), String> {
+        if self.region(name).is_some() {
+            return Err(format!("a region called {name} is already mapped"));
+        }
+        let end = start as u64 + device.size() as u64;
+        if end > 1 << 32 {
+            return Err(format!("{name} runs past the end of the address space"));
+        }
+        if let Some(other) = self.regions.iter().find(|r| (start as u64) < r.end() && end > r.start as u64) {
+            return Err(format!("{name} at {start:#010x} overlaps {} at {:#010x}", other.name, other.start));
+        }
+        self.regions.push(Region { name: name.to_string(), start, permissions, device });
+        Ok(())
+    }
+
+    pub fn map_ram(&mut self, name: &str, start: u32, size: u32) -> Result<(), String> {
+        self.map(name, start, Permissions::RWX, Box::new(Memory::new(size)))
+    }
+
+    pub fn map_rom(&mut self, name: &str, start: u32, contents: Vec<u8>) -> Result<(), String> {
+        self.map(name, start, Permissions::RX, Box::new(Memory::with_contents(contents)))
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn regions_mut(&mut self) -> &mut [Region] {
+        &mut self.regions
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    pub fn region_mut(&mut self, name: &str) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|r| r.name == name)
+    }
+
+    // The device mapped as name, if it is a T
+    pub fn device<T: Device>(&self, name: &str) -> Option<&T> {
+        (self.region(name)?.device.as_ref() as &dyn Any).downcast_ref()
+    }
+
+    pub fn device_mut<T: Device>(&mut self, name: &str) -> Option<&mut T> {
+        (self.region_mut(name)?.device.as_mut() as &mut dyn Any).downcast_mut()
+    }
+
+    // Put bytes straight into memories whatever their permissions, as a loader does.
+    // false if some of them don't land in a memory
+    pub fn load(&mut self, address: u32, payload: &[u8]) -> bool {
+        payload.iter().enumerate().all(|(i, &byte)| {
+            let address = address.wrapping_add(i as u32);
+            let Some(region) = self.regions.iter_mut().find(|r| r.covers(address, 1)) else { return false };
+            let offset = (address - region.start) as usize;
+            region.device.bytes_mut().map(|bytes| bytes[offset] = byte).is_some()
+        })
+    }
+
+    pub fn tick(&mut self) {
+        self.regions.iter_mut().for_each(|r| r.device.tick());
+    }
+
+    pub fn clock(&mut self, cycles: u64) {
+        self.regions.iter_mut().for_each(|r| r.device.clock(cycles));
+    }
+
+    // Whether a store of width bytes at address lands somewhere that takes stores
+    pub fn writable(&self, address: u32, width: u32) -> bool {
+        address.is_multiple_of(width) && self.regions.iter().any(|r| r.covers(address, width) && r.permissions.allow(Access::Store))
+    }
+
+    fn find(&mut self, address: u32, width: u32, access: Access) -> Result<(&mut Region, u32), BusError> {
+        if !address.is_multiple_of(width) {
+            return Err(BusError::Unmapped);
+        }
+        let region = self.regions.iter_mut().find(|r| r.covers(address, width)).ok_or(BusError::Unmapped)?;
+        if !region.permissions.allow(access) {
+            return Err(BusError::Protected);
+        }
+        let offset = address - region.start;
+        Ok((region, offset))
+    }
+}
+
+impl Bus for RegionMap {
+    fn read(&mut self, address: u32, width: u32, access: Access) -> Result<u32, BusError> {
+        let endian = self.endian;
+        let (region, offset) = self.find(address, width, access)?;
+        region.device.read(offset, width, endian).ok_or(BusError::Device)
+    }
+
+    fn write(&mut self, address: u32, width: u32, value: u32) -> Result<(), BusError> {
+        let endian = self.endian;
+        let (region, offset) = self.find(address, width, Access::Store)?;
+        region.device.write(offset, width, value, endian).ok_or(BusError::Device)
+    }
+
+    fn peek(&self, address: u32, width: u32) -> Option<u32> {
+        if !address.is_multiple_of(width) {
+            return None;
+        }
+        let region = self.regions.iter().find(|r| r.covers(address, width))?;
+        region.device.peek(address - region.start, width, self.endian)
+    }
+}
+
+impl fmt::Display for RegionMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in &self.regions {
+            writeln!(f, "{:#010x}-{:#010x} {} {}", region.start, region.end() - 1, region.permissions, region.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::{self, Computer, Fault};
+    use crate::hardware::cp0::ExceptionCode;
+    use crate::hardware::cpu::CPU;
+
+    #[test]
+    fn accesses_follow_regions_permissions_and_byte_order() {
+        let mut bus = RegionMap::new(Endian::Big);
+        bus.map_rom("rom", 0x0, vec![0x12, 0x34, 0x56, 0x78]).unwrap();
+        bus.map_ram("ram", 0x100, 0x100).unwrap();
+        assert!(bus.map_ram("overlap", 0x1FC, 8).is_err());
+        assert_eq!(bus.map_ram("ram", 0x400, 0x100), Err(String::from("a region called ram is already mapped")));
+
+        assert_eq!(bus.read(0x0, 4, Access::Fetch), Ok(0x1234_5678));
+        assert_eq!(bus.read(0x2, 2, Access::Load), Ok(0x5678));
+        assert_eq!(bus.write(0x0, 4, 0), Err(BusError::Protected));
+        assert_eq!(bus.read(0x4, 4, Access::Load), Err(BusError::Unmapped));
+        assert_eq!(bus.read(0x1FE, 4, Access::Load), Err(BusError::Unmapped));
+
+        bus.write(0x100, 4, 0xAABB_CCDD).unwrap();
+        assert_eq!(bus.peek(0x101, 1), Some(0xBB));
+        bus.endian = Endian::Little;
+        assert_eq!(bus.read(0x100, 4, Access::Load), Ok(0xDDCC_BBAA));
+        assert_eq!(bus.read(0x102, 2, Access::Load), Ok(0xDDCC));
+
+        assert!(bus.load(0x0, &[0xFF]));
+        assert!(!bus.load(0x200, &[0xFF]));
+        assert_eq!(bus.peek(0x0, 1), Some(0xFF));
+    }
+
+    #[test]
+    fn regions_can_run_to_the_top_of_the_address_space() {
+        let mut bus = RegionMap::new(Endian::Big);
+        bus.map_ram("top", 0xFFFF_FFF0, 0x10).unwrap();
+        assert!(bus.map_ram("overlap", 0xFFFF_FFFC, 4).is_err());
+        assert!(bus.map_ram("past", 0xFFFF_FFF8, 0x10).is_err());
+        bus.map_ram("below", 0xFFFF_FFE0, 0x10).unwrap();
+
+        bus.write(0xFFFF_FFFC, 4, 0x1234_5678).unwrap();
+        assert_eq!(bus.read(0xFFFF_FFFC, 4, Access::Load), Ok(0x1234_5678));
+        assert!(bus.writable(0xFFFF_FFFC, 4));
+        assert_eq!(bus.peek(0xFFFF_FFFF, 1), Some(0x78));
+        assert_eq!(bus.to_string(), "0xfffffff0-0xffffffff rwx top\n0xffffffe0-0xffffffef rwx below\n");
+    }
+
+    #[test]
+    fn stores_to_rom_are_bus_errors() {
+        let mut cpu = CPU::assembled("li $t0, 7\nsw $t0, 0x1000($zero)\nsw $t0, 0x40($zero)\nhalt", |cpu, _| {
+            cpu.bus = RegionMap::new(Endian::Big);
+            cpu.bus.map_rom("text", 0, vec![0; arch::STATIC_DATA as usize]).unwrap();
+            cpu.bus.map_ram("data", arch::STATIC_DATA, arch::END_MEM - arch::STATIC_DATA).unwrap();
+        });
+
+        let Err(Fault::Exception(exception)) = cpu.start() else { panic!("expected a bus error") };
+        assert_eq!((exception.code, exception.pc), (ExceptionCode::DataBus, 0x48));
+        assert_eq!(cpu.read_word_from_mem(0x1000), Some(7));
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::HashSet;
+use std::fmt;
+use super::snapshot::{join, section, split};
+
+/*
+ * Cache hierarchy: split L1 instruction and data caches over an optional unified L2,
+ * then memory. The caches keep tags only, the data stays in CPU::memory, so they change
+ * how long an access takes and never what it returns. Every miss is put in one of the
+ * three Cs: compulsory if the block was never touched before, capacity if a fully
+ * associative LRU cache of the same size would have missed too, conflict otherwise
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    Lru, Fifo, Random,
+    Plru        // tree pseudo LRU, needs a power of two associativity
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteBack,      // stores dirty the line, memory is written when it is evicted
+    WriteThrough    // stores go on to the next level as well
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub size: u32,              // bytes of data
+    pub block_size: u32,        // bytes per line
+    pub associativity: u32,     // lines per set
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,   // a store miss brings the block in
+    pub hit_latency: u64        // cycles
+}
+
+impl CacheConfig {
+    // Why the geometry can't be built, sizes have to be powers of two that divide up evenly
+    pub fn check(&self) -> Result<(), String> {
+        let lines = self.size / self.block_size.max(1);
+        if !self.size.is_power_of_two() || !self.block_size.is_power_of_two() || self.block_size < 4 {
+            Err(format!("cache size {} and block size {} must be powers of two, blocks at least a word", self.size, self.block_size))
+        } else if self.associativity == 0 || lines < self.associativity || !lines.is_multiple_of(self.associativity) {
+            Err(format!("{lines} lines can't be split into sets of {}", self.associativity))
+        } else if self.replacement == Replacement::Plru && !self.associativity.is_power_of_two() {
+            Err(format!("pseudo LRU needs a power of two associativity, not {}", self.associativity))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub hits: u64,
+    pub compulsory: u64,
+    pub capacity: u64,
+    pub conflict: u64,
+    pub evictions: u64,         // valid lines replaced
+    pub writebacks: u64         // dirty lines written back on eviction
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.compulsory + self.capacity + self.conflict
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        if self.accesses() == 0 { 0.0 } else { self.hits as f64 / self.accesses() as f64 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    stamp: u64      // last use for LRU, fill for FIFO
+}
+
+// What an access needs from the level below
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Access {
+    pub hit: bool,
+    pub fill: bool,                 // the block has to be read in
+    pub write_below: bool,          // the store goes on down
+    pub writeback: Option<u32>      // address of a dirty block evicted to make room
+}
+
+pub struct Cache {
+    pub name: String,
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    sets: Vec<Vec<Line>>,
+    plru: Vec<Vec<bool>>,       // tree bits per set, each pointing at the less recent half
+    clock: u64,
+    seed: u32,                  // xorshift state for random replacement
+    seen: HashSet<u32>,         // blocks ever touched
+    shadow: Vec<u32>,           // fully associative LRU of the same size, most recent first
+    lines: usize                // lines in the cache, and so in the shadow
+}
+
+impl Cache {
+    pub fn new(name: &str, config: CacheConfig) -> Result<Self, String> {
+        config.check()?;
+        let lines = config.size / config.block_size;
+        let sets = lines / config.associativity;
+        Ok(Cache {
+            name: name.to_string(),
+            config,
+            stats: CacheStats::default(),
+            sets: vec![vec![Line::default(); config.associativity as usize]; sets as usize],
+            plru: vec![vec![false; config.associativity as usize - 1]; sets as usize],
+            clock: 0,
+            seed: 0x2545_F491,
+            seen: HashSet::new(),
+            shadow: Vec::with_capacity(lines as usize),
+            lines: lines as usize
+        })
+    }
+
+    // Look an address up, updating the lines, replacement state and statistics
+    pub fn access(&mut self, address: u32, write: bool) -> Access {
+        self.clock += 1;
+        let block = address / self.config.block_size;
+        let set = (block as usize) % self.sets.len();
+        let tag = block / self.sets.len() as u32;
+        let write_through = self.config.write_policy == WritePolicy::WriteThrough;
+        if write { self.stats.writes += 1 } else { self.stats.reads += 1 }
+
+        let first_touch = self.seen.insert(block);
+        let shadow_hit = self.touch_shadow(block);
+
+        if let Some(way) = self.sets[set].iter().position(|line| line.valid && line.tag == tag) {
+            self.stats.hits += 1;
+            self.touch(set, way);
+            if write && !write_through {
+                self.sets[set][way].dirty = true;
+            }
+            return Access { hit: true, write_below: write && write_through, ..Access::default() };
+        }
+
+        match (first_touch, shadow_hit) {
+            (true, _) => self.stats.compulsory += 1,
+            (false, false) => self.stats.capacity += 1,
+            (false, true) => self.stats.conflict += 1
+        }
+        if write && !self.config.write_allocate {
+            return Access { write_below: true, ..Access::default() };
+        }
+
+        let way = self.victim(set);
+        let victim = self.sets[set][way];
+        let mut writeback = None;
+        if victim.valid {
+            self.stats.evictions += 1;
+            if victim.dirty {
+                self.stats.writebacks += 1;
+                writeback = Some((victim.tag * self.sets.len() as u32 + set as u32) * self.config.block_size);
+            }
+        }
+        self.sets[set][way] = Line { valid: true, dirty: write && !write_through, tag, stamp: self.clock };
+        self.touch(set, way);
+        Access { hit: false, fill: true, write_below: write && write_through, writeback }
+    }
+
+    // Line to replace in a set, an invalid one if there is one
+    fn victim(&mut self, set: usize) -> usize {
+        let lines = &self.sets[set];
+        if let Some(way) = lines.iter().position(|line| !line.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru | Replacement::Fifo => {
+                (0..lines.len()).min_by_key(|&way| lines[way].stamp).unwrap()
+            }
+            Replacement::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                self.seed as usize % lines.len()
+            }
+            Replacement::Plru => {
+                let bits = &self.plru[set];
+                let mut node = 0;
+                while node < bits.len() {
+                    node = 2 * node + 1 + bits[node] as usize;
+                }
+                node - bits.len()
+            }
+        }
+    }
+
+    // A use of a line: LRU restamps it, PLRU points the tree away from it
+    fn touch(&mut self, set: usize, way: usize) {
+        if self.config.replacement == Replacement::Lru {
+            self.sets[set][way].stamp = self.clock;
+        }
+        let bits = &mut self.plru[set];
+        let mut node = way + bits.len();
+        while node > 0 {
+            let parent = (node - 1) / 2;
+            bits[parent] = node == 2 * parent + 1;
+            node = parent;
+        }
+    }
+
+    // true if the fully associative cache hits
+    fn touch_shadow(&mut self, block: u32) -> bool {
+        let position = self.shadow.iter().position(|&b| b == block);
+        match position {
+            Some(position) => { self.shadow.remove(position); }
+            None if self.shadow.len() == self.lines => { self.shadow.pop(); }
+            None => ()
+        }
+        self.shadow.insert(0, block);
+        position.is_some()
+    }
+
+    // Lines, replacement state and statistics as words for snapshots, after the geometry
+    // they only fit
+    pub fn save(&self) -> Vec<u32> {
+        let s = &self.stats;
+        let mut words = vec![self.config.size, self.config.block_size, self.config.associativity];
+        for count in [s.reads, s.writes, s.hits, s.compulsory, s.capacity, s.conflict, s.evictions, s.writebacks, self.clock] {
+            words.extend(split(count));
+        }
+        words.push(self.seed);
+        for line in self.sets.iter().flatten() {
+            words.extend([line.valid as u32 | (line.dirty as u32) << 1, line.tag]);
+            words.extend(split(line.stamp));
+        }
+        words.extend(self.plru.iter().flatten().map(|&bit| bit as u32));
+        let mut seen: Vec<u32> = self.seen.iter().copied().collect();
+        seen.sort_unstable();
+        words.push(seen.len() as u32);
+        words.extend(seen);
+        words.push(self.shadow.len() as u32);
+        words.extend(&self.shadow);
+        words
+    }
+
+    // Put back what save gave, None without changing anything if it isn't a cache of the
+    // same geometry
+    pub fn restore(&mut self, words: &[u32]) -> Option<()> {
+        *self = self.restored(words)?;
+        Some(())
+    }
+
+    fn restored(&self, words: &[u32]) -> Option<Cache> {
+        let mut words = words.iter().copied();
+        let geometry = [self.config.size, self.config.block_size, self.config.associativity];
+        if geometry.into_iter().any(|word| words.next() != Some(word)) {
+            return None;
+        }
+        let mut counts = [0; 9];
+        for count in &mut counts {
+            *count = join(&mut words)?;
+        }
+        let [reads, writes, hits, compulsory, capacity, conflict, evictions, writebacks, clock] = counts;
+        let seed = words.next()?;
+        let mut sets = self.sets.clone();
+        for line in sets.iter_mut().flatten() {
+            let (flags, tag) = (words.next()?, words.next()?);
+            *line = Line { valid: flags & 1 != 0, dirty: flags & 2 != 0, tag, stamp: join(&mut words)? };
+        }
+        let mut plru = self.plru.clone();
+        for bit in plru.iter_mut().flatten() {
+            *bit = words.next()? != 0;
+        }
+        let seen = (0..words.next()?).map(|_| words.next()).collect::<Option<_>>()?;
+        let shadow: Vec<u32> = (0..words.next()?).map(|_| words.next()).collect::<Option<_>>()?;
+        if shadow.len() > self.lines || words.next().is_some() {
+            return None;
+        }
+        Some(Cache {
+            name: self.name.clone(),
+            config: self.config,
+            stats: CacheStats { reads, writes, hits, compulsory, capacity, conflict, evictions, writebacks },
+            sets,
+            plru,
+            clock,
+            seed,
+            seen,
+            shadow,
+            lines: self.lines
+        })
+    }
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.stats;
+        write!(f, "{:<4} accesses {} (reads {}, writes {}), hits {} ({:.2}%), misses {} (compulsory {}, capacity {}, conflict {}), evictions {}, writebacks {}",
+            self.name, s.accesses(), s.reads, s.writes, s.hits, s.hit_rate() * 100.0,
+            s.misses(), s.compulsory, s.capacity, s.conflict, s.evictions, s.writebacks)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Fetch, Read, Write
+}
+
+pub struct Hierarchy {
+    pub l1i: Cache,
+    pub l1d: Cache,
+    pub l2: Option<Cache>,
+    pub memory_latency: u64,
+    pub stall_cycles: u64       // cycles spent beyond L1 hits
+}
+
+impl Hierarchy {
+    pub fn new(l1i: Cache, l1d: Cache, l2: Option<Cache>, memory_latency: u64) -> Self {
+        Hierarchy { l1i, l1d, l2, memory_latency, stall_cycles: 0 }
+    }
+
+    // Cycles an L1 hit takes, hidden in the pipeline stage doing the access
+    pub fn l1_latency(&self, kind: Kind) -> u64 {
+        if kind == Kind::Fetch { self.l1i.config.hit_latency } else { self.l1d.config.hit_latency }
+    }
+
+    // Cycles an access takes, of which those beyond an L1 hit also go on stall_cycles
+    pub fn access(&mut self, kind: Kind, address: u32) -> u64 {
+        let l1 = if kind == Kind::Fetch { &mut self.l1i } else { &mut self.l1d };
+        let result = l1.access(address, kind == Kind::Write);
+        let hit_latency = l1.config.hit_latency;
+        let block = address & !(l1.config.block_size - 1);
+
+        let mut latency = hit_latency;
+        if result.fill {
+            latency += below(&mut self.l2, self.memory_latency, block, false);
+        }
+        if result.write_below {
+            latency += below(&mut self.l2, self.memory_latency, address, true);
+        }
+        if let Some(victim) = result.writeback {
+            latency += below(&mut self.l2, self.memory_latency, victim, true);
+        }
+        self.stall_cycles += latency - hit_latency;
+        latency
+    }
+
+    // Every cache's save, each after its length, then the stall cycles
+    pub fn save(&self) -> Vec<u32> {
+        let mut words = Vec::new();
+        for cache in [Some(&self.l1i), Some(&self.l1d), self.l2.as_ref()] {
+            let saved = cache.map(Cache::save).unwrap_or_default();
+            words.push(saved.len() as u32);
+            words.extend(saved);
+        }
+        words.extend(split(self.stall_cycles));
+        words
+    }
+
+    // Put back what save gave, None without changing anything if a cache isn't the same
+    pub fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let mut rest = words;
+        let (l1i, l1d, l2) = (section(&mut rest)?, section(&mut rest)?, section(&mut rest)?);
+        let l1i = self.l1i.restored(l1i)?;
+        let l1d = self.l1d.restored(l1d)?;
+        let l2 = match &self.l2 {
+            Some(cache) => Some(cache.restored(l2)?),
+            None if l2.is_empty() => None,
+            None => return None
+        };
+        let &[high, low] = rest else { return None };
+        (self.l1i, self.l1d, self.l2) = (l1i, l1d, l2);
+        self.stall_cycles = (high as u64) << 32 | low as u64;
+        Some(())
+    }
+}
+
+// Cycles the L2, or memory without one, takes for an access from L1
+fn below(l2: &mut Option<Cache>, memory_latency: u64, address: u32, write: bool) -> u64 {
+    let Some(l2) = l2 else { return memory_latency };
+    let result = l2.access(address, write);
+    let transfers = [result.fill, result.write_below, result.writeback.is_some()];
+    l2.config.hit_latency + memory_latency * transfers.iter().filter(|&&t| t).count() as u64
+}
+
+impl Default for Hierarchy {
+    // 1 KiB 2-way L1s and a 8 KiB 4-way L2, write-back and write-allocate throughout
+    fn default() -> Self {
+        let l1 = CacheConfig {
+            size: 1024, block_size: 16, associativity: 2, replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack, write_allocate: true, hit_latency: 1
+        };
+        let l2 = CacheConfig { size: 8192, block_size: 32, associativity: 4, hit_latency: 10, ..l1 };
+        Hierarchy::new(
+            Cache::new("L1I", l1).unwrap(),
+            Cache::new("L1D", l1).unwrap(),
+            Some(Cache::new("L2", l2).unwrap()),
+            100)
+    }
+}
+
+impl fmt::Display for Hierarchy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.l1i)?;
+        writeln!(f, "{}", self.l1d)?;
+        if let Some(l2) = &self.l2 {
+            writeln!(f, "{l2}")?;
+        }
+        write!(f, "memory stall cycles {}", self.stall_cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::cpu::CPU;
+
+    fn cache(size: u32, associativity: u32, replacement: Replacement, write_policy: WritePolicy, write_allocate: bool) -> Cache {
+        let config = CacheConfig { size, block_size: 16, associativity, replacement, write_policy, write_allocate, hit_latency: 1 };
+        Cache::new("test", config).unwrap()
+    }
+
+    fn hits(cache: &mut Cache, addresses: &[u32]) -> Vec<bool> {
+        addresses.iter().map(|&address| cache.access(address, false).hit).collect()
+    }
+
+    #[test]
+    fn misses_fall_into_the_three_cs() {
+        // two blocks fighting over one line of a direct mapped cache that could hold both
+        let mut direct = cache(64, 1, Replacement::Lru, WritePolicy::WriteBack, true);
+        hits(&mut direct, &[0x000, 0x040, 0x000, 0x040]);
+        assert_eq!((direct.stats.compulsory, direct.stats.conflict, direct.stats.capacity), (2, 2, 0));
+
+        // five blocks cycling through four lines miss even fully associative
+        let mut full = cache(64, 4, Replacement::Lru, WritePolicy::WriteBack, true);
+        hits(&mut full, &[0x00, 0x10, 0x20, 0x30, 0x40, 0x00]);
+        assert_eq!((full.stats.compulsory, full.stats.capacity, full.stats.evictions), (5, 1, 2));
+    }
+
+    #[test]
+    fn replacement_policies_pick_different_victims() {
+        // one 4-way set: A B C D, touch A, then E replaces B for LRU, A for FIFO and C for PLRU
+        let pattern = [0x00, 0x40, 0x80, 0xC0, 0x00, 0x100, 0x00, 0x80];
+        let expect = |replacement, last_two: [bool; 2]| {
+            let mut cache = cache(64, 4, replacement, WritePolicy::WriteBack, true);
+            assert_eq!(hits(&mut cache, &pattern)[6..], last_two, "{replacement:?}");
+        };
+        expect(Replacement::Lru, [true, true]);
+        expect(Replacement::Fifo, [false, true]);
+        expect(Replacement::Plru, [true, false]);
+
+        let mut random = cache(64, 4, Replacement::Random, WritePolicy::WriteBack, true);
+        hits(&mut random, &pattern);
+        assert_eq!(random.stats.evictions, 2);
+    }
+
+    #[test]
+    fn write_policies_decide_the_traffic_below() {
+        let mut back = cache(32, 1, Replacement::Lru, WritePolicy::WriteBack, true);
+        assert_eq!(back.access(0x00, true), Access { fill: true, ..Access::default() });
+        assert_eq!(back.access(0x20, false), Access { fill: true, writeback: Some(0x00), ..Access::default() });
+        assert_eq!(back.stats.writebacks, 1);
+
+        let mut through = cache(32, 1, Replacement::Lru, WritePolicy::WriteThrough, false);
+        assert_eq!(through.access(0x00, true), Access { write_below: true, ..Access::default() });
+        assert!(through.access(0x00, false).fill);
+        assert_eq!(through.access(0x00, true), Access { hit: true, write_below: true, ..Access::default() });
+        assert_eq!((through.stats.writebacks, through.stats.misses()), (0, 2));
+    }
+
+    #[test]
+    fn latencies_feed_the_cycle_count() {
+        let source = "
+            .data
+            values: .word 1, 2, 3, 4, 5, 6, 7, 8
+            .text
+            la $t0, values
+            li $t1, 8
+            loop: lw $t2, 0($t0)
+            addu $s0, $s0, $t2
+            addiu $t0, $t0, 4
+            addiu $t1, $t1, -1
+            bgtz $t1, loop
+            halt
+        ";
+        let mut cpu = CPU::assembled(source, |cpu, _| cpu.caches = Some(Hierarchy::default()));
+        cpu.start().unwrap();
+
+        let caches = cpu.caches.as_ref().unwrap();
+        assert_eq!(cpu.registers[16], 36);
+        // 16 byte blocks: the loop's instructions span 2 blocks after la's, the data 2
+        assert_eq!((caches.l1i.stats.misses(), caches.l1d.stats.misses()), (3, 2));
+        assert_eq!(caches.l1d.stats.hits, 6);
+        assert_eq!(cpu.cycles, 43 + caches.stall_cycles);
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+
+/*
+ * Coprocessor 0, the system control coprocessor. Only the registers the
+ * exception model, the TLB and the timer need are kept: Index, Random,
+ * EntryLo0/1, Context, BadVAddr, Count, EntryHi, Compare, Status, Cause and EPC
+ */
+
+// CP0 register numbers, as used by mfc0 and mtc0
+pub const INDEX: u32 = 0;
+pub const RANDOM: u32 = 1;
+pub const ENTRY_LO0: u32 = 2;
+pub const ENTRY_LO1: u32 = 3;
+pub const CONTEXT: u32 = 4;
+pub const BAD_VADDR: u32 = 8;
+pub const COUNT: u32 = 9;
+pub const ENTRY_HI: u32 = 10;
+pub const COMPARE: u32 = 11;
+pub const STATUS: u32 = 12;
+pub const CAUSE: u32 = 13;
+pub const EPC: u32 = 14;
+
+// Status bits
+pub const STATUS_IE: u32 = 1 << 0;      // interrupts enabled
+pub const STATUS_EXL: u32 = 1 << 1;     // exception level, set while a handler runs
+pub const STATUS_UM: u32 = 1 << 4;      // user mode, when EXL is clear
+pub const STATUS_IM: u32 = 0xFF << 8;   // interrupt mask, one bit per Cause.IP bit
+
+// Index bits
+pub const INDEX_PROBE_FAILED: u32 = 1 << 31;   // P, set by a tlbp that found nothing
+
+// EntryHi fields
+pub const ENTRY_HI_VPN2: u32 = 0xFFFF_E000;     // virtual page pair, 31..13
+pub const ENTRY_HI_ASID: u32 = 0xFF;            // address space, 7..0
+
+// Context fields
+const CONTEXT_PTE_BASE: u32 = 0xFF80_0000;      // 31..23, set by the OS
+const CONTEXT_BAD_VPN2: u32 = 0x007F_FFF0;      // 22..4, set by TLB exceptions
+
+// Cause bits
+pub const CAUSE_IP: u32 = 0xFF << 8;            // interrupts pending, 15..8
+pub const CAUSE_IP_SOFTWARE: u32 = 0x3 << 8;    // IP1..0, set by mtc0
+pub const CAUSE_IP_TIMER: u32 = 1 << 15;        // IP7, shared by the timer and line 5
+pub const CAUSE_TI: u32 = 1 << 30;              // timer interrupt, Count reached Compare
+const CAUSE_EXC_CODE: u32 = 0x1F << 2;  // ExcCode field, 6..2
+
+// ExcCode values of the exceptions the CPU raises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionCode {
+    Interrupt = 0,              // Int, an enabled line in Cause.IP
+    TlbModified = 1,            // Mod, store to a page that is not dirty
+    TlbLoad = 2,                // TLBL, load or fetch with no valid mapping
+    TlbStore = 3,               // TLBS, store with no valid mapping
+    AddressLoad = 4,            // AdEL, misaligned or unmapped load or fetch
+    AddressStore = 5,           // AdES, misaligned or unmapped store
+    InstructionBus = 6,         // IBE, fetch the bus refused
+    DataBus = 7,                // DBE, load or store the bus refused
+    Syscall = 8,
+    Breakpoint = 9,
+    ReservedInstruction = 10,
+    CoprocessorUnusable = 11,   // CpU, a CP0 instruction in user mode
+    Overflow = 12,
+    FloatingPoint = 15          // FPE, an FPU exception enabled in FCSR
+}
+
+impl ExceptionCode {
+    pub fn description(&self) -> &'static str {
+        match self {
+            ExceptionCode::Interrupt => "interrupt",
+            ExceptionCode::TlbModified => "TLB modification",
+            ExceptionCode::TlbLoad => "TLB miss on load or fetch",
+            ExceptionCode::TlbStore => "TLB miss on store",
+            ExceptionCode::AddressLoad => "address error on load or fetch",
+            ExceptionCode::AddressStore => "address error on store",
+            ExceptionCode::InstructionBus => "bus error on fetch",
+            ExceptionCode::DataBus => "bus error on load or store",
+            ExceptionCode::Syscall => "syscall",
+            ExceptionCode::Breakpoint => "breakpoint",
+            ExceptionCode::ReservedInstruction => "reserved instruction",
+            ExceptionCode::CoprocessorUnusable => "coprocessor unusable",
+            ExceptionCode::Overflow => "arithmetic overflow",
+            ExceptionCode::FloatingPoint => "floating point exception"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cp0 {
+    pub index: u32,
+    pub random: u32,
+    pub entry_lo0: u32,
+    pub entry_lo1: u32,
+    pub context: u32,
+    pub bad_vaddr: u32,
+    pub count: u32,
+    pub entry_hi: u32,
+    pub compare: u32,
+    pub status: u32,
+    pub cause: u32,
+    pub epc: u32
+}
+
+impl Cp0 {
+    // registers without a model read as 0
+    pub fn read(&self, register: u32) -> u32 {
+        match register {
+            INDEX => self.index,
+            RANDOM => self.random,
+            ENTRY_LO0 => self.entry_lo0,
+            ENTRY_LO1 => self.entry_lo1,
+            CONTEXT => self.context,
+            BAD_VADDR => self.bad_vaddr,
+            COUNT => self.count,
+            ENTRY_HI => self.entry_hi,
+            COMPARE => self.compare,
+            STATUS => self.status,
+            CAUSE => self.cause,
+            EPC => self.epc,
+            _ => 0
+        }
+    }
+
+    // Random, BadVAddr and the hardware fields of Index, Context and Cause are read only,
+    // writes to registers without a model are dropped. Writing Compare acknowledges the timer
+    pub fn write(&mut self, register: u32, value: u32) {
+        match register {
+            INDEX => self.index = (self.index & INDEX_PROBE_FAILED) | (value & !INDEX_PROBE_FAILED),
+            ENTRY_LO0 => self.entry_lo0 = value,
+            ENTRY_LO1 => self.entry_lo1 = value,
+            CONTEXT => self.context = (self.context & !CONTEXT_PTE_BASE) | (value & CONTEXT_PTE_BASE),
+            COUNT => self.count = value,
+            ENTRY_HI => self.entry_hi = value & (ENTRY_HI_VPN2 | ENTRY_HI_ASID),
+            COMPARE => {
+                self.compare = value;
+                self.cause &= !CAUSE_TI;
+            }
+            STATUS => self.status = value,
+            CAUSE => self.cause = (self.cause & !CAUSE_IP_SOFTWARE) | (value & CAUSE_IP_SOFTWARE),
+            EPC => self.epc = value,
+            _ => ()
+        }
+    }
+
+    // Record an exception taken by the instruction at pc.
+    // EPC is kept if the exception happens inside a handler
+    pub fn enter(&mut self, code: ExceptionCode, pc: u32, bad_vaddr: Option<u32>) {
+        if self.status & STATUS_EXL == 0 {
+            self.epc = pc;
+        }
+        self.cause = (self.cause & !CAUSE_EXC_CODE) | (code as u32) << 2;
+        if let Some(address) = bad_vaddr {
+            self.bad_vaddr = address;
+        }
+        self.status |= STATUS_EXL;
+    }
+
+    // Record the page a TLB exception happened on, for the handler to refill
+    pub fn enter_tlb(&mut self, address: u32) {
+        self.context = (self.context & CONTEXT_PTE_BASE) | ((address >> 9) & CONTEXT_BAD_VPN2);
+        self.entry_hi = (address & ENTRY_HI_VPN2) | (self.entry_hi & ENTRY_HI_ASID);
+    }
+
+    // Kernel and user mode, user only when the program has asked for it and no handler is running
+    pub fn user_mode(&self) -> bool {
+        self.status & (STATUS_UM | STATUS_EXL) == STATUS_UM
+    }
+
+    // Count a cycle, raising the timer interrupt when Count reaches Compare
+    pub fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.cause |= CAUSE_TI;
+        }
+    }
+
+    // Set the hardware IP bits, 7..2, from the interrupt lines of the devices.
+    // The timer shares IP7 with whatever is on that line
+    pub fn signal(&mut self, lines: u32) {
+        let timer = if self.cause & CAUSE_TI != 0 { CAUSE_IP_TIMER } else { 0 };
+        self.cause = (self.cause & !(CAUSE_IP & !CAUSE_IP_SOFTWARE)) | (lines & CAUSE_IP & !CAUSE_IP_SOFTWARE) | timer;
+    }
+
+    // Pending interrupts the program has enabled and is in a state to take
+    pub fn interrupts(&self) -> u32 {
+        if self.status & (STATUS_IE | STATUS_EXL) != STATUS_IE {
+            return 0;
+        }
+        self.cause & CAUSE_IP & self.status & STATUS_IM
+    }
+
+    // eret, back to EPC
+    pub fn leave(&mut self) -> u32 {
+        self.status &= !STATUS_EXL;
+        self.epc
+    }
+}
+
+// An exception with no handler to take it, which ends the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    pub code: ExceptionCode,
+    pub pc: u32,                    // address of the instruction that raised it
+    pub bad_vaddr: Option<u32>      // offending address of an address error
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unhandled exception: {} at pc {:#010x}", self.code.description(), self.pc)?;
+        if let Some(address) = self.bad_vaddr {
+            write!(f, ", bad address {:#010x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Exception {}
@@ -0,0 +1,409 @@
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/*
+ * Coprocessor 1, the floating point unit. 32 single precision registers, even/odd
+ * pairs of them holding doubles, and the FCSR with its rounding mode, condition flag
+ * and IEEE-754 exception flags.
+ *
+ * The host only rounds to nearest, so every operation is computed to nearest and the
+ * error of that result is recovered exactly (TwoSum, or a fused multiply-add), which
+ * says whether the exact result lies above or below it. That is enough to step to the
+ * correctly rounded result for the other modes, and to tell when it is inexact
+ */
+
+// FCR numbers, as used by cfc1 and ctc1
+pub const FIR: u32 = 0;
+pub const FCSR: u32 = 31;
+
+// FIR: singles, doubles and words are implemented
+const FIR_VALUE: u32 = 1 << 16 | 1 << 17 | 1 << 20;
+
+// IEEE-754 exceptions, in the order of the FCSR Flags, Enables and Cause fields
+pub const INEXACT: u32 = 1 << 0;
+pub const UNDERFLOW: u32 = 1 << 1;
+pub const OVERFLOW: u32 = 1 << 2;
+pub const DIVIDE_BY_ZERO: u32 = 1 << 3;
+pub const INVALID: u32 = 1 << 4;
+
+// FCSR fields
+const FLAGS_SHIFT: u32 = 2;         // 6..2, sticky
+const ENABLES_SHIFT: u32 = 7;       // 11..7, trap instead of setting the flag
+const CAUSE_SHIFT: u32 = 12;        // 16..12, exceptions of the last operation
+const EXCEPTIONS: u32 = 0x1F;
+const FCC0: u32 = 1 << 23;          // condition flag set by c.cond and read by bc1t/bc1f
+
+// cvt.w result when the value is NaN or doesn't fit
+const INVALID_WORD: i32 = i32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Nearest,    // RN, ties to even
+    Zero,       // RZ
+    Up,         // RP, towards +infinity
+    Down        // RM, towards -infinity
+}
+
+impl Rounding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rounding::Nearest => "nearest",
+            Rounding::Zero => "zero",
+            Rounding::Up => "+inf",
+            Rounding::Down => "-inf"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cp1 {
+    pub registers: [u32; 32],
+    pub fcsr: u32
+}
+
+impl Cp1 {
+    pub fn single(&self, register: u32) -> f32 {
+        f32::from_bits(self.registers[register as usize])
+    }
+
+    pub fn set_single(&mut self, register: u32, value: f32) {
+        self.registers[register as usize] = value.to_bits();
+    }
+
+    // the even register holds the low word and the odd one the high word
+    pub fn double(&self, register: u32) -> f64 {
+        let low = self.registers[register as usize] as u64;
+        let high = self.registers[register as usize + 1] as u64;
+        f64::from_bits(high << 32 | low)
+    }
+
+    pub fn set_double(&mut self, register: u32, value: f64) {
+        let bits = value.to_bits();
+        self.registers[register as usize] = bits as u32;
+        self.registers[register as usize + 1] = (bits >> 32) as u32;
+    }
+
+    pub fn word(&self, register: u32) -> i32 {
+        self.registers[register as usize] as i32
+    }
+
+    pub fn set_word(&mut self, register: u32, value: i32) {
+        self.registers[register as usize] = value as u32;
+    }
+
+    // FCRs without a model read as 0
+    pub fn read_control(&self, register: u32) -> u32 {
+        match register {
+            FIR => FIR_VALUE,
+            FCSR => self.fcsr,
+            _ => 0
+        }
+    }
+
+    // FIR is read only
+    pub fn write_control(&mut self, register: u32, value: u32) {
+        if register == FCSR {
+            self.fcsr = value;
+        }
+    }
+
+    pub fn rounding(&self) -> Rounding {
+        match self.fcsr & 0x3 {
+            0 => Rounding::Nearest,
+            1 => Rounding::Zero,
+            2 => Rounding::Up,
+            _ => Rounding::Down
+        }
+    }
+
+    pub fn condition(&self) -> bool {
+        self.fcsr & FCC0 != 0
+    }
+
+    pub fn set_condition(&mut self, value: bool) {
+        if value { self.fcsr |= FCC0 } else { self.fcsr &= !FCC0 }
+    }
+
+    // sticky exception flags
+    pub fn flags(&self) -> u32 {
+        (self.fcsr >> FLAGS_SHIFT) & EXCEPTIONS
+    }
+
+    // Record the exceptions of an operation in Cause. True if one of them is enabled,
+    // in which case the operation traps and neither the flags nor the destination change
+    pub fn signal(&mut self, exceptions: u32) -> bool {
+        self.fcsr = (self.fcsr & !(EXCEPTIONS << CAUSE_SHIFT)) | exceptions << CAUSE_SHIFT;
+        let enables = (self.fcsr >> ENABLES_SHIFT) & EXCEPTIONS;
+        if exceptions & enables != 0 {
+            return true;
+        }
+        self.fcsr |= exceptions << FLAGS_SHIFT;
+        false
+    }
+}
+
+// Names of the exceptions set in a mask, for print_state
+pub fn exception_names(exceptions: u32) -> String {
+    let names = [(INVALID, "V"), (DIVIDE_BY_ZERO, "Z"), (OVERFLOW, "O"), (UNDERFLOW, "U"), (INEXACT, "I")];
+    let set: Vec<&str> = names.iter().filter(|(bit, _)| exceptions & bit != 0).map(|(_, name)| *name).collect();
+    if set.is_empty() { String::from("-") } else { set.join("") }
+}
+
+// The two host float types, for arithmetic written once for both precisions
+pub trait Real: Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self>
+    + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self> {
+    const ZERO: Self;
+    const MAX: Self;
+    const MIN_POSITIVE: Self;
+    const SCALE: Self;      // power of two that lifts the error of a tiny result out of underflow
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_infinite(self) -> bool;
+}
+
+macro_rules! real {
+    ($t:ty, $scale:literal) => {
+        impl Real for $t {
+            const ZERO: Self = 0.0;
+            const MAX: Self = <$t>::MAX;
+            const MIN_POSITIVE: Self = <$t>::MIN_POSITIVE;
+            const SCALE: Self = $scale;
+            fn mul_add(self, a: Self, b: Self) -> Self { <$t>::mul_add(self, a, b) }
+            fn sqrt(self) -> Self { <$t>::sqrt(self) }
+            fn abs(self) -> Self { <$t>::abs(self) }
+            fn next_up(self) -> Self { <$t>::next_up(self) }
+            fn next_down(self) -> Self { <$t>::next_down(self) }
+            fn is_nan(self) -> bool { <$t>::is_nan(self) }
+            fn is_finite(self) -> bool { <$t>::is_finite(self) }
+            fn is_infinite(self) -> bool { <$t>::is_infinite(self) }
+        }
+    };
+}
+
+real!(f32, 1.2676506e30);     // 2^100
+real!(f64, 4.149515568880993e180);     // 2^600
+
+fn sign<T: Real>(value: T) -> Ordering {
+    value.partial_cmp(&T::ZERO).unwrap_or(Ordering::Equal)
+}
+
+fn product_sign<T: Real>(a: T, b: T) -> Ordering {
+    match sign(b) {
+        Ordering::Less => sign(a).reverse(),
+        Ordering::Equal => Ordering::Equal,
+        Ordering::Greater => sign(a)
+    }
+}
+
+// How a * b compares to its rounded result. The fused multiply-add is exact unless
+// the difference underflows, so for tiny results the smaller operand is scaled up first
+fn product_error<T: Real>(a: T, b: T, nearest: T) -> Ordering {
+    if nearest == T::ZERO {
+        return product_sign(a, b);
+    }
+    if nearest.abs() >= T::MIN_POSITIVE {
+        return sign(a.mul_add(b, -nearest));
+    }
+    let (small, large) = if a.abs() < b.abs() { (a, b) } else { (b, a) };
+    sign((small * T::SCALE).mul_add(large, -(nearest * T::SCALE)))
+}
+
+// How a / b compares to its rounded result, from the sign of the remainder a - nearest * b
+fn quotient_error<T: Real>(a: T, b: T, nearest: T) -> Ordering {
+    if nearest == T::ZERO {
+        return product_sign(a, b);
+    }
+    let remainder = if nearest.abs() >= T::MIN_POSITIVE {
+        (-nearest).mul_add(b, a)
+    } else {
+        (-(nearest * T::SCALE)).mul_add(b, a * T::SCALE)
+    };
+    product_sign(remainder, b)
+}
+
+// Round an operation to the rounding mode. nearest is its round to nearest result and
+// error how the exact result compares to nearest. Returns the result and its exceptions
+fn round<T: Real>(nearest: T, error: Ordering, rounding: Rounding) -> (T, u32) {
+    if nearest.is_infinite() {
+        // overflow from finite operands, some modes stop at the largest finite value
+        let positive = nearest > T::ZERO;
+        let result = match (rounding, positive) {
+            (Rounding::Zero, _) | (Rounding::Down, true) | (Rounding::Up, false) => if positive { T::MAX } else { -T::MAX },
+            _ => nearest
+        };
+        return (result, OVERFLOW | INEXACT);
+    }
+    if error == Ordering::Equal {
+        return (nearest, 0);
+    }
+
+    let result = match rounding {
+        Rounding::Nearest => nearest,
+        Rounding::Up if error == Ordering::Greater => nearest.next_up(),
+        Rounding::Down if error == Ordering::Less => nearest.next_down(),
+        // towards zero, only when nearest rounded away from it
+        Rounding::Zero if error != sign(nearest) && sign(nearest) != Ordering::Equal => {
+            if nearest > T::ZERO { nearest.next_down() } else { nearest.next_up() }
+        }
+        _ => nearest
+    };
+    if result.is_infinite() {
+        return (result, OVERFLOW | INEXACT);
+    }
+    let tiny = nearest.abs() < T::MIN_POSITIVE;
+    (result, if tiny { INEXACT | UNDERFLOW } else { INEXACT })
+}
+
+// NaN results: operand NaNs pass through quietly, new ones are invalid operations
+fn special<T: Real>(result: T, operands: &[T]) -> Option<(T, u32)> {
+    if !result.is_nan() {
+        return None;
+    }
+    let exceptions = if operands.iter().any(|x| x.is_nan()) { 0 } else { INVALID };
+    Some((result, exceptions))
+}
+
+pub fn add<T: Real>(a: T, b: T, rounding: Rounding) -> (T, u32) {
+    let nearest = a + b;
+    if let Some(result) = special(nearest, &[a, b]) {
+        return result;
+    }
+    if !(a.is_finite() && b.is_finite()) {
+        return (nearest, 0);
+    }
+    // TwoSum, the exact error of a + b
+    let b_virtual = nearest - a;
+    let error = (a - (nearest - b_virtual)) + (b - b_virtual);
+    round(nearest, sign(error), rounding)
+}
+
+pub fn sub<T: Real>(a: T, b: T, rounding: Rounding) -> (T, u32) {
+    add(a, -b, rounding)
+}
+
+pub fn mul<T: Real>(a: T, b: T, rounding: Rounding) -> (T, u32) {
+    let nearest = a * b;
+    if let Some(result) = special(nearest, &[a, b]) {
+        return result;
+    }
+    if !(a.is_finite() && b.is_finite()) {
+        return (nearest, 0);
+    }
+    round(nearest, product_error(a, b, nearest), rounding)
+}
+
+pub fn div<T: Real>(a: T, b: T, rounding: Rounding) -> (T, u32) {
+    let nearest = a / b;
+    if let Some(result) = special(nearest, &[a, b]) {
+        return result;
+    }
+    if b == T::ZERO && a.is_finite() {
+        return (nearest, DIVIDE_BY_ZERO);
+    }
+    if !(a.is_finite() && b.is_finite()) {
+        return (nearest, 0);
+    }
+    round(nearest, quotient_error(a, b, nearest), rounding)
+}
+
+pub fn sqrt<T: Real>(a: T, rounding: Rounding) -> (T, u32) {
+    let nearest = a.sqrt();
+    if let Some(result) = special(nearest, &[a]) {
+        return result;
+    }
+    if !a.is_finite() {
+        return (nearest, 0);
+    }
+    round(nearest, sign((-nearest).mul_add(nearest, a)), rounding)
+}
+
+// cvt.s.d
+pub fn narrow(value: f64, rounding: Rounding) -> (f32, u32) {
+    let nearest = value as f32;
+    if value.is_nan() || value.is_infinite() {
+        return (nearest, 0);
+    }
+    round(nearest, sign(value - nearest as f64), rounding)
+}
+
+// cvt.s.w, words past 2^24 don't all fit in a single
+pub fn word_to_single(value: i32, rounding: Rounding) -> (f32, u32) {
+    let nearest = value as f32;
+    round(nearest, sign(value as f64 - nearest as f64), rounding)
+}
+
+// cvt.w.s and cvt.w.d
+pub fn to_word(value: f64, rounding: Rounding) -> (i32, u32) {
+    let rounded = match rounding {
+        Rounding::Nearest => value.round_ties_even(),
+        Rounding::Zero => value.trunc(),
+        Rounding::Up => value.ceil(),
+        Rounding::Down => value.floor()
+    };
+    if !(i32::MIN as f64..=i32::MAX as f64).contains(&rounded) {
+        return (INVALID_WORD, INVALID);
+    }
+    (rounded as i32, if rounded == value { 0 } else { INEXACT })
+}
+
+// c.eq is a quiet comparison, c.lt and c.le signal invalid on NaN
+pub fn compare<T: Real>(a: T, b: T, signaling: bool) -> (Option<Ordering>, u32) {
+    let order = a.partial_cmp(&b);
+    let exceptions = if order.is_none() && signaling { INVALID } else { 0 };
+    (order, exceptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directed_rounding_brackets_the_exact_result() {
+        let third = |rounding| div(1.0f32, 3.0, rounding);
+        let (down, exceptions) = third(Rounding::Down);
+        let (up, _) = third(Rounding::Up);
+        assert_eq!(exceptions, INEXACT);
+        assert_eq!(up, down.next_up());
+        assert_eq!(third(Rounding::Zero).0, down);
+        assert_eq!(div(-1.0f32, 3.0, Rounding::Zero).0, -down);
+
+        assert_eq!(add(1.0f64, 2.0, Rounding::Up), (3.0, 0));
+        assert_eq!(add(1.0f64, 1e-30, Rounding::Up), (1.0f64.next_up(), INEXACT));
+        assert_eq!(mul(f32::MAX, 2.0, Rounding::Zero), (f32::MAX, OVERFLOW | INEXACT));
+        assert_eq!(sqrt(2.0f64, Rounding::Up).0, std::f64::consts::SQRT_2);
+        assert_eq!(sqrt(2.0f64, Rounding::Down).0, std::f64::consts::SQRT_2.next_down());
+        assert_eq!(to_word(2.5, Rounding::Nearest), (2, INEXACT));
+        assert_eq!(to_word(-2.5, Rounding::Down), (-3, INEXACT));
+        assert_eq!(to_word(f64::NAN, Rounding::Nearest), (i32::MAX, INVALID));
+    }
+
+    #[test]
+    fn special_operands_raise_the_right_flags() {
+        assert_eq!(div(1.0f32, 0.0, Rounding::Nearest), (f32::INFINITY, DIVIDE_BY_ZERO));
+        assert_eq!(div(0.0f32, 0.0, Rounding::Nearest).1, INVALID);
+        assert_eq!(sqrt(-1.0f64, Rounding::Nearest).1, INVALID);
+        assert_eq!(add(f64::NAN, 1.0, Rounding::Nearest).1, 0);
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.5, Rounding::Nearest), (f32::MIN_POSITIVE / 2.0, 0));
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.3, Rounding::Nearest).1, INEXACT | UNDERFLOW);
+        assert_eq!(mul(f64::MIN_POSITIVE, 1e-300, Rounding::Up), (f64::from_bits(1), INEXACT | UNDERFLOW));
+        assert_eq!(div(f64::MIN_POSITIVE, 3.0, Rounding::Nearest).1, INEXACT | UNDERFLOW);
+        assert_eq!(compare(f32::NAN, 1.0, true), (None, INVALID));
+        assert_eq!(compare(f32::NAN, 1.0, false), (None, 0));
+    }
+
+    #[test]
+    fn enabled_exceptions_trap_without_setting_flags() {
+        let mut cp1 = Cp1::default();
+        assert!(!cp1.signal(INEXACT));
+        assert_eq!(cp1.flags(), INEXACT);
+        cp1.fcsr |= DIVIDE_BY_ZERO << ENABLES_SHIFT;
+        assert!(cp1.signal(DIVIDE_BY_ZERO));
+        assert_eq!(cp1.flags(), INEXACT);
+        assert_eq!((cp1.fcsr >> CAUSE_SHIFT) & EXCEPTIONS, DIVIDE_BY_ZERO);
+    }
+}
@@ -1,15 +1,63 @@
+use std::collections::HashMap;
 use super::arch;
+use super::arch::Fault;
+use super::bus::{Bus, Endian, RegionMap};
+use super::cache::{Hierarchy, Kind};
+use super::mmu::{self, Access, Miss, Mmu, TlbEntry, Translation};
+use super::predictor::BranchUnit;
+use super::cp0::{self, Cp0, Exception, ExceptionCode};
+use super::interrupt::{InterruptController, Taken};
+use super::cp1::{self, Cp1};
+use super::syscall::{Outcome, Syscalls};
 use crate::datatypes::Program;
-/**
+use crate::software::disassemble;
+/*
  * CPU implementation of the architecture defintions
  * sammc
  */
 
+// A load or store an instruction, or a syscall service on its behalf, made at a virtual address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u32,
+    pub size: u32,
+    pub access: Access,
+    pub value: u32          // loaded, or stored masked to size
+}
+
+// What the trace records: instructions as they execute and the interrupts taken between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traced {
+    Instruction { address: u32, word: u32 },
+    Interrupt(Taken)
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub debug_mode: bool,      // debug_mode
     pub registers: Vec<i32>,   // Registers
-    pub memory: Vec<u8>,       // Memory. This needs to be a Vec() otherwise it stack overflows
-    pub program_counter: u32   // Program Counter
+    pub bus: RegionMap,        // Physical address space, RAM from 0 to END_MEM unless remapped
+    pub program_counter: u32,  // Program Counter
+    pub hi: i32,               // HI, upper product word or remainder
+    pub lo: i32,               // LO, lower product word or quotient
+    pub cp0: Cp0,              // Coprocessor 0, exception state
+    pub cp1: Cp1,              // Coprocessor 1, FPU registers and FCSR
+    pub exception_handler: Option<u32>,  // Address exceptions vector to, None stops the run
+    pub syscalls: Option<Syscalls>,  // Services behind syscall, None leaves it to the exception handler
+    pub heap_end: u32,         // Program break, moved up by sbrk
+    pub symbols: HashMap<String, u32>,  // Labels of the loaded program, for debug output
+    pub trace: Option<Vec<Traced>>,  // Executed instructions and interrupts taken, recorded while Some
+    pub accesses: Option<Vec<MemoryAccess>>,  // Loads and stores that went through, recorded while Some
+    pub overwritten: Option<Vec<(u32, u8)>>,  // Physical bytes written over and what they held, recorded while Some
+    pub mmu: Option<Mmu>,      // Segments and TLB in front of memory, None addresses memory directly
+    pub interrupts: InterruptController,  // Device lines onto Cause.IP, and the interrupts taken
+    pub caches: Option<Hierarchy>,  // Caches fetches, loads and stores go through, None for flat memory
+    pub branches: Option<BranchUnit>,  // Branch predictor scoring every control transfer
+    pub cycles: u64,           // One per instruction executed plus memory and misprediction stalls
+    pub retired: u64,          // Instructions executed
+    instruction_address: u32,  // Address of the instruction executing, EPC if it raises
+    exit_code: Option<i32>,    // Set when the program exits through a syscall
+    fault: Option<Fault>       // Why the run has to stop
 }
 
 // CPU Implementation
@@ -20,63 +68,278 @@ impl CPU {
         let res = CPU {
             debug_mode: true,
             registers: vec![0; arch::REG_NUM as usize],
-            memory: vec![0; arch::MEM_SIZE as usize],
-            program_counter: arch::PC_START as u32
+            bus: CPU::default_bus(),
+            program_counter: arch::PC_START,
+            hi: 0,
+            lo: 0,
+            cp0: Cp0::default(),
+            cp1: Cp1::default(),
+            exception_handler: None,
+            syscalls: Some(Syscalls::stdio()),
+            heap_end: arch::DYNAMIC_DATA,
+            symbols: HashMap::new(),
+            trace: None,
+            accesses: None,
+            overwritten: None,
+            mmu: None,
+            interrupts: InterruptController::new(),
+            caches: None,
+            branches: None,
+            cycles: 0,
+            retired: 0,
+            instruction_address: arch::PC_START,
+            exit_code: None,
+            fault: None
         };
         if res.debug_mode { res.print_state() };
         res
     }
 
 
-    fn fetch_decode_execute_loop(&mut self) {
-        let mut cycle_count: u32 = 1;
-        while self.program_counter < arch::STATIC_DATA {
-            // Fetch instruction
-            let instruction: u32 = self.read_word_from_mem(self.program_counter);
+    fn fetch_decode_execute_loop(&mut self) -> Result<i32, Fault> {
+        while self.step()?.is_none() {}
 
-            if self.debug_mode { 
-                print!("CYCLE::{:03} INSTRUCTION::{:#010x}  ", cycle_count, instruction);
-                if cycle_count % 4 == 0 { println!(); }
-            }
+        if self.debug_mode {
+            self.print_state();
+            println!("cycles {}", self.cycles);
+            self.print_statistics();
+        }
+        Ok(self.exit_code.take().unwrap_or(0))
+    }
 
-            if instruction == 0xFFFFFFFF as u32 {
-                break;
-            }
+    // Run the instruction at the PC, or take the exception fetching it raises. Some exit code
+    // once the program has ended, by running out of text, halting or exiting through a
+    // syscall, and again on every step after that
+    pub fn step(&mut self) -> Result<Option<i32>, Fault> {
+        if self.exit_code.is_some() || !self.in_text(self.program_counter) {
+            return Ok(Some(self.exit_code.unwrap_or(0)));
+        }
+        let Some(instruction) = self.fetch()? else { return Ok(None) };
+
+        if self.debug_mode {
+            let text = disassemble::disassemble(instruction, self.program_counter, &self.symbols);
+            println!("CYCLE::{:03} PC::{:#010x} INSTRUCTION::{:#010x}  {}", self.retired + 1, self.program_counter, instruction, text);
+        }
+
+        if instruction == arch::HALT {
+            return Ok(Some(0));
+        }
+        self.execute(instruction)?;
+        Ok(self.exit_code)
+    }
+
+    // Read the instruction at the PC. A PC that can't be read takes an address or TLB
+    // exception, which gives None when a handler takes it
+    pub fn fetch(&mut self) -> Result<Option<u32>, Fault> {
+        self.instruction_address = self.program_counter;
+        let instruction = self.read(self.program_counter, 4, Access::Fetch);
+        self.check_fault()?;
+        Ok(instruction)
+    }
+
+    // Read an instruction word through the MMU and instruction cache, None instead of
+    // an exception if it can't be, for fetches that may be on the wrong path
+    pub fn fetch_word(&mut self, address: u32) -> Option<u32> {
+        if !address.is_multiple_of(4) {
+            return None;
+        }
+        let translation = self.try_translate(address, Access::Fetch).ok()?;
+        let word = self.bus.read(translation.physical, 4, Access::Fetch).ok()?;
+        self.cache(Kind::Fetch, translation);
+        Some(word)
+    }
+
+    // Whether a fetch from address is still in the program. Without an MMU that ends with
+    // the text segment, with one the program decides where code lives
+    pub fn in_text(&self, address: u32) -> bool {
+        self.mmu.is_some() || address < arch::STATIC_DATA
+    }
+
+    // Physical address a virtual one maps to for the running program, without counting
+    // the translation or raising anything. Used by syscall services and debuggers
+    pub fn physical(&self, address: u32, access: Access) -> Option<u32> {
+        match &self.mmu {
+            Some(mmu) => mmu.lookup(address, access, self.cp0.user_mode(), self.cp0.entry_hi).ok().map(|t| t.physical),
+            None => Some(address)
+        }
+    }
+
+    // Execute the instruction fetched from the PC, leaving the PC at the next one to run
+    pub fn execute(&mut self, instruction: u32) -> Result<(), Fault> {
+        self.begin(instruction);
+        // advance first so branches and jal see the address of the next instruction
+        self.program_counter = self.program_counter.wrapping_add(4);
+        if !arch::MipsIsa::decode_execute(self, instruction) {
+            self.raise(ExceptionCode::ReservedInstruction, None);
+        }
+        self.registers[0] = 0; // ensure zero register is 0
+        self.finish(instruction)
+    }
+
+    // Account for an instruction at address that a pipeline carried out on its own datapath
+    // and that went on to next, as execute would have: counting and tracing it, ticking the
+    // devices and taking a pending interrupt, which leaves the PC at the handler
+    pub fn retire(&mut self, address: u32, instruction: u32, next: u32) -> Result<(), Fault> {
+        self.program_counter = address;
+        self.begin(instruction);
+        self.program_counter = next;
+        self.finish(instruction)
+    }
 
-            arch::MipsIsa::decode_execute(self, instruction);
-            self.registers[0] = 0; // ensure zero register is 0
-            self.program_counter += 4;
-            cycle_count += 1;
+    // count and trace the instruction at the PC
+    fn begin(&mut self, instruction: u32) {
+        self.instruction_address = self.program_counter;
+        self.cycles += 1;
+        self.retired += 1;
+        if let Some(trace) = &mut self.trace {
+            trace.push(Traced::Instruction { address: self.program_counter, word: instruction });
         }
+    }
 
+    // everything after an instruction has run, the PC being where it went
+    fn finish(&mut self, instruction: u32) -> Result<(), Fault> {
+        if let Some(branches) = &mut self.branches {
+            self.cycles += branches.observe(self.instruction_address, instruction, self.program_counter);
+        }
+        self.bus.tick();
+        self.bus.clock(self.cycles);
+        self.cp0.tick();
+        self.cp0.signal(self.interrupts.lines(&self.bus));
+        self.check_fault()?;
+        self.interrupt();
+        Ok(())
+    }
+
+    // Take a pending interrupt before the next instruction, if the program has enabled it and
+    // has a handler. EPC is the instruction it would have run
+    fn interrupt(&mut self) {
+        let pending = self.cp0.interrupts();
+        let Some(handler) = self.exception_handler.filter(|_| pending != 0) else { return };
+        self.cp0.enter(ExceptionCode::Interrupt, self.program_counter, None);
+        let taken = Taken { cycle: self.cycles, epc: self.program_counter, pending };
         if self.debug_mode {
-            println!();
-            self.print_state();
+            println!("INTERRUPT::{taken}");
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.push(Traced::Interrupt(taken));
+        }
+        self.interrupts.log.push(taken);
+        self.program_counter = handler;
+    }
+
+    // How the caches and the branch predictor did, for those attached
+    pub fn print_statistics(&self) {
+        if let Some(mmu) = &self.mmu {
+            println!("{mmu}");
+        }
+        if let Some(caches) = &self.caches {
+            println!("{caches}");
+        }
+        if let Some(branches) = &self.branches {
+            print!("{}", branches.report(&self.symbols));
+        }
+    }
+
+    // Exit code of a program that has exited through a syscall
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // Take the exit code, so the next run starts without one
+    pub fn take_exit_code(&mut self) -> Option<i32> {
+        self.exit_code.take()
+    }
+
+    // Put back an exit code, or the lack of one, as a restored snapshot had it
+    pub fn set_exit_code(&mut self, code: Option<i32>) {
+        self.exit_code = code;
+    }
+
+    // Take an exception for the executing instruction: record it in CP0 and vector
+    // to the handler, or keep it to stop the run when there is none
+    pub fn raise(&mut self, code: ExceptionCode, bad_vaddr: Option<u32>) {
+        self.raise_to(code, bad_vaddr, None);
+    }
+
+    // raise, vectoring to handler rather than exception_handler if there is one
+    fn raise_to(&mut self, code: ExceptionCode, bad_vaddr: Option<u32>, handler: Option<u32>) {
+        self.cp0.enter(code, self.instruction_address, bad_vaddr);
+        match handler.or(self.exception_handler) {
+            Some(handler) => self.program_counter = handler,
+            None => self.fault = Some(Fault::Exception(Exception { code, pc: self.instruction_address, bad_vaddr }))
+        }
+    }
+
+    fn check_fault(&mut self) -> Result<(), Fault> {
+        match self.fault.take() {
+            Some(fault) => {
+                if self.debug_mode {
+                    println!("{fault}");
+                    self.print_state();
+                }
+                Err(fault)
+            }
+            None => Ok(())
         }
     }
 
-    
     // pc and registers
-    fn print_state(&self) {
+    pub fn print_state(&self) {
         println!("-----------------------------------------------------------------------------------------------------------------------------------------------");
         println!("                                                               PC {:#010x}                                      ", self.program_counter);
         println!("-----------------------------------------------------------------------------------------------------------------------------------------------");
-        let width = 8;
-        let height = 4;
+        let width = 4;
+        let height = 8;
 
         for i in 0..height {
             for j in 0..width {
                 let reg_num: u32 = (width*i) + j;
-                print!("R[{:02}]: {:#010x} ", reg_num, self.registers[reg_num as usize]);
+                let name = format!("R[{:02}]/${}:", reg_num, arch::REGISTER_NAMES[reg_num as usize]);
+                print!("{:<13} {:#010x} ", name, self.registers[reg_num as usize]);
             }
             println!();
         }
+        print!("{:<13} {:#010x} ", "HI:", self.hi);
+        println!("{:<13} {:#010x} ", "LO:", self.lo);
+        print!("{:<13} {:#010x} ", "Status:", self.cp0.status);
+        print!("{:<13} {:#010x} ", "Cause:", self.cp0.cause);
+        print!("{:<13} {:#010x} ", "EPC:", self.cp0.epc);
+        println!("{:<13} {:#010x} ", "BadVAddr:", self.cp0.bad_vaddr);
+        print!("{:<13} {:#010x} ", "Count:", self.cp0.count);
+        println!("{:<13} {:#010x} ", "Compare:", self.cp0.compare);
+        if self.mmu.is_some() {
+            print!("{:<13} {:#010x} ", "Index:", self.cp0.index);
+            print!("{:<13} {:#010x} ", "EntryHi:", self.cp0.entry_hi);
+            print!("{:<13} {:#010x} ", "EntryLo0:", self.cp0.entry_lo0);
+            println!("{:<13} {:#010x} ", "EntryLo1:", self.cp0.entry_lo1);
+        }
+        println!("-----------------------------------------------------------------------------------------------------------------------------------------------");
+        self.print_fpu_state();
         println!("-----------------------------------------------------------------------------------------------------------------------------------------------");
         self.print_memory_contents();
         println!("-----------------------------------------------------------------------------------------------------------------------------------------------");
 
     }
     
+    // FPU registers, as raw bits since a register may hold a single, half a double or a word
+    fn print_fpu_state(&self) {
+        let width = 4;
+        let height = 8;
+
+        for i in 0..height {
+            for j in 0..width {
+                let reg_num: u32 = (width*i) + j;
+                let name = format!("F[{:02}]/$f{}:", reg_num, reg_num);
+                print!("{:<13} {:#010x} ", name, self.cp1.registers[reg_num as usize]);
+            }
+            println!();
+        }
+        print!("{:<13} {:#010x} ", "FCSR:", self.cp1.fcsr);
+        print!("{:<13} {:<10} ", "Rounding:", self.cp1.rounding().name());
+        print!("{:<13} {:<10} ", "FCC0:", self.cp1.condition() as u8);
+        println!("{:<13} {:<10} ", "Flags:", cp1::exception_names(self.cp1.flags()));
+    }
+
     // prints a configured amount of memory content
     fn print_memory_contents(&self) {
         let width = 11;
@@ -84,102 +347,315 @@ impl CPU {
 
         let mut addr: u32 = arch::PC_START;
         println!("Starting @ {:#010x}", arch::PC_START);
-        for i in 0..height {
+        for _ in 0..height {
             print!("ADDR:{:#010x}      |", addr);
-            for j in 0..width {
-                print!("{:#010x}|", self.read_word_from_mem(addr));
+            for _ in 0..width {
+                print!("{:#010x}|", self.read_word_from_mem(addr).unwrap_or_default());
                 addr += 4;
             }
-            println!("");
+            println!();
 
         }
 
     }
 
 
+    // RAM over the whole of the original memory map
+    fn default_bus() -> RegionMap {
+        let mut bus = RegionMap::new(Endian::Big);
+        bus.map_ram("ram", 0, arch::MEM_SIZE).expect("an empty bus has room for RAM");
+        bus
+    }
+
     pub fn load_memory(&mut self, address: u32, payload: Vec<u32>) {
-        if address % 4 != 0 {
-            return ();
+        if !address.is_multiple_of(4) {
+            return;
+        }
+        let bytes: Vec<u8> = payload.iter().flat_map(|&word| self.bus.endian.to_bytes(word, 4)).collect();
+        self.bus.load(address, &bytes);
+    }
+
+    pub fn load_bytes(&mut self, address: u32, payload: Vec<u8>) {
+        self.bus.load(address, &payload);
+    }
+
+    // take a branch, offset in words from the instruction after the branch
+    fn branch(&mut self, immediate: i16) {
+        self.program_counter = self.program_counter.wrapping_add(((immediate as i32) << 2) as u32);
+    }
+
+    // base register plus sign extended offset, as used by loads and stores
+    fn effective_address(&self, rs: u32, immediate: i16) -> u32 {
+        self.registers[rs as usize].wrapping_add(immediate as i32) as u32
+    }
+
+    // Physical reads and writes through the bus, None if misaligned or nothing there.
+    // Reads peek, so looking at memory from outside never disturbs a device
+    pub fn read_byte_from_mem(&self, address: u32) -> Option<u8> {
+        self.bus.peek(address, 1).map(|value| value as u8)
+    }
+
+    pub fn read_half_from_mem(&self, address: u32) -> Option<u16> {
+        self.bus.peek(address, 2).map(|value| value as u16)
+    }
+
+    pub fn read_word_from_mem(&self, address: u32) -> Option<u32> {
+        self.bus.peek(address, 4)
+    }
+
+    pub fn write_byte_to_mem(&mut self, address: u32, value: u8) -> Option<()> {
+        self.overwrite(address, 1);
+        self.bus.write(address, 1, value as u32).ok()
+    }
+
+    pub fn write_half_to_mem(&mut self, address: u32, value: u16) -> Option<()> {
+        self.overwrite(address, 2);
+        self.bus.write(address, 2, value as u32).ok()
+    }
+
+    pub fn write_word_to_mem(&mut self, address: u32, value: u32) -> Option<()> {
+        self.overwrite(address, 4);
+        self.bus.write(address, 4, value).ok()
+    }
+
+    // Keep the bytes a write is about to replace, while they are being recorded
+    fn overwrite(&mut self, address: u32, size: u32) {
+        let Some(overwritten) = &mut self.overwritten else { return };
+        for address in (0..size).map(|i| address.wrapping_add(i)) {
+            if let Some(byte) = self.bus.peek(address, 1) {
+                overwritten.push((address, byte as u8));
+            }
+        }
+    }
+
+    // Loads and stores for a pipeline's MEM stage. None instead of the exception the access
+    // would raise, which the pipeline takes by replaying the instruction through execute
+    pub fn try_load(&mut self, address: u32, size: u32) -> Option<u32> {
+        self.physical(address, Access::Load).filter(|_| address.is_multiple_of(size))?;
+        let translation = self.try_translate(address, Access::Load).ok()?;
+        let value = self.bus.read(translation.physical, size, Access::Load).ok()?;
+        self.cache(Kind::Read, translation);
+        self.record(MemoryAccess { address, size, access: Access::Load, value });
+        Some(value)
+    }
+
+    pub fn try_store(&mut self, address: u32, size: u32, value: u32) -> Option<()> {
+        let physical = self.physical(address, Access::Store).filter(|_| address.is_multiple_of(size))?;
+        if !self.bus.writable(physical, size) {
+            return None;
+        }
+        let translation = self.try_translate(address, Access::Store).ok()?;
+        let mask = if size == 4 { u32::MAX } else { (1 << (size * 8)) - 1 };
+        self.overwrite(translation.physical, size);
+        self.bus.write(translation.physical, size, value & mask).ok()?;
+        self.cache(Kind::Write, translation);
+        self.record(MemoryAccess { address, size, access: Access::Store, value: value & mask });
+        Some(())
+    }
+
+    // Read of size bytes for a load instruction, raising the exception it takes if it can't be done
+    fn load(&mut self, address: u32, size: u32) -> Option<u32> {
+        let value = self.read(address, size, Access::Load)?;
+        self.record(MemoryAccess { address, size, access: Access::Load, value });
+        Some(value)
+    }
+
+    // Note a load or store while accesses are being recorded, including those a syscall
+    // service makes for the program, so watchpoints see its buffers change
+    pub fn record(&mut self, access: MemoryAccess) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(access);
+        }
+    }
+
+    fn read(&mut self, address: u32, size: u32, access: Access) -> Option<u32> {
+        let translation = self.translate(address, size, access)?;
+        match self.bus.read(translation.physical, size, access) {
+            Ok(value) => {
+                self.cache(if access == Access::Fetch { Kind::Fetch } else { Kind::Read }, translation);
+                Some(value)
+            }
+            Err(_) => {
+                self.bus_error(address, access);
+                None
+            }
+        }
+    }
+
+    // A physical access the bus refused, unmapped or against the region's permissions.
+    // The address goes in BadVAddr for the handler, though MIPS leaves it alone
+    fn bus_error(&mut self, address: u32, access: Access) {
+        let code = if access == Access::Fetch { ExceptionCode::InstructionBus } else { ExceptionCode::DataBus };
+        self.raise(code, Some(address));
+    }
+
+    // Physical address of an aligned access, raising the address or TLB exception it takes if there is none
+    fn translate(&mut self, address: u32, size: u32, access: Access) -> Option<Translation> {
+        let result = if address.is_multiple_of(size) { self.try_translate(address, access) } else { Err(Miss::Address) };
+        let miss = match result {
+            Ok(translation) => return Some(translation),
+            Err(miss) => miss
+        };
+
+        let code = match (miss, access) {
+            (Miss::Address, Access::Store) => ExceptionCode::AddressStore,
+            (Miss::Address, _) => ExceptionCode::AddressLoad,
+            (Miss::Modified, _) => ExceptionCode::TlbModified,
+            (_, Access::Store) => ExceptionCode::TlbStore,
+            _ => ExceptionCode::TlbLoad
+        };
+        if miss != Miss::Address {
+            self.cp0.enter_tlb(address);
         }
+        // refills outside a handler have their own vector
+        let refill = miss == Miss::Refill && self.cp0.status & cp0::STATUS_EXL == 0;
+        let handler = self.mmu.as_ref().and_then(|mmu| mmu.refill_handler).filter(|_| refill);
+        self.raise_to(code, Some(address), handler);
+        None
+    }
 
-        if address + (payload.len() * 4) as u32 > arch::END_MEM {
-            return ();
+    // Translate and count an access without raising, addresses are physical without an MMU
+    fn try_translate(&mut self, address: u32, access: Access) -> Result<Translation, Miss> {
+        let (user, entry_hi) = (self.cp0.user_mode(), self.cp0.entry_hi);
+        match &mut self.mmu {
+            Some(mmu) => mmu.translate(address, access, user, entry_hi),
+            None => Ok(Translation { physical: address, cached: true })
         }
+    }
 
-        let mut offset = 0;
-        for data in payload {
-            self.write_word_to_mem(address + (offset), data);
-            offset += 4;
+    // An access that went through, charged to the cycle count when there are caches
+    fn cache(&mut self, kind: Kind, translation: Translation) {
+        if let (Some(caches), true) = (&mut self.caches, translation.cached) {
+            let latency = caches.access(kind, translation.physical);
+            self.cycles += latency - caches.l1_latency(kind);
         }
     }
 
-    pub fn read_word_from_mem(&self, address: u32) -> u32 {
-        // must be word boundary
-        if address % 4 != 0 {
-            return 0;
+    // CP0 instructions are for the kernel, user mode takes a coprocessor unusable exception
+    fn kernel(&mut self) -> bool {
+        if self.cp0.user_mode() {
+            self.raise(ExceptionCode::CoprocessorUnusable, None);
+            return false;
         }
-        // construct word
-        let mut acc: u32 = 0;
-        acc += (self.memory[address as usize] as u32) << 24;
-        acc += (self.memory[(address+1) as usize] as u32) << 16;
-        acc += (self.memory[(address+2) as usize] as u32) << 8;
-        acc += self.memory[(address+3) as usize] as u32;
-        acc
+        true
     }
 
-    pub fn write_word_to_mem(&mut self, address: u32, value: u32) {
-        // must be word boundary
-        if address % 4 != 0 {
-            return ();
+    // Write an entry from EntryHi and EntryLo0/1, reserved without a TLB
+    fn write_tlb(&mut self, index: impl FnOnce(&mut Cp0, usize) -> usize) {
+        if !self.kernel() {
+            return;
         }
+        let Some(mmu) = &mut self.mmu else { return self.raise(ExceptionCode::ReservedInstruction, None) };
+        let i = index(&mut self.cp0, mmu.tlb.len());
+        mmu.tlb[i] = TlbEntry { entry_hi: self.cp0.entry_hi, entry_lo0: self.cp0.entry_lo0, entry_lo1: self.cp0.entry_lo1 };
+    }
 
-        // split the word into bytes
-        let msb: u8 = (value >> 24) as u8;
-        let higher: u8 = ((value >> 16) & 0xFF) as u8;
-        let lower: u8 = ((value >> 8) & 0xFF) as u8;
-        let lsb: u8 = ((value) & 0xFF) as u8;
+    // Cause and flags for the exceptions of an FPU operation, false if one traps
+    // and the result must not be written
+    fn fpu_signal(&mut self, exceptions: u32) -> bool {
+        if self.cp1.signal(exceptions) {
+            self.raise(ExceptionCode::FloatingPoint, None);
+            return false;
+        }
+        true
+    }
 
-        // store each byte
-        self.memory[(address) as usize] = msb;
-        self.memory[(address + 1) as usize] = higher;
-        self.memory[(address + 2) as usize] = lower;
-        self.memory[(address + 3) as usize] = lsb;
+    // doubles live in even/odd register pairs, naming an odd register is reserved
+    fn even_registers(&mut self, registers: &[u32]) -> bool {
+        if registers.iter().any(|r| r % 2 != 0) {
+            self.raise(ExceptionCode::ReservedInstruction, None);
+            return false;
+        }
+        true
+    }
+
+    fn single_result(&mut self, fd: u32, (value, exceptions): (f32, u32)) {
+        if self.fpu_signal(exceptions) {
+            self.cp1.set_single(fd, value);
+        }
+    }
+
+    fn double_result(&mut self, fd: u32, (value, exceptions): (f64, u32)) {
+        if self.fpu_signal(exceptions) {
+            self.cp1.set_double(fd, value);
+        }
+    }
+
+    fn word_result(&mut self, fd: u32, (value, exceptions): (i32, u32)) {
+        if self.fpu_signal(exceptions) {
+            self.cp1.set_word(fd, value);
+        }
+    }
+
+    fn condition_result(&mut self, (order, exceptions): (Option<std::cmp::Ordering>, u32), holds: fn(std::cmp::Ordering) -> bool) {
+        if self.fpu_signal(exceptions) {
+            self.cp1.set_condition(order.is_some_and(holds));
+        }
+    }
+
+    // Write of the low size bytes of value for a store instruction
+    fn store(&mut self, address: u32, size: u32, value: u32) {
+        let Some(translation) = self.translate(address, size, Access::Store) else { return };
+        self.store_translated(address, size, value, translation);
+    }
+
+    // store once the address has been translated
+    fn store_translated(&mut self, address: u32, size: u32, value: u32, translation: Translation) {
+        let mask = if size == 4 { u32::MAX } else { (1 << (size * 8)) - 1 };
+        self.overwrite(translation.physical, size);
+        match self.bus.write(translation.physical, size, value & mask) {
+            Ok(()) => self.cache(Kind::Write, translation),
+            Err(_) => return self.bus_error(address, Access::Store)
+        }
+        self.record(MemoryAccess { address, size, access: Access::Store, value: value & mask });
     }
 
 }
 
 
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl arch::Computer for CPU {
     fn load_program(&mut self, program: Program) {
         let max_program_size: usize = (arch::STATIC_DATA - arch::PC_START) as usize;
         let max_static_data_size: usize = (arch::DYNAMIC_DATA - arch::STATIC_DATA) as usize;
 
         // if either the program or the data is too long, return with no action
-        if program.instructions.len() > max_program_size || program.data.len() > max_static_data_size {
-            return ();
+        if program.instructions.len() * 4 > max_program_size || program.data.len() > max_static_data_size {
+            return;
         }
 
         // Load Instructions
         self.load_memory(arch::PC_START, program.instructions);
         // Load Static Data
-        self.load_memory(arch::STATIC_DATA, program.data);
+        self.load_bytes(arch::STATIC_DATA, program.data);
+        self.symbols = program.symbols;
+        // With an MMU the program boots as the kernel, through unmapped kseg0
+        if let Some(mmu) = &self.mmu {
+            self.program_counter = mmu::KSEG0 | arch::PC_START;
+            self.cp0.random = mmu.tlb.len() as u32 - 1;
+        }
     }
 
-    fn start(&mut self) {
-        self.fetch_decode_execute_loop();
+    fn start(&mut self) -> Result<i32, Fault> {
+        self.fetch_decode_execute_loop()
     }
 }
 
 impl arch::MipsIsa for CPU {
     fn add(&mut self, rs: u32, rt: u32, rd: u32) {
-        self.registers[rd as usize] = self.registers[rs as usize] + self.registers[rt as usize];
-
-        // TODO overflow
-
+        match self.registers[rs as usize].checked_add(self.registers[rt as usize]) {
+            Some(sum) => self.registers[rd as usize] = sum,
+            None => self.raise(ExceptionCode::Overflow, None)
+        }
     }
 
     fn addu(&mut self, rs: u32, rt: u32, rd: u32) {
-        self.registers[rd as usize] = self.registers[rs as usize] + self.registers[rt as usize];
+        self.registers[rd as usize] = self.registers[rs as usize].wrapping_add(self.registers[rt as usize]);
     }
 
     fn and(&mut self, rs: u32, rt: u32, rd: u32) {
@@ -211,65 +687,227 @@ impl arch::MipsIsa for CPU {
         self.registers[rd as usize] = if cmp {1} else {0};
     }
 
-    fn sll(&mut self, rs: u32, rt: u32, shamt: u32) {
-        self.registers[rt as usize] = self.registers[rs as usize] << shamt;
+    fn sll(&mut self, rt: u32, rd: u32, shamt: u32) {
+        self.registers[rd as usize] = self.registers[rt as usize] << shamt;
+
+    }
+
+    fn srl(&mut self, rt: u32, rd: u32, shamt: u32) {
+        self.registers[rd as usize] = ((self.registers[rt as usize] as u32) >> shamt) as i32;
+    }
 
+    fn sra(&mut self, rt: u32, rd: u32, shamt: u32) {
+        self.registers[rd as usize] = self.registers[rt as usize] >> shamt;
     }
 
-    fn srl(&mut self, rs: u32, rt: u32, shamt: u32) {
-        self.registers[rt as usize] = self.registers[rs as usize] >> shamt;
+    // variable shifts use the low 5 bits of rs
+    fn sllv(&mut self, rs: u32, rt: u32, rd: u32) {
+        let shamt = self.registers[rs as usize] as u32 & 0x1F;
+        self.registers[rd as usize] = self.registers[rt as usize] << shamt;
+    }
+
+    fn srlv(&mut self, rs: u32, rt: u32, rd: u32) {
+        let shamt = self.registers[rs as usize] as u32 & 0x1F;
+        self.registers[rd as usize] = ((self.registers[rt as usize] as u32) >> shamt) as i32;
+    }
+
+    fn srav(&mut self, rs: u32, rt: u32, rd: u32) {
+        let shamt = self.registers[rs as usize] as u32 & 0x1F;
+        self.registers[rd as usize] = self.registers[rt as usize] >> shamt;
     }
 
     fn sub(&mut self, rs: u32, rt: u32, rd: u32) {
-        self.registers[rd as usize] = self.registers[rs as usize] - self.registers[rt as usize];
+        match self.registers[rs as usize].checked_sub(self.registers[rt as usize]) {
+            Some(difference) => self.registers[rd as usize] = difference,
+            None => self.raise(ExceptionCode::Overflow, None)
+        }
     }
 
     fn subu(&mut self, rs: u32, rt: u32, rd: u32) {
-        self.registers[rd as usize] = (self.registers[rs as usize]) - (self.registers[rt as usize]); // TODO
+        self.registers[rd as usize] = self.registers[rs as usize].wrapping_sub(self.registers[rt as usize]);
     }
 
-    fn addi(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.registers[rs as usize] + immediate as i32; // TODO OVERFLOW
+    fn xor(&mut self, rs: u32, rt: u32, rd: u32) {
+        self.registers[rd as usize] = self.registers[rs as usize] ^ self.registers[rt as usize];
+    }
+
+    fn jalr(&mut self, rs: u32, rd: u32) {
+        let target = self.registers[rs as usize] as u32; // read before the link, rs may equal rd
+        self.registers[rd as usize] = self.program_counter as i32;
+        self.program_counter = target;
+    }
+
+    fn mult(&mut self, rs: u32, rt: u32) {
+        let product = self.registers[rs as usize] as i64 * self.registers[rt as usize] as i64;
+        self.hi = (product >> 32) as i32;
+        self.lo = product as i32;
+    }
+
+    fn multu(&mut self, rs: u32, rt: u32) {
+        let product = self.registers[rs as usize] as u32 as u64 * self.registers[rt as usize] as u32 as u64;
+        self.hi = (product >> 32) as i32;
+        self.lo = product as i32;
+    }
+
+    // division by zero leaves HI and LO unchanged, the result is unpredictable on hardware
+    fn div(&mut self, rs: u32, rt: u32) {
+        let divisor = self.registers[rt as usize];
+        if divisor == 0 {
+            return;
+        }
+        let dividend = self.registers[rs as usize];
+        self.lo = dividend.wrapping_div(divisor);
+        self.hi = dividend.wrapping_rem(divisor);
+    }
 
+    fn divu(&mut self, rs: u32, rt: u32) {
+        let divisor = self.registers[rt as usize] as u32;
+        if divisor == 0 {
+            return;
+        }
+        let dividend = self.registers[rs as usize] as u32;
+        self.lo = (dividend / divisor) as i32;
+        self.hi = (dividend % divisor) as i32;
+    }
+
+    fn mfhi(&mut self, rd: u32) {
+        self.registers[rd as usize] = self.hi;
+    }
+
+    fn mflo(&mut self, rd: u32) {
+        self.registers[rd as usize] = self.lo;
+    }
+
+    fn mthi(&mut self, rs: u32) {
+        self.hi = self.registers[rs as usize];
+    }
+
+    fn mtlo(&mut self, rs: u32) {
+        self.lo = self.registers[rs as usize];
+    }
+
+    // service $v0, or a syscall exception if no service has that number
+    fn syscall(&mut self) {
+        let Some(mut syscalls) = self.syscalls.take() else {
+            return self.raise(ExceptionCode::Syscall, None);
+        };
+        let service = self.registers[2] as u32;
+        match syscalls.call(self, service) {
+            Some(Ok(Outcome::Continue)) => (),
+            Some(Ok(Outcome::Exit(code))) => self.exit_code = Some(code),
+            Some(Err(error)) => self.fault = Some(Fault::Syscall { service, pc: self.instruction_address, error }),
+            None => self.raise(ExceptionCode::Syscall, None)
+        }
+        self.syscalls = Some(syscalls);
+    }
+
+    fn brk(&mut self) {
+        self.raise(ExceptionCode::Breakpoint, None);
+    }
+
+    fn addi(&mut self, rs: u32, rt: u32, immediate: i16) {
+        match self.registers[rs as usize].checked_add(immediate as i32) {
+            Some(sum) => self.registers[rt as usize] = sum,
+            None => self.raise(ExceptionCode::Overflow, None)
+        }
     }
 
     fn addiu(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.registers[rs as usize] + immediate as i32;
+        self.registers[rt as usize] = self.registers[rs as usize].wrapping_add(immediate as i32);
     }
 
     fn andi(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.registers[rs as usize] & immediate as i32;
+        self.registers[rt as usize] = self.registers[rs as usize] & immediate as u16 as i32; // zero extended
     }
 
     fn ori(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.registers[rs as usize] | immediate as i32;
+        self.registers[rt as usize] = self.registers[rs as usize] | immediate as u16 as i32; // zero extended
+
+    }
 
+    fn xori(&mut self, rs: u32, rt: u32, immediate: i16) {
+        self.registers[rt as usize] = self.registers[rs as usize] ^ immediate as u16 as i32; // zero extended
     }
 
     fn beq(&mut self, rs: u32, rt: u32, immediate: i16) {
         if self.registers[rs as usize] == self.registers[rt as usize] { 
-            self.program_counter = (self.program_counter as i64 + (immediate*4) as i64) as u32;
+            self.branch(immediate);
         }
     }
 
     fn bne(&mut self, rs: u32, rt: u32, immediate: i16) {
         if self.registers[rs as usize] != self.registers[rt as usize] { 
-            self.program_counter = (self.program_counter as i64 + (immediate*4) as i64) as u32;
+            self.branch(immediate);
+        }
+    }
+
+    fn blez(&mut self, rs: u32, immediate: i16) {
+        if self.registers[rs as usize] <= 0 {
+            self.branch(immediate);
+        }
+    }
+
+    fn bgtz(&mut self, rs: u32, immediate: i16) {
+        if self.registers[rs as usize] > 0 {
+            self.branch(immediate);
+        }
+    }
+
+    fn bltz(&mut self, rs: u32, immediate: i16) {
+        if self.registers[rs as usize] < 0 {
+            self.branch(immediate);
+        }
+    }
+
+    fn bgez(&mut self, rs: u32, immediate: i16) {
+        if self.registers[rs as usize] >= 0 {
+            self.branch(immediate);
+        }
+    }
+
+    // the and-link branches write $ra whether or not they are taken
+    fn bltzal(&mut self, rs: u32, immediate: i16) {
+        let value = self.registers[rs as usize];
+        self.registers[31] = self.program_counter as i32;
+        if value < 0 {
+            self.branch(immediate);
+        }
+    }
+
+    fn bgezal(&mut self, rs: u32, immediate: i16) {
+        let value = self.registers[rs as usize];
+        self.registers[31] = self.program_counter as i32;
+        if value >= 0 {
+            self.branch(immediate);
+        }
+    }
+
+    fn lb(&mut self, rs: u32, rt: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 1) {
+            self.registers[rt as usize] = value as u8 as i8 as i32; // sign extended
+        }
+    }
+
+    fn lh(&mut self, rs: u32, rt: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 2) {
+            self.registers[rt as usize] = value as u16 as i16 as i32; // sign extended
         }
     }
 
     fn lbu(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.memory[(self.registers[rs as usize] + immediate as i32) as usize] as i32;
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 1) {
+            self.registers[rt as usize] = value as i32;
+        }
     }
 
     fn lhu(&mut self, rs: u32, rt: u32, immediate: i16) {
-        // if not on a half word boundary, fail
-        if (self.registers[rs as usize] + immediate as i32) % 2 != 0 {
-            return ();
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 2) {
+            self.registers[rt as usize] = value as i32;
         }
-
-        self.registers[rt as usize] = self.memory[(self.registers[rs as usize] + immediate as i32) as usize] as i32;
-        self.registers[rt as usize] = self.memory[(self.registers[rs as usize] + immediate as i32 + 1) as usize] as i32;
     }
 
     fn lui(&mut self, rt: u32, immediate: i16) {
@@ -277,55 +915,500 @@ impl arch::MipsIsa for CPU {
     }
 
     fn lw(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.registers[rt as usize] = self.read_word_from_mem((rs as i32 + immediate as i32) as u32) as i32;
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 4) {
+            self.registers[rt as usize] = value as i32;
+        }
     }
 
     fn sb(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.memory[(rt + immediate as u32) as usize] = (self.registers[rs as usize] & 0xFF) as u8;
+        let address = self.effective_address(rs, immediate);
+        self.store(address, 1, self.registers[rt as usize] as u32);
     }
 
     fn sh(&mut self, rs: u32, rt: u32, immediate: i16) {
-        // if not on a half word boundary, fail
-        if (self.registers[rs as usize] + immediate as i32) % 2 != 0 {
-            return ();
-        }
-
-
-        let val: i32 = self.registers[rs as usize] & 0xFFFF; 
-
-        self.memory[(self.registers[rt as usize] + immediate as i32) as usize] = (val >> 8) as u8;
-        self.memory[(self.registers[rt as usize] + immediate as i32 + 1) as usize] = (val & 0xFF) as u8;
+        let address = self.effective_address(rs, immediate);
+        self.store(address, 2, self.registers[rt as usize] as u32);
     }
 
     fn sw(&mut self, rs: u32, rt: u32, immediate: i16) {
-        self.write_word_to_mem((self.registers[rt as usize] + immediate as i32) as u32, self.registers[rs as usize] as u32);
+        let address = self.effective_address(rs, immediate);
+        self.store(address, 4, self.registers[rt as usize] as u32);
     }
 
     fn slti(&mut self, rs: u32, rt: u32, immediate: i16) {
         if self.registers[rs as usize] < immediate as i32 {
             self.registers[rt as usize] = 1;
-            return ();
+            return;
         }
         self.registers[rt as usize] = 0;
     }
 
-    fn sltiu(&mut self, rs: u32, rt: u32, immediate: u16) {
-        if (self.registers[rs as usize] as u32) < (immediate as u32) {
+    fn sltiu(&mut self, rs: u32, rt: u32, immediate: i16) {
+        // sign extended, then compared unsigned
+        if (self.registers[rs as usize] as u32) < (immediate as i32 as u32) {
             self.registers[rt as usize] = 1;
-            return ();
+            return;
         }
         self.registers[rt as usize] = 0;
     }
 
     fn j(&mut self, address: u32) {
-        let addr_real = (address & 0xFF_FFFF) << 2; // ensure a 24 bit number, append 2 zeros
+        let addr_real = (address & arch::JUMP_ADDRESS_MASK) << 2; // ensure a 26 bit number, append 2 zeros
         self.program_counter = addr_real | (self.program_counter & 0xF000_0000); // borrow 4 msb from pc
     }
 
     fn jal(&mut self, address: u32) {
         self.registers[31] = self.program_counter as i32; // ra
-        let addr_real = (address & 0xFF_FFFF) << 2; // ensure a 24 bit number, append 2 zeros
+        let addr_real = (address & arch::JUMP_ADDRESS_MASK) << 2; // ensure a 26 bit number, append 2 zeros
         self.program_counter = addr_real | (self.program_counter & 0xF000_0000); // borrow 4 msb from pc
     }
+
+    fn mfc0(&mut self, rt: u32, rd: u32) {
+        if self.kernel() {
+            self.registers[rt as usize] = self.cp0.read(rd) as i32;
+        }
+    }
+
+    fn mtc0(&mut self, rt: u32, rd: u32) {
+        if self.kernel() {
+            self.cp0.write(rd, self.registers[rt as usize] as u32);
+        }
+    }
+
+    fn eret(&mut self) {
+        if self.kernel() {
+            self.program_counter = self.cp0.leave();
+        }
+    }
+
+    fn tlbr(&mut self) {
+        if !self.kernel() {
+            return;
+        }
+        let Some(mmu) = &self.mmu else { return self.raise(ExceptionCode::ReservedInstruction, None) };
+        let entry = mmu.tlb[self.cp0.index as usize % mmu.tlb.len()];
+        self.cp0.entry_hi = entry.entry_hi;
+        self.cp0.entry_lo0 = entry.entry_lo0;
+        self.cp0.entry_lo1 = entry.entry_lo1;
+    }
+
+    fn tlbwi(&mut self) {
+        self.write_tlb(|cp0, entries| cp0.index as usize % entries);
+    }
+
+    // Random counts down through the entries, one step per tlbwr
+    fn tlbwr(&mut self) {
+        self.write_tlb(|cp0, entries| {
+            let i = cp0.random as usize % entries;
+            cp0.random = ((i + entries - 1) % entries) as u32;
+            i
+        });
+    }
+
+    fn tlbp(&mut self) {
+        if !self.kernel() {
+            return;
+        }
+        let Some(mmu) = &self.mmu else { return self.raise(ExceptionCode::ReservedInstruction, None) };
+        self.cp0.index = mmu.probe(self.cp0.entry_hi).map_or(cp0::INDEX_PROBE_FAILED, |i| i as u32);
+    }
+
+    fn mfc1(&mut self, rt: u32, fs: u32) {
+        self.registers[rt as usize] = self.cp1.registers[fs as usize] as i32;
+    }
+
+    fn mtc1(&mut self, rt: u32, fs: u32) {
+        self.cp1.registers[fs as usize] = self.registers[rt as usize] as u32;
+    }
+
+    fn cfc1(&mut self, rt: u32, rd: u32) {
+        self.registers[rt as usize] = self.cp1.read_control(rd) as i32;
+    }
+
+    fn ctc1(&mut self, rt: u32, rd: u32) {
+        self.cp1.write_control(rd, self.registers[rt as usize] as u32);
+    }
+
+    fn bc1f(&mut self, immediate: i16) {
+        if !self.cp1.condition() {
+            self.branch(immediate);
+        }
+    }
+
+    fn bc1t(&mut self, immediate: i16) {
+        if self.cp1.condition() {
+            self.branch(immediate);
+        }
+    }
+
+    fn lwc1(&mut self, rs: u32, ft: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        if let Some(value) = self.load(address, 4) {
+            self.cp1.registers[ft as usize] = value;
+        }
+    }
+
+    // the word at the lower address is the high half of the double
+    fn ldc1(&mut self, rs: u32, ft: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        if !self.even_registers(&[ft]) {
+            return;
+        }
+        if !address.is_multiple_of(8) {
+            return self.raise(ExceptionCode::AddressLoad, Some(address));
+        }
+        if let (Some(high), Some(low)) = (self.load(address, 4), self.load(address + 4, 4)) {
+            self.cp1.registers[ft as usize] = low;
+            self.cp1.registers[ft as usize + 1] = high;
+        }
+    }
+
+    fn swc1(&mut self, rs: u32, ft: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        self.store(address, 4, self.cp1.registers[ft as usize]);
+    }
+
+    fn sdc1(&mut self, rs: u32, ft: u32, immediate: i16) {
+        let address = self.effective_address(rs, immediate);
+        if !self.even_registers(&[ft]) {
+            return;
+        }
+        if !address.is_multiple_of(8) {
+            return self.raise(ExceptionCode::AddressStore, Some(address));
+        }
+        // both words are translated and checked before either is written, so a fault on the
+        // second leaves memory as it was
+        let Some(high) = self.translate(address, 4, Access::Store) else { return };
+        let Some(low) = self.translate(address + 4, 4, Access::Store) else { return };
+        if let Some(refused) = [(address, high), (address + 4, low)].into_iter().find(|(_, t)| !self.bus.writable(t.physical, 4)) {
+            return self.bus_error(refused.0, Access::Store);
+        }
+        self.store_translated(address, 4, self.cp1.registers[ft as usize + 1], high);
+        self.store_translated(address + 4, 4, self.cp1.registers[ft as usize], low);
+    }
+
+    fn add_s(&mut self, fs: u32, ft: u32, fd: u32) {
+        self.single_result(fd, cp1::add(self.cp1.single(fs), self.cp1.single(ft), self.cp1.rounding()));
+    }
+
+    fn add_d(&mut self, fs: u32, ft: u32, fd: u32) {
+        if self.even_registers(&[fs, ft, fd]) {
+            self.double_result(fd, cp1::add(self.cp1.double(fs), self.cp1.double(ft), self.cp1.rounding()));
+        }
+    }
+
+    fn sub_s(&mut self, fs: u32, ft: u32, fd: u32) {
+        self.single_result(fd, cp1::sub(self.cp1.single(fs), self.cp1.single(ft), self.cp1.rounding()));
+    }
+
+    fn sub_d(&mut self, fs: u32, ft: u32, fd: u32) {
+        if self.even_registers(&[fs, ft, fd]) {
+            self.double_result(fd, cp1::sub(self.cp1.double(fs), self.cp1.double(ft), self.cp1.rounding()));
+        }
+    }
+
+    fn mul_s(&mut self, fs: u32, ft: u32, fd: u32) {
+        self.single_result(fd, cp1::mul(self.cp1.single(fs), self.cp1.single(ft), self.cp1.rounding()));
+    }
+
+    fn mul_d(&mut self, fs: u32, ft: u32, fd: u32) {
+        if self.even_registers(&[fs, ft, fd]) {
+            self.double_result(fd, cp1::mul(self.cp1.double(fs), self.cp1.double(ft), self.cp1.rounding()));
+        }
+    }
+
+    fn div_s(&mut self, fs: u32, ft: u32, fd: u32) {
+        self.single_result(fd, cp1::div(self.cp1.single(fs), self.cp1.single(ft), self.cp1.rounding()));
+    }
+
+    fn div_d(&mut self, fs: u32, ft: u32, fd: u32) {
+        if self.even_registers(&[fs, ft, fd]) {
+            self.double_result(fd, cp1::div(self.cp1.double(fs), self.cp1.double(ft), self.cp1.rounding()));
+        }
+    }
+
+    fn sqrt_s(&mut self, fs: u32, fd: u32) {
+        self.single_result(fd, cp1::sqrt(self.cp1.single(fs), self.cp1.rounding()));
+    }
+
+    fn sqrt_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs, fd]) {
+            self.double_result(fd, cp1::sqrt(self.cp1.double(fs), self.cp1.rounding()));
+        }
+    }
+
+    // abs, mov and neg only touch the sign bit and never raise anything
+    fn abs_s(&mut self, fs: u32, fd: u32) {
+        self.cp1.set_single(fd, self.cp1.single(fs).abs());
+    }
+
+    fn abs_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs, fd]) {
+            self.cp1.set_double(fd, self.cp1.double(fs).abs());
+        }
+    }
+
+    fn mov_s(&mut self, fs: u32, fd: u32) {
+        self.cp1.registers[fd as usize] = self.cp1.registers[fs as usize];
+    }
+
+    fn mov_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs, fd]) {
+            self.cp1.set_double(fd, self.cp1.double(fs));
+        }
+    }
+
+    fn neg_s(&mut self, fs: u32, fd: u32) {
+        self.cp1.set_single(fd, -self.cp1.single(fs));
+    }
+
+    fn neg_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs, fd]) {
+            self.cp1.set_double(fd, -self.cp1.double(fs));
+        }
+    }
+
+    fn cvt_s_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs]) {
+            self.single_result(fd, cp1::narrow(self.cp1.double(fs), self.cp1.rounding()));
+        }
+    }
+
+    fn cvt_s_w(&mut self, fs: u32, fd: u32) {
+        self.single_result(fd, cp1::word_to_single(self.cp1.word(fs), self.cp1.rounding()));
+    }
+
+    // widening to a double is always exact
+    fn cvt_d_s(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fd]) {
+            self.double_result(fd, (self.cp1.single(fs) as f64, 0));
+        }
+    }
+
+    fn cvt_d_w(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fd]) {
+            self.double_result(fd, (self.cp1.word(fs) as f64, 0));
+        }
+    }
+
+    fn cvt_w_s(&mut self, fs: u32, fd: u32) {
+        self.word_result(fd, cp1::to_word(self.cp1.single(fs) as f64, self.cp1.rounding()));
+    }
+
+    fn cvt_w_d(&mut self, fs: u32, fd: u32) {
+        if self.even_registers(&[fs]) {
+            self.word_result(fd, cp1::to_word(self.cp1.double(fs), self.cp1.rounding()));
+        }
+    }
+
+    fn c_eq_s(&mut self, fs: u32, ft: u32) {
+        self.condition_result(cp1::compare(self.cp1.single(fs), self.cp1.single(ft), false), |order| order.is_eq());
+    }
+
+    fn c_eq_d(&mut self, fs: u32, ft: u32) {
+        if self.even_registers(&[fs, ft]) {
+            self.condition_result(cp1::compare(self.cp1.double(fs), self.cp1.double(ft), false), |order| order.is_eq());
+        }
+    }
+
+    fn c_lt_s(&mut self, fs: u32, ft: u32) {
+        self.condition_result(cp1::compare(self.cp1.single(fs), self.cp1.single(ft), true), |order| order.is_lt());
+    }
+
+    fn c_lt_d(&mut self, fs: u32, ft: u32) {
+        if self.even_registers(&[fs, ft]) {
+            self.condition_result(cp1::compare(self.cp1.double(fs), self.cp1.double(ft), true), |order| order.is_lt());
+        }
+    }
+
+    fn c_le_s(&mut self, fs: u32, ft: u32) {
+        self.condition_result(cp1::compare(self.cp1.single(fs), self.cp1.single(ft), true), |order| order.is_le());
+    }
+
+    fn c_le_d(&mut self, fs: u32, ft: u32) {
+        if self.even_registers(&[fs, ft]) {
+            self.condition_result(cp1::compare(self.cp1.double(fs), self.cp1.double(ft), true), |order| order.is_le());
+        }
+    }
+}
+
+#[cfg(test)]
+impl CPU {
+    // A quiet CPU running source, which setup configures first knowing the program's labels
+    pub(crate) fn assembled(source: &str, setup: impl FnOnce(&mut CPU, &HashMap<String, u32>)) -> CPU {
+        use arch::Computer;
+        let (program, _) = crate::software::assemble::assemble_source("test.s", source.to_string()).expect("test program assembles");
+        let mut cpu = CPU::new();
+        cpu.debug_mode = false;
+        setup(&mut cpu, &program.symbols);
+        cpu.load_program(program);
+        cpu
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arch::Computer;
+
+    fn run(source: &str) -> CPU {
+        let mut cpu = CPU::assembled(source, |_, _| ());
+        cpu.start().expect("test program runs");
+        cpu
+    }
+
+    #[test]
+    fn multiply_and_divide_go_through_hi_and_lo() {
+        let cpu = run("
+            li $t0, -7
+            li $t1, 3
+            mult $t0, $t1
+            mfhi $s0
+            mflo $s1
+            multu $t0, $t1
+            mfhi $s2
+            mflo $s3
+            div $t0, $t1
+            mfhi $s4
+            mflo $s5
+            divu $t0, $t1
+            mfhi $s6
+            mflo $s7
+            li $t2, 0x1234
+            mthi $t2
+            mtlo $t0
+            div $t0, $zero
+            divu $t1, $zero
+            mfhi $t3
+            mflo $t4
+            halt");
+        let r = |n: usize| cpu.registers[n];
+        assert_eq!((r(16), r(17)), (-1, -21), "mult is signed");
+        assert_eq!((r(18), r(19) as u32), (2, 0xFFFF_FFEB), "multu is 0xfffffff9 * 3");
+        assert_eq!((r(20), r(21)), (-1, -2), "div truncates toward zero");
+        assert_eq!((r(22), r(23)), (0, 0x5555_5553), "divu");
+        assert_eq!((r(11), r(12)), (0x1234, -7), "division by zero leaves HI and LO as they were");
+    }
+
+    #[test]
+    fn narrow_loads_and_arithmetic_shifts_extend_the_sign() {
+        let cpu = run("
+            .data
+            bytes: .byte 0x80, 0x7f, 0xff, 0xfe
+            .text
+            la $t0, bytes
+            lb $s0, 0($t0)
+            lbu $s1, 0($t0)
+            lh $s2, 2($t0)
+            lhu $s3, 2($t0)
+            lb $s4, 1($t0)
+            li $t1, -16
+            li $t2, 35
+            sra $s5, $t1, 2
+            srav $s6, $t1, $t2
+            srl $s7, $t1, 28
+            halt");
+        let r = |n: usize| cpu.registers[n];
+        assert_eq!([r(16), r(17), r(18), r(19), r(20)], [-128, 0x80, -2, 0xFFFE, 0x7F]);
+        // srav shifts by the low 5 bits of rs, 35 & 31 = 3
+        assert_eq!([r(21), r(22), r(23)], [-4, -2, 0xF]);
+    }
+
+    #[test]
+    fn links_hold_the_address_after_the_call() {
+        let cpu = run("
+            la $t0, first
+            jalr $s0, $t0
+            back1: li $t1, -1
+            bltzal $t1, second
+            back2: bgezal $t1, never
+            back3: halt
+            first: jr $s0
+            second: move $s1, $ra
+            jr $ra
+            never: halt");
+        let label = |name: &str| cpu.symbols[name] as i32;
+        assert_eq!(cpu.registers[16], label("back1"));
+        assert_eq!(cpu.registers[17], label("back2"));
+        // bgezal links whether or not it branches
+        assert_eq!(cpu.registers[31], label("back3"));
+    }
+
+    // Logs ExcCode, EPC, BadVAddr and Status for every exception to 0x2000 on, then returns
+    // past the instruction that raised it
+    const EXCEPTIONS: &str = "
+        li $s7, 0x2000
+        li $t0, 0x7fffffff
+        overflow: add $t1, $t0, $t0
+        misaligned_load: lw $t2, 2($zero)
+        misaligned_store: sh $t2, 0x1001($zero)
+        reserved: nop
+        mfc0 $s0, $12
+        halt
+        handler: mfc0 $k0, $13
+        srl $k0, $k0, 2
+        andi $k0, $k0, 0x1f
+        sw $k0, 0($s7)
+        mfc0 $k1, $14
+        sw $k1, 4($s7)
+        mfc0 $k1, $8
+        sw $k1, 8($s7)
+        mfc0 $k1, $12
+        sw $k1, 12($s7)
+        addiu $s7, $s7, 16
+        mfc0 $k1, $14
+        addiu $k1, $k1, 4
+        mtc0 $k1, $14
+        eret";
+
+    #[test]
+    fn exceptions_vector_to_the_handler_and_eret_returns() {
+        let mut cpu = CPU::assembled(EXCEPTIONS, |cpu, symbols| cpu.exception_handler = Some(symbols["handler"]));
+        let reserved = cpu.symbols["reserved"];
+        cpu.write_word_to_mem(reserved, 0xFC00_0000);
+        assert_eq!(cpu.start().unwrap(), 0);
+
+        let label = |name: &str| cpu.symbols[name];
+        let logged: Vec<[u32; 4]> = (0..4)
+            .map(|n| [0, 4, 8, 12].map(|offset| cpu.read_word_from_mem(0x2000 + 16 * n + offset).unwrap()))
+            .collect();
+        let exl = cp0::STATUS_EXL;
+        assert_eq!(logged, [
+            [ExceptionCode::Overflow as u32, label("overflow"), 0, exl],
+            [ExceptionCode::AddressLoad as u32, label("misaligned_load"), 2, exl],
+            [ExceptionCode::AddressStore as u32, label("misaligned_store"), 0x1001, exl],
+            // BadVAddr keeps the last address error
+            [ExceptionCode::ReservedInstruction as u32, reserved, 0x1001, exl]
+        ]);
+        assert_eq!(cpu.registers[9], 0, "the overflowing add writes nothing");
+        assert_eq!(cpu.registers[16] as u32 & exl, 0, "eret clears EXL");
+    }
+
+    #[test]
+    fn exceptions_without_a_handler_end_the_run() {
+        let fault = |source: &str| match CPU::assembled(source, |_, _| ()).start() {
+            Err(Fault::Exception(exception)) => exception,
+            other => panic!("{source} ended with {other:?}")
+        };
+        let at = |code, pc, bad_vaddr| Exception { code, pc, bad_vaddr };
+        assert_eq!(fault("li $t0, 0x7fffffff\nadd $t1, $t0, $t0"), at(ExceptionCode::Overflow, 0x48, None));
+        assert_eq!(fault("nop\nlh $t0, 3($zero)"), at(ExceptionCode::AddressLoad, 0x44, Some(3)));
+        assert_eq!(fault("sw $t0, 6($zero)"), at(ExceptionCode::AddressStore, 0x40, Some(6)));
+        assert_eq!(fault("tlbwr"), at(ExceptionCode::ReservedInstruction, 0x40, None));
+    }
+
+    #[test]
+    fn sdc1_writes_neither_word_when_the_second_is_refused() {
+        let source = "li $t0, 0x2000\nli $t1, -1\nmtc1 $t1, $f2\nmtc1 $t1, $f3\nsdc1 $f2, 0($t0)\nhalt";
+        let mut cpu = CPU::assembled(source, |cpu, _| {
+            cpu.bus = RegionMap::new(Endian::Big);
+            cpu.bus.map_ram("ram", 0, 0x2004).unwrap();
+        });
+        match cpu.start() {
+            Err(Fault::Exception(exception)) => assert_eq!((exception.code, exception.bad_vaddr), (ExceptionCode::DataBus, Some(0x2004))),
+            other => panic!("sdc1 ended with {other:?}")
+        }
+        assert_eq!(cpu.read_word_from_mem(0x2000), Some(0));
+    }
+}
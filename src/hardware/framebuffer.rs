@@ -0,0 +1,342 @@
+use std::io;
+use super::bus::{Device, Endian, Memory};
+
+/*
+ * Bitmap display, a memory of width x height pixels stored row by row from the top left,
+ * like the MARS bitmap display. Programs draw by storing to it, and the picture can be
+ * written out as PPM or PNG on demand, or every so many CPU cycles, stalls included, as a
+ * numbered sequence of frames while the program runs
+ */
+
+pub const BASE: u32 = 0x1001_0000;     // where MARS puts its display by default
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb888,     // a word a pixel, 0x00RRGGBB
+    Rgb565,     // a halfword a pixel, 5 bits red, 6 green, 5 blue
+    Gray8       // a byte a pixel
+}
+
+impl PixelFormat {
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "rgb888" => Some(PixelFormat::Rgb888),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            "gray8" => Some(PixelFormat::Gray8),
+            _ => None
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb888 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Gray8 => 1
+        }
+    }
+
+    fn rgb(self, value: u32) -> [u8; 3] {
+        // widen 5 and 6 bit channels so full scale stays full scale
+        let widen = |channel: u32, bits: u32| ((channel * 255 + ((1 << bits) - 1) / 2) / ((1 << bits) - 1)) as u8;
+        match self {
+            PixelFormat::Rgb888 => [(value >> 16) as u8, (value >> 8) as u8, value as u8],
+            PixelFormat::Rgb565 => [widen(value >> 11 & 0x1F, 5), widen(value >> 5 & 0x3F, 6), widen(value & 0x1F, 5)],
+            PixelFormat::Gray8 => [value as u8; 3]
+        }
+    }
+}
+
+// Frames written every so many cycles, numbered from 1 into the path before its extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequence {
+    pub every: u64,
+    pub path: String,
+    pub written: u32,
+    pub failed: Option<String>      // the frame that could not be written and why, none are tried after it
+}
+
+impl Sequence {
+    pub fn new(every: u64, path: &str) -> Self {
+        Sequence { every: every.max(1), path: path.to_string(), written: 0, failed: None }
+    }
+
+    // frames/life.png, frame 3, is frames/life00003.png
+    pub fn frame_path(&self, frame: u32) -> String {
+        match self.path.rfind('.').filter(|&dot| !self.path[dot..].contains('/')) {
+            Some(dot) => format!("{}{frame:05}{}", &self.path[..dot], &self.path[dot..]),
+            None => format!("{}{frame:05}", self.path)
+        }
+    }
+}
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub endian: Endian,                 // how the bus stores the pixels
+    pub sequence: Option<Sequence>,
+    memory: Memory,
+    cycles: u64                         // CPU cycle count when last clocked
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat, endian: Endian) -> Result<Self, String> {
+        let size = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(format.bytes_per_pixel()))
+            .ok_or_else(|| format!("a {width}x{height} display is larger than the address space"))?;
+        Ok(Framebuffer { width, height, format, endian, sequence: None, memory: Memory::new(size), cycles: 0 })
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let width = self.format.bytes_per_pixel();
+        let value = self.memory.peek((y * self.width + x) * width, width, self.endian).unwrap_or(0);
+        self.format.rgb(value)
+    }
+
+    // The picture as RGB bytes, row by row
+    pub fn rgb(&self) -> Vec<u8> {
+        (0..self.height).flat_map(|y| (0..self.width).flat_map(move |x| self.pixel(x, y))).collect()
+    }
+
+    // Binary PPM, P6
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend(self.rgb());
+        out
+    }
+
+    // 8 bit RGB PNG, uncompressed
+    pub fn png(&self) -> Vec<u8> {
+        let mut scanlines = Vec::with_capacity(((self.width * 3 + 1) * self.height) as usize);
+        for row in self.rgb().chunks((self.width * 3).max(1) as usize) {
+            scanlines.push(0);  // no filter
+            scanlines.extend_from_slice(row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        header.extend([8, 2, 0, 0, 0]);     // 8 bits a channel, RGB, deflate, adaptive filters, no interlace
+
+        let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        chunk(&mut out, b"IHDR", &header);
+        chunk(&mut out, b"IDAT", &zlib_stored(&scanlines));
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    // Write the picture to path, PNG if it ends in .png and PPM otherwise
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let image = if path.ends_with(".png") { self.png() } else { self.ppm() };
+        std::fs::write(path, image)
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> u32 {
+        self.memory.size()
+    }
+
+    fn read(&mut self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        self.memory.read(offset, width, endian)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32, endian: Endian) -> Option<()> {
+        self.memory.write(offset, width, value, endian)
+    }
+
+    fn peek(&self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        self.memory.peek(offset, width, endian)
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        self.memory.bytes()
+    }
+
+    fn bytes_mut(&mut self) -> Option<&mut [u8]> {
+        self.memory.bytes_mut()
+    }
+
+    // the cycle count and frames written, so a sequence carries on where it was
+    fn save(&self) -> Vec<u32> {
+        let written = self.sequence.as_ref().map_or(0, |sequence| sequence.written);
+        vec![(self.cycles >> 32) as u32, self.cycles as u32, written]
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let &[high, low, written] = words else { return None };
+        self.cycles = (high as u64) << 32 | low as u64;
+        if let Some(sequence) = &mut self.sequence {
+            sequence.written = written;
+        }
+        Some(())
+    }
+
+    // Write a frame each time the count passes a multiple of every. A stall can carry it
+    // past more than one, which would only repeat the same picture
+    fn clock(&mut self, cycles: u64) {
+        let last = std::mem::replace(&mut self.cycles, cycles);
+        let Some(sequence) = &self.sequence else { return };
+        if sequence.failed.is_some() || cycles / sequence.every == last / sequence.every {
+            return;
+        }
+        let path = sequence.frame_path(sequence.written + 1);
+        let written = self.save(&path);
+        let sequence = self.sequence.as_mut().unwrap();
+        match written {
+            Ok(()) => sequence.written += 1,
+            Err(e) => sequence.failed = Some(format!("could not write frame {path}: {e}"))
+        }
+    }
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+// A zlib stream of stored deflate blocks, which every decoder reads
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let length = block.len() as u16;
+        out.extend(length.to_le_bytes());
+        out.extend((!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::bus::Permissions;
+    use crate::hardware::cache::Hierarchy;
+    use crate::hardware::cpu::CPU;
+
+    // A white diagonal across an 8x8 display, a word a pixel
+    const LINE: &str = "
+        lui $s0, 0x1001
+        li $t0, 0xffffff
+        li $t1, 8
+        loop: sw $t0, 0($s0)
+        addiu $s0, $s0, 36
+        addiu $t1, $t1, -1
+        bne $t1, $zero, loop
+        halt";
+
+    fn run(source: &str, framebuffer: Framebuffer) -> CPU {
+        let mut cpu = CPU::assembled(source, |cpu, _| cpu.bus.map("display", BASE, Permissions::RW, Box::new(framebuffer)).unwrap());
+        cpu.start().unwrap();
+        cpu
+    }
+
+    fn display(cpu: &CPU) -> &Framebuffer {
+        cpu.bus.device("display").unwrap()
+    }
+
+    #[test]
+    fn stores_draw_pixels_that_compare_as_images() {
+        let cpu = run(LINE, Framebuffer::new(8, 8, PixelFormat::Rgb888, Endian::Big).unwrap());
+        let mut expected = b"P6\n8 8\n255\n".to_vec();
+        for i in 0..64 {
+            expected.extend(if i % 9 == 0 { [255; 3] } else { [0; 3] });
+        }
+        assert_eq!(display(&cpu).ppm(), expected);
+
+        let mut gray = Framebuffer::new(2, 1, PixelFormat::Rgb565, Endian::Little).unwrap();
+        gray.write(0, 4, 0x07E0_F800, Endian::Little);
+        assert_eq!((gray.pixel(0, 0), gray.pixel(1, 0)), ([255, 0, 0], [0, 255, 0]));
+
+        assert!(Framebuffer::new(0x1_0000, 0x1_0000, PixelFormat::Gray8, Endian::Big).is_err());
+        assert!(Framebuffer::new(0x8000, 0x8000, PixelFormat::Rgb888, Endian::Big).is_err());
+    }
+
+    #[test]
+    fn png_holds_the_pixels_in_stored_blocks() {
+        let cpu = run(LINE, Framebuffer::new(8, 8, PixelFormat::Rgb888, Endian::Big).unwrap());
+        let png = display(&cpu).png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..25], &[0, 0, 0, 8, 0, 0, 0, 8, 8]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        // zlib header, then one final stored block holding every scanline
+        let idat = &png[33 + 8..];
+        assert_eq!(&idat[..7], &[0x78, 0x01, 1, 200, 0, !200, 0xFF]);
+        assert_eq!(&idat[7..10], &[0, 255, 255]);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn sequences_number_frames_every_n_cycles() {
+        let directory = std::env::temp_dir().join(format!("framebuffer-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("line.ppm").to_string_lossy().into_owned();
+        let mut framebuffer = Framebuffer::new(8, 8, PixelFormat::Rgb888, Endian::Big).unwrap();
+        framebuffer.sequence = Some(Sequence::new(10, &path));
+        let cpu = run(LINE, framebuffer);
+
+        // 36 instructions, so frames at cycles 10, 20 and 30, the last with 7 pixels drawn
+        let sequence = Sequence::new(10, &path);
+        assert_eq!(cpu.cycles, 36);
+        assert!((1..=3).all(|frame| std::path::Path::new(&sequence.frame_path(frame)).exists()));
+        assert!(!std::path::Path::new(&sequence.frame_path(4)).exists());
+        let third = std::fs::read(sequence.frame_path(3)).unwrap();
+        assert_eq!(third.iter().filter(|&&byte| byte == 255).count(), 7 * 3);
+
+        // behind caches the misses count too, a frame each 10 cycles of them all
+        std::fs::remove_dir_all(&directory).unwrap();
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut framebuffer = Framebuffer::new(8, 8, PixelFormat::Rgb888, Endian::Big).unwrap();
+        framebuffer.sequence = Some(Sequence::new(10, &path));
+        let mut cpu = CPU::assembled(LINE, |cpu, _| {
+            cpu.bus.map("display", BASE, Permissions::RW, Box::new(framebuffer)).unwrap();
+            cpu.caches = Some(Hierarchy::default());
+        });
+        cpu.start().unwrap();
+        let frames = display(&cpu).sequence.as_ref().unwrap().written;
+        assert!(cpu.cycles > 40);
+        assert!(frames > 3 && frames as u64 <= cpu.cycles / 10);
+        assert!(std::path::Path::new(&sequence.frame_path(frames)).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn a_frame_that_cannot_be_written_stops_the_sequence() {
+        let path = std::env::temp_dir().join(format!("framebuffer-missing-{}", std::process::id())).join("line.ppm");
+        let mut framebuffer = Framebuffer::new(8, 8, PixelFormat::Rgb888, Endian::Big).unwrap();
+        framebuffer.sequence = Some(Sequence::new(10, path.to_str().unwrap()));
+        let cpu = run(LINE, framebuffer);
+
+        let sequence = display(&cpu).sequence.as_ref().unwrap();
+        assert_eq!(sequence.written, 0);
+        let failed = sequence.failed.as_deref().unwrap();
+        assert!(failed.starts_with("could not write frame ") && failed.contains("line00001.ppm"), "{failed}");
+    }
+}
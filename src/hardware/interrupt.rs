@@ -0,0 +1,125 @@
+use std::fmt;
+use super::bus::RegionMap;
+
+/*
+ * Interrupt controller. Devices on the bus raise a line, and the controller routes the line
+ * of each region to one of the six hardware interrupts, HW0..HW5 in Cause.IP2..IP7. The CPU
+ * takes an interrupt between instructions when one is pending, Status.IE is set, its
+ * Status.IM bit is set and no handler is running, and logs every one it takes
+ */
+
+pub const LINES: u32 = 6;
+
+// Hardware interrupt n is Cause.IP(n + 2)
+pub fn ip_bit(line: u32) -> u32 {
+    1 << (line + 10)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub region: String,
+    pub line: u32
+}
+
+// An interrupt the CPU took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Taken {
+    pub cycle: u64,
+    pub epc: u32,           // where the interrupted program resumes
+    pub pending: u32        // Cause.IP bits that were enabled and pending
+}
+
+impl Taken {
+    // the Cause.IP bits pending, as " IP2 IP7"
+    pub fn lines(&self) -> String {
+        (0..8).filter(|ip| self.pending & (1 << (ip + 8)) != 0).map(|ip| format!(" IP{ip}")).collect()
+    }
+}
+
+impl fmt::Display for Taken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycle {} interrupt{} at pc {:#010x}", self.cycle, self.lines(), self.epc)
+    }
+}
+
+#[derive(Default)]
+pub struct InterruptController {
+    routes: Vec<Route>,
+    pub log: Vec<Taken>
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Connect the device mapped as region to a hardware interrupt line, replacing its route
+    pub fn route(&mut self, region: &str, line: u32) -> Result<(), String> {
+        if line >= LINES {
+            return Err(format!("{region} can't be routed to line {line}, there are {LINES}"));
+        }
+        self.routes.retain(|route| route.region != region);
+        self.routes.push(Route { region: region.to_string(), line });
+        Ok(())
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // Cause.IP bits of the lines devices are raising
+    pub fn lines(&self, bus: &RegionMap) -> u32 {
+        self.routes.iter()
+            .filter(|route| bus.region(&route.region).is_some_and(|region| region.device.interrupt()))
+            .fold(0, |lines, route| lines | ip_bit(route.line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hardware::arch::Computer;
+    use crate::hardware::cp0::{self, ExceptionCode};
+    use crate::hardware::cpu::CPU;
+
+    // Count in a loop while the CP0 timer interrupts every 40 cycles, until it has three times
+    const TICKS: &str = "
+        li $t0, 40
+        mtc0 $t0, $11
+        li $t0, 0x8001
+        mtc0 $t0, $12
+        li $t1, 3
+        loop: addiu $s0, $s0, 1
+        bne $s1, $t1, loop
+        halt
+        handler: addiu $s1, $s1, 1
+        mfc0 $k0, $9
+        addiu $k0, $k0, 40
+        mtc0 $k0, $11
+        eret";
+
+    #[test]
+    fn the_cp0_timer_preempts_the_program() {
+        let mut cpu = CPU::assembled(TICKS, |cpu, symbols| cpu.exception_handler = Some(symbols["handler"]));
+        let lp = cpu.symbols["loop"];
+        assert_eq!(cpu.start().unwrap(), 0);
+
+        let log = &cpu.interrupts.log;
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[0].cycle, 40);
+        assert!(log.iter().all(|taken| taken.pending == cp0::CAUSE_IP_TIMER && (lp..lp + 8).contains(&taken.epc)));
+        assert_eq!(log[1].cycle - log[0].cycle, log[2].cycle - log[1].cycle);
+        assert_eq!((cpu.cp0.cause >> 2) & 0x1F, ExceptionCode::Interrupt as u32);
+        assert_eq!(log[0].to_string(), format!("cycle 40 interrupt IP7 at pc {:#010x}", log[0].epc));
+    }
+
+    #[test]
+    fn nothing_is_taken_while_disabled() {
+        let mut cpu = CPU::assembled(&TICKS.replace("0x8001", "0x8000"), |cpu, symbols| cpu.exception_handler = Some(symbols["handler"]));
+        for _ in 0..200 {
+            let word = cpu.fetch().unwrap().unwrap();
+            cpu.execute(word).unwrap();
+        }
+        assert!(cpu.interrupts.log.is_empty());
+        assert_ne!(cpu.cp0.cause & cp0::CAUSE_TI, 0);
+    }
+}
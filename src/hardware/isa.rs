@@ -0,0 +1,547 @@
+use std::fmt;
+use super::arch::{Fields, MipsIsa, JUMP_ADDRESS_MASK, REGISTER_NAMES};
+
+/*
+ * The instruction table: mnemonic, encoding and operands of every real instruction
+ * in one place. Decoding, assembling and disassembling are all driven by it, so
+ * adding an instruction is one line here plus its MipsIsa method
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    R, I, J,
+    Regimm,     // I-Type under opcode 1, told apart by the rt field
+    Cop,        // coprocessor move, told apart by the rs field
+    CopOp,      // coprocessor operation, rs is 0x10 and func tells them apart
+    CopBranch,  // coprocessor branch, rs is 0x8 and rt tells them apart
+    FpuS,       // FPU operation on singles, rs is the fmt and func tells them apart
+    FpuD,       // on doubles
+    FpuW        // on words
+}
+
+impl Format {
+    // the value of the rs field the format fixes, if any
+    pub fn fixed_rs(&self) -> Option<u32> {
+        match self {
+            Format::CopOp => Some(COP_OPERATION),
+            Format::CopBranch => Some(COP_BRANCH),
+            Format::FpuS => Some(FMT_S),
+            Format::FpuD => Some(FMT_D),
+            Format::FpuW => Some(FMT_W),
+            _ => None
+        }
+    }
+}
+
+pub const COP_OPERATION: u32 = 0x10;    // rs field of CopOp instructions
+pub const COP_BRANCH: u32 = 0x8;        // rs field of CopBranch instructions
+const FMT_S: u32 = 0x10;
+const FMT_D: u32 = 0x11;
+const FMT_W: u32 = 0x14;
+
+// Operand layout of an instruction as written in assembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    RdRsRt,         // add $rd, $rs, $rt
+    RdRtShamt,      // sll $rd, $rt, shamt
+    RdRtRs,         // sllv $rd, $rt, $rs
+    RsRt,           // mult $rs, $rt
+    RdRs,           // jalr $rd, $rs, or jalr $rs linking $ra
+    Rs,             // jr $rs
+    Rd,             // mfhi $rd
+    RtRd,           // mfc0 $rt, $rd
+    RtFs,           // mfc1 $rt, $fs
+    FdFsFt,         // add.s $fd, $fs, $ft
+    FdFs,           // sqrt.s $fd, $fs
+    FsFt,           // c.eq.s $fs, $ft
+    RtRsImm,        // addi $rt, $rs, imm
+    RtImm,          // lui $rt, imm
+    RsRtLabel,      // beq $rs, $rt, label
+    RsLabel,        // bgez $rs, label
+    RtOffsetRs,     // lw $rt, imm($rs)
+    FtOffsetRs,     // lwc1 $ft, imm($rs)
+    Offset,         // bc1t label
+    Label,          // j label
+    None            // syscall
+}
+
+impl Syntax {
+    // fewest and most operands the syntax can be written with
+    pub fn operand_count(&self) -> (usize, usize) {
+        match self {
+            Syntax::RdRsRt | Syntax::RdRtShamt | Syntax::RdRtRs | Syntax::RtRsImm | Syntax::RsRtLabel
+                | Syntax::FdFsFt => (3, 3),
+            Syntax::RsRt | Syntax::RtRd | Syntax::RtFs | Syntax::FdFs | Syntax::FsFt | Syntax::RtImm
+                | Syntax::RsLabel | Syntax::RtOffsetRs | Syntax::FtOffsetRs => (2, 2),
+            Syntax::RdRs => (1, 2),
+            Syntax::Rs | Syntax::Rd | Syntax::Label | Syntax::Offset => (1, 1),
+            Syntax::None => (0, 0)
+        }
+    }
+}
+
+// How the 16 bit immediate is widened to 32 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Immediate {
+    Signed, Unsigned, None
+}
+
+pub struct InstructionSpec {
+    pub mnemonic: &'static str,
+    pub format: Format,
+    pub opcode: u32,
+    pub func: u32,              // func for R-Type, CopOp and FPU operations, rt for REGIMM and CopBranch, rs for Cop
+    pub syntax: Syntax,
+    pub immediate: Immediate
+}
+
+impl InstructionSpec {
+    // true if the instruction word is an encoding of this instruction
+    pub fn matches(&self, fields: &Fields) -> bool {
+        matches_fields(self.format, self.opcode, self.func, fields)
+    }
+}
+
+fn matches_fields(format: Format, opcode: u32, func: u32, fields: &Fields) -> bool {
+    fields.opcode == opcode && match format {
+        Format::R => fields.func == func,
+        Format::Regimm => fields.rt == func,
+        Format::Cop => fields.rs == func,
+        Format::CopBranch => fields.rs == COP_BRANCH && fields.rt == func,
+        Format::CopOp | Format::FpuS | Format::FpuD | Format::FpuW => Some(fields.rs) == format.fixed_rs() && fields.func == func,
+        Format::I | Format::J => true
+    }
+}
+
+// Call the MipsIsa method an instruction's operands go to, in its syntax
+macro_rules! call {
+    (RdRsRt, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.rd) };
+    (RdRtShamt, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rt, $f.rd, $f.shamt) };
+    (RdRtRs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.rd) };
+    (RsRt, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt) };
+    (RdRs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rd) };
+    (Rs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs) };
+    (Rd, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rd) };
+    (RtRd, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rt, $f.rd) };
+    (RtFs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rt, $f.rd) };
+    // FPU operations keep ft in rt, fs in rd and fd in shamt
+    (FdFsFt, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rd, $f.rt, $f.shamt) };
+    (FdFs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rd, $f.shamt) };
+    (FsFt, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rd, $f.rt) };
+    (RtRsImm, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.immediate) };
+    (RtImm, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rt, $f.immediate) };
+    (RsRtLabel, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.immediate) };
+    (RsLabel, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.immediate) };
+    (RtOffsetRs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.immediate) };
+    (FtOffsetRs, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.rs, $f.rt, $f.immediate) };
+    (Offset, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.immediate) };
+    (Label, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m($f.address) };
+    (None, $cpu:ident, $m:ident, $f:ident) => { $cpu.$m() };
+}
+
+// Mnemonic of a table line, the method name unless it is spelled out with `as`
+macro_rules! mnemonic {
+    ($method:ident) => { stringify!($method) };
+    ($method:ident $name:literal) => { $name };
+}
+
+macro_rules! instruction_table {
+    ($( $method:ident $(as $name:literal)? => $format:ident, $opcode:literal, $func:literal, $syntax:ident, $immediate:ident; )*) => {
+        pub const INSTRUCTIONS: &[InstructionSpec] = &[
+            $( InstructionSpec {
+                mnemonic: mnemonic!($method $($name)?),
+                format: Format::$format,
+                opcode: $opcode,
+                func: $func,
+                syntax: Syntax::$syntax,
+                immediate: Immediate::$immediate
+            }, )*
+        ];
+
+        // Execute an instruction word, false if it is not in the table
+        pub fn dispatch<T: MipsIsa + ?Sized>(cpu: &mut T, instruction: u32) -> bool {
+            let f = Fields::extract(instruction);
+            $(
+                if matches_fields(Format::$format, $opcode, $func, &f) {
+                    call!($syntax, cpu, $method, f);
+                    return true;
+                }
+            )*
+            false
+        }
+    };
+}
+
+instruction_table! {
+    // R-Type
+    add => R, 0x0, 0x20, RdRsRt, None;
+    addu => R, 0x0, 0x21, RdRsRt, None;
+    and => R, 0x0, 0x24, RdRsRt, None;
+    jr => R, 0x0, 0x8, Rs, None;
+    nor => R, 0x0, 0x27, RdRsRt, None;
+    or => R, 0x0, 0x25, RdRsRt, None;
+    slt => R, 0x0, 0x2a, RdRsRt, None;
+    sltu => R, 0x0, 0x2b, RdRsRt, None;
+    sll => R, 0x0, 0x0, RdRtShamt, None;
+    srl => R, 0x0, 0x2, RdRtShamt, None;
+    sub => R, 0x0, 0x22, RdRsRt, None;
+    subu => R, 0x0, 0x23, RdRsRt, None;
+    xor => R, 0x0, 0x26, RdRsRt, None;
+    sra => R, 0x0, 0x3, RdRtShamt, None;
+    sllv => R, 0x0, 0x4, RdRtRs, None;
+    srlv => R, 0x0, 0x6, RdRtRs, None;
+    srav => R, 0x0, 0x7, RdRtRs, None;
+    jalr => R, 0x0, 0x9, RdRs, None;
+    syscall => R, 0x0, 0xc, None, None;
+    brk as "break" => R, 0x0, 0xd, None, None;
+    mfhi => R, 0x0, 0x10, Rd, None;
+    mthi => R, 0x0, 0x11, Rs, None;
+    mflo => R, 0x0, 0x12, Rd, None;
+    mtlo => R, 0x0, 0x13, Rs, None;
+    mult => R, 0x0, 0x18, RsRt, None;
+    multu => R, 0x0, 0x19, RsRt, None;
+    div => R, 0x0, 0x1a, RsRt, None;
+    divu => R, 0x0, 0x1b, RsRt, None;
+
+    // J-Type
+    j => J, 0x2, 0x0, Label, None;
+    jal => J, 0x3, 0x0, Label, None;
+
+    // I-Type
+    addi => I, 0x8, 0x0, RtRsImm, Signed;
+    addiu => I, 0x9, 0x0, RtRsImm, Signed;
+    andi => I, 0xc, 0x0, RtRsImm, Unsigned;
+    beq => I, 0x4, 0x0, RsRtLabel, Signed;
+    bne => I, 0x5, 0x0, RsRtLabel, Signed;
+    lui => I, 0xf, 0x0, RtImm, Unsigned;
+    lw => I, 0x23, 0x0, RtOffsetRs, Signed;
+    ori => I, 0xd, 0x0, RtRsImm, Unsigned;
+    slti => I, 0xa, 0x0, RtRsImm, Signed;
+    sltiu => I, 0xb, 0x0, RtRsImm, Signed;
+    sw => I, 0x2b, 0x0, RtOffsetRs, Signed;
+    lbu => I, 0x24, 0x0, RtOffsetRs, Signed;
+    lhu => I, 0x25, 0x0, RtOffsetRs, Signed;
+    sb => I, 0x28, 0x0, RtOffsetRs, Signed;
+    sh => I, 0x29, 0x0, RtOffsetRs, Signed;
+    xori => I, 0xe, 0x0, RtRsImm, Unsigned;
+    lb => I, 0x20, 0x0, RtOffsetRs, Signed;
+    lh => I, 0x21, 0x0, RtOffsetRs, Signed;
+    blez => I, 0x6, 0x0, RsLabel, Signed;
+    bgtz => I, 0x7, 0x0, RsLabel, Signed;
+
+    // REGIMM, func is the rt field
+    bltz => Regimm, 0x1, 0x0, RsLabel, Signed;
+    bgez => Regimm, 0x1, 0x1, RsLabel, Signed;
+    bltzal => Regimm, 0x1, 0x10, RsLabel, Signed;
+    bgezal => Regimm, 0x1, 0x11, RsLabel, Signed;
+
+    // Coprocessor 0
+    mfc0 => Cop, 0x10, 0x0, RtRd, None;
+    mtc0 => Cop, 0x10, 0x4, RtRd, None;
+    tlbr => CopOp, 0x10, 0x1, None, None;
+    tlbwi => CopOp, 0x10, 0x2, None, None;
+    tlbwr => CopOp, 0x10, 0x6, None, None;
+    tlbp => CopOp, 0x10, 0x8, None, None;
+    eret => CopOp, 0x10, 0x18, None, None;
+
+    // Coprocessor 1
+    mfc1 => Cop, 0x11, 0x0, RtFs, None;
+    cfc1 => Cop, 0x11, 0x2, RtRd, None;
+    mtc1 => Cop, 0x11, 0x4, RtFs, None;
+    ctc1 => Cop, 0x11, 0x6, RtRd, None;
+    bc1f => CopBranch, 0x11, 0x0, Offset, Signed;
+    bc1t => CopBranch, 0x11, 0x1, Offset, Signed;
+    lwc1 => I, 0x31, 0x0, FtOffsetRs, Signed;
+    ldc1 => I, 0x35, 0x0, FtOffsetRs, Signed;
+    swc1 => I, 0x39, 0x0, FtOffsetRs, Signed;
+    sdc1 => I, 0x3d, 0x0, FtOffsetRs, Signed;
+    add_s as "add.s" => FpuS, 0x11, 0x0, FdFsFt, None;
+    add_d as "add.d" => FpuD, 0x11, 0x0, FdFsFt, None;
+    sub_s as "sub.s" => FpuS, 0x11, 0x1, FdFsFt, None;
+    sub_d as "sub.d" => FpuD, 0x11, 0x1, FdFsFt, None;
+    mul_s as "mul.s" => FpuS, 0x11, 0x2, FdFsFt, None;
+    mul_d as "mul.d" => FpuD, 0x11, 0x2, FdFsFt, None;
+    div_s as "div.s" => FpuS, 0x11, 0x3, FdFsFt, None;
+    div_d as "div.d" => FpuD, 0x11, 0x3, FdFsFt, None;
+    sqrt_s as "sqrt.s" => FpuS, 0x11, 0x4, FdFs, None;
+    sqrt_d as "sqrt.d" => FpuD, 0x11, 0x4, FdFs, None;
+    abs_s as "abs.s" => FpuS, 0x11, 0x5, FdFs, None;
+    abs_d as "abs.d" => FpuD, 0x11, 0x5, FdFs, None;
+    mov_s as "mov.s" => FpuS, 0x11, 0x6, FdFs, None;
+    mov_d as "mov.d" => FpuD, 0x11, 0x6, FdFs, None;
+    neg_s as "neg.s" => FpuS, 0x11, 0x7, FdFs, None;
+    neg_d as "neg.d" => FpuD, 0x11, 0x7, FdFs, None;
+    cvt_s_d as "cvt.s.d" => FpuD, 0x11, 0x20, FdFs, None;
+    cvt_s_w as "cvt.s.w" => FpuW, 0x11, 0x20, FdFs, None;
+    cvt_d_s as "cvt.d.s" => FpuS, 0x11, 0x21, FdFs, None;
+    cvt_d_w as "cvt.d.w" => FpuW, 0x11, 0x21, FdFs, None;
+    cvt_w_s as "cvt.w.s" => FpuS, 0x11, 0x24, FdFs, None;
+    cvt_w_d as "cvt.w.d" => FpuD, 0x11, 0x24, FdFs, None;
+    c_eq_s as "c.eq.s" => FpuS, 0x11, 0x32, FsFt, None;
+    c_eq_d as "c.eq.d" => FpuD, 0x11, 0x32, FsFt, None;
+    c_lt_s as "c.lt.s" => FpuS, 0x11, 0x3c, FsFt, None;
+    c_lt_d as "c.lt.d" => FpuD, 0x11, 0x3c, FsFt, None;
+    c_le_s as "c.le.s" => FpuS, 0x11, 0x3e, FsFt, None;
+    c_le_d as "c.le.d" => FpuD, 0x11, 0x3e, FsFt, None;
+}
+
+pub fn lookup(mnemonic: &str) -> Option<&'static InstructionSpec> {
+    INSTRUCTIONS.iter().find(|spec| spec.mnemonic == mnemonic)
+}
+
+// The table entry an instruction's fields encode
+pub fn find(fields: &Fields) -> Option<&'static InstructionSpec> {
+    INSTRUCTIONS.iter().find(|spec| spec.matches(fields))
+}
+
+pub fn decode(instruction: u32) -> Option<&'static InstructionSpec> {
+    find(&Fields::extract(instruction))
+}
+
+// Storage an instruction reads or writes, for finding hazards between instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    Gpr(u32), Fpr(u32), Hi, Lo, Fcsr
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Gpr(n) => write!(f, "${}", REGISTER_NAMES[*n as usize]),
+            Location::Fpr(n) => write!(f, "$f{n}"),
+            Location::Hi => write!(f, "hi"),
+            Location::Lo => write!(f, "lo"),
+            Location::Fcsr => write!(f, "fcsr")
+        }
+    }
+}
+
+// What an instruction reads and writes, and when its result and next PC are known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dataflow {
+    pub reads: Vec<Location>,
+    pub writes: Vec<Location>,
+    pub load: bool,         // the result comes from memory, so it is ready after MEM rather than EX
+    pub control: bool,      // may change the flow of control
+    pub jump: bool          // the target is in the instruction word, known once it is decoded
+}
+
+impl Dataflow {
+    fn read(&mut self, location: Location) {
+        if location != Location::Gpr(0) {
+            self.reads.push(location);
+        }
+    }
+
+    fn write(&mut self, location: Location) {
+        if location != Location::Gpr(0) {
+            self.writes.push(location);
+        }
+    }
+
+    // a floating point operand, both registers of the pair for a double
+    fn fpr(&mut self, register: u32, double: bool, write: bool) {
+        let registers = if double { vec![register & !1, register | 1] } else { vec![register] };
+        for r in registers {
+            if write { self.write(Location::Fpr(r)) } else { self.read(Location::Fpr(r)) }
+        }
+    }
+}
+
+// Dataflow of an instruction word, empty for words that are not instructions
+pub fn dataflow(instruction: u32) -> Dataflow {
+    let mut flow = Dataflow::default();
+    let spec = match decode(instruction) {
+        Some(spec) => spec,
+        None => return flow
+    };
+    let Fields { rs, rt, rd, shamt, .. } = Fields::extract(instruction);
+    let gpr = Location::Gpr;
+    let m = spec.mnemonic;
+    // double operands, and a cvt's destination which has the precision of its name
+    let double = spec.format == Format::FpuD || matches!(m, "ldc1" | "sdc1");
+    let double_result = if m.starts_with("cvt.") { m.starts_with("cvt.d") } else { double };
+
+    match spec.syntax {
+        Syntax::RdRsRt => { flow.read(gpr(rs)); flow.read(gpr(rt)); flow.write(gpr(rd)); }
+        Syntax::RdRtShamt => { flow.read(gpr(rt)); flow.write(gpr(rd)); }
+        Syntax::RdRtRs => { flow.read(gpr(rt)); flow.read(gpr(rs)); flow.write(gpr(rd)); }
+        Syntax::RsRt => { flow.read(gpr(rs)); flow.read(gpr(rt)); flow.write(Location::Hi); flow.write(Location::Lo); }
+        Syntax::RdRs => { flow.read(gpr(rs)); flow.write(gpr(rd)); flow.control = true; }
+        Syntax::Rs => match m {
+            "mthi" => { flow.read(gpr(rs)); flow.write(Location::Hi); }
+            "mtlo" => { flow.read(gpr(rs)); flow.write(Location::Lo); }
+            _ => { flow.read(gpr(rs)); flow.control = true; }
+        },
+        Syntax::Rd => {
+            flow.read(if m == "mfhi" { Location::Hi } else { Location::Lo });
+            flow.write(gpr(rd));
+        }
+        Syntax::RtRd => match m {
+            "cfc1" => { flow.read(Location::Fcsr); flow.write(gpr(rt)); }
+            "ctc1" => { flow.read(gpr(rt)); flow.write(Location::Fcsr); }
+            "mfc0" => flow.write(gpr(rt)),
+            _ => flow.read(gpr(rt))
+        },
+        Syntax::RtFs => if m == "mfc1" {
+            flow.fpr(rd, false, false);
+            flow.write(gpr(rt));
+        } else {
+            flow.read(gpr(rt));
+            flow.fpr(rd, false, true);
+        },
+        // FPU operations keep ft in rt, fs in rd and fd in shamt, and all of them touch the FCSR
+        Syntax::FdFsFt => {
+            flow.fpr(rd, double, false);
+            flow.fpr(rt, double, false);
+            flow.fpr(shamt, double, true);
+            flow.write(Location::Fcsr);
+        }
+        Syntax::FdFs => {
+            flow.fpr(rd, double, false);
+            flow.fpr(shamt, double_result, true);
+            flow.write(Location::Fcsr);
+        }
+        Syntax::FsFt => {
+            flow.fpr(rd, double, false);
+            flow.fpr(rt, double, false);
+            flow.write(Location::Fcsr);
+        }
+        Syntax::RtRsImm => { flow.read(gpr(rs)); flow.write(gpr(rt)); }
+        Syntax::RtImm => flow.write(gpr(rt)),
+        Syntax::RsRtLabel => { flow.read(gpr(rs)); flow.read(gpr(rt)); flow.control = true; }
+        Syntax::RsLabel => {
+            flow.read(gpr(rs));
+            if m.ends_with("al") {
+                flow.write(gpr(31));
+            }
+            flow.control = true;
+        }
+        // stores have the high opcodes
+        Syntax::RtOffsetRs | Syntax::FtOffsetRs => {
+            let store = spec.opcode >= 0x28;
+            let fp = spec.syntax == Syntax::FtOffsetRs;
+            flow.read(gpr(rs));
+            match (fp, store) {
+                (false, false) => flow.write(gpr(rt)),
+                (false, true) => flow.read(gpr(rt)),
+                (true, _) => flow.fpr(rt, double, !store)
+            }
+            flow.load = !store;
+        }
+        Syntax::Offset => { flow.read(Location::Fcsr); flow.control = true; }
+        Syntax::Label => {
+            if m == "jal" {
+                flow.write(gpr(31));
+            }
+            flow.control = true;
+            flow.jump = true;
+        }
+        // syscall takes its service in $v0 and arguments in $a0 and $a1, and may return in $v0.
+        // It and break only leave the straight line through an exception
+        Syntax::None => match m {
+            "syscall" => {
+                flow.read(gpr(2)); flow.read(gpr(4)); flow.read(gpr(5));
+                flow.write(gpr(2));
+            }
+            "eret" => flow.control = true,
+            _ => ()
+        }
+    }
+    flow
+}
+
+// Machine word for an instruction, taking from fields only what its format uses
+pub fn encode(spec: &InstructionSpec, fields: &Fields) -> u32 {
+    let op = spec.opcode << 26;
+    match spec.format {
+        Format::R => op
+            | (fields.rs & 0x1F) << 21
+            | (fields.rt & 0x1F) << 16
+            | (fields.rd & 0x1F) << 11
+            | (fields.shamt & 0x1F) << 6
+            | spec.func,
+        Format::I => op
+            | (fields.rs & 0x1F) << 21
+            | (fields.rt & 0x1F) << 16
+            | fields.immediate as u16 as u32,
+        Format::Regimm => op
+            | (fields.rs & 0x1F) << 21
+            | spec.func << 16
+            | fields.immediate as u16 as u32,
+        Format::Cop => op
+            | spec.func << 21
+            | (fields.rt & 0x1F) << 16
+            | (fields.rd & 0x1F) << 11,
+        Format::CopOp => op | COP_OPERATION << 21 | spec.func,
+        Format::CopBranch => op
+            | COP_BRANCH << 21
+            | spec.func << 16
+            | fields.immediate as u16 as u32,
+        Format::FpuS | Format::FpuD | Format::FpuW => op
+            | spec.format.fixed_rs().unwrap_or_default() << 21
+            | (fields.rt & 0x1F) << 16
+            | (fields.rd & 0x1F) << 11
+            | (fields.shamt & 0x1F) << 6
+            | spec.func,
+        Format::J => op | (fields.address & JUMP_ADDRESS_MASK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::hardware::arch::PC_START;
+    use crate::software::{assemble, disassemble};
+
+    // every entry survives encode -> decode -> disassemble -> assemble unchanged
+    #[test]
+    fn table_round_trips() {
+        // branches and jumps at PC_START point at the label two instructions on
+        let target = PC_START + 8;
+        let symbols = HashMap::from([(String::from("target"), target)]);
+
+        for spec in INSTRUCTIONS {
+            let immediate = match (spec.syntax, spec.immediate) {
+                (Syntax::RsRtLabel | Syntax::RsLabel | Syntax::Offset, _) => 1,
+                (_, Immediate::Unsigned) => 0x8765_u16 as i16,
+                _ => -12
+            };
+            // the assembler leaves the fields a syntax does not use at zero
+            let (rs, rt, rd, shamt) = match spec.syntax {
+                Syntax::RdRsRt => (9, 10, 11, 0),
+                Syntax::RdRtShamt => (0, 10, 11, 5),
+                Syntax::RdRtRs => (9, 10, 11, 0),
+                Syntax::RsRt => (9, 10, 0, 0),
+                Syntax::RdRs => (9, 0, 11, 0),
+                Syntax::Rs => (9, 0, 0, 0),
+                Syntax::Rd => (0, 0, 11, 0),
+                Syntax::RtRd | Syntax::RtFs => (0, 10, 11, 0),
+                Syntax::FdFsFt => (0, 10, 12, 4),
+                Syntax::FdFs => (0, 0, 12, 4),
+                Syntax::FsFt => (0, 10, 12, 0),
+                Syntax::RtRsImm | Syntax::RsRtLabel | Syntax::RtOffsetRs | Syntax::FtOffsetRs => (9, 10, 0, 0),
+                Syntax::RsLabel if spec.format == Format::Regimm => (9, spec.func, 0, 0),
+                Syntax::RsLabel => (9, 0, 0, 0),
+                Syntax::RtImm => (0, 10, 0, 0),
+                Syntax::Label | Syntax::Offset | Syntax::None => (0, 0, 0, 0)
+            };
+            let fields = Fields { opcode: spec.opcode, rs, rt, rd, shamt, func: spec.func, immediate, address: target >> 2 };
+            let word = encode(spec, &fields);
+
+            let decoded = decode(word).unwrap_or_else(|| panic!("{} does not decode", spec.mnemonic));
+            assert_eq!(decoded.mnemonic, spec.mnemonic);
+
+            let text = disassemble::disassemble(word, PC_START, &symbols);
+            assert!(text.starts_with(spec.mnemonic), "{} disassembled as {}", spec.mnemonic, text);
+
+            let source = format!("{text}\nnop\ntarget: nop\n");
+            let program = match assemble::assemble_source("round_trip.s", source) {
+                Ok((program, _)) => program,
+                Err(e) => panic!("{text} does not assemble: {}", e[0])
+            };
+            assert_eq!(program.instructions[0], word, "{text}");
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use super::cp0::{ENTRY_HI_ASID, ENTRY_HI_VPN2};
+
+/*
+ * Memory management unit: the MIPS32 segments in front of physical memory and a software
+ * managed TLB of 4 KiB page pairs. kuseg is mapped and open to user mode, kseg0 and kseg1
+ * map straight onto the bottom of physical memory, kseg1 bypassing the caches, and kseg2
+ * and up are mapped and kernel only. The OS fills the TLB itself with tlbwi and tlbwr from
+ * its refill handler, which a miss in kuseg or kseg2 vectors to
+ */
+
+pub const KSEG0: u32 = 0x8000_0000;     // unmapped, cached
+pub const KSEG1: u32 = 0xA000_0000;     // unmapped, uncached
+pub const KSEG2: u32 = 0xC000_0000;     // mapped, kernel only
+
+// EntryLo fields
+pub const ENTRY_LO_PFN: u32 = 0x03FF_FFC0;     // page frame number, 25..6
+pub const ENTRY_LO_DIRTY: u32 = 1 << 2;        // D, writes allowed
+pub const ENTRY_LO_VALID: u32 = 1 << 1;        // V
+pub const ENTRY_LO_GLOBAL: u32 = 1 << 0;       // G, matches any ASID
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_OFFSET: u32 = (1 << PAGE_SHIFT) - 1;
+
+// One TLB entry, mapping an even and odd pair of pages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlbEntry {
+    pub entry_hi: u32,
+    pub entry_lo0: u32,     // even page
+    pub entry_lo1: u32      // odd page
+}
+
+impl TlbEntry {
+    fn global(&self) -> bool {
+        self.entry_lo0 & self.entry_lo1 & ENTRY_LO_GLOBAL != 0
+    }
+
+    fn matches(&self, entry_hi: u32) -> bool {
+        (self.entry_hi ^ entry_hi) & ENTRY_HI_VPN2 == 0
+            && (self.global() || (self.entry_hi ^ entry_hi) & ENTRY_HI_ASID == 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch, Load, Store
+}
+
+// Why a translation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Miss {
+    Refill,     // no entry matches, the refill handler takes it
+    Invalid,    // an entry matches but the page is not valid
+    Modified,   // a store to a page that is not dirty
+    Address     // a user mode access outside kuseg
+}
+
+// Where a virtual address went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub physical: u32,
+    pub cached: bool
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MmuStats {
+    pub translations: u64,
+    pub unmapped: u64,      // kseg0 and kseg1, no TLB lookup
+    pub tlb_hits: u64,
+    pub refills: u64,
+    pub invalid: u64,
+    pub modified: u64,
+    pub address_errors: u64
+}
+
+impl MmuStats {
+    pub fn tlb_lookups(&self) -> u64 {
+        self.tlb_hits + self.refills + self.invalid + self.modified
+    }
+
+    pub fn tlb_hit_rate(&self) -> f64 {
+        if self.tlb_lookups() == 0 { 0.0 } else { self.tlb_hits as f64 / self.tlb_lookups() as f64 }
+    }
+}
+
+pub struct Mmu {
+    pub tlb: Vec<TlbEntry>,
+    pub refill_handler: Option<u32>,    // TLB refills vector here, exception_handler if None
+    pub stats: MmuStats
+}
+
+impl Mmu {
+    // Entries start out on distinct pages of kseg0, which is never looked up, so an empty
+    // TLB misses rather than matching page 0
+    pub fn new(entries: usize) -> Self {
+        let tlb = (0..entries.max(1) as u32).map(|i| TlbEntry { entry_hi: KSEG0 + (i << 13), ..TlbEntry::default() }).collect();
+        Mmu { tlb, refill_handler: None, stats: MmuStats::default() }
+    }
+
+    // Translate an access and count it. entry_hi supplies the current ASID
+    pub fn translate(&mut self, address: u32, access: Access, user: bool, entry_hi: u32) -> Result<Translation, Miss> {
+        self.stats.translations += 1;
+        let result = self.lookup(address, access, user, entry_hi);
+        let stat = match (result, address) {
+            (Ok(_), KSEG0..KSEG2) => &mut self.stats.unmapped,
+            (Ok(_), _) => &mut self.stats.tlb_hits,
+            (Err(Miss::Refill), _) => &mut self.stats.refills,
+            (Err(Miss::Invalid), _) => &mut self.stats.invalid,
+            (Err(Miss::Modified), _) => &mut self.stats.modified,
+            (Err(Miss::Address), _) => &mut self.stats.address_errors
+        };
+        *stat += 1;
+        result
+    }
+
+    // Translate without counting, for debuggers and syscall services
+    pub fn lookup(&self, address: u32, access: Access, user: bool, entry_hi: u32) -> Result<Translation, Miss> {
+        if user && address >= KSEG0 {
+            return Err(Miss::Address);
+        }
+        match address {
+            KSEG0..KSEG1 => return Ok(Translation { physical: address - KSEG0, cached: true }),
+            KSEG1..KSEG2 => return Ok(Translation { physical: address - KSEG1, cached: false }),
+            _ => ()
+        }
+
+        let asid = entry_hi & ENTRY_HI_ASID;
+        let entry = self.probe((address & ENTRY_HI_VPN2) | asid).map(|i| self.tlb[i]).ok_or(Miss::Refill)?;
+        let entry_lo = if address & (1 << PAGE_SHIFT) == 0 { entry.entry_lo0 } else { entry.entry_lo1 };
+        if entry_lo & ENTRY_LO_VALID == 0 {
+            return Err(Miss::Invalid);
+        }
+        if access == Access::Store && entry_lo & ENTRY_LO_DIRTY == 0 {
+            return Err(Miss::Modified);
+        }
+        let frame = (entry_lo & ENTRY_LO_PFN) >> 6;
+        Ok(Translation { physical: (frame << PAGE_SHIFT) | (address & PAGE_OFFSET), cached: true })
+    }
+
+    // Entry matching the VPN2 and ASID of entry_hi
+    pub fn probe(&self, entry_hi: u32) -> Option<usize> {
+        self.tlb.iter().position(|entry| entry.matches(entry_hi))
+    }
+}
+
+impl fmt::Display for Mmu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.stats;
+        write!(f, "MMU  translations {} (unmapped {}), TLB lookups {}, hits {} ({:.2}%), refills {}, invalid {}, modified {}, address errors {}",
+            s.translations, s.unmapped, s.tlb_lookups(), s.tlb_hits, s.tlb_hit_rate() * 100.0,
+            s.refills, s.invalid, s.modified, s.address_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::{Computer, Fault};
+    use crate::hardware::cp0::ExceptionCode;
+    use crate::hardware::cpu::CPU;
+
+    // A kernel that drops to user mode, with a refill handler mapping every page pair
+    // onto the same physical frames
+    const KERNEL: &str = "
+        .data
+        value: .word 42
+        .text
+        la $t0, user
+        mtc0 $t0, $14
+        li $t0, 0x12
+        mtc0 $t0, $12
+        eret
+        refill: mfc0 $k0, $10
+        srl $k0, $k0, 13
+        sll $k0, $k0, 7
+        ori $k1, $k0, 0x7
+        mtc0 $k1, $2
+        addiu $k1, $k1, 0x40
+        mtc0 $k1, $3
+        tlbwr
+        eret
+        user: la $t0, value
+        lw $t1, 0($t0)
+        li $t2, 0x4000
+        sw $t1, 4($t2)
+    ";
+
+    fn run(user: &str) -> (CPU, Result<i32, Fault>) {
+        let source = format!("{KERNEL}\n{user}\nhalt");
+        let mut cpu = CPU::assembled(&source, |cpu, symbols| {
+            let mut mmu = Mmu::new(16);
+            mmu.refill_handler = Some(KSEG0 | symbols["refill"]);
+            cpu.mmu = Some(mmu);
+        });
+        let result = cpu.start();
+        (cpu, result)
+    }
+
+    #[test]
+    fn refills_let_user_code_run_mapped() {
+        let (cpu, result) = run("");
+        assert_eq!(result.unwrap(), 0);
+        assert_eq!((cpu.registers[9], cpu.read_word_from_mem(0x4004)), (42, Some(42)));
+
+        // the user fetch refills pages 0 and 1, code and data, and the store pages 4 and 5
+        let mmu = cpu.mmu.as_ref().unwrap();
+        assert_eq!((mmu.stats.refills, mmu.stats.invalid, mmu.stats.modified), (2, 0, 0));
+        assert_eq!(mmu.tlb[15].entry_hi, 0);
+        assert_eq!(mmu.tlb[14], TlbEntry { entry_hi: 0x4000, entry_lo0: 0x107, entry_lo1: 0x147 });
+        assert!(mmu.stats.tlb_hits > 0 && mmu.stats.unmapped > 0);
+    }
+
+    #[test]
+    fn user_mode_is_kept_out_of_the_kernel() {
+        let (_, result) = run("lui $t3, 0x8000\nlw $t1, 0($t3)");
+        let Err(Fault::Exception(exception)) = result else { panic!("expected an address error") };
+        assert_eq!((exception.code, exception.bad_vaddr), (ExceptionCode::AddressLoad, Some(KSEG0)));
+
+        let (_, result) = run("mfc0 $t1, $12");
+        let Err(Fault::Exception(exception)) = result else { panic!("expected coprocessor unusable") };
+        assert_eq!(exception.code, ExceptionCode::CoprocessorUnusable);
+    }
+
+    #[test]
+    fn entries_match_by_page_and_asid() {
+        let (mut cpu, _) = run("");
+        let mmu = cpu.mmu.as_mut().unwrap();
+        assert_eq!(mmu.probe(0x4000), Some(14));
+        // a global entry matches whatever the ASID
+        mmu.tlb[3] = TlbEntry { entry_hi: 0x0001_0005, entry_lo0: 0x3, entry_lo1: 0x1 };
+        assert_eq!(mmu.probe(0x0001_00FF), Some(3));
+        assert_eq!(mmu.lookup(0x0001_1000, Access::Load, false, 0), Err(Miss::Invalid));
+        assert_eq!(mmu.lookup(0x0001_0010, Access::Store, false, 0), Err(Miss::Modified));
+        assert_eq!(mmu.lookup(0xA000_0010, Access::Store, false, 0), Ok(Translation { physical: 0x10, cached: false }));
+    }
+}
@@ -1,2 +1,17 @@
 pub mod arch;
-pub mod cpu;
\ No newline at end of file
+pub mod bus;
+pub mod cache;
+pub mod cp0;
+pub mod cp1;
+pub mod cpu;
+pub mod framebuffer;
+pub mod interrupt;
+pub mod isa;
+pub mod mmu;
+pub mod pipeline;
+pub mod predictor;
+pub mod snapshot;
+pub mod syscall;
+pub mod timer;
+pub mod timing;
+pub mod uart;
\ No newline at end of file
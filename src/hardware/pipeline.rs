@@ -0,0 +1,787 @@
+use std::fmt;
+use super::arch::{self, Computer, Fault, Fields};
+use super::cpu::CPU;
+use super::isa::{self, Dataflow, Immediate, Location};
+use crate::datatypes::Program;
+
+/*
+ * Five stage pipeline engine, IF ID EX MEM WB, driving the same CPU a cycle at a time.
+ * Instructions pass from stage to stage through the IF/ID, ID/EX, EX/MEM and MEM/WB
+ * latches, each carrying what the stage before worked out: ID decodes, reads the register
+ * operands and extends the immediate, EX runs the ALU and works out branch outcomes and
+ * memory addresses, MEM loads and stores through the CPU's MMU and caches, and WB writes
+ * the register file and retires the instruction, ticking the devices and taking interrupts
+ * as CPU::execute would. Forwarding passes results out of the EX/MEM and MEM/WB latches
+ * into EX, and out of EX/MEM into the branch comparator in ID. The hazard unit holds an
+ * instruction in ID until its operands can be read or forwarded, and fetch always predicts
+ * not taken, squashing what it fetched behind a control transfer that went elsewhere.
+ *
+ * The datapath covers integer arithmetic, loads and stores, branches and jumps. The rest,
+ * HI/LO, CP0, the FPU, syscall and break, run whole through CPU::execute in EX once every
+ * instruction ahead has written back. An instruction that would raise an exception is run
+ * through CPU::execute when it reaches MEM, where everything older has retired and nothing
+ * younger has touched state, so exceptions are precise and results match the single cycle CPU
+ */
+
+const STAGE_NAMES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+// Stage where conditional branches and register jumps find out where they go.
+// j and jal always go in ID, their target is in the instruction word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchStage {
+    Id, Ex
+}
+
+// Forwarding paths, from the pipeline register after EX or after MEM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forwarding {
+    pub ex_mem: bool,   // EX/MEM to EX, and to the branch comparator in ID
+    pub mem_wb: bool    // MEM/WB to EX
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    pub forwarding: Forwarding,
+    pub branch_stage: BranchStage
+}
+
+impl PipelineConfig {
+    pub fn resolves_in_id(&self, flow: &Dataflow) -> bool {
+        flow.jump || (flow.control && self.branch_stage == BranchStage::Id)
+    }
+
+    // The hazard rule, shared with the timing chart: whether a result can be read distance
+    // cycles after its producer was in EX, by a consumer reading it in ID when in_id and in
+    // EX otherwise
+    pub fn operand_ready(&self, distance: u64, load: bool, in_id: bool) -> bool {
+        match (distance, in_id) {
+            (0, _) => false,
+            // ALU results leave EX at the end of the cycle, loads leave MEM
+            (1, _) => !load && self.forwarding.ex_mem,
+            (2, false) => self.forwarding.mem_wb,
+            // the register file is written in the first half of WB and read in the second half of ID
+            _ => true
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig { forwarding: Forwarding { ex_mem: true, mem_wb: true }, branch_stage: BranchStage::Ex }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub cycles: u64,
+    pub instructions: u64,      // instructions that reached WB
+    pub load_use_stalls: u64,   // cycles ID waited on a load
+    pub data_stalls: u64,       // cycles ID waited on any other result
+    pub serial_stalls: u64,     // cycles ID held an instruction outside the datapath for those ahead of it
+    pub memory_stalls: u64,     // cycles the whole pipeline waited on the caches
+    pub flushes: u64            // instructions squashed behind a control transfer
+}
+
+impl PipelineStats {
+    pub fn stalls(&self) -> u64 {
+        self.load_use_stalls + self.data_stalls + self.serial_stalls + self.memory_stalls
+    }
+
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 { 0.0 } else { self.cycles as f64 / self.instructions as f64 }
+    }
+}
+
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cycles {}, instructions {}, CPI {:.2}", self.cycles, self.instructions, self.cpi())?;
+        write!(f, "stalls {} (load-use {}, data {}, serial {}, memory {}), flushes {}",
+            self.stalls(), self.load_use_stalls, self.data_stalls, self.serial_stalls, self.memory_stalls, self.flushes)
+    }
+}
+
+// IF/ID: an instruction word as fetched
+#[derive(Debug, Clone)]
+struct IfId {
+    address: u32,
+    word: Option<u32>,      // None when the fetch failed, the exception is taken in MEM
+    flow: Dataflow,
+    end: bool               // halt, or the end of the text segment
+}
+
+// What an instruction has left to do past ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Alu,                    // arithmetic, branches and jumps, all done in EX
+    Load(u32),              // of that many bytes
+    Store(u32),
+    Serial,                 // outside the datapath, run whole through the CPU in EX
+    Replay,                 // raises an exception, run through the CPU in MEM to take it
+    Executed,               // run through the CPU already, only retirement is left
+    End
+}
+
+// ID/EX: the decoded instruction with its operands as read from the register file
+#[derive(Debug, Clone)]
+struct IdEx {
+    fetched: IfId,
+    op: Op,
+    mnemonic: &'static str,
+    rs: u32,
+    rt: u32,
+    a: i32,                 // value of rs
+    b: i32,                 // value of rt
+    immediate: i32,         // extended as the instruction wants it
+    shamt: u32,
+    destination: Option<u32>,
+    next: Option<u32>       // where a control transfer resolved in ID goes
+}
+
+// EX/MEM: the ALU result, and the address and data of a load or store
+#[derive(Debug, Clone)]
+struct ExMem {
+    fetched: IfId,
+    op: Op,
+    mnemonic: &'static str,
+    result: i32,            // ALU result, or the return address of a call
+    address: u32,
+    data: i32,              // what a store writes
+    destination: Option<u32>,
+    next: u32               // the instruction after it in program order
+}
+
+// MEM/WB: the value to write back
+#[derive(Debug, Clone)]
+struct MemWb {
+    fetched: IfId,
+    op: Op,
+    value: i32,
+    destination: Option<u32>,
+    next: u32
+}
+
+pub struct Pipeline {
+    pub cpu: CPU,
+    pub config: PipelineConfig,
+    pub stats: PipelineStats,
+    if_id: Option<IfId>,
+    id_ex: Option<IdEx>,
+    ex_mem: Option<ExMem>,
+    mem_wb: Option<MemWb>,
+    fetch_pc: u32,
+    ending: bool            // the program has ended, drain what is left
+}
+
+impl Pipeline {
+    pub fn new(cpu: CPU, config: PipelineConfig) -> Self {
+        let fetch_pc = cpu.program_counter;
+        Pipeline { cpu, config, stats: PipelineStats::default(), if_id: None, id_ex: None, ex_mem: None, mem_wb: None, fetch_pc, ending: false }
+    }
+
+    fn run(&mut self) -> Result<i32, Fault> {
+        (self.if_id, self.id_ex, self.ex_mem, self.mem_wb) = (None, None, None, None);
+        self.fetch_pc = self.cpu.program_counter;
+        self.ending = false;
+        let mut waited = self.memory_stalls();
+
+        while !self.drained() {
+            self.stats.cycles += 1;
+            self.cycle()?;
+            // a cache miss holds every stage until it is served
+            let memory = self.memory_stalls();
+            self.stats.cycles += memory - waited;
+            self.stats.memory_stalls += memory - waited;
+            waited = memory;
+        }
+        // a halt, or running off the text, leaves the PC on it
+        let end = self.in_flight().find(|fetched| fetched.end).map(|fetched| fetched.address);
+        if let Some(end) = end {
+            self.cpu.program_counter = end;
+        }
+
+        if self.cpu.debug_mode {
+            self.cpu.print_state();
+            println!("{}", self.stats);
+            self.cpu.print_statistics();
+        }
+        Ok(self.cpu.take_exit_code().unwrap_or(0))
+    }
+
+    fn memory_stalls(&self) -> u64 {
+        self.cpu.caches.as_ref().map_or(0, |caches| caches.stall_cycles)
+    }
+
+    fn in_flight(&self) -> impl Iterator<Item = &IfId> {
+        self.if_id.iter()
+            .chain(self.id_ex.iter().map(|latch| &latch.fetched))
+            .chain(self.ex_mem.iter().map(|latch| &latch.fetched))
+            .chain(self.mem_wb.iter().map(|latch| &latch.fetched))
+    }
+
+    // nothing left in flight but the end of the program
+    fn drained(&self) -> bool {
+        self.ending && self.in_flight().all(|fetched| fetched.end)
+    }
+
+    // One clock. Every stage works on the latch in front of it as it was when the cycle began,
+    // oldest instruction first, and the latches take what the stages produced. A stage that
+    // finds the program went somewhere other than where fetch went squashes everything younger
+    fn cycle(&mut self) -> Result<(), Fault> {
+        let retiring = self.mem_wb.take();
+        let mut memory = self.ex_mem.take();
+        let mut executing = self.id_ex.take();
+        let mut decoding = self.if_id.take();
+        let mut occupied = [None, decoding.as_ref().map(shown), executing.as_ref().map(|l| shown(&l.fetched)),
+            memory.as_ref().map(|l| shown(&l.fetched)), retiring.as_ref().map(|l| shown(&l.fetched))];
+        let mut redirect = None;
+
+        // WB, in the first half of the cycle so ID reads what it writes
+        if let Some(latch) = &retiring {
+            if let Some(target) = self.write_back(latch)? {
+                self.flush(&[memory.take().is_some(), executing.take().is_some(), decoding.take().is_some()]);
+                redirect = Some(target);
+            }
+        }
+
+        if let Some(latch) = &memory {
+            let (written, replayed) = self.memory(latch)?;
+            if replayed {
+                self.flush(&[executing.take().is_some(), decoding.take().is_some()]);
+                redirect = Some(self.cpu.program_counter);
+            }
+            self.mem_wb = Some(written);
+        }
+        // an older instruction went elsewhere, so the end of the program was on the wrong path
+        if redirect.is_some() {
+            self.ending = false;
+        }
+
+        if let Some(latch) = &executing {
+            let executed = self.execute(latch, memory.as_ref(), retiring.as_ref())?;
+            if executed.op == Op::End || self.cpu.exit_code().is_some() {
+                self.ending = true;
+                decoding = None;
+            } else if executed.next != decoding.as_ref().map_or(self.fetch_pc, |fetched| fetched.address) {
+                self.flush(&[decoding.take().is_some()]);
+                redirect = Some(executed.next);
+            }
+            self.ex_mem = Some(executed);
+        }
+
+        let mut stall = None;
+        if let Some(fetched) = decoding {
+            stall = self.hazard(&fetched, executing.as_ref(), memory.as_ref());
+            match stall {
+                // hold the instruction in IF/ID and send a bubble on into EX
+                Some(stall) => {
+                    match stall {
+                        Stall::LoadUse => self.stats.load_use_stalls += 1,
+                        Stall::Data => self.stats.data_stalls += 1,
+                        Stall::Serial => self.stats.serial_stalls += 1
+                    }
+                    self.if_id = Some(fetched);
+                }
+                None => {
+                    let decoded = self.decode(fetched, memory.as_ref());
+                    if let Some(next) = decoded.next.filter(|&next| next != self.fetch_pc) {
+                        redirect = Some(next);
+                    }
+                    self.id_ex = Some(decoded);
+                }
+            }
+        }
+
+        if !self.ending && stall.is_none() {
+            let fetched = self.fetch();
+            occupied[0] = Some(shown(&fetched));
+            // fetched down the path not taken
+            match redirect {
+                Some(_) => self.stats.flushes += 1,
+                None => self.if_id = Some(fetched)
+            }
+        }
+        if let Some(target) = redirect {
+            self.fetch_pc = target;
+        }
+
+        if self.cpu.debug_mode {
+            self.print_cycle(&occupied, stall);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, squashed: &[bool]) {
+        self.stats.flushes += squashed.iter().filter(|&&squashed| squashed).count() as u64;
+    }
+
+    fn fetch(&mut self) -> IfId {
+        let address = self.fetch_pc;
+        self.fetch_pc = self.fetch_pc.wrapping_add(4);
+        let word = if self.cpu.in_text(address) { self.cpu.fetch_word(address) } else { Some(arch::HALT) };
+        IfId {
+            address,
+            word,
+            flow: word.map(isa::dataflow).unwrap_or_default(),
+            end: word == Some(arch::HALT)
+        }
+    }
+
+    // Whether the instruction in ID has to wait this cycle, and on what. Its operands are
+    // needed in EX next cycle, or in ID now when it resolves there. Results of instructions
+    // past MEM are in the register file
+    fn hazard(&self, consumer: &IfId, in_ex: Option<&IdEx>, in_mem: Option<&ExMem>) -> Option<Stall> {
+        let in_id = self.config.resolves_in_id(&consumer.flow);
+        let producers = [in_ex.map(|latch| &latch.fetched), in_mem.map(|latch| &latch.fetched)];
+
+        for location in &consumer.flow.reads {
+            // the youngest older instruction writing it decides
+            let producer = producers.iter().enumerate()
+                .find_map(|(stage, producer)| producer.filter(|p| p.flow.writes.contains(location)).map(|p| (stage, p)));
+            let Some((stage, producer)) = producer else { continue };
+
+            let distance = stage as u64 + u64::from(!in_id);
+            if !self.config.operand_ready(distance, producer.flow.load, in_id) {
+                return Some(if producer.flow.load { Stall::LoadUse } else { Stall::Data });
+            }
+        }
+        // outside the datapath, an instruction waits for everything ahead to write back
+        if classify(consumer).0 == Op::Serial && in_ex.is_some() {
+            return Some(Stall::Serial);
+        }
+        None
+    }
+
+    // ID: decode, read the register file and extend the immediate. Control transfers that
+    // resolve here take ALU results forwarded from EX/MEM into the branch comparator
+    fn decode(&self, fetched: IfId, in_mem: Option<&ExMem>) -> IdEx {
+        let (op, mnemonic) = classify(&fetched);
+        let word = fetched.word.unwrap_or_default();
+        let f = Fields::extract(word);
+        let in_id = op == Op::Alu && self.config.resolves_in_id(&fetched.flow);
+        let read = |register: u32| {
+            let forwarded = in_mem.filter(|latch| in_id && self.config.forwarding.ex_mem && latch.op == Op::Alu && latch.destination == Some(register));
+            forwarded.map_or(self.cpu.registers[register as usize], |latch| latch.result)
+        };
+        let unsigned = isa::decode(word).is_some_and(|spec| matches!(spec.immediate, Immediate::Unsigned));
+        let destination = match op {
+            Op::Alu | Op::Load(_) => fetched.flow.writes.iter().find_map(|location| match location {
+                Location::Gpr(register) => Some(*register),
+                _ => None
+            }),
+            _ => None
+        };
+
+        let mut decoded = IdEx {
+            op,
+            mnemonic,
+            rs: f.rs,
+            rt: f.rt,
+            a: read(f.rs),
+            b: read(f.rt),
+            immediate: if unsigned { f.immediate as u16 as i32 } else { f.immediate as i32 },
+            shamt: f.shamt,
+            destination,
+            next: None,
+            fetched
+        };
+        if in_id {
+            decoded.next = target(&decoded, decoded.a, decoded.b);
+        }
+        decoded
+    }
+
+    // EX: the ALU, on operands forwarded from the EX/MEM and MEM/WB latches, working out
+    // results, where control transfers go and the addresses of loads and stores. Instructions
+    // outside the datapath run whole on the CPU here
+    fn execute(&mut self, latch: &IdEx, in_mem: Option<&ExMem>, in_wb: Option<&MemWb>) -> Result<ExMem, Fault> {
+        let a = self.forward(latch.rs, latch.a, in_mem, in_wb);
+        let b = self.forward(latch.rt, latch.b, in_mem, in_wb);
+        let address = latch.fetched.address;
+        let mut executed = ExMem {
+            fetched: latch.fetched.clone(),
+            op: latch.op,
+            mnemonic: latch.mnemonic,
+            result: 0,
+            address: a.wrapping_add(latch.immediate) as u32,
+            data: b,
+            destination: latch.destination,
+            next: address.wrapping_add(4)
+        };
+
+        match latch.op {
+            Op::Alu => match alu(latch.mnemonic, a, b, latch.immediate, latch.shamt, address) {
+                Some(result) => {
+                    executed.result = result;
+                    executed.next = latch.next.or_else(|| target(latch, a, b)).unwrap_or(executed.next);
+                }
+                // overflow, taken in MEM
+                None => executed.op = Op::Replay
+            },
+            Op::Serial => {
+                self.cpu.program_counter = address;
+                self.cpu.execute(latch.fetched.word.unwrap_or_default())?;
+                executed.op = Op::Executed;
+                executed.next = self.cpu.program_counter;
+            }
+            _ => ()
+        }
+        Ok(executed)
+    }
+
+    // The forwarding unit: an operand from the youngest older instruction writing it, out of
+    // EX/MEM or MEM/WB, or as ID read it. The hazard unit has already held back any
+    // instruction whose operand is not on a path
+    fn forward(&self, register: u32, read: i32, in_mem: Option<&ExMem>, in_wb: Option<&MemWb>) -> i32 {
+        let forwarding = self.config.forwarding;
+        let in_mem = in_mem.filter(|latch| latch.destination == Some(register));
+        let in_wb = in_wb.filter(|latch| latch.destination == Some(register));
+        match (in_mem, in_wb) {
+            (Some(latch), _) if forwarding.ex_mem && latch.op == Op::Alu => latch.result,
+            (None, Some(latch)) if forwarding.mem_wb => latch.value,
+            _ => read
+        }
+    }
+
+    // MEM: the load or store at the address EX worked out. One that would raise an exception
+    // is run whole through the CPU instead, to take it, and true comes back with the latch
+    fn memory(&mut self, latch: &ExMem) -> Result<(MemWb, bool), Fault> {
+        let value = match latch.op {
+            Op::Load(size) => self.cpu.try_load(latch.address, size).map(|loaded| extended(latch.mnemonic, loaded)),
+            Op::Store(size) => self.cpu.try_store(latch.address, size, latch.data as u32).map(|()| 0),
+            Op::Replay => None,
+            _ => Some(latch.result)
+        };
+        let written = MemWb {
+            fetched: latch.fetched.clone(),
+            op: latch.op,
+            value: value.unwrap_or_default(),
+            destination: latch.destination,
+            next: latch.next
+        };
+        if value.is_some() {
+            return Ok((written, false));
+        }
+
+        // everything older has retired, so the exception is taken where it belongs
+        self.cpu.program_counter = latch.fetched.address;
+        match latch.fetched.word {
+            Some(word) => self.cpu.execute(word)?,
+            None => { self.cpu.fetch()?; }
+        }
+        Ok((MemWb { op: Op::Executed, destination: None, ..written }, true))
+    }
+
+    // WB: write the register file and retire the instruction. Some address the CPU went to
+    // instead of the next instruction when it took an interrupt on the way
+    fn write_back(&mut self, latch: &MemWb) -> Result<Option<u32>, Fault> {
+        if latch.op == Op::End {
+            return Ok(None);
+        }
+        self.stats.instructions += 1;
+        if latch.op == Op::Executed {
+            return Ok(None);
+        }
+        if let Some(register) = latch.destination {
+            self.cpu.registers[register as usize] = latch.value;
+        }
+        self.cpu.retire(latch.fetched.address, latch.fetched.word.unwrap_or_default(), latch.next)?;
+        Ok(Some(self.cpu.program_counter).filter(|&pc| pc != latch.next))
+    }
+
+    fn print_cycle(&self, occupied: &[Option<(u32, bool)>; 5], stall: Option<Stall>) {
+        print!("CYCLE::{:03}", self.stats.cycles);
+        for (name, stage) in STAGE_NAMES.iter().zip(occupied) {
+            match stage {
+                Some((_, true)) => print!("  {name:<3} end       "),
+                Some((address, false)) => print!("  {name:<3} {address:#010x}"),
+                None => print!("  {name:<3} ----------")
+            }
+        }
+        match stall {
+            Some(Stall::LoadUse) => println!("  stall (load-use)"),
+            Some(Stall::Data) => println!("  stall (data)"),
+            Some(Stall::Serial) => println!("  stall (serial)"),
+            None => println!()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stall {
+    LoadUse, Data, Serial
+}
+
+fn shown(fetched: &IfId) -> (u32, bool) {
+    (fetched.address, fetched.end)
+}
+
+// What an instruction does past ID, and its mnemonic
+fn classify(fetched: &IfId) -> (Op, &'static str) {
+    if fetched.end {
+        return (Op::End, "");
+    }
+    let Some(spec) = fetched.word.and_then(isa::decode) else { return (Op::Replay, "") };
+    let op = match spec.mnemonic {
+        "lb" | "lbu" => Op::Load(1),
+        "lh" | "lhu" => Op::Load(2),
+        "lw" => Op::Load(4),
+        "sb" => Op::Store(1),
+        "sh" => Op::Store(2),
+        "sw" => Op::Store(4),
+        "add" | "addu" | "sub" | "subu" | "and" | "or" | "xor" | "nor" | "slt" | "sltu"
+        | "sll" | "srl" | "sra" | "sllv" | "srlv" | "srav"
+        | "addi" | "addiu" | "andi" | "ori" | "xori" | "slti" | "sltiu" | "lui"
+        | "beq" | "bne" | "blez" | "bgtz" | "bltz" | "bgez" | "bltzal" | "bgezal"
+        | "j" | "jal" | "jr" | "jalr" => Op::Alu,
+        _ => Op::Serial
+    };
+    (op, spec.mnemonic)
+}
+
+// The ALU, None on a signed overflow. Calls put their return address through it
+fn alu(mnemonic: &str, a: i32, b: i32, immediate: i32, shamt: u32, address: u32) -> Option<i32> {
+    Some(match mnemonic {
+        "add" => a.checked_add(b)?,
+        "addi" => a.checked_add(immediate)?,
+        "sub" => a.checked_sub(b)?,
+        "addu" => a.wrapping_add(b),
+        "addiu" => a.wrapping_add(immediate),
+        "subu" => a.wrapping_sub(b),
+        "and" => a & b,
+        "andi" => a & immediate,
+        "or" => a | b,
+        "ori" => a | immediate,
+        "xor" => a ^ b,
+        "xori" => a ^ immediate,
+        "nor" => !(a | b),
+        "slt" => i32::from(a < b),
+        "slti" => i32::from(a < immediate),
+        "sltu" => i32::from((a as u32) < (b as u32)),
+        "sltiu" => i32::from((a as u32) < (immediate as u32)),
+        "sll" => b << shamt,
+        "srl" => ((b as u32) >> shamt) as i32,
+        "sra" => b >> shamt,
+        "sllv" => b << (a & 0x1F),
+        "srlv" => ((b as u32) >> (a & 0x1F)) as i32,
+        "srav" => b >> (a & 0x1F),
+        "lui" => immediate << 16,
+        "jal" | "jalr" | "bltzal" | "bgezal" => address.wrapping_add(4) as i32,
+        _ => 0
+    })
+}
+
+// Where a control transfer goes on these operands, None for anything else
+fn target(latch: &IdEx, a: i32, b: i32) -> Option<u32> {
+    let next = latch.fetched.address.wrapping_add(4);
+    let taken = match latch.mnemonic {
+        "beq" => a == b,
+        "bne" => a != b,
+        "blez" => a <= 0,
+        "bgtz" => a > 0,
+        "bltz" | "bltzal" => a < 0,
+        "bgez" | "bgezal" => a >= 0,
+        "j" | "jal" => {
+            let address = Fields::extract(latch.fetched.word.unwrap_or_default()).address;
+            return Some(((address & arch::JUMP_ADDRESS_MASK) << 2) | (next & 0xF000_0000));
+        }
+        "jr" | "jalr" => return Some(a as u32),
+        _ => return None
+    };
+    Some(if taken { next.wrapping_add((latch.immediate << 2) as u32) } else { next })
+}
+
+// A loaded value extended to a register as the load wants it
+fn extended(mnemonic: &str, loaded: u32) -> i32 {
+    match mnemonic {
+        "lb" => loaded as u8 as i8 as i32,
+        "lh" => loaded as u16 as i16 as i32,
+        _ => loaded as i32
+    }
+}
+
+impl Computer for Pipeline {
+    fn load_program(&mut self, program: Program) {
+        self.cpu.load_program(program);
+    }
+
+    fn start(&mut self) -> Result<i32, Fault> {
+        self.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::bus::Permissions;
+    use crate::hardware::timer::{self, Timer};
+
+    fn cpu(source: &str) -> CPU {
+        CPU::assembled(source, |_, _| ())
+    }
+
+    fn run(source: &str, config: PipelineConfig) -> Pipeline {
+        let mut pipeline = Pipeline::new(cpu(source), config);
+        pipeline.start().expect("test program runs");
+        pipeline
+    }
+
+    const NO_FORWARDING: Forwarding = Forwarding { ex_mem: false, mem_wb: false };
+
+    #[test]
+    fn results_match_the_single_cycle_cpu() {
+        let source = "
+            .data
+            values: .word 3, 1, 4, 1, 5, 9, 2, 6
+            .text
+            la $t0, values
+            li $t1, 8
+            li $s0, 0
+            loop: lw $t2, 0($t0)
+            addu $s0, $s0, $t2
+            addiu $t0, $t0, 4
+            addiu $t1, $t1, -1
+            bgtz $t1, loop
+            jal double
+            sw $s0, 0($t0)
+            halt
+            double: sll $s0, $s0, 1
+            jr $ra
+        ";
+        let mut reference = cpu(source);
+        reference.start().unwrap();
+
+        for forwarding in [NO_FORWARDING, Forwarding { ex_mem: true, mem_wb: true }] {
+            for branch_stage in [BranchStage::Id, BranchStage::Ex] {
+                let pipeline = run(source, PipelineConfig { forwarding, branch_stage });
+                assert_eq!(pipeline.cpu.registers, reference.registers);
+                assert_eq!(pipeline.cpu.bus.region("ram").unwrap().device.bytes(), reference.bus.region("ram").unwrap().device.bytes());
+                assert_eq!(pipeline.cpu.program_counter, reference.program_counter);
+                assert_eq!(pipeline.stats.instructions, 48);
+            }
+        }
+    }
+
+    #[test]
+    fn exceptions_interrupts_and_the_rest_of_the_isa_match_too() {
+        let source = "
+            .data
+            values: .word 0x80ff7f01, 0x12345678, -7
+            out: .space 16
+            .text
+            la $s0, values
+            lb $t0, 0($s0)
+            lbu $t1, 3($s0)
+            lh $t2, 2($s0)
+            lhu $t3, 2($s0)
+            la $s1, out
+            sb $t0, 0($s1)
+            sh $t2, 2($s1)
+            lw $t4, 8($s0)
+            mult $t4, $t3
+            mflo $t5
+            mfhi $t6
+            div $t3, $t4
+            mflo $t7
+            mfhi $t8
+            sw $t5, 4($s1)
+            lw $t9, 4($s1)
+            bne $t9, $t5, wrong
+            sltu $v0, $t4, $t9
+            slt $v1, $t4, $t9
+            sltiu $a0, $t4, 5
+            nor $a1, $t4, $t0
+            xori $a2, $a1, 0xf0f0
+            li $a3, 3
+            srav $t0, $t4, $a3
+            sllv $t1, $t1, $a3
+            lui $t2, 0x8000
+            add $t3, $t2, $t2
+            addiu $t3, $t3, 1
+            lw $s4, 2($s0)
+            addiu $s4, $s4, 1
+            bltzal $t4, called
+            bgezal $t4, called
+            la $t5, called
+            jalr $t5
+            sw $s3, 12($s1)
+            wrong: halt
+            called: addiu $s3, $s3, 1
+            jr $ra
+            handler: mfc0 $k0, $14
+            addiu $k0, $k0, 4
+            mtc0 $k0, $14
+            addiu $s5, $s5, 1
+            eret
+        ";
+        // the handler skips the overflow and the misaligned load
+        let build = || CPU::assembled(source, |cpu, symbols| cpu.exception_handler = Some(symbols["handler"]));
+        let mut reference = build();
+        reference.start().unwrap();
+        assert_eq!(reference.registers[21], 2);
+
+        for forwarding in [NO_FORWARDING, Forwarding { ex_mem: true, mem_wb: false }, Forwarding { ex_mem: true, mem_wb: true }] {
+            for branch_stage in [BranchStage::Id, BranchStage::Ex] {
+                let mut pipeline = Pipeline::new(build(), PipelineConfig { forwarding, branch_stage });
+                pipeline.start().unwrap();
+                let cpu = &pipeline.cpu;
+                assert_eq!((&cpu.registers, cpu.hi, cpu.lo), (&reference.registers, reference.hi, reference.lo));
+                assert_eq!(cpu.bus.region("ram").unwrap().device.bytes(), reference.bus.region("ram").unwrap().device.bytes());
+                assert_eq!((cpu.program_counter, cpu.retired), (reference.program_counter, reference.retired));
+            }
+        }
+
+        let ticking = "
+            lui $s0, 0xffff
+            li $t0, 10
+            sw $t0, 0x14($s0)
+            li $t0, 7
+            sw $t0, 0x10($s0)
+            li $t0, 0x801
+            mtc0 $t0, $12
+            li $t1, 4
+            spin: lw $t2, 0($zero)
+            addu $s2, $s2, $t2
+            bne $s1, $t1, spin
+            halt
+            handler: addiu $s1, $s1, 1
+            li $k0, 1
+            sw $k0, 0x1c($s0)
+            eret";
+        let build = || CPU::assembled(ticking, |cpu, symbols| {
+            cpu.bus.map("timer", timer::BASE, Permissions::RW, Box::new(Timer::new())).unwrap();
+            cpu.interrupts.route("timer", 1).unwrap();
+            cpu.exception_handler = Some(symbols["handler"]);
+        });
+        // interrupts come in between instructions in flight, and are taken on the same ones
+        let mut reference = build();
+        reference.start().unwrap();
+        assert_eq!(reference.interrupts.log.len(), 4);
+        let taken = |cpu: &CPU| cpu.interrupts.log.iter().map(|taken| (taken.cycle, taken.epc)).collect::<Vec<_>>();
+        for branch_stage in [BranchStage::Id, BranchStage::Ex] {
+            let mut pipeline = Pipeline::new(build(), PipelineConfig { branch_stage, ..PipelineConfig::default() });
+            pipeline.start().unwrap();
+            assert_eq!(pipeline.cpu.registers, reference.registers);
+            assert_eq!(taken(&pipeline.cpu), taken(&reference));
+        }
+    }
+
+    #[test]
+    fn hazards_cost_the_textbook_cycles() {
+        // five instructions fill and drain in five plus four cycles
+        let straight = run("addiu $t0, $zero, 1\naddiu $t1, $zero, 2\nnop\nnop\nnop\nhalt", PipelineConfig::default());
+        assert_eq!((straight.stats.cycles, straight.stats.stalls()), (9, 0));
+
+        let load_use = "lw $t0, 0($zero)\naddu $t1, $t0, $t0\nhalt";
+        let forwarded = run(load_use, PipelineConfig::default());
+        assert_eq!((forwarded.stats.cycles, forwarded.stats.load_use_stalls), (7, 1));
+        let unforwarded = run(load_use, PipelineConfig { forwarding: NO_FORWARDING, ..PipelineConfig::default() });
+        assert_eq!((unforwarded.stats.cycles, unforwarded.stats.load_use_stalls), (8, 2));
+
+        // a taken branch squashes what was fetched behind it, one instruction if it resolves in ID
+        let branch = "beq $zero, $zero, skip\naddiu $t0, $zero, 1\naddiu $t0, $zero, 2\nskip: addiu $t1, $zero, 3\nhalt";
+        let in_ex = run(branch, PipelineConfig::default());
+        assert_eq!((in_ex.stats.flushes, in_ex.stats.cycles, in_ex.cpu.registers[8]), (2, 8, 0));
+        let in_id = run(branch, PipelineConfig { branch_stage: BranchStage::Id, ..PipelineConfig::default() });
+        assert_eq!((in_id.stats.flushes, in_id.stats.cycles), (1, 7));
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use super::isa::{self, Format, Syntax};
+use super::snapshot::{join, section, split};
+use crate::software::disassemble;
+
+/*
+ * Branch prediction models. The CPU shows the branch unit every control transfer it
+ * executes together with where it went, and the unit scores what it would have predicted
+ * at fetch. Conditional branches need the direction right, and with a BTB the target of a
+ * taken prediction must come from it as well. Without a BTB j and jal count as predicted,
+ * their target is in the word, and jr and jalr as mispredicted, theirs is in a register
+ */
+
+// A direction predictor for conditional branches
+pub trait Predictor {
+    fn name(&self) -> String;
+    fn predict(&self, pc: u32, target: u32) -> bool;
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+
+    // the tables as words for snapshots, and putting them back, None without changing
+    // anything if they don't fit
+    fn save(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        words.is_empty().then_some(())
+    }
+}
+
+// Always taken, or never
+pub struct Static(pub bool);
+
+impl Predictor for Static {
+    fn name(&self) -> String {
+        String::from(if self.0 { "static taken" } else { "static not taken" })
+    }
+
+    fn predict(&self, _: u32, _: u32) -> bool {
+        self.0
+    }
+
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+}
+
+// Backward taken, forward not taken, right for most loops
+pub struct Btfn;
+
+impl Predictor for Btfn {
+    fn name(&self) -> String {
+        String::from("BTFN")
+    }
+
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        target <= pc
+    }
+
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+}
+
+// Last outcome of each branch, in a table indexed by the PC
+pub struct OneBit {
+    table: Vec<bool>
+}
+
+impl OneBit {
+    pub fn new(entries: usize) -> Self {
+        OneBit { table: vec![false; entries.max(1)] }
+    }
+}
+
+impl Predictor for OneBit {
+    fn name(&self) -> String {
+        format!("1-bit ({} entries)", self.table.len())
+    }
+
+    fn predict(&self, pc: u32, _: u32) -> bool {
+        self.table[index(pc, self.table.len())]
+    }
+
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        let i = index(pc, self.table.len());
+        self.table[i] = taken;
+    }
+
+    fn save(&self) -> Vec<u32> {
+        self.table.iter().map(|&taken| taken as u32).collect()
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        if words.len() != self.table.len() || words.iter().any(|&word| word > 1) {
+            return None;
+        }
+        self.table = words.iter().map(|&word| word == 1).collect();
+        Some(())
+    }
+}
+
+// 2-bit saturating counters, 0 and 1 predict not taken, 2 and 3 taken
+#[derive(Debug, Clone)]
+struct Counters(Vec<u8>);
+
+impl Counters {
+    fn new(entries: usize) -> Self {
+        Counters(vec![1; entries.max(1)])   // weakly not taken
+    }
+
+    fn taken(&self, i: usize) -> bool {
+        self.0[i % self.0.len()] >= 2
+    }
+
+    fn train(&mut self, i: usize, taken: bool) {
+        let entries = self.0.len();
+        let counter = &mut self.0[i % entries];
+        *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+    }
+
+    fn save(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().map(|&counter| counter as u32)
+    }
+
+    // As many counters as these, from the front of words
+    fn restored(&self, words: &[u32]) -> Option<Counters> {
+        let words = words.get(..self.0.len())?;
+        words.iter().map(|&word| (word <= 3).then_some(word as u8)).collect::<Option<_>>().map(Counters)
+    }
+}
+
+pub struct TwoBit {
+    counters: Counters
+}
+
+impl TwoBit {
+    pub fn new(entries: usize) -> Self {
+        TwoBit { counters: Counters::new(entries) }
+    }
+}
+
+impl Predictor for TwoBit {
+    fn name(&self) -> String {
+        format!("2-bit ({} entries)", self.counters.0.len())
+    }
+
+    fn predict(&self, pc: u32, _: u32) -> bool {
+        self.counters.taken(index(pc, usize::MAX))
+    }
+
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        self.counters.train(index(pc, usize::MAX), taken);
+    }
+
+    fn save(&self) -> Vec<u32> {
+        self.counters.save().collect()
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        if words.len() != self.counters.0.len() {
+            return None;
+        }
+        self.counters = self.counters.restored(words)?;
+        Some(())
+    }
+}
+
+// 2-bit counters indexed by the PC xor the global history of the last bits outcomes
+pub struct Gshare {
+    bits: u32,
+    history: u32,
+    counters: Counters
+}
+
+impl Gshare {
+    pub fn new(bits: u32) -> Self {
+        Gshare { bits, history: 0, counters: Counters::new(1 << bits) }
+    }
+
+    fn slot(&self, pc: u32) -> usize {
+        index(pc, usize::MAX) ^ self.history as usize
+    }
+}
+
+impl Predictor for Gshare {
+    fn name(&self) -> String {
+        format!("gshare ({} bits of history)", self.bits)
+    }
+
+    fn predict(&self, pc: u32, _: u32) -> bool {
+        self.counters.taken(self.slot(pc))
+    }
+
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        self.counters.train(self.slot(pc), taken);
+        self.history = ((self.history << 1) | taken as u32) & ((1 << self.bits) - 1);
+    }
+
+    fn save(&self) -> Vec<u32> {
+        [self.history].into_iter().chain(self.counters.save()).collect()
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let (&history, counters) = words.split_first()?;
+        if history >> self.bits != 0 || counters.len() != self.counters.0.len() {
+            return None;
+        }
+        self.counters = self.counters.restored(counters)?;
+        self.history = history;
+        Some(())
+    }
+}
+
+// A 2-bit and a gshare predictor, with per branch 2-bit counters choosing between them
+pub struct Tournament {
+    local: TwoBit,
+    global: Gshare,
+    chooser: Counters       // 2 and 3 pick gshare
+}
+
+impl Tournament {
+    pub fn new(entries: usize, bits: u32) -> Self {
+        Tournament { local: TwoBit::new(entries), global: Gshare::new(bits), chooser: Counters::new(entries) }
+    }
+}
+
+impl Predictor for Tournament {
+    fn name(&self) -> String {
+        format!("tournament of {} and {}", self.local.name(), self.global.name())
+    }
+
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        if self.chooser.taken(index(pc, usize::MAX)) {
+            self.global.predict(pc, target)
+        } else {
+            self.local.predict(pc, target)
+        }
+    }
+
+    fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let local = self.local.predict(pc, target) == taken;
+        let global = self.global.predict(pc, target) == taken;
+        if local != global {
+            self.chooser.train(index(pc, usize::MAX), global);
+        }
+        self.local.update(pc, target, taken);
+        self.global.update(pc, target, taken);
+    }
+
+    // the 2-bit tables, gshare's and the chooser, one after another
+    fn save(&self) -> Vec<u32> {
+        let mut words = self.local.save();
+        words.extend(self.global.save());
+        words.extend(self.chooser.save());
+        words
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let (local, rest) = words.split_at_checked(self.local.counters.0.len())?;
+        let (global, chooser) = rest.split_at_checked(1 + self.global.counters.0.len())?;
+        if chooser.len() != self.chooser.0.len() {
+            return None;
+        }
+        let (local, chooser) = (self.local.counters.restored(local)?, self.chooser.restored(chooser)?);
+        self.global.restore(global)?;
+        (self.local.counters, self.chooser) = (local, chooser);
+        Some(())
+    }
+}
+
+// Predictor by the name the command line uses for it
+pub fn by_name(name: &str) -> Option<Box<dyn Predictor>> {
+    Some(match name {
+        "taken" => Box::new(Static(true)),
+        "not-taken" => Box::new(Static(false)),
+        "btfn" => Box::new(Btfn),
+        "1bit" => Box::new(OneBit::new(256)),
+        "2bit" => Box::new(TwoBit::new(256)),
+        "gshare" => Box::new(Gshare::new(8)),
+        "tournament" => Box::new(Tournament::new(256, 8)),
+        _ => return None
+    })
+}
+
+// Branch target buffer, direct mapped on the PC
+pub struct Btb {
+    entries: Vec<Option<(u32, u32)>>    // (pc, target)
+}
+
+impl Btb {
+    pub fn new(entries: usize) -> Self {
+        Btb { entries: vec![None; entries.max(1)] }
+    }
+
+    pub fn target(&self, pc: u32) -> Option<u32> {
+        match self.entries[index(pc, self.entries.len())] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None
+        }
+    }
+
+    pub fn insert(&mut self, pc: u32, target: u32) {
+        let i = index(pc, self.entries.len());
+        self.entries[i] = Some((pc, target));
+    }
+
+    // Each entry as whether it is valid, its PC and target, for snapshots
+    pub fn save(&self) -> Vec<u32> {
+        self.entries.iter().flat_map(|entry| match entry {
+            Some((pc, target)) => [1, *pc, *target],
+            None => [0; 3]
+        }).collect()
+    }
+
+    pub fn restore(&mut self, words: &[u32]) -> Option<()> {
+        if words.len() != self.entries.len() * 3 {
+            return None;
+        }
+        self.entries = words.chunks(3).map(|entry| match *entry {
+            [0, ..] => Some(None),
+            [1, pc, target] => Some(Some((pc, target))),
+            _ => None
+        }).collect::<Option<_>>()?;
+        Some(())
+    }
+}
+
+// table index of a word aligned PC
+fn index(pc: u32, entries: usize) -> usize {
+    (pc >> 2) as usize % entries
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Conditional,    // beq, bne, blez, bgtz, REGIMM and FPU branches
+    Jump,           // j and jal
+    Register        // jr and jalr
+}
+
+fn kind(word: u32) -> Option<Kind> {
+    let spec = isa::decode(word)?;
+    match (spec.format, spec.syntax) {
+        (_, Syntax::RsRtLabel | Syntax::RsLabel) | (Format::CopBranch, _) => Some(Kind::Conditional),
+        (Format::J, _) => Some(Kind::Jump),
+        (Format::R, _) if matches!(spec.mnemonic, "jr" | "jalr") => Some(Kind::Register),
+        _ => None
+    }
+}
+
+// What happened at one branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    pub kind: Kind,
+    pub word: u32,
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64
+}
+
+impl Site {
+    pub fn accuracy(&self) -> f64 {
+        if self.executed == 0 { 0.0 } else { 1.0 - self.mispredicted as f64 / self.executed as f64 }
+    }
+}
+
+pub struct BranchUnit {
+    pub predictor: Box<dyn Predictor>,
+    pub btb: Option<Btb>,
+    pub penalty: u64,           // cycles a misprediction adds to the cycle count
+    pub sites: BTreeMap<u32, Site>
+}
+
+impl BranchUnit {
+    pub fn new(predictor: Box<dyn Predictor>, btb: Option<Btb>, penalty: u64) -> Self {
+        BranchUnit { predictor, btb, penalty, sites: BTreeMap::new() }
+    }
+
+    // Score the instruction at pc, which went on to next. Returns the cycles it costs,
+    // 0 unless it is a control transfer that was mispredicted
+    pub fn observe(&mut self, pc: u32, word: u32, next: u32) -> u64 {
+        let Some(kind) = kind(word) else { return 0 };
+        let fall_through = pc.wrapping_add(4);
+        let (target, taken) = match kind {
+            Kind::Conditional => {
+                let target = fall_through.wrapping_add(((word as i16 as i32) << 2) as u32);
+                (target, next == target && target != fall_through)
+            }
+            _ => (next, true)
+        };
+
+        let direction = match kind {
+            Kind::Conditional => self.predictor.predict(pc, target),
+            _ => true
+        };
+        let correct = match (&self.btb, direction) {
+            (_, false) => !taken,
+            (Some(btb), true) => taken && btb.target(pc) == Some(target),
+            (None, true) => taken && kind != Kind::Register
+        };
+
+        if kind == Kind::Conditional {
+            self.predictor.update(pc, target, taken);
+        }
+        if let (Some(btb), true) = (&mut self.btb, taken) {
+            btb.insert(pc, target);
+        }
+
+        let site = self.sites.entry(pc).or_insert(Site { kind, word, executed: 0, taken: 0, mispredicted: 0 });
+        site.executed += 1;
+        site.taken += taken as u64;
+        site.mispredicted += !correct as u64;
+        if correct { 0 } else { self.penalty }
+    }
+
+    // The predictor's tables and the BTB, each after its length, then the sites, for
+    // snapshots
+    pub fn save(&self) -> Vec<u32> {
+        let mut words = Vec::new();
+        for saved in [self.predictor.save(), self.btb.as_ref().map(Btb::save).unwrap_or_default()] {
+            words.push(saved.len() as u32);
+            words.extend(saved);
+        }
+        words.push(self.sites.len() as u32);
+        for (&pc, site) in &self.sites {
+            words.extend([pc, site.kind as u32, site.word]);
+            for count in [site.executed, site.taken, site.mispredicted] {
+                words.extend(split(count));
+            }
+        }
+        words
+    }
+
+    // Put back what save gave, None without changing anything if the predictor or BTB
+    // isn't the same
+    pub fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let mut rest = words;
+        let (predictor, btb) = (section(&mut rest)?, section(&mut rest)?);
+        let mut words = rest.iter().copied();
+        let sites = (0..words.next()?).map(|_| {
+            let (pc, kind, word) = (words.next()?, words.next()?, words.next()?);
+            let kind = [Kind::Conditional, Kind::Jump, Kind::Register].get(kind as usize).copied()?;
+            Some((pc, Site { kind, word, executed: join(&mut words)?, taken: join(&mut words)?, mispredicted: join(&mut words)? }))
+        }).collect::<Option<_>>()?;
+        if words.next().is_some() {
+            return None;
+        }
+        let btb = match &self.btb {
+            Some(mine) => {
+                let mut restored = Btb::new(mine.entries.len());
+                restored.restore(btb)?;
+                Some(restored)
+            }
+            None if btb.is_empty() => None,
+            None => return None
+        };
+        self.predictor.restore(predictor)?;
+        (self.btb, self.sites) = (btb, sites);
+        Some(())
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.sites.values().map(|site| site.executed).sum()
+    }
+
+    pub fn mispredicted(&self) -> u64 {
+        self.sites.values().map(|site| site.mispredicted).sum()
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        if self.executed() == 0 { 0.0 } else { 1.0 - self.mispredicted() as f64 / self.executed() as f64 }
+    }
+
+    // Totals, then a line per branch site
+    pub fn report(&self, symbols: &HashMap<String, u32>) -> String {
+        let mut out = format!("predictor {}", self.predictor.name());
+        if let Some(btb) = &self.btb {
+            write!(out, ", BTB of {} entries", btb.entries.len()).unwrap();
+        }
+        writeln!(out, "\nbranches {}, mispredicted {}, accuracy {:.2}%, penalty cycles {}",
+            self.executed(), self.mispredicted(), self.accuracy() * 100.0, self.mispredicted() * self.penalty).unwrap();
+        for (&pc, site) in &self.sites {
+            writeln!(out, "{:#010x}  {:<28} executed {}, taken {}, mispredicted {}, accuracy {:.2}%",
+                pc, disassemble::disassemble(site.word, pc, symbols),
+                site.executed, site.taken, site.mispredicted, site.accuracy() * 100.0).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::cpu::CPU;
+
+    // eight trips round a loop, then a call and return
+    const SOURCE: &str = "
+        li $t0, 8
+        loop: addiu $t0, $t0, -1
+        bgtz $t0, loop
+        jal leaf
+        halt
+        leaf: jr $ra
+    ";
+
+    fn run(predictor: Box<dyn Predictor>, btb: Option<Btb>) -> CPU {
+        let mut cpu = CPU::assembled(SOURCE, |cpu, _| cpu.branches = Some(BranchUnit::new(predictor, btb, 3)));
+        cpu.start().expect("test program runs");
+        cpu
+    }
+
+    #[test]
+    fn predictors_score_the_loop_branch() {
+        let loop_misses = |predictor| {
+            let cpu = run(predictor, None);
+            let branches = cpu.branches.as_ref().unwrap();
+            let site = branches.sites[&0x48];
+            assert_eq!((site.kind, site.executed, site.taken), (Kind::Conditional, 8, 7));
+            site.mispredicted
+        };
+        assert_eq!(loop_misses(Box::new(Static(false))), 7);
+        assert_eq!(loop_misses(Box::new(Static(true))), 1);
+        assert_eq!(loop_misses(Box::new(Btfn)), 1);
+        assert_eq!(loop_misses(Box::new(OneBit::new(16))), 2);
+        assert_eq!(loop_misses(Box::new(TwoBit::new(16))), 2);
+    }
+
+    #[test]
+    fn mispredictions_cost_the_penalty() {
+        // without a BTB only jr misses besides the loop branch, with one jal misses cold too
+        let cpu = run(Box::new(TwoBit::new(16)), None);
+        let branches = cpu.branches.as_ref().unwrap();
+        assert_eq!((branches.executed(), branches.mispredicted()), (10, 3));
+        assert_eq!(cpu.cycles, 19 + 3 * 3);
+
+        let cpu = run(Box::new(TwoBit::new(16)), Some(Btb::new(16)));
+        let branches = cpu.branches.as_ref().unwrap();
+        assert_eq!(branches.sites[&0x48].mispredicted, 2);
+        assert_eq!(branches.mispredicted(), 4);
+    }
+
+    #[test]
+    fn history_learns_what_counters_cannot() {
+        // one branch alternating taken and not taken
+        let mut twobit = TwoBit::new(16);
+        let mut gshare = Gshare::new(4);
+        let mut tournament = Tournament::new(16, 4);
+        let (mut a, mut b, mut c) = (0, 0, 0);
+        for i in 0..64 {
+            let taken = i % 2 == 0;
+            for (predictor, misses) in [(&mut twobit as &mut dyn Predictor, &mut a), (&mut gshare, &mut b), (&mut tournament, &mut c)] {
+                if i >= 32 && predictor.predict(0x40, 0x80) != taken {
+                    *misses += 1;
+                }
+                predictor.update(0x40, 0x80, taken);
+            }
+        }
+        assert!(a >= 16);
+        assert_eq!((b, c), (0, 0));
+    }
+}
@@ -0,0 +1,724 @@
+use std::io;
+use crate::hardware::arch;
+use crate::hardware::cache::Hierarchy;
+use crate::hardware::cp0::Cp0;
+use crate::hardware::cp1::Cp1;
+use crate::hardware::cpu::CPU;
+use crate::hardware::framebuffer::crc32;
+use crate::hardware::mmu::TlbEntry;
+use crate::hardware::predictor::BranchUnit;
+
+/*
+ * Machine state saved to a file and restored from it, for handing a reproduction on,
+ * resuming a long run or starting from a known state. A snapshot holds the registers, PC,
+ * HI/LO, CP0 and CP1, the TLB, the program break, the instruction and cycle counts, and for
+ * every region of the bus its name and place, the device's own registers and the bytes of
+ * its memory. With caches or a branch predictor attached, their lines, tables and statistics
+ * are kept as well. Restoring it into a machine with the same regions, devices, caches and
+ * predictor runs on exactly as the saved one would have, cycle counts included. Input a UART
+ * backend or the syscalls had buffered is not kept, nor the log of interrupts taken.
+ *
+ * The file is big-endian whatever the bus is:
+ *
+ *   "MIPSSNAP"  u16 version  u16 flags, CACHES and BRANCHES for the sections at the end
+ *   u64 retired  u64 cycles  u32 pc  u32 hi  u32 lo  u32 heap_end  u8 exited  u32 exit code
+ *   32 x u32 registers  12 x u32 CP0 in the order of CP0_NAMES  32 x u32 FPRs  u32 FCSR
+ *   u8 has MMU [u32 entries, entries x (u32 EntryHi, u32 EntryLo0, u32 EntryLo1)]
+ *   u32 regions, each:
+ *     u16 name length, name  u32 start  u32 size  u32 words, words of device state
+ *     u8 has memory [u32 pages, pages x (u32 offset, u32 length, PackBits bytes)]
+ *   with CACHES: u16 section version  u32 words, words of Hierarchy::save
+ *   with BRANCHES: u16 section version  u16 name length, predictor name  u32 words, words of BranchUnit::save
+ *   u32 CRC-32 of everything before it
+ *
+ * Memory is cut into pages and only those with a byte other than zero are written, each
+ * run-length coded. Version 1 files, from before the sections, are still read. The JSON form
+ * spells the same out for reading and can't be restored
+ */
+
+pub const MAGIC: &[u8; 8] = b"MIPSSNAP";
+pub const VERSION: u16 = 2;
+pub const PAGE: usize = 4096;
+
+// Flags for the sections that follow the regions, and the versions of those sections
+pub const CACHES: u16 = 1 << 0;
+pub const BRANCHES: u16 = 1 << 1;
+pub const CACHES_VERSION: u16 = 1;
+pub const BRANCHES_VERSION: u16 = 1;
+
+pub const CP0_NAMES: [&str; 12] = [
+    "index", "random", "entry_lo0", "entry_lo1", "context", "bad_vaddr",
+    "count", "entry_hi", "compare", "status", "cause", "epc"
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub retired: u64,
+    pub cycles: u64,
+    pub pc: u32,
+    pub hi: i32,
+    pub lo: i32,
+    pub heap_end: u32,
+    pub exit_code: Option<i32>,
+    pub registers: Vec<i32>,
+    pub cp0: Cp0,
+    pub cp1: Cp1,
+    pub tlb: Option<Vec<TlbEntry>>,
+    pub regions: Vec<Image>,
+    pub caches: Option<Vec<u32>>,               // Hierarchy::save
+    pub branches: Option<(String, Vec<u32>)>    // the predictor's name and BranchUnit::save
+}
+
+// A region as it was: the device's registers, and the pages of its memory that aren't all zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub state: Vec<u32>,
+    pub pages: Option<Vec<(u32, Vec<u8>)>>     // offset and bytes, None for devices without memory
+}
+
+impl Snapshot {
+    pub fn of(cpu: &CPU) -> Self {
+        let regions = cpu.bus.regions().iter().map(|region| Image {
+            name: region.name.clone(),
+            start: region.start,
+            size: region.device.size(),
+            state: region.device.save(),
+            pages: region.device.bytes().map(|bytes| bytes.chunks(PAGE).enumerate()
+                .filter(|(_, page)| page.iter().any(|&byte| byte != 0))
+                .map(|(n, page)| ((n * PAGE) as u32, page.to_vec()))
+                .collect())
+        }).collect();
+        Snapshot {
+            retired: cpu.retired,
+            cycles: cpu.cycles,
+            pc: cpu.program_counter,
+            hi: cpu.hi,
+            lo: cpu.lo,
+            heap_end: cpu.heap_end,
+            exit_code: cpu.exit_code(),
+            registers: cpu.registers.clone(),
+            cp0: cpu.cp0,
+            cp1: cpu.cp1,
+            tlb: cpu.mmu.as_ref().map(|mmu| mmu.tlb.clone()),
+            regions,
+            caches: cpu.caches.as_ref().map(Hierarchy::save),
+            branches: cpu.branches.as_ref().map(|branches| (branches.predictor.name(), branches.save()))
+        }
+    }
+
+    // Put the state back into cpu, which has to have the same regions, devices, caches and
+    // predictor. Nothing is changed when it doesn't
+    pub fn restore(&self, cpu: &mut CPU) -> Result<(), String> {
+        match (&self.tlb, &cpu.mmu) {
+            (Some(tlb), Some(mmu)) if tlb.len() != mmu.tlb.len() =>
+                return Err(format!("the snapshot has {} TLB entries and this machine {}", tlb.len(), mmu.tlb.len())),
+            (Some(_), None) => return Err(String::from("the snapshot has a TLB and this machine no MMU")),
+            (None, Some(_)) => return Err(String::from("the snapshot has no TLB and this machine an MMU")),
+            _ => {}
+        }
+        match (&self.caches, &cpu.caches) {
+            (Some(_), None) => return Err(String::from("the snapshot has caches and this machine none")),
+            (None, Some(_)) => return Err(String::from("the snapshot has no caches and this machine does")),
+            _ => {}
+        }
+        match (&self.branches, &cpu.branches) {
+            (Some((name, _)), Some(branches)) if *name != branches.predictor.name() =>
+                return Err(format!("the snapshot has a {name} predictor and this machine a {}", branches.predictor.name())),
+            (Some((name, _)), None) => return Err(format!("the snapshot has a {name} predictor and this machine none")),
+            (None, Some(_)) => return Err(String::from("the snapshot has no branch predictor and this machine one")),
+            _ => {}
+        }
+        let regions = cpu.bus.regions();
+        if let Some(region) = regions.iter().find(|r| !self.regions.iter().any(|image| image.name == r.name)) {
+            return Err(format!("the snapshot has no {} region", region.name));
+        }
+        for image in &self.regions {
+            let Some(region) = regions.iter().find(|r| r.name == image.name) else {
+                return Err(format!("the snapshot has a {} region this machine doesn't", image.name));
+            };
+            if (region.start, region.device.size()) != (image.start, image.size) {
+                return Err(format!("{} is {} bytes at {:#010x} in the snapshot and {} bytes at {:#010x} here",
+                    image.name, image.size, image.start, region.device.size(), region.start));
+            }
+            if region.device.save().len() != image.state.len() || region.device.bytes().is_some() != image.pages.is_some() {
+                return Err(format!("{} in the snapshot isn't the device mapped here", image.name));
+            }
+        }
+
+        // the caches, predictor and devices' registers go back first, and should one refuse its
+        // state those before it get their own back
+        let caches = cpu.caches.as_ref().map(Hierarchy::save);
+        if let (Some(hierarchy), Some(words)) = (&mut cpu.caches, &self.caches) {
+            hierarchy.restore(words).ok_or("the caches in the snapshot aren't laid out like the ones here")?;
+        }
+        let branches = cpu.branches.as_ref().map(BranchUnit::save);
+        if let (Some(unit), Some((_, words))) = (&mut cpu.branches, &self.branches) {
+            if unit.restore(words).is_none() {
+                put_back(cpu, caches, None);
+                return Err(String::from("the branch predictor in the snapshot isn't the one here"));
+            }
+        }
+        let mut restored: Vec<(&str, Vec<u32>)> = Vec::new();
+        for image in &self.regions {
+            let device = &mut cpu.bus.region_mut(&image.name).expect("checked above").device;
+            let before = device.save();
+            if device.restore(&image.state).is_none() {
+                for (name, before) in restored {
+                    let device = &mut cpu.bus.region_mut(name).expect("checked above").device;
+                    device.restore(&before).expect("a device takes back the state it saved");
+                }
+                put_back(cpu, caches, branches);
+                return Err(format!("{} would not take its state from the snapshot", image.name));
+            }
+            restored.push((&image.name, before));
+        }
+        for image in &self.regions {
+            let device = &mut cpu.bus.region_mut(&image.name).expect("checked above").device;
+            if let (Some(bytes), Some(pages)) = (device.bytes_mut(), &image.pages) {
+                bytes.fill(0);
+                for (offset, page) in pages {
+                    bytes[*offset as usize..*offset as usize + page.len()].copy_from_slice(page);
+                }
+            }
+        }
+        cpu.retired = self.retired;
+        cpu.cycles = self.cycles;
+        cpu.program_counter = self.pc;
+        (cpu.hi, cpu.lo) = (self.hi, self.lo);
+        cpu.heap_end = self.heap_end;
+        cpu.set_exit_code(self.exit_code);
+        cpu.registers.clone_from(&self.registers);
+        (cpu.cp0, cpu.cp1) = (self.cp0, self.cp1);
+        if let (Some(mmu), Some(tlb)) = (&mut cpu.mmu, &self.tlb) {
+            mmu.tlb.clone_from(tlb);
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_be_bytes());
+        let flags = if self.caches.is_some() { CACHES } else { 0 } | if self.branches.is_some() { BRANCHES } else { 0 };
+        out.extend(flags.to_be_bytes());
+        out.extend(self.retired.to_be_bytes());
+        out.extend(self.cycles.to_be_bytes());
+        for word in [self.pc, self.hi as u32, self.lo as u32, self.heap_end] {
+            out.extend(word.to_be_bytes());
+        }
+        out.push(self.exit_code.is_some() as u8);
+        out.extend(self.exit_code.unwrap_or(0).to_be_bytes());
+        let words = self.registers.iter().map(|&r| r as u32)
+            .chain(cp0_words(&self.cp0))
+            .chain(self.cp1.registers)
+            .chain([self.cp1.fcsr]);
+        for word in words {
+            out.extend(word.to_be_bytes());
+        }
+
+        out.push(self.tlb.is_some() as u8);
+        if let Some(tlb) = &self.tlb {
+            out.extend((tlb.len() as u32).to_be_bytes());
+            for entry in tlb {
+                for word in [entry.entry_hi, entry.entry_lo0, entry.entry_lo1] {
+                    out.extend(word.to_be_bytes());
+                }
+            }
+        }
+
+        out.extend((self.regions.len() as u32).to_be_bytes());
+        for image in &self.regions {
+            out.extend((image.name.len() as u16).to_be_bytes());
+            out.extend(image.name.as_bytes());
+            out.extend(image.start.to_be_bytes());
+            out.extend(image.size.to_be_bytes());
+            extend_words(&mut out, &image.state);
+            out.push(image.pages.is_some() as u8);
+            if let Some(pages) = &image.pages {
+                out.extend((pages.len() as u32).to_be_bytes());
+                for (offset, page) in pages {
+                    let packed = pack(page);
+                    out.extend(offset.to_be_bytes());
+                    out.extend((packed.len() as u32).to_be_bytes());
+                    out.extend(packed);
+                }
+            }
+        }
+        if let Some(words) = &self.caches {
+            out.extend(CACHES_VERSION.to_be_bytes());
+            extend_words(&mut out, words);
+        }
+        if let Some((name, words)) = &self.branches {
+            out.extend(BRANCHES_VERSION.to_be_bytes());
+            out.extend((name.len() as u16).to_be_bytes());
+            out.extend(name.as_bytes());
+            extend_words(&mut out, words);
+        }
+        out.extend(crc32(&out).to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(match bytes.first() {
+                Some(b'{') => String::from("a JSON snapshot is for reading, restore the binary one"),
+                _ => String::from("not a snapshot")
+            });
+        }
+        let mut reader = Reader { bytes, at: MAGIC.len() };
+        let version = reader.u16()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(format!("snapshot format version {version}, this simulator reads up to version {VERSION}"));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len().saturating_sub(4).max(reader.at));
+        if checksum.len() != 4 || crc32(body) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(String::from("the snapshot is damaged, its checksum doesn't match"));
+        }
+        reader.bytes = body;
+        let flags = reader.u16()?;
+        if flags & !(CACHES | BRANCHES) != 0 {
+            return Err(format!("the snapshot has sections {flags:#06x} this simulator doesn't know"));
+        }
+
+        let retired = reader.u64()?;
+        let cycles = reader.u64()?;
+        let (pc, hi, lo, heap_end) = (reader.u32()?, reader.u32()? as i32, reader.u32()? as i32, reader.u32()?);
+        let exited = reader.u8()? != 0;
+        let exit_code = Some(reader.u32()? as i32).filter(|_| exited);
+        let registers = (0..arch::REG_NUM).map(|_| reader.u32().map(|r| r as i32)).collect::<Result<_, _>>()?;
+        let mut cp0 = [0; 12];
+        for word in &mut cp0 {
+            *word = reader.u32()?;
+        }
+        let mut cp1 = Cp1::default();
+        for word in cp1.registers.iter_mut().chain([&mut cp1.fcsr]) {
+            *word = reader.u32()?;
+        }
+
+        let tlb = match reader.u8()? {
+            0 => None,
+            _ => Some((0..reader.u32()?)
+                .map(|_| Ok(TlbEntry { entry_hi: reader.u32()?, entry_lo0: reader.u32()?, entry_lo1: reader.u32()? }))
+                .collect::<Result<_, String>>()?)
+        };
+
+        let mut regions = Vec::new();
+        for _ in 0..reader.u32()? {
+            let length = reader.u16()? as usize;
+            let name = String::from_utf8(reader.take(length)?.to_vec()).map_err(|_| "a region name isn't UTF-8")?;
+            let (start, size) = (reader.u32()?, reader.u32()?);
+            let state = reader.words()?;
+            let pages = match reader.u8()? {
+                0 => None,
+                _ => Some((0..reader.u32()?).map(|_| {
+                    let offset = reader.u32()?;
+                    let length = reader.u32()? as usize;
+                    let expected = (size as usize).saturating_sub(offset as usize).min(PAGE);
+                    match unpack(reader.take(length)?, expected) {
+                        Some(page) if expected > 0 && (offset as usize).is_multiple_of(PAGE) => Ok((offset, page)),
+                        _ => Err(format!("page {offset:#x} of {name} doesn't unpack"))
+                    }
+                }).collect::<Result<_, String>>()?)
+            };
+            regions.push(Image { name, start, size, state, pages });
+        }
+        let caches = match flags & CACHES {
+            0 => None,
+            _ => {
+                reader.section_version("cache", CACHES_VERSION)?;
+                Some(reader.words()?)
+            }
+        };
+        let branches = match flags & BRANCHES {
+            0 => None,
+            _ => {
+                reader.section_version("branch predictor", BRANCHES_VERSION)?;
+                let length = reader.u16()? as usize;
+                let name = String::from_utf8(reader.take(length)?.to_vec()).map_err(|_| "the predictor name isn't UTF-8")?;
+                Some((name, reader.words()?))
+            }
+        };
+        if reader.at != reader.bytes.len() {
+            return Err(String::from("the snapshot has bytes after its last section"));
+        }
+
+        Ok(Snapshot { retired, cycles, pc, hi, lo, heap_end, exit_code, registers, cp0: cp0_from(cp0), cp1, tlb, regions, caches, branches })
+    }
+
+    // The same as JSON, numbers that are addresses or register contents in hex
+    pub fn json(&self) -> String {
+        let hex = |value: u32| format!("\"{value:#010x}\"");
+        let mut out = format!("{{\n  \"format\": \"mips-snapshot\",\n  \"version\": {VERSION},\n");
+        out += &format!("  \"retired\": {},\n  \"cycles\": {},\n", self.retired, self.cycles);
+        out += &format!("  \"pc\": {},\n  \"hi\": {},\n  \"lo\": {},\n", hex(self.pc), hex(self.hi as u32), hex(self.lo as u32));
+        out += &format!("  \"heap_end\": {},\n", hex(self.heap_end));
+        out += &format!("  \"exit_code\": {},\n", self.exit_code.map_or(String::from("null"), |code| code.to_string()));
+        let fields = |names: &mut dyn Iterator<Item = String>, words: &mut dyn Iterator<Item = u32>| {
+            names.zip(words).map(|(name, word)| format!("\"{name}\": {}", hex(word))).collect::<Vec<_>>().join(", ")
+        };
+        out += &format!("  \"registers\": {{{}}},\n", fields(
+            &mut arch::REGISTER_NAMES.iter().map(|name| name.to_string()),
+            &mut self.registers.iter().map(|&r| r as u32)));
+        out += &format!("  \"cp0\": {{{}}},\n", fields(&mut CP0_NAMES.iter().map(|name| name.to_string()), &mut cp0_words(&self.cp0).into_iter()));
+        out += &format!("  \"cp1\": {{{}}},\n", fields(
+            &mut (0..32).map(|n| format!("f{n}")).chain([String::from("fcsr")]),
+            &mut self.cp1.registers.into_iter().chain([self.cp1.fcsr])));
+        out += &match &self.tlb {
+            Some(tlb) => format!("  \"tlb\": [{}],\n", tlb.iter()
+                .map(|e| format!("{{\"entry_hi\": {}, \"entry_lo0\": {}, \"entry_lo1\": {}}}", hex(e.entry_hi), hex(e.entry_lo0), hex(e.entry_lo1)))
+                .collect::<Vec<_>>().join(", ")),
+            None => String::from("  \"tlb\": null,\n")
+        };
+        let regions: Vec<String> = self.regions.iter().map(|image| {
+            let pages = match &image.pages {
+                Some(pages) => format!("{{{}}}", pages.iter()
+                    .map(|(offset, page)| format!("\n      {}: \"{}\"", hex(*offset), page.iter().map(|b| format!("{b:02x}")).collect::<String>()))
+                    .collect::<Vec<_>>().join(",")),
+                None => String::from("null")
+            };
+            format!("    {{\"name\": \"{}\", \"start\": {}, \"size\": {}, \"state\": [{}], \"pages\": {pages}}}",
+                escape(&image.name), hex(image.start), image.size,
+                image.state.iter().map(|&word| hex(word)).collect::<Vec<_>>().join(", "))
+        }).collect();
+        out += &format!("  \"regions\": [\n{}\n  ],\n", regions.join(",\n"));
+        let words = |words: &[u32]| words.iter().map(|&word| hex(word)).collect::<Vec<_>>().join(", ");
+        out += &match &self.caches {
+            Some(state) => format!("  \"caches\": {{\"version\": {CACHES_VERSION}, \"state\": [{}]}},\n", words(state)),
+            None => String::from("  \"caches\": null,\n")
+        };
+        out += &match &self.branches {
+            Some((name, state)) => format!("  \"branches\": {{\"version\": {BRANCHES_VERSION}, \"predictor\": \"{}\", \"state\": [{}]}}\n}}\n",
+                escape(name), words(state)),
+            None => String::from("  \"branches\": null\n}\n")
+        };
+        out
+    }
+
+    // Write to path, as JSON if it ends in .json and in the binary format otherwise
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        match path.ends_with(".json") {
+            true => std::fs::write(path, self.json()),
+            false => std::fs::write(path, self.encode())
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("could not read {path}: {e}"))?;
+        Snapshot::decode(&bytes).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+// Give the caches and predictor back the state saved from them before a restore failed
+fn put_back(cpu: &mut CPU, caches: Option<Vec<u32>>, branches: Option<Vec<u32>>) {
+    if let (Some(hierarchy), Some(words)) = (&mut cpu.caches, caches) {
+        hierarchy.restore(&words).expect("the caches take back the state they saved");
+    }
+    if let (Some(unit), Some(words)) = (&mut cpu.branches, branches) {
+        unit.restore(&words).expect("the predictor takes back the state it saved");
+    }
+}
+
+// A count as the two words of saved state it takes, high first
+pub(crate) fn split(count: u64) -> [u32; 2] {
+    [(count >> 32) as u32, count as u32]
+}
+
+// The count split into the next two words
+pub(crate) fn join(words: &mut impl Iterator<Item = u32>) -> Option<u64> {
+    Some((words.next()? as u64) << 32 | words.next()? as u64)
+}
+
+// Words saved after their length at the front of words, moving words on past them
+pub(crate) fn section<'a>(words: &mut &'a [u32]) -> Option<&'a [u32]> {
+    let (&length, rest) = words.split_first()?;
+    let (section, rest) = rest.split_at_checked(length as usize)?;
+    *words = rest;
+    Some(section)
+}
+
+fn cp0_words(cp0: &Cp0) -> [u32; 12] {
+    [cp0.index, cp0.random, cp0.entry_lo0, cp0.entry_lo1, cp0.context, cp0.bad_vaddr,
+        cp0.count, cp0.entry_hi, cp0.compare, cp0.status, cp0.cause, cp0.epc]
+}
+
+fn cp0_from(words: [u32; 12]) -> Cp0 {
+    let [index, random, entry_lo0, entry_lo1, context, bad_vaddr, count, entry_hi, compare, status, cause, epc] = words;
+    Cp0 { index, random, entry_lo0, entry_lo1, context, bad_vaddr, count, entry_hi, compare, status, cause, epc }
+}
+
+fn extend_words(out: &mut Vec<u8>, words: &[u32]) {
+    out.extend((words.len() as u32).to_be_bytes());
+    for word in words {
+        out.extend(word.to_be_bytes());
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.chars().flat_map(|c| match c {
+        '"' | '\\' => vec!['\\', c],
+        c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32).chars().collect(),
+        c => vec![c]
+    }).collect()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.at..self.at + length).ok_or("the snapshot ends early")?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // A count, then that many words
+    fn words(&mut self) -> Result<Vec<u32>, String> {
+        (0..self.u32()?).map(|_| self.u32()).collect()
+    }
+
+    fn section_version(&mut self, what: &str, supported: u16) -> Result<(), String> {
+        match self.u16()? {
+            version if version == supported => Ok(()),
+            version => Err(format!("{what} state version {version}, this simulator reads version {supported}"))
+        }
+    }
+}
+
+// PackBits: a header n up to 127 is followed by n + 1 bytes as they are, and 257 - n
+// copies of the next byte for n from 129
+fn pack(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    let run_at = |i: usize| data[i..].iter().take(128).take_while(|&&byte| byte == data[i]).count();
+    while i < data.len() {
+        let run = run_at(i);
+        if run >= 3 {
+            out.extend([(257 - run) as u8, data[i]]);
+            i += run;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && i - start < 128 && (i == start || run_at(i) < 3) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend(&data[start..i]);
+    }
+    out
+}
+
+fn unpack(data: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(length);
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i] as usize;
+        i += 1;
+        match header {
+            0..=127 => {
+                out.extend(data.get(i..i + header + 1)?);
+                i += header + 1;
+            }
+            128 => {}
+            _ => {
+                out.extend(std::iter::repeat_n(*data.get(i)?, 257 - header));
+                i += 1;
+            }
+        }
+    }
+    (out.len() == length).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::bus::{Bus, Device, Endian, Permissions};
+    use crate::hardware::cache::Hierarchy;
+    use crate::hardware::predictor::{self, Btb};
+    use crate::hardware::timer::{self, Timer};
+
+    // Counts timer interrupts into memory while filling an array, so registers, memory,
+    // CP0 and the timer all move
+    const PROGRAM: &str = "
+        lui $s0, 0xffff
+        li $t0, 7
+        sw $t0, 0x14($s0)
+        li $t0, 7
+        sw $t0, 0x10($s0)
+        li $t0, 0x8801
+        mtc0 $t0, $12
+        li $t1, 0x2000
+        li $t2, 200
+        fill: sw $t2, 0($t1)
+        addiu $t1, $t1, 4
+        addiu $t2, $t2, -1
+        bne $t2, $zero, fill
+        halt
+        handler: lw $k1, 0x1800($zero)
+        addiu $k1, $k1, 1
+        sw $k1, 0x1800($zero)
+        li $k0, 1
+        sw $k0, 0x1c($s0)
+        eret";
+
+    fn machine() -> CPU {
+        CPU::assembled(PROGRAM, |cpu, symbols| {
+            cpu.bus.map("timer", timer::BASE, Permissions::RW, Box::new(Timer::new())).unwrap();
+            cpu.interrupts.route("timer", 1).unwrap();
+            cpu.exception_handler = Some(symbols["handler"]);
+        })
+    }
+
+    fn finish(cpu: &mut CPU) -> Snapshot {
+        while cpu.step().unwrap().is_none() {}
+        Snapshot::of(cpu)
+    }
+
+    #[test]
+    fn a_restored_machine_runs_on_identically() {
+        let mut original = machine();
+        for _ in 0..333 {
+            original.step().unwrap();
+        }
+        let saved = Snapshot::of(&original).encode();
+        let expected = finish(&mut original);
+
+        let mut restored = machine();
+        Snapshot::decode(&saved).unwrap().restore(&mut restored).unwrap();
+        assert_eq!(restored.retired, 333);
+        let continued = finish(&mut restored);
+        assert_eq!(continued, expected);
+        assert!(restored.bus.peek(0x1800, 4).unwrap() > 0, "the timer interrupted");
+    }
+
+    #[test]
+    fn snapshots_are_compact_and_checked() {
+        let mut cpu = machine();
+        let snapshot = finish(&mut cpu);
+        let bytes = snapshot.encode();
+        assert!(bytes.len() < 2048, "32 KiB of mostly zeros packs small, got {}", bytes.len());
+        assert_eq!(Snapshot::decode(&bytes).unwrap(), snapshot);
+
+        let mut damaged = bytes.clone();
+        damaged[40] ^= 1;
+        assert!(Snapshot::decode(&damaged).unwrap_err().contains("checksum"));
+        let mut newer = bytes.clone();
+        newer[9] = 3;
+        assert!(Snapshot::decode(&newer).unwrap_err().contains("version 3"));
+        assert!(Snapshot::decode(&bytes[..bytes.len() / 2]).is_err());
+
+        let mut bare = CPU::new();
+        assert_eq!(snapshot.restore(&mut bare).unwrap_err(), "the snapshot has a timer region this machine doesn't");
+        assert_eq!(bare.retired, 0);
+
+        let json = snapshot.json();
+        assert!(json.contains("\"exit_code\": null") && json.contains("\"name\": \"timer\""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+
+    #[test]
+    fn caches_and_predictors_come_back_too() {
+        let equipped = |predictor: &str, btb: Option<Btb>| {
+            let mut cpu = machine();
+            cpu.caches = Some(Hierarchy::default());
+            cpu.branches = Some(BranchUnit::new(predictor::by_name(predictor).unwrap(), btb, 2));
+            cpu
+        };
+        let mut original = equipped("gshare", Some(Btb::new(16)));
+        for _ in 0..333 {
+            original.step().unwrap();
+        }
+        let saved = Snapshot::of(&original).encode();
+        let expected = finish(&mut original);
+
+        let mut restored = equipped("gshare", Some(Btb::new(16)));
+        let snapshot = Snapshot::decode(&saved).unwrap();
+        snapshot.restore(&mut restored).unwrap();
+        assert_eq!(finish(&mut restored), expected);
+        assert_eq!(restored.caches.as_ref().unwrap().to_string(), original.caches.as_ref().unwrap().to_string());
+        assert!(restored.caches.as_ref().unwrap().stall_cycles > 0 && restored.branches.as_ref().unwrap().mispredicted() > 0);
+        assert!(snapshot.json().contains("\"predictor\": \"gshare (8 bits of history)\""));
+
+        assert_eq!(snapshot.restore(&mut machine()).unwrap_err(), "the snapshot has caches and this machine none");
+        let mut other = equipped("2bit", None);
+        assert_eq!(snapshot.restore(&mut other).unwrap_err(), "the snapshot has a gshare (8 bits of history) predictor and this machine a 2-bit (256 entries)");
+        let mut unbuffered = equipped("gshare", None);
+        let before = Snapshot::of(&unbuffered);
+        assert_eq!(snapshot.restore(&mut unbuffered).unwrap_err(), "the branch predictor in the snapshot isn't the one here");
+        assert!(Snapshot::of(&unbuffered) == before, "the caches are put back");
+    }
+
+    // A register that won't hold zero
+    struct Latch(u32);
+
+    impl Device for Latch {
+        fn size(&self) -> u32 {
+            4
+        }
+
+        fn read(&mut self, _offset: u32, _width: u32, _endian: Endian) -> Option<u32> {
+            Some(self.0)
+        }
+
+        fn write(&mut self, _offset: u32, _width: u32, value: u32, _endian: Endian) -> Option<()> {
+            self.0 = value;
+            Some(())
+        }
+
+        fn save(&self) -> Vec<u32> {
+            vec![self.0]
+        }
+
+        fn restore(&mut self, words: &[u32]) -> Option<()> {
+            let &[value] = words else { return None };
+            self.0 = (value != 0).then_some(value)?;
+            Some(())
+        }
+    }
+
+    #[test]
+    fn a_device_refusing_its_state_leaves_the_machine_as_it_was() {
+        let latched = || {
+            let mut cpu = machine();
+            cpu.bus.map("latch", 0xffff_0100, Permissions::RW, Box::new(Latch(1))).unwrap();
+            cpu
+        };
+        let mut original = latched();
+        for _ in 0..333 {
+            original.step().unwrap();
+        }
+        let mut snapshot = Snapshot::of(&original);
+        snapshot.regions.iter_mut().find(|image| image.name == "latch").unwrap().state = vec![0];
+
+        let mut cpu = latched();
+        let before = Snapshot::of(&cpu);
+        assert_eq!(snapshot.restore(&mut cpu).unwrap_err(), "latch would not take its state from the snapshot");
+        assert!(Snapshot::of(&cpu) == before, "the timer and memory are untouched");
+    }
+
+    #[test]
+    fn packbits_round_trips() {
+        let data: Vec<u8> = (0..PAGE).map(|n| if n % 300 < 200 { 0 } else { (n * 7) as u8 }).collect();
+        assert_eq!(unpack(&pack(&data), data.len()).unwrap(), data);
+        assert_eq!(pack(&[1, 1, 2, 3, 3, 3]), [2, 1, 1, 2, 254, 3]);
+    }
+}
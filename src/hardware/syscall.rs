@@ -0,0 +1,272 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use super::arch;
+use super::cpu::{CPU, MemoryAccess};
+use super::mmu::Access;
+
+/*
+ * Syscall services, numbered as in SPIM and MARS. $v0 holds the service number,
+ * arguments come in $a0 and $a1 and results go back in $v0. Services read and
+ * write through the console handles, so a test or an embedder can supply its own
+ * input and capture the output. Buffers are at virtual addresses of the caller
+ */
+
+// Service numbers
+pub const PRINT_INT: u32 = 1;
+pub const PRINT_STRING: u32 = 4;
+pub const READ_INT: u32 = 5;
+pub const READ_STRING: u32 = 8;
+pub const SBRK: u32 = 9;
+pub const EXIT: u32 = 10;
+pub const PRINT_CHAR: u32 = 11;
+pub const EXIT2: u32 = 17;
+
+const V0: usize = 2;
+const A0: usize = 4;
+const A1: usize = 5;
+
+// What the run does after a service returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Continue,
+    Exit(i32)
+}
+
+pub struct Console {
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>
+}
+
+pub type Service = Box<dyn FnMut(&mut CPU, &mut Console) -> io::Result<Outcome>>;
+
+pub struct Syscalls {
+    pub console: Console,
+    services: HashMap<u32, Service>
+}
+
+impl Syscalls {
+    // The standard services on the given handles
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        let mut syscalls = Syscalls { console: Console { input, output }, services: HashMap::new() };
+        syscalls.register(PRINT_INT, print_int);
+        syscalls.register(PRINT_STRING, print_string);
+        syscalls.register(READ_INT, read_int);
+        syscalls.register(READ_STRING, read_string);
+        syscalls.register(SBRK, sbrk);
+        syscalls.register(EXIT, |_, _| Ok(Outcome::Exit(0)));
+        syscalls.register(PRINT_CHAR, print_char);
+        syscalls.register(EXIT2, |cpu, _| Ok(Outcome::Exit(cpu.registers[A0])));
+        syscalls
+    }
+
+    // The standard services on the process's stdin and stdout
+    pub fn stdio() -> Self {
+        Self::new(Box::new(io::BufReader::new(io::stdin())), Box::new(io::stdout()))
+    }
+
+    // Add a service, or replace the one already under that number
+    pub fn register(&mut self, code: u32, service: impl FnMut(&mut CPU, &mut Console) -> io::Result<Outcome> + 'static) {
+        self.services.insert(code, Box::new(service));
+    }
+
+    // Run service code, None if nothing is registered under it
+    pub fn call(&mut self, cpu: &mut CPU, code: u32) -> Option<io::Result<Outcome>> {
+        let service = self.services.get_mut(&code)?;
+        Some(service(cpu, &mut self.console))
+    }
+}
+
+// An output handle that can still be read after it is handed to a Console
+#[derive(Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn print_int(cpu: &mut CPU, console: &mut Console) -> io::Result<Outcome> {
+    write!(console.output, "{}", cpu.registers[A0])?;
+    console.output.flush()?;
+    Ok(Outcome::Continue)
+}
+
+fn print_string(cpu: &mut CPU, console: &mut Console) -> io::Result<Outcome> {
+    let mut address = cpu.registers[A0] as u32;
+    let mut bytes = Vec::new();
+    loop {
+        let byte = cpu.physical(address, Access::Load).and_then(|physical| cpu.read_byte_from_mem(physical))
+            .ok_or_else(|| outside_memory(address))?;
+        cpu.record(MemoryAccess { address, size: 1, access: Access::Load, value: byte as u32 });
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+        // a string running off the top of the address space has no end
+        address = address.wrapping_add(1);
+        if address == 0 {
+            return Err(outside_memory(address));
+        }
+    }
+    console.output.write_all(&bytes)?;
+    console.output.flush()?;
+    Ok(Outcome::Continue)
+}
+
+fn print_char(cpu: &mut CPU, console: &mut Console) -> io::Result<Outcome> {
+    console.output.write_all(&[cpu.registers[A0] as u8])?;
+    console.output.flush()?;
+    Ok(Outcome::Continue)
+}
+
+fn read_int(cpu: &mut CPU, console: &mut Console) -> io::Result<Outcome> {
+    let line = read_line(console)?;
+    let value = line.trim().parse::<i32>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("expected an integer, read '{}'", line.trim())))?;
+    cpu.registers[V0] = value;
+    Ok(Outcome::Continue)
+}
+
+// Reads a line into the buffer at $a0, at most $a1 - 1 bytes and a terminating 0
+fn read_string(cpu: &mut CPU, console: &mut Console) -> io::Result<Outcome> {
+    let address = cpu.registers[A0] as u32;
+    let length = cpu.registers[A1];
+    if length < 1 {
+        return Ok(Outcome::Continue);
+    }
+    let line = read_line(console)?;
+    let bytes = line.bytes().take(length as usize - 1).chain([0]);
+    for (offset, byte) in bytes.enumerate() {
+        let target = address.wrapping_add(offset as u32);
+        cpu.physical(target, Access::Store)
+            .and_then(|physical| cpu.write_byte_to_mem(physical, byte))
+            .ok_or_else(|| outside_memory(target))?;
+        cpu.record(MemoryAccess { address: target, size: 1, access: Access::Store, value: byte as u32 });
+    }
+    Ok(Outcome::Continue)
+}
+
+// Grows the heap by $a0 bytes, word aligned, and returns the old break in $v0
+fn sbrk(cpu: &mut CPU, _: &mut Console) -> io::Result<Outcome> {
+    let amount = cpu.registers[A0];
+    let grown = (amount as u32).wrapping_add(3) & !3;
+    let end = cpu.heap_end.wrapping_add(grown);
+    if amount < 0 || end > arch::END_MEM || end < cpu.heap_end {
+        return Err(io::Error::new(io::ErrorKind::OutOfMemory, format!("sbrk of {amount} bytes does not fit in the heap")));
+    }
+    cpu.registers[V0] = cpu.heap_end as i32;
+    cpu.heap_end = end;
+    Ok(Outcome::Continue)
+}
+
+fn read_line(console: &mut Console) -> io::Result<String> {
+    let mut line = String::new();
+    if console.input.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input"));
+    }
+    Ok(line)
+}
+
+fn outside_memory(address: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("address {address:#010x} is not mapped to memory"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::{Computer, Fault};
+
+    fn run(source: &str, input: &str) -> (i32, String) {
+        let output = Capture::default();
+        let mut cpu = CPU::assembled(source, |cpu, _| {
+            cpu.syscalls = Some(Syscalls::new(Box::new(io::Cursor::new(input.to_string())), Box::new(output.clone())));
+        });
+        let code = cpu.start().expect("test program runs");
+        (code, output.text())
+    }
+
+    #[test]
+    fn services_use_the_console() {
+        let source = r#"
+            .data
+            prompt: .asciiz "n? "
+            buffer: .space 8
+            .text
+            la $a0, prompt
+            li $v0, 4
+            syscall
+            li $v0, 5
+            syscall
+            addiu $a0, $v0, 1
+            li $v0, 1
+            syscall
+            li $a0, 10
+            li $v0, 11
+            syscall
+            la $a0, buffer
+            li $a1, 4
+            li $v0, 8
+            syscall
+            li $v0, 4
+            syscall
+            li $a0, 3
+            li $v0, 17
+            syscall
+            halt
+        "#;
+        assert_eq!(run(source, "41\nhello\n"), (3, String::from("n? 42\nhel")));
+    }
+
+    #[test]
+    fn sbrk_hands_out_the_heap_in_order() {
+        let source = r#"
+            li $a0, 5
+            li $v0, 9
+            syscall
+            move $s0, $v0
+            li $a0, 4
+            li $v0, 9
+            syscall
+            subu $a0, $v0, $s0
+            li $v0, 1
+            syscall
+            li $v0, 10
+            syscall
+        "#;
+        assert_eq!(run(source, ""), (0, String::from("8")));
+    }
+
+    #[test]
+    fn a_string_running_off_the_top_of_memory_is_an_error() {
+        let source = "
+            li $t0, 0xfffffffc
+            li $t1, 0x41424344
+            sw $t1, 0($t0)
+            move $a0, $t0
+            li $v0, 4
+            syscall
+            halt";
+        let mut cpu = CPU::assembled(source, |cpu, _| {
+            cpu.bus.map_ram("top", 0xFFFF_FFF0, 16).unwrap();
+            cpu.syscalls = Some(Syscalls::new(Box::new(io::empty()), Box::new(Capture::default())));
+        });
+        match cpu.start() {
+            Err(Fault::Syscall { service: 4, error, .. }) => assert_eq!(error.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("printing ended with {other:?}")
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use super::bus::{Device, Endian};
+
+/*
+ * Memory-mapped interval timer, four word registers. Writing the period loads the counter,
+ * which counts down once a cycle while the timer is enabled. Reaching 0 sets expired, and
+ * the timer either reloads the period or stops. Its interrupt line is up while expired is
+ * set and interrupts are enabled, and stays up until the program writes 1 to expired
+ */
+
+pub const BASE: u32 = 0xFFFF_0010;     // after the MARS UART
+
+// Register offsets
+pub const CONTROL: u32 = 0x0;
+pub const PERIOD: u32 = 0x4;
+pub const COUNTER: u32 = 0x8;
+pub const STATUS: u32 = 0xC;
+
+// Control bits
+pub const CONTROL_ENABLE: u32 = 1 << 0;
+pub const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+pub const CONTROL_PERIODIC: u32 = 1 << 2;      // reload the period on expiry, stop if clear
+
+// Status bits
+pub const STATUS_EXPIRED: u32 = 1 << 0;
+
+const SIZE: u32 = 0x10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timer {
+    pub control: u32,
+    pub period: u32,
+    pub counter: u32,
+    pub status: u32
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> u32 {
+        SIZE
+    }
+
+    fn read(&mut self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        self.peek(offset, width, endian)
+    }
+
+    // Registers are words, narrower accesses go unanswered
+    fn write(&mut self, offset: u32, width: u32, value: u32, _endian: Endian) -> Option<()> {
+        if width != 4 {
+            return None;
+        }
+        match offset {
+            CONTROL => self.control = value & (CONTROL_ENABLE | CONTROL_INTERRUPT_ENABLE | CONTROL_PERIODIC),
+            PERIOD => (self.period, self.counter) = (value, value),
+            COUNTER => self.counter = value,
+            STATUS => self.status &= !(value & STATUS_EXPIRED),
+            _ => return None
+        }
+        Some(())
+    }
+
+    fn peek(&self, offset: u32, width: u32, _endian: Endian) -> Option<u32> {
+        if width != 4 {
+            return None;
+        }
+        match offset {
+            CONTROL => Some(self.control),
+            PERIOD => Some(self.period),
+            COUNTER => Some(self.counter),
+            STATUS => Some(self.status),
+            _ => None
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.control & CONTROL_ENABLE == 0 || self.counter == 0 {
+            return;
+        }
+        self.counter -= 1;
+        if self.counter == 0 {
+            self.status |= STATUS_EXPIRED;
+            if self.control & CONTROL_PERIODIC != 0 {
+                self.counter = self.period;
+            } else {
+                self.control &= !CONTROL_ENABLE;
+            }
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.status & STATUS_EXPIRED != 0 && self.control & CONTROL_INTERRUPT_ENABLE != 0
+    }
+
+    fn save(&self) -> Vec<u32> {
+        vec![self.control, self.period, self.counter, self.status]
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let &[control, period, counter, status] = words else { return None };
+        *self = Timer { control, period, counter, status };
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::bus::Permissions;
+    use crate::hardware::cpu::CPU;
+    use crate::hardware::interrupt;
+
+    // A periodic timer on HW1, masked until the program has counted to 50, then taken and
+    // acknowledged every 10 cycles until it has been 4 times
+    const KERNEL: &str = "
+        lui $s0, 0xffff
+        li $t0, 10
+        sw $t0, 0x14($s0)
+        li $t0, 7
+        sw $t0, 0x10($s0)
+        li $t0, 0x8001
+        mtc0 $t0, $12
+        li $t1, 50
+        masked: addiu $s2, $s2, 1
+        bne $s2, $t1, masked
+        li $t0, 0x8801
+        mtc0 $t0, $12
+        unmasked: li $t1, 4
+        spin: bne $s1, $t1, spin
+        halt
+        handler: addiu $s1, $s1, 1
+        li $k0, 1
+        sw $k0, 0x1c($s0)
+        eret";
+
+    #[test]
+    fn routed_timer_interrupts_respect_the_mask() {
+        let mut cpu = CPU::assembled(KERNEL, |cpu, symbols| {
+            cpu.bus.map("timer", BASE, Permissions::RW, Box::new(Timer::new())).unwrap();
+            cpu.interrupts.route("timer", 1).unwrap();
+            cpu.exception_handler = Some(symbols["handler"]);
+        });
+        let unmasked = cpu.symbols["unmasked"];
+        assert_eq!(cpu.start().unwrap(), 0);
+
+        // pending since long before, it is taken as soon as IM3 is set, then on every period
+        let log = &cpu.interrupts.log;
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[0].epc, unmasked);
+        assert!(log.iter().all(|taken| taken.pending == interrupt::ip_bit(1)));
+        assert!(log[1..].windows(2).all(|pair| pair[1].cycle - pair[0].cycle == 10));
+        assert!(cpu.interrupts.route("timer", 6).is_err());
+    }
+}
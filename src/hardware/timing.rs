@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use super::cpu::Traced;
+use super::interrupt::Taken;
+use super::isa::{self, Location};
+use super::pipeline::PipelineConfig;
+use crate::software::disassemble;
+
+/*
+ * Pipeline timing chart of an executed instruction stream, the one CPU::trace records.
+ * The stream is replayed through an idealised IF/ID/EX/MEM/WB pipeline: every stage takes
+ * one cycle and branches are always predicted right, so the only hazards are data hazards,
+ * resolved by stalling in ID until forwarding or the register file can supply the operand.
+ * When an operand is ready is PipelineConfig::operand_ready, the rule the pipeline runs by.
+ * Interrupts the trace took are noted on the first instruction of the handler, like a
+ * correctly predicted branch they cost no cycles here
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    LoadUse,    // waiting on a load, whose result is only there after MEM
+    Data        // waiting on any other result the forwarding paths cannot supply yet
+}
+
+// Why an instruction waited in ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub cause: Cause,
+    pub location: Location,     // the operand it waited for
+    pub producer: usize         // row of the instruction writing it
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub address: u32,
+    pub word: u32,
+    pub text: String,
+    pub fetch: u64,             // cycle it is fetched in, from 1
+    pub decode: u64,            // first cycle in ID
+    pub execute: u64,           // cycle in EX, MEM and WB follow
+    pub stall: Option<Stall>,
+    pub interrupt: Option<Taken>    // taken just before this instruction, the handler's first
+}
+
+impl Row {
+    pub fn stall_cycles(&self) -> u64 {
+        self.execute - self.decode - 1
+    }
+
+    // The stage in a cycle, "st" while held in IF or ID
+    pub fn cell(&self, cycle: u64) -> Option<&'static str> {
+        match cycle {
+            c if c < self.fetch => None,
+            c if c == self.fetch => Some("IF"),
+            c if c < self.decode => Some("st"),
+            c if c == self.decode => Some("ID"),
+            c if c < self.execute => Some("st"),
+            c if c == self.execute => Some("EX"),
+            c if c == self.execute + 1 => Some("MEM"),
+            c if c == self.execute + 2 => Some("WB"),
+            _ => None
+        }
+    }
+}
+
+pub struct Chart {
+    pub rows: Vec<Row>
+}
+
+impl Chart {
+    // Schedule a trace, each instruction as early as its operands allow
+    pub fn new(trace: &[Traced], config: PipelineConfig, symbols: &HashMap<String, u32>) -> Self {
+        let mut rows: Vec<Row> = Vec::with_capacity(trace.len());
+        let mut writers: HashMap<Location, usize> = HashMap::new();
+        let mut interrupt = None;
+
+        for &traced in trace {
+            let (address, word) = match traced {
+                Traced::Instruction { address, word } => (address, word),
+                Traced::Interrupt(taken) => {
+                    interrupt = Some(taken);
+                    continue;
+                }
+            };
+            let flow = isa::dataflow(word);
+            // an instruction enters IF as the one ahead enters ID, and ID as it leaves
+            let (fetch, decode) = match rows.last() {
+                Some(previous) => (previous.decode, (previous.decode + 1).max(previous.execute)),
+                None => (1, 2)
+            };
+
+            // operands are read in EX, or in the last cycle in ID by what resolves there
+            let in_id = config.resolves_in_id(&flow);
+            let mut execute = decode + 1;
+            let mut stall = None;
+            while let Some((location, producer)) = flow.reads.iter()
+                .filter_map(|location| writers.get(location).map(|&producer| (*location, producer)))
+                .find(|&(_, producer)| {
+                    let distance = execute - u64::from(in_id) - rows[producer].execute;
+                    !config.operand_ready(distance, is_load(&rows[producer]), in_id)
+                })
+            {
+                execute += 1;
+                let cause = if is_load(&rows[producer]) { Cause::LoadUse } else { Cause::Data };
+                stall = Some(Stall { cause, location, producer });
+            }
+
+            for location in &flow.writes {
+                writers.insert(*location, rows.len());
+            }
+            let text = disassemble::disassemble(word, address, symbols);
+            rows.push(Row { address, word, text, fetch, decode, execute, stall, interrupt: interrupt.take() });
+        }
+        Chart { rows }
+    }
+
+    // cycle the last instruction leaves WB
+    pub fn cycles(&self) -> u64 {
+        self.rows.last().map_or(0, |row| row.execute + 2)
+    }
+
+    pub fn stall_cycles(&self) -> u64 {
+        self.rows.iter().map(Row::stall_cycles).sum()
+    }
+
+    // The interrupt a row starts the handler of and why it stalled, in words
+    pub fn annotation(&self, row: &Row) -> Option<String> {
+        let interrupt = row.interrupt.map(|taken| format!("interrupt{} from {:#010x}", taken.lines(), taken.epc));
+        let stall = row.stall.map(|stall| {
+            let producer = &self.rows[stall.producer];
+            let cause = match stall.cause {
+                Cause::LoadUse => "load-use",
+                Cause::Data => "data"
+            };
+            format!("{} cycle{} {cause} stall on {} from {:#010x} {}",
+                row.stall_cycles(), if row.stall_cycles() == 1 { "" } else { "s" },
+                stall.location, producer.address, producer.text)
+        });
+        match (interrupt, stall) {
+            (Some(interrupt), Some(stall)) => Some(format!("{interrupt}; {stall}")),
+            (interrupt, stall) => interrupt.or(stall)
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let width = self.rows.iter().map(|row| row.text.len()).max().unwrap_or(0);
+        let mut out = format!("{:<1$}", "", width + 14);
+        for cycle in 1..=self.cycles() {
+            write!(out, "{cycle:<4}").unwrap();
+        }
+        out = out.trim_end().to_string();
+        out.push('\n');
+
+        for row in &self.rows {
+            let mut line = format!("{:#010x}  {:<2$}  ", row.address, row.text, width);
+            for cycle in 1..=self.cycles() {
+                write!(line, "{:<4}", row.cell(cycle).unwrap_or("")).unwrap();
+            }
+            if let Some(annotation) = self.annotation(row) {
+                write!(line, "  {annotation}").unwrap();
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        writeln!(out, "{} instructions, {} cycles, {} stalled", self.rows.len(), self.cycles(), self.stall_cycles()).unwrap();
+        out
+    }
+
+    pub fn csv(&self) -> String {
+        let mut out = String::from("address,instruction");
+        for cycle in 1..=self.cycles() {
+            write!(out, ",{cycle}").unwrap();
+        }
+        out.push_str(",stall\n");
+
+        for row in &self.rows {
+            write!(out, "{:#010x},{}", row.address, csv_field(&row.text)).unwrap();
+            for cycle in 1..=self.cycles() {
+                write!(out, ",{}", row.cell(cycle).unwrap_or("")).unwrap();
+            }
+            writeln!(out, ",{}", csv_field(&self.annotation(row).unwrap_or_default())).unwrap();
+        }
+        out
+    }
+
+    // A page with no outside references, stall cells carry their cause as a tooltip
+    pub fn html(&self) -> String {
+        let mut out = String::from(concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Pipeline chart</title>\n<style>\n",
+            "body { font-family: sans-serif; }\n",
+            "table { border-collapse: collapse; font-size: 13px; }\n",
+            "th, td { border: 1px solid #ccc; padding: 2px 6px; text-align: center; }\n",
+            "td.instruction { font-family: monospace; text-align: left; white-space: pre; }\n",
+            "td.note { text-align: left; color: #a00; }\n",
+            "td.IF { background: #dbe9f6; } td.ID { background: #dcf0dc; } td.EX { background: #fbe7c6; }\n",
+            "td.MEM { background: #eadcf4; } td.WB { background: #f6dada; } td.st { background: #eee; color: #a00; }\n",
+            "</style>\n</head>\n<body>\n"));
+        writeln!(out, "<p>{} instructions, {} cycles, {} stalled</p>", self.rows.len(), self.cycles(), self.stall_cycles()).unwrap();
+        out.push_str("<table>\n<tr><th>address</th><th>instruction</th>");
+        for cycle in 1..=self.cycles() {
+            write!(out, "<th>{cycle}</th>").unwrap();
+        }
+        out.push_str("<th>stall</th></tr>\n");
+
+        for row in &self.rows {
+            let annotation = self.annotation(row).map(|a| escape(&a)).unwrap_or_default();
+            write!(out, "<tr><td class=\"instruction\">{:#010x}</td><td class=\"instruction\">{}</td>", row.address, escape(&row.text)).unwrap();
+            for cycle in 1..=self.cycles() {
+                match row.cell(cycle) {
+                    Some("st") if cycle > row.decode => write!(out, "<td class=\"st\" title=\"{annotation}\">st</td>").unwrap(),
+                    Some(stage) => write!(out, "<td class=\"{stage}\">{stage}</td>").unwrap(),
+                    None => out.push_str("<td></td>")
+                }
+            }
+            writeln!(out, "<td class=\"note\">{annotation}</td></tr>").unwrap();
+        }
+        out.push_str("</table>\n</body>\n</html>\n");
+        out
+    }
+}
+
+fn is_load(row: &Row) -> bool {
+    isa::dataflow(row.word).load
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::cpu::CPU;
+    use crate::hardware::bus::Permissions;
+    use crate::hardware::pipeline::{Forwarding, Pipeline};
+    use crate::hardware::timer::{self, Timer};
+
+    fn chart(source: &str, forwarding: Forwarding) -> Chart {
+        let mut cpu = CPU::assembled(source, |cpu, _| cpu.trace = Some(Vec::new()));
+        cpu.start().expect("test program runs");
+        Chart::new(&cpu.trace.take().unwrap(), PipelineConfig { forwarding, ..PipelineConfig::default() }, &cpu.symbols)
+    }
+
+    const SOURCE: &str = "lw $t0, 0($zero)\naddu $t1, $t0, $t0\nsubu $t2, $t1, $t0\nhalt";
+
+    #[test]
+    fn stalls_follow_the_forwarding_paths() {
+        let forwarded = chart(SOURCE, Forwarding { ex_mem: true, mem_wb: true });
+        let stalls: Vec<u64> = forwarded.rows.iter().map(Row::stall_cycles).collect();
+        assert_eq!((stalls, forwarded.cycles()), (vec![0, 1, 0], 8));
+        assert_eq!(forwarded.rows[1].stall, Some(Stall { cause: Cause::LoadUse, location: Location::Gpr(8), producer: 0 }));
+
+        let unforwarded = chart(SOURCE, Forwarding { ex_mem: false, mem_wb: false });
+        let stalls: Vec<u64> = unforwarded.rows.iter().map(Row::stall_cycles).collect();
+        assert_eq!((stalls, unforwarded.cycles()), (vec![0, 2, 2], 11));
+        assert_eq!(unforwarded.rows[2].stall.map(|stall| stall.cause), Some(Cause::Data));
+    }
+
+    #[test]
+    fn stalls_and_cycles_agree_with_the_pipeline() {
+        // results two instructions on, which only the MEM/WB path reaches in time
+        let source = "lw $t0, 0($zero)\naddiu $t1, $t0, 1\nnop\naddu $t2, $t1, $t0\nsll $t3, $t2, 2\nnop\nsubu $t4, $t3, $t2\nhalt";
+        for ex_mem in [false, true] {
+            for mem_wb in [false, true] {
+                let forwarding = Forwarding { ex_mem, mem_wb };
+                let chart = chart(source, forwarding);
+                let mut pipeline = Pipeline::new(CPU::assembled(source, |_, _| ()), PipelineConfig { forwarding, ..PipelineConfig::default() });
+                pipeline.start().expect("test program runs");
+                let stats = pipeline.stats;
+                assert_eq!((chart.cycles(), chart.stall_cycles()), (stats.cycles, stats.load_use_stalls + stats.data_stalls), "{forwarding:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn renders_text_csv_and_html() {
+        let chart = chart(SOURCE, Forwarding { ex_mem: true, mem_wb: true });
+        let text = chart.text();
+        let line = text.lines().nth(2).unwrap();
+        assert!(line.starts_with("0x00000044  addu $t1, $t0, $t0      IF  ID  st  EX  MEM WB"));
+        assert!(line.ends_with("1 cycle load-use stall on $t0 from 0x00000040 lw $t0, 0($zero)"));
+
+        let csv = chart.csv();
+        assert!(csv.starts_with("address,instruction,1,2,3,4,5,6,7,8,stall\n"));
+        assert!(csv.lines().nth(2).unwrap().starts_with("0x00000044,\"addu $t1, $t0, $t0\",,IF,ID,st,EX,MEM,WB,"));
+
+        let html = chart.html();
+        assert!(html.contains("<td class=\"st\" title=\"1 cycle load-use stall"));
+        assert!(!html.contains("src=") && !html.contains("href="));
+    }
+
+    #[test]
+    fn taken_interrupts_are_noted_on_the_handler() {
+        // a one shot timer on HW1, acknowledged by the handler
+        let source = "
+            lui $s0, 0xffff
+            li $t0, 10
+            sw $t0, 0x14($s0)
+            li $t0, 3
+            sw $t0, 0x10($s0)
+            li $t0, 0x801
+            mtc0 $t0, $12
+            spin: beq $s1, $zero, spin
+            halt
+            handler: addiu $s1, $s1, 1
+            li $k0, 1
+            sw $k0, 0x1c($s0)
+            eret";
+        let mut cpu = CPU::assembled(source, |cpu, symbols| {
+            cpu.trace = Some(Vec::new());
+            cpu.exception_handler = Some(symbols["handler"]);
+            cpu.bus.map("timer", timer::BASE, Permissions::RW, Box::new(Timer::new())).unwrap();
+            cpu.interrupts.route("timer", 1).unwrap();
+        });
+        cpu.start().expect("test program runs");
+        let trace = cpu.trace.take().unwrap();
+        assert_eq!(trace.iter().filter(|traced| matches!(traced, Traced::Interrupt(_))).count(), 1);
+
+        let chart = Chart::new(&trace, PipelineConfig::default(), &cpu.symbols);
+        let noted: Vec<&Row> = chart.rows.iter().filter(|row| row.interrupt.is_some()).collect();
+        assert_eq!(noted.len(), 1);
+        assert_eq!(noted[0].text, "addiu $s1, $s1, 1");
+        assert_eq!(chart.annotation(noted[0]), Some(String::from("interrupt IP3 from 0x0000005c")));
+        assert!(chart.text().lines().any(|line| line.contains("addiu $s1, $s1, 1") && line.ends_with("interrupt IP3 from 0x0000005c")));
+    }
+}
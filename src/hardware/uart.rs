@@ -0,0 +1,477 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use super::bus::{Device, Endian};
+use super::syscall::Capture;
+
+/*
+ * Memory-mapped console, laid out as the MARS keyboard and display: four word registers,
+ * receiver control and data then transmitter control and data. Bit 0 of a control register
+ * is ready, bit 1 enables the interrupt line while ready is set. Reading receiver data takes
+ * the byte and clears ready, writing transmitter data sends the low byte if the transmitter
+ * is ready. Bytes come from and go to a backend: the host terminal, streams or files.
+ *
+ * The same receiver and transmitter can instead show the byte registers of a 16550: RBR and
+ * THR at 0, IER at 1 enabling the received data and transmitter empty interrupts, IIR at 2
+ * saying which is pending, and LSR at 5 with data ready and transmitter empty. The line,
+ * modem, divisor and scratch registers read as 0 and ignore writes
+ */
+
+pub const MARS_BASE: u32 = 0xFFFF_0000;
+
+// Register offsets
+pub const RECEIVER_CONTROL: u32 = 0x0;
+pub const RECEIVER_DATA: u32 = 0x4;
+pub const TRANSMITTER_CONTROL: u32 = 0x8;
+pub const TRANSMITTER_DATA: u32 = 0xC;
+
+// Control bits
+pub const CONTROL_READY: u32 = 1 << 0;
+pub const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+// 16550 register offsets
+pub const RBR: u32 = 0x0;
+pub const THR: u32 = 0x0;
+pub const IER: u32 = 0x1;
+pub const IIR: u32 = 0x2;
+pub const LSR: u32 = 0x5;
+
+// 16550 register bits
+pub const IER_RECEIVED: u32 = 1 << 0;
+pub const IER_TRANSMITTER_EMPTY: u32 = 1 << 1;
+pub const IIR_NONE: u32 = 0x1;
+pub const IIR_TRANSMITTER_EMPTY: u32 = 0x2;
+pub const IIR_RECEIVED: u32 = 0x4;
+pub const LSR_DATA_READY: u32 = 1 << 0;
+pub const LSR_TRANSMITTER_EMPTY: u32 = 1 << 5;
+pub const LSR_IDLE: u32 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Mars,       // four word registers
+    Ns16550     // eight byte registers
+}
+
+impl Layout {
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "mars" => Some(Layout::Mars),
+            "16550" => Some(Layout::Ns16550),
+            _ => None
+        }
+    }
+
+    fn size(self) -> u32 {
+        match self {
+            Layout::Mars => 0x10,
+            Layout::Ns16550 => 0x8
+        }
+    }
+
+    // registers reading which polls the backend and takes the received byte
+    fn receiver(self) -> (u32, u32) {
+        match self {
+            Layout::Mars => (RECEIVER_CONTROL, RECEIVER_DATA),
+            Layout::Ns16550 => (LSR, RBR)
+        }
+    }
+}
+
+// Where received bytes come from and transmitted ones go
+pub trait Backend {
+    // next byte if one has arrived, without waiting for one
+    fn receive(&mut self) -> Option<u8>;
+    fn transmit(&mut self, byte: u8);
+
+    // push out anything transmitted but still buffered, as the run ends
+    fn flush(&mut self) {}
+}
+
+// The host terminal. stdin is read on a thread of its own so polling never blocks the run
+pub struct Terminal {
+    input: Receiver<u8>
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Terminal { input }
+    }
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Terminal {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+}
+
+// Input read from one stream and output written to another, nothing more to receive
+// once the input ends
+pub struct Streams {
+    pub input: Box<dyn Read>,
+    pub output: Box<dyn Write>
+}
+
+impl Streams {
+    // Input from memory and output to a Capture the caller keeps
+    pub fn buffer(input: &[u8]) -> (Self, Capture) {
+        let output = Capture::default();
+        let streams = Streams { input: Box::new(VecDeque::from(input.to_vec())), output: Box::new(output.clone()) };
+        (streams, output)
+    }
+
+    // Input from a file if one is given and output to a file, created or truncated
+    pub fn files(input: Option<&str>, output: &str) -> io::Result<Self> {
+        let input: Box<dyn Read> = match input {
+            Some(path) => Box::new(io::BufReader::new(File::open(path)?)),
+            None => Box::new(io::empty())
+        };
+        Ok(Streams { input, output: Box::new(BufWriter::new(File::create(output)?)) })
+    }
+}
+
+impl Backend for Streams {
+    fn receive(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let _ = self.output.write_all(&[byte]);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+pub struct Uart {
+    pub backend: Box<dyn Backend>,
+    pub transmit_delay: u32,    // cycles the transmitter stays busy after a byte, 0 for never
+    pub layout: Layout,
+    receiver_control: u32,
+    receiver_data: u32,
+    transmitter_control: u32,
+    transmitter_data: u32,
+    busy: u32                   // cycles until the transmitter is ready again
+}
+
+impl Uart {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Uart {
+            backend,
+            transmit_delay: 0,
+            layout: Layout::Mars,
+            receiver_control: 0,
+            receiver_data: 0,
+            transmitter_control: CONTROL_READY,
+            transmitter_data: 0,
+            busy: 0
+        }
+    }
+
+    fn register(&self, offset: u32) -> u32 {
+        match offset {
+            RECEIVER_CONTROL => self.receiver_control,
+            RECEIVER_DATA => self.receiver_data,
+            TRANSMITTER_CONTROL => self.transmitter_control,
+            _ => self.transmitter_data
+        }
+    }
+
+    // A 16550 register, made out of the MARS ones
+    fn byte_register(&self, offset: u32) -> u32 {
+        let ready = |control: u32| control & CONTROL_READY != 0;
+        let enabled = |control: u32| control & CONTROL_INTERRUPT_ENABLE != 0;
+        let bits = |set: bool, bits: u32| if set { bits } else { 0 };
+        match offset {
+            RBR => self.receiver_data,
+            IER => bits(enabled(self.receiver_control), IER_RECEIVED) | bits(enabled(self.transmitter_control), IER_TRANSMITTER_EMPTY),
+            IIR if ready(self.receiver_control) && enabled(self.receiver_control) => IIR_RECEIVED,
+            IIR if ready(self.transmitter_control) && enabled(self.transmitter_control) => IIR_TRANSMITTER_EMPTY,
+            IIR => IIR_NONE,
+            LSR => bits(ready(self.receiver_control), LSR_DATA_READY) | bits(ready(self.transmitter_control), LSR_TRANSMITTER_EMPTY | LSR_IDLE),
+            _ => 0
+        }
+    }
+
+    fn write_byte_register(&mut self, offset: u32, value: u32) {
+        let enable = |control: u32, bit: u32| (control & CONTROL_READY) | if value & bit != 0 { CONTROL_INTERRUPT_ENABLE } else { 0 };
+        match offset {
+            THR => self.send(value),
+            IER => {
+                self.receiver_control = enable(self.receiver_control, IER_RECEIVED);
+                self.transmitter_control = enable(self.transmitter_control, IER_TRANSMITTER_EMPTY);
+            }
+            _ => ()
+        }
+    }
+
+    // whether an access covers a register
+    fn touches(&self, offset: u32, width: u32, register: u32) -> bool {
+        match self.layout {
+            Layout::Mars => offset & !3 == register,
+            Layout::Ns16550 => (offset..offset + width).contains(&register)
+        }
+    }
+
+    // Hand the low byte to the backend, a busy transmitter drops it
+    fn send(&mut self, value: u32) {
+        if self.transmitter_control & CONTROL_READY == 0 {
+            return;
+        }
+        self.transmitter_data = value & 0xFF;
+        self.backend.transmit(value as u8);
+        if self.transmit_delay > 0 {
+            self.busy = self.transmit_delay;
+            self.transmitter_control &= !CONTROL_READY;
+        }
+    }
+
+    // Take a byte from the backend if the last one has been read
+    fn poll(&mut self) {
+        if self.receiver_control & CONTROL_READY == 0 {
+            if let Some(byte) = self.backend.receive() {
+                self.receiver_data = byte as u32;
+                self.receiver_control |= CONTROL_READY;
+            }
+        }
+    }
+}
+
+impl Device for Uart {
+    fn size(&self) -> u32 {
+        self.layout.size()
+    }
+
+    fn read(&mut self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        let (status, data) = self.layout.receiver();
+        if self.touches(offset, width, status) {
+            self.poll();
+        }
+        let value = self.peek(offset, width, endian)?;
+        if self.touches(offset, width, data) {
+            self.receiver_control &= !CONTROL_READY;
+        }
+        Some(value)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32, endian: Endian) -> Option<()> {
+        if offset >= self.size() {
+            return None;
+        }
+        if self.layout == Layout::Ns16550 {
+            // each byte of a wider store goes to its own register
+            for (i, byte) in endian.to_bytes(value, width).into_iter().enumerate() {
+                self.write_byte_register(offset + i as u32, byte as u32);
+            }
+            return Some(());
+        }
+        // a narrow store lands in its bytes of the register, the rest reads as 0
+        let mut bytes = [0; 4];
+        let lane = (offset & 3) as usize;
+        bytes[lane..lane + width as usize].copy_from_slice(&endian.to_bytes(value, width));
+        let value = endian.from_bytes(&bytes);
+
+        match offset & !3 {
+            RECEIVER_CONTROL => self.receiver_control = (self.receiver_control & CONTROL_READY) | (value & CONTROL_INTERRUPT_ENABLE),
+            TRANSMITTER_CONTROL => self.transmitter_control = (self.transmitter_control & CONTROL_READY) | (value & CONTROL_INTERRUPT_ENABLE),
+            TRANSMITTER_DATA => self.send(value),
+            // receiver data is read only
+            _ => ()
+        }
+        Some(())
+    }
+
+    fn peek(&self, offset: u32, width: u32, endian: Endian) -> Option<u32> {
+        if offset >= self.size() {
+            return None;
+        }
+        if self.layout == Layout::Ns16550 {
+            let bytes: Vec<u8> = (offset..offset + width).map(|register| self.byte_register(register) as u8).collect();
+            return Some(endian.from_bytes(&bytes));
+        }
+        let lane = (offset & 3) as usize;
+        let bytes = endian.to_bytes(self.register(offset & !3), 4);
+        Some(endian.from_bytes(&bytes[lane..lane + width as usize]))
+    }
+
+    fn tick(&mut self) {
+        self.poll();
+        if self.busy > 0 {
+            self.busy -= 1;
+            if self.busy == 0 {
+                self.transmitter_control |= CONTROL_READY;
+            }
+        }
+    }
+
+    fn external(&self) -> bool {
+        true
+    }
+
+    fn interrupt(&self) -> bool {
+        let pending = |control| control & (CONTROL_READY | CONTROL_INTERRUPT_ENABLE) == CONTROL_READY | CONTROL_INTERRUPT_ENABLE;
+        pending(self.receiver_control) || pending(self.transmitter_control)
+    }
+
+    // the registers and how long the transmitter stays busy, not the backend
+    fn save(&self) -> Vec<u32> {
+        vec![self.receiver_control, self.receiver_data, self.transmitter_control, self.transmitter_data, self.busy]
+    }
+
+    fn restore(&mut self, words: &[u32]) -> Option<()> {
+        let &[receiver_control, receiver_data, transmitter_control, transmitter_data, busy] = words else { return None };
+        (self.receiver_control, self.receiver_data) = (receiver_control, receiver_data);
+        (self.transmitter_control, self.transmitter_data, self.busy) = (transmitter_control, transmitter_data, busy);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::arch::Computer;
+    use crate::hardware::bus::{Bus, Permissions};
+    use crate::hardware::cpu::CPU;
+
+    // Echo what comes in until a newline, upper cased, polling both sides
+    const ECHO: &str = "
+        lui $s0, 0xffff
+        receive: lw $t0, 0($s0)
+        andi $t0, $t0, 1
+        beq $t0, $zero, receive
+        lw $t1, 4($s0)
+        transmit: lw $t0, 8($s0)
+        andi $t0, $t0, 1
+        beq $t0, $zero, transmit
+        li $t2, 10
+        beq $t1, $t2, done
+        addiu $t1, $t1, -32
+        sb $t1, 15($s0)
+        j receive
+        done: sw $t1, 12($s0)
+        halt";
+
+    #[test]
+    fn programs_poll_the_uart_like_mars_mmio() {
+        let (streams, output) = Streams::buffer(b"mips\nignored");
+        let mut uart = Uart::new(Box::new(streams));
+        uart.transmit_delay = 5;
+        let mut cpu = CPU::assembled(ECHO, |cpu, _| cpu.bus.map("uart", MARS_BASE, Permissions::RW, Box::new(uart)).unwrap());
+
+        assert_eq!(cpu.start().unwrap(), 0);
+        assert_eq!(output.text(), "MIPS\n");
+        // the next byte is waiting, the rest of the input is left alone
+        assert_eq!(cpu.read_word_from_mem(MARS_BASE + RECEIVER_DATA), Some(b'i' as u32));
+    }
+
+    #[test]
+    fn ready_registers_drive_the_interrupt_line() {
+        let (streams, output) = Streams::buffer(b"a");
+        let mut uart = Uart::new(Box::new(streams));
+        assert!(!uart.interrupt());
+        uart.write(RECEIVER_CONTROL, 4, CONTROL_INTERRUPT_ENABLE | CONTROL_READY, Endian::Big);
+        assert_eq!(uart.peek(RECEIVER_CONTROL, 4, Endian::Big), Some(CONTROL_INTERRUPT_ENABLE));
+
+        uart.tick();
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(RECEIVER_DATA + 3, 1, Endian::Big), Some(b'a' as u32));
+        assert!(!uart.interrupt());
+
+        uart.write(TRANSMITTER_DATA, 4, b'z' as u32, Endian::Little);
+        uart.write(TRANSMITTER_CONTROL, 4, CONTROL_INTERRUPT_ENABLE, Endian::Big);
+        assert!(uart.interrupt());
+        assert_eq!(output.text(), "z");
+    }
+
+    // ECHO again, on the 16550's byte registers
+    const ECHO_16550: &str = "
+        lui $s0, 0xffff
+        receive: lbu $t0, 5($s0)
+        andi $t0, $t0, 0x01
+        beq $t0, $zero, receive
+        lbu $t1, 0($s0)
+        transmit: lbu $t0, 5($s0)
+        andi $t0, $t0, 0x20
+        beq $t0, $zero, transmit
+        li $t2, 10
+        beq $t1, $t2, done
+        addiu $t1, $t1, -32
+        sb $t1, 0($s0)
+        j receive
+        done: sb $t1, 0($s0)
+        halt";
+
+    #[test]
+    fn programs_poll_the_16550_layout() {
+        let (streams, output) = Streams::buffer(b"uart\nignored");
+        let mut uart = Uart::new(Box::new(streams));
+        (uart.transmit_delay, uart.layout) = (5, Layout::Ns16550);
+        let mut cpu = CPU::assembled(ECHO_16550, |cpu, _| cpu.bus.map("uart", MARS_BASE, Permissions::RW, Box::new(uart)).unwrap());
+
+        assert_eq!(cpu.start().unwrap(), 0);
+        assert_eq!(output.text(), "UART\n");
+        // the next byte is waiting and the last one is still going out
+        assert_eq!(cpu.bus.peek(MARS_BASE + LSR, 1), Some(LSR_DATA_READY));
+        assert_eq!(cpu.bus.peek(MARS_BASE + RBR, 1), Some(b'i' as u32));
+    }
+
+    #[test]
+    fn the_16550_interrupt_registers_show_what_is_pending() {
+        let (streams, output) = Streams::buffer(b"a");
+        let mut uart = Uart::new(Box::new(streams));
+        uart.layout = Layout::Ns16550;
+        assert_eq!(uart.size(), 8);
+        assert_eq!(uart.peek(IIR, 1, Endian::Big), Some(IIR_NONE));
+
+        uart.write(IER, 1, IER_RECEIVED | IER_TRANSMITTER_EMPTY, Endian::Big);
+        assert_eq!(uart.peek(IER, 1, Endian::Big), Some(IER_RECEIVED | IER_TRANSMITTER_EMPTY));
+        assert_eq!(uart.peek(IIR, 1, Endian::Big), Some(IIR_TRANSMITTER_EMPTY));
+        uart.tick();
+        assert!(uart.interrupt());
+        assert_eq!(uart.peek(IIR, 1, Endian::Big), Some(IIR_RECEIVED));
+        // a word read takes in RBR, IER, IIR and the line control register
+        assert_eq!(uart.read(RBR, 4, Endian::Big), Some(u32::from_be_bytes([b'a', 0x3, IIR_RECEIVED as u8, 0])));
+        assert_eq!(uart.peek(LSR, 1, Endian::Big), Some(LSR_TRANSMITTER_EMPTY | LSR_IDLE));
+
+        uart.write(IER, 1, 0, Endian::Big);
+        assert!(!uart.interrupt());
+        uart.write(THR, 1, b'z' as u32, Endian::Big);
+        assert_eq!(output.text(), "z");
+    }
+
+    #[test]
+    fn file_output_is_written_when_flushed_not_every_byte() {
+        let path = std::env::temp_dir().join(format!("uart-output-{}", std::process::id()));
+        let mut streams = Streams::files(None, path.to_str().unwrap()).unwrap();
+        b"buffered".iter().for_each(|&byte| streams.transmit(byte));
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+        streams.flush();
+        assert_eq!(std::fs::read(&path).unwrap(), b"buffered");
+        std::fs::remove_file(path).unwrap();
+    }
+}
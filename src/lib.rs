@@ -0,0 +1,4 @@
+pub mod datatypes;
+pub mod debug;
+pub mod hardware;
+pub mod software;
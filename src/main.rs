@@ -1,34 +1,304 @@
-use hardware::arch::Computer;
+use rust_32b_cpu_sim::hardware::arch::Computer;
+use rust_32b_cpu_sim::hardware::bus::{Endian, Permissions};
+use rust_32b_cpu_sim::hardware::cache::Hierarchy;
+use rust_32b_cpu_sim::hardware::framebuffer::{self, Framebuffer, PixelFormat, Sequence};
+use rust_32b_cpu_sim::hardware::cpu::CPU as CPU;
+use rust_32b_cpu_sim::hardware::mmu::{self, Mmu};
+use rust_32b_cpu_sim::hardware::pipeline::{Pipeline, PipelineConfig};
+use rust_32b_cpu_sim::hardware::predictor::{self, Btb, BranchUnit};
+use rust_32b_cpu_sim::hardware::snapshot::Snapshot;
+use rust_32b_cpu_sim::hardware::timer::{self, Timer};
+use rust_32b_cpu_sim::hardware::timing::Chart;
+use rust_32b_cpu_sim::hardware::uart::{self, Backend, Streams, Terminal, Uart};
+use rust_32b_cpu_sim::hardware::arch::Fault;
+use rust_32b_cpu_sim::datatypes::Program;
+use rust_32b_cpu_sim::debug::debugger::Debugger;
+use rust_32b_cpu_sim::debug::gdbstub;
+use rust_32b_cpu_sim::software;
 
-use hardware::cpu::CPU as CPU;
-use crate::datatypes::Program;
+fn main() {
+    // --pipeline runs the program on the 5-stage pipeline instead of the single cycle CPU
+    let pipelined = std::env::args().skip(1).any(|arg| arg == "--pipeline");
+    // --debug runs the program under the command line debugger
+    let debugging = std::env::args().skip(1).any(|arg| arg == "--debug");
+    // --gdb=PORT waits for gdb's target remote on a local port, HOST:PORT, or a Unix socket path
+    let gdb = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--gdb=").map(String::from));
+    // --cache runs it behind the default cache hierarchy and reports how the caches did
+    let cached = std::env::args().skip(1).any(|arg| arg == "--cache");
+    // --mmu boots the program as a kernel behind a 16 entry TLB, refills going to its refill label
+    let mapped = std::env::args().skip(1).any(|arg| arg == "--mmu");
+    // --predictor=NAME scores a branch predictor with a 64 entry BTB, 2 cycles a misprediction
+    let predictor = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--predictor=").map(String::from));
+    // --chart=FILE writes the pipeline timing chart of the run, as CSV or HTML by extension, text otherwise
+    let chart = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--chart=").map(String::from));
+    // --uart maps a console at the MARS MMIO address, --uart=ADDR at another, on the terminal
+    // unless --uart-output=FILE (and --uart-input=FILE) put it on files. --uart-layout=16550
+    // gives it the registers of a 16550 rather than the MARS ones
+    let uart = std::env::args().skip(1).find_map(|arg| match arg.as_str() {
+        "--uart" => Some(Some(uart::MARS_BASE)),
+        _ => arg.strip_prefix("--uart=").map(|address| u32::from_str_radix(address.trim_start_matches("0x"), 16).ok())
+    });
+    let uart_input = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--uart-input=").map(String::from));
+    let uart_output = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--uart-output=").map(String::from));
+    let uart_layout = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--uart-layout=").map(String::from));
+    // --timer maps the interval timer after the UART. Interrupts from the UART come in on HW0
+    // and from the timer on HW1, and go to the program's handler label
+    let timed = std::env::args().skip(1).any(|arg| arg == "--timer");
+    // --display=WIDTHxHEIGHT[,FORMAT] maps a bitmap display where MARS has it, rgb888 unless
+    // rgb565 or gray8 is given. --screenshot=FILE saves it at the end of the run and
+    // --frames=N,FILE every N cycles, numbered, as PNG if FILE ends in .png and PPM otherwise
+    let display = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--display=").map(String::from));
+    let screenshot = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--screenshot=").map(String::from));
+    let frames = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--frames=").map(String::from));
+    // --restore=FILE starts from a saved snapshot of a machine configured the same way.
+    // --snapshot=FILE saves one at the end of the run, as JSON if FILE ends in .json, and
+    // --snapshot=N,FILE after N instructions on the single cycle CPU, stopping there
+    let restore = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--restore=").map(String::from));
+    let snapshot = std::env::args().skip(1).find_map(|arg| arg.strip_prefix("--snapshot=").map(String::from));
+    let (stop_at, snapshot) = match snapshot.as_deref().map(|spec| spec.split_once(',').map(|(n, path)| (n.parse::<u64>(), path))) {
+        Some(Some((Ok(n), path))) => (Some(n), Some(path.to_string())),
+        Some(Some((Err(_), _))) => {
+            eprintln!("--snapshot expects FILE or N,FILE");
+            std::process::exit(1)
+        }
+        _ => (None, snapshot)
+    };
 
-mod datatypes;
-mod hardware;
-mod software;
+    // Assemble the file given on the command line, or fall back to the sample program
+    let mut program = match std::env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(path) => match software::assemble::assemble(path) {
+            Ok((program, warnings)) => {
+                warnings.iter().for_each(|w| eprintln!("{w}\n"));
+                program
+            }
+            Err(diagnostics) => {
+                diagnostics.iter().for_each(|d| eprintln!("{d}\n"));
+                std::process::exit(1)
+            }
+        },
+        None => Program::new()
+    };
 
-fn main() {
-    // Create a sample program and load it into memory
-    let mut program = crate::datatypes::Program::new();
-    test_cpu(&mut program);
+    if program.instructions.is_empty() {
+        test_cpu(&mut program);
+    }
+
+    let mut cpu = CPU::new();
+    if mapped {
+        let mut mmu = Mmu::new(16);
+        mmu.refill_handler = program.symbols.get("refill").map(|&address| mmu::KSEG0 | address);
+        cpu.mmu = Some(mmu);
+    }
+    if cached {
+        cpu.caches = Some(Hierarchy::default());
+    }
+    if let Some(name) = predictor {
+        match predictor::by_name(&name) {
+            Some(predictor) => cpu.branches = Some(BranchUnit::new(predictor, Some(Btb::new(64)), 2)),
+            None => {
+                eprintln!("unknown predictor '{name}', expected taken, not-taken, btfn, 1bit, 2bit, gshare or tournament");
+                std::process::exit(1)
+            }
+        }
+    }
+    if let Some(address) = uart {
+        let Some(address) = address else {
+            eprintln!("--uart expects a hexadecimal address");
+            std::process::exit(1)
+        };
+        let backend: Box<dyn Backend> = match &uart_output {
+            Some(output) => match Streams::files(uart_input.as_deref(), output) {
+                Ok(streams) => Box::new(streams),
+                Err(e) => {
+                    eprintln!("could not open the UART files: {e}");
+                    std::process::exit(1)
+                }
+            },
+            None => Box::new(Terminal::new())
+        };
+        let mut device = Uart::new(backend);
+        if let Some(name) = &uart_layout {
+            let Some(layout) = uart::Layout::by_name(name) else {
+                eprintln!("unknown UART layout '{name}', expected mars or 16550");
+                std::process::exit(1)
+            };
+            device.layout = layout;
+        }
+        if let Err(e) = cpu.bus.map("uart", address, Permissions::RW, Box::new(device)) {
+            eprintln!("{e}");
+            std::process::exit(1)
+        }
+        cpu.interrupts.route("uart", 0).expect("HW0 is a line");
+    }
+    if timed {
+        if let Err(e) = cpu.bus.map("timer", timer::BASE, Permissions::RW, Box::new(Timer::new())) {
+            eprintln!("{e}");
+            std::process::exit(1)
+        }
+        cpu.interrupts.route("timer", 1).expect("HW1 is a line");
+    }
+    if let Some(display) = &display {
+        let mut framebuffer = match parse_display(display, cpu.bus.endian) {
+            Ok(framebuffer) => framebuffer,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1)
+            }
+        };
+        if let Some(frames) = &frames {
+            match frames.split_once(',').and_then(|(every, path)| Some(Sequence::new(every.parse().ok()?, path))) {
+                Some(sequence) => framebuffer.sequence = Some(sequence),
+                None => {
+                    eprintln!("--frames expects N,FILE");
+                    std::process::exit(1)
+                }
+            }
+        }
+        if let Err(e) = cpu.bus.map("display", framebuffer::BASE, Permissions::RW, Box::new(framebuffer)) {
+            eprintln!("{e}");
+            std::process::exit(1)
+        }
+    }
+    if uart.is_some() || timed {
+        let segment = if mapped { mmu::KSEG0 } else { 0 };
+        cpu.exception_handler = program.symbols.get("handler").map(|&address| segment | address);
+    }
+    if chart.is_some() {
+        cpu.trace = Some(Vec::new());
+    }
+    if debugging {
+        cpu.load_program(program);
+        restore_snapshot(&mut cpu, restore.as_deref());
+        let mut debugger = Debugger::new(cpu);
+        let code = debugger.repl();
+        flush_uart(&mut debugger.cpu);
+        std::process::exit(code.unwrap_or(0))
+    }
+    if let Some(address) = gdb {
+        cpu.load_program(program);
+        restore_snapshot(&mut cpu, restore.as_deref());
+        let mut debugger = Debugger::new(cpu);
+        if let Err(e) = gdbstub::listen(&mut debugger, &address) {
+            eprintln!("gdb stub on {address}: {e}");
+            std::process::exit(1)
+        }
+        flush_uart(&mut debugger.cpu);
+        std::process::exit(debugger.cpu.exit_code().unwrap_or(0))
+    }
+    let mut pipeline = Pipeline::new(cpu, PipelineConfig::default());
+    pipeline.load_program(program.clone());
+    restore_snapshot(&mut pipeline.cpu, restore.as_deref());
+    let result = match stop_at {
+        Some(retired) => run_until(&mut pipeline.cpu, retired),
+        None if pipelined => pipeline.start(),
+        None => pipeline.cpu.start()
+    };
+
+    if let Some(path) = chart {
+        let cpu = &mut pipeline.cpu;
+        let chart = Chart::new(&cpu.trace.take().unwrap_or_default(), PipelineConfig::default(), &cpu.symbols);
+        let rendered = match path.rsplit('.').next() {
+            Some("csv") => chart.csv(),
+            Some("html") => chart.html(),
+            _ => chart.text()
+        };
+        if let Err(e) = std::fs::write(&path, rendered) {
+            eprintln!("could not write {path}: {e}");
+        }
+    }
+
+    let failed = pipeline.cpu.bus.device::<Framebuffer>("display").and_then(|framebuffer| framebuffer.sequence.as_ref()?.failed.as_ref());
+    if let Some(failed) = failed {
+        eprintln!("{failed}");
+    }
+    if let Some(path) = screenshot {
+        match pipeline.cpu.bus.device::<Framebuffer>("display") {
+            Some(framebuffer) => if let Err(e) = framebuffer.save(&path) {
+                eprintln!("could not write {path}: {e}");
+            },
+            None => eprintln!("--screenshot needs a --display")
+        }
+    }
+
+    if let Some(path) = snapshot {
+        if let Err(e) = Snapshot::of(&pipeline.cpu).save(&path) {
+            eprintln!("could not write {path}: {e}");
+        }
+    }
+
+    flush_uart(&mut pipeline.cpu);
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(fault) => {
+            eprintln!("{fault}");
+            std::process::exit(1)
+        }
+    }
+}
+
+fn restore_snapshot(cpu: &mut CPU, path: Option<&str>) {
+    let Some(path) = path else { return };
+    if let Err(e) = Snapshot::load(path).and_then(|snapshot| snapshot.restore(cpu)) {
+        eprintln!("{e}");
+        std::process::exit(1)
+    }
 }
 
+// Write out what the UART has buffered for its output file, process::exit won't
+fn flush_uart(cpu: &mut CPU) {
+    if let Some(uart) = cpu.bus.device_mut::<Uart>("uart") {
+        uart.backend.flush();
+    }
+}
+
+// Step until retired instructions have run, or the program ends before that
+fn run_until(cpu: &mut CPU, retired: u64) -> Result<i32, Fault> {
+    while cpu.retired < retired {
+        if let Some(code) = cpu.step()? {
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+// WIDTHxHEIGHT with an optional pixel format after a comma
+fn parse_display(spec: &str, endian: Endian) -> Result<Framebuffer, String> {
+    let parse = || {
+        let (size, format) = spec.split_once(',').unwrap_or((spec, "rgb888"));
+        let (width, height) = size.split_once('x')?;
+        Some((width.parse().ok()?, height.parse().ok()?, PixelFormat::by_name(format)?))
+    };
+    let Some((width, height, format)) = parse() else {
+        return Err(String::from("--display expects WIDTHxHEIGHT with an optional ,rgb888, ,rgb565 or ,gray8"));
+    };
+    Framebuffer::new(width, height, format, endian)
+}
+
+// test.s, assembled by hand
 fn test_cpu(program: &mut Program) {
     program.instructions.push(0x24080005);
     program.instructions.push(0x01084820);
     program.instructions.push(0x0109502a);
     program.instructions.push(0x290b0000);
     program.instructions.push(0x290c0009);
-    program.instructions.push(0x15600002);
-    program.instructions.push(0x15200003);
-    program.instructions.push(0x11800003);
-    program.instructions.push(0x2401ffff);
-    program.instructions.push(0x0800100b);
-    program.instructions.push(0x24020007);
+    program.instructions.push(0x140b0002);
+    program.instructions.push(0x14090003);
+    program.instructions.push(0x100c0003);
+    program.instructions.push(0x2402ffff);
+    program.instructions.push(0x0800001b);
+    program.instructions.push(0x24020005);
     program.instructions.push(0xffffffff);
-
-    let mut cpu = CPU::new();
-    cpu.load_program(program.clone());
-    cpu.start();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_fallback_program_is_test_s_assembled() {
+        let (assembled, _) = software::assemble::assemble(String::from("test.s")).expect("test.s assembles");
+        let mut program = Program::new();
+        test_cpu(&mut program);
+        assert_eq!(program.instructions, assembled.instructions);
+    }
+}
@@ -1,47 +1,165 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::fs::File;
 use std::io::prelude::*;
-use super::tokenize;
+use super::{tokenize, parse, expand, validate};
+use super::diagnostic::{self, Diagnostic, Span};
+use crate::datatypes::{*};
+use crate::hardware::arch::{self, Fields};
+use crate::hardware::isa;
+
+/*
+ * Two pass assembler: tokenize and parse the source into a Protogram, expand the
+ * pseudo-instructions and validate the result, collect the address of every label,
+ * then encode each instruction into its machine word.
+ *
+ * Returns the program with any warnings, or every error and warning found
+ */
+
+pub type Assembly = Result<(Program, Vec<Diagnostic>), Vec<Diagnostic>>;
+
+pub fn assemble(filepath: String) -> Assembly {
+    match read_file(&filepath) {
+        Ok(asm_file_string) => assemble_source(&filepath, asm_file_string),
+        Err(mut e) => {
+            e.locate(&filepath, "");
+            Err(vec![e])
+        }
+    }
+}
 
+pub fn assemble_source(file: &str, source: String) -> Assembly {
+    let (asm_tokens, mut diagnostics) = tokenize::tokenize(source.clone());
+    let (protogram, parse_diagnostics) = parse::parse(asm_tokens);
+    diagnostics.extend(parse_diagnostics);
 
+    let protogram = expand::expand(protogram);
+    diagnostics.extend(validate::validate(&protogram));
 
-fn assemble(filepath: String) {
+    diagnostic::normalize(&mut diagnostics);
+    for d in diagnostics.iter_mut() {
+        d.locate(file, &source);
+    }
 
-    let mut asm_file_string = read_file(filepath);
-    let mut asm_tokens = tokenize::tokenize(asm_file_string);
+    if diagnostics.iter().any(|d| d.is_error()) {
+        return Err(diagnostics);
+    }
+    Ok((encode_protogram(&protogram), diagnostics))
+}
 
+// First pass: address of every label in the text and data segments, the first definition wins
+pub fn collect_symbols(protogram: &Protogram) -> HashMap<String, u32> {
+    let mut symbols = HashMap::new();
 
+    let mut address = arch::PC_START;
+    for instruction in &protogram.text {
+        for label in instruction.labels() {
+            symbols.entry(label.name.clone()).or_insert(address);
+        }
+        address += 4;
+    }
 
+    let mut address = arch::STATIC_DATA;
+    for data in &protogram.data {
+        address = align(address, data.align);
+        for label in &data.labels {
+            symbols.entry(label.name.clone()).or_insert(address);
+        }
+        address += data.value.len() as u32;
+    }
+
+    symbols
 }
 
+// bytes of static data once every item is aligned
+pub fn data_size(protogram: &Protogram) -> u32 {
+    protogram.data.iter().fold(arch::STATIC_DATA, |address, data| align(address, data.align) + data.value.len() as u32)
+        - arch::STATIC_DATA
+}
 
+// branches are relative to the instruction after the branch, in words
+pub fn branch_offset(target: u32, address: u32) -> i64 {
+    (target as i64 - (address as i64 + 4)) / 4
+}
 
+// round address up to the next multiple of alignment
+fn align(address: u32, alignment: u32) -> u32 {
+    address.div_ceil(alignment) * alignment
+}
 
+// Second pass: encode every instruction now that all labels are known
+fn encode_protogram(protogram: &Protogram) -> Program {
+    let mut program = Program::new();
+    program.symbols = collect_symbols(protogram);
+    let symbols = &program.symbols;
+
+    let mut address = arch::PC_START;
+    for instruction in &protogram.text {
+        let word = encode(instruction, address, symbols);
+        program.instructions.push(word);
+        address += 4;
+    }
 
+    // same layout as collect_symbols, padding with zeros up to each alignment
+    for data in &protogram.data {
+        let offset = align(arch::STATIC_DATA + program.data.len() as u32, data.align) - arch::STATIC_DATA;
+        program.data.resize(offset as usize, 0);
+        program.data.extend(&data.value);
+    }
 
+    program
+}
+
+// Build the machine word through the instruction table.
+// Label operands were checked by validate, so they all resolve here
+fn encode(instruction: &Instruction, address: u32, symbols: &HashMap<String, u32>) -> u32 {
+    let fields = match instruction {
+        Instruction::R(r) => Fields {
+            opcode: r.opcode as u32, rs: r.rs as u32, rt: r.rt as u32, rd: r.rd as u32, shamt: r.shamt as u32, func: r.func as u32,
+            ..Fields::default()
+        },
+        Instruction::I(i) => {
+            let immediate = if i.lbl_op.is_empty() {
+                i.immediate
+            } else {
+                let target = symbols[&i.lbl_op];
+                match i.lbl_use {
+                    LabelUse::Branch => branch_offset(target, address) as i16,
+                    LabelUse::Upper => (target >> 16) as i16,
+                    LabelUse::Lower => target as u16 as i16
+                }
+            };
+            Fields { opcode: i.opcode as u32, rs: i.rs as u32, rt: i.rt as u32, immediate, ..Fields::default() }
+        }
+        Instruction::J(j) => {
+            let address = if j.lbl_op.is_empty() { j.address } else { symbols[&j.lbl_op] >> 2 };
+            Fields { opcode: j.opcode as u32, address, ..Fields::default() }
+        }
+        Instruction::Pseudo(p) => unreachable!("pseudo-instruction '{}' was not expanded", p.mnemonic)
+    };
 
+    // halt is the one word outside the table
+    if fields.opcode == 0x3f {
+        return arch::HALT;
+    }
+    let spec = isa::find(&fields).expect("parser only produces instructions from the table");
+    isa::encode(spec, &fields)
+}
 
 
-// Read file into a mut String
-fn read_file(filepath: String) -> String{
-    let path = Path::new(&filepath);
+// Read file into a String
+fn read_file(filepath: &str) -> Result<String, Diagnostic> {
+    let path = Path::new(filepath);
+    let no_span = Span::default();
 
-    let mut file = match File::open(path){
-        
+    let mut file = match File::open(path) {
         Ok(r) => r,
-        Err(e) => panic!("couldn't open {}: {}", filepath, e)
-
+        Err(e) => return Err(Diagnostic::error(format!("couldn't open {filepath}: {e}"), no_span))
     };
 
     let mut s = String::new();
     match file.read_to_string(&mut s) {
-        Ok(_) => s,
-        Err(_) => panic!("Couldn't read file {} to string", filepath)
+        Ok(_) => Ok(s),
+        Err(e) => Err(Diagnostic::error(format!("couldn't read {filepath}: {e}"), no_span))
     }
 }
-
-
-
-
-
-
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/*
+ * Errors and warnings produced while assembling, each pointing at a place in the source
+ */
+
+// Position of a piece of source text, lines and columns count from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error, Warning
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub file: String,           // filled in by locate once the source is known
+    pub source_line: String
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Span) -> Self {
+        Diagnostic { severity: Severity::Error, message, span, file: String::new(), source_line: String::new() }
+    }
+
+    pub fn warning(message: String, span: Span) -> Self {
+        Diagnostic { severity: Severity::Warning, message, span, file: String::new(), source_line: String::new() }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // attach the file name and the text of the offending line
+    pub fn locate(&mut self, file: &str, source: &str) {
+        self.file = file.to_string();
+        self.source_line = source.lines().nth(self.span.line.saturating_sub(1)).unwrap_or("").to_string();
+    }
+}
+
+// Sort by position and drop repeats, e.g. a bad register reported by both tokenizer and parser
+pub fn normalize(diagnostics: &mut Vec<Diagnostic>) {
+    diagnostics.sort_by_key(|d| d.span);
+    diagnostics.dedup();
+}
+
+impl fmt::Display for Diagnostic {
+    // error: unknown instruction 'addd'
+    //  --> test.s:3:1
+    //   |
+    // 3 | addd $t0 $t1 $t2
+    //   | ^^^^
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        writeln!(f, "{severity}: {}", self.message)?;
+        // problems with the file as a whole, like failing to open it
+        if self.span.line == 0 {
+            return write!(f, " --> {}", self.file);
+        }
+
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        writeln!(f, "{gutter}--> {}:{}:{}", self.file, self.span.line, self.span.column)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", self.span.line, self.source_line)?;
+        let indent: String = self.source_line.chars()
+            .take(self.span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{gutter} | {indent}{}", "^".repeat(self.span.length.max(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::software::assemble::assemble_source;
+
+    // every diagnostic assembling source gives, rendered
+    fn rendered(source: &str) -> Vec<String> {
+        match assemble_source("test.s", source.to_string()) {
+            Err(diagnostics) => diagnostics.iter().map(|d| d.to_string()).collect(),
+            Ok(_) => panic!("{source} assembles")
+        }
+    }
+
+    #[test]
+    fn errors_point_at_file_line_and_column() {
+        assert_eq!(rendered("addd $t0, $t1, $t2"), [
+            "error: unknown instruction 'addd'\n --> test.s:1:1\n  |\n1 | addd $t0, $t1, $t2\n  | ^^^^"
+        ]);
+        assert_eq!(rendered("nop\n\tadd $t0, $t1"), [
+            "error: 'add' expects 3 operands, found 2\n --> test.s:2:2\n  |\n2 | \tadd $t0, $t1\n  | \t^^^"
+        ]);
+        assert_eq!(rendered("  beq $t0, $zero, nowhere"), [
+            "error: undefined label 'nowhere'\n --> test.s:1:3\n  |\n1 |   beq $t0, $zero, nowhere\n  |   ^^^"
+        ]);
+        assert_eq!(rendered("twice: nop\ntwice: nop"), [
+            "error: label 'twice' already defined on line 1\n --> test.s:2:1\n  |\n2 | twice: nop\n  | ^^^^^"
+        ]);
+        assert_eq!(rendered("addiu $t0, $t0, 70000"), [
+            "error: immediate 70000 does not fit in 16 bits\n --> test.s:1:17\n  |\n1 | addiu $t0, $t0, 70000\n  |                 ^^^^^"
+        ]);
+    }
+
+    #[test]
+    fn branches_past_16_bits_of_offset_are_out_of_range() {
+        // the data segment overflows too, which is reported first
+        let source = format!(".data\n{}far: .word 0\n.text\nb far", ".space 0x3000\n".repeat(11));
+        let diagnostics = rendered(&source);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[1],
+            "error: branch to 'far' is 34799 instructions away, out of range\n  --> test.s:15:1\n   |\n15 | b far\n   | ^");
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use crate::datatypes::Program;
+use crate::hardware::arch::{self, Fields};
+use crate::hardware::isa::{self, Immediate, Syntax};
+use crate::hardware::cpu::CPU;
+
+/*
+ * Turn machine words back into assembly, using the same field extraction and
+ * instruction table as MipsIsa::decode_execute. Branch and jump targets are shown as labels when the
+ * symbol table has one for the address, and as absolute addresses otherwise
+ */
+
+// Listing of the text segment of a program, labels on their own lines
+pub fn disassemble_program(program: &Program) -> String {
+    let words = program.instructions.iter().enumerate()
+        .map(|(i, word)| (arch::PC_START + 4 * i as u32, *word));
+    listing(words, &program.symbols)
+}
+
+// Listing of the words in start..end of the CPU's memory
+pub fn disassemble_memory(cpu: &CPU, start: u32, end: u32, symbols: &HashMap<String, u32>) -> String {
+    let start = start & !0x3;
+    let end = end.min(arch::END_MEM);
+    let words = (start..end).step_by(4).map_while(|address| cpu.read_word_from_mem(address).map(|word| (address, word)));
+    listing(words, symbols)
+}
+
+fn listing(words: impl Iterator<Item = (u32, u32)>, symbols: &HashMap<String, u32>) -> String {
+    let mut res = String::new();
+    for (address, word) in words {
+        for label in labels_at(symbols, address) {
+            res.push_str(&format!("{label}:\n"));
+        }
+        res.push_str(&format!("  {:#010x}  {:#010x}  {}\n", address, word, disassemble(word, address, symbols)));
+    }
+    res
+}
+
+// every label at an address, sorted so listings come out the same each time
+fn labels_at(symbols: &HashMap<String, u32>, address: u32) -> Vec<&str> {
+    let mut labels: Vec<&str> = symbols.iter()
+        .filter(|(_, a)| **a == address)
+        .map(|(label, _)| label.as_str())
+        .collect();
+    labels.sort();
+    labels
+}
+
+// label for an address if there is one, the address itself otherwise
+fn target(symbols: &HashMap<String, u32>, address: u32) -> String {
+    match labels_at(symbols, address).first() {
+        Some(label) => label.to_string(),
+        None => format!("{:#010x}", address)
+    }
+}
+
+fn reg(n: u32) -> String {
+    format!("${}", arch::REGISTER_NAMES[n as usize])
+}
+
+fn freg(n: u32) -> String {
+    format!("$f{n}")
+}
+
+// One instruction in canonical syntax, address is where the word lives
+pub fn disassemble(instruction: u32, address: u32, symbols: &HashMap<String, u32>) -> String {
+    if instruction == arch::HALT {
+        return String::from("halt");
+    }
+    if instruction == 0 {
+        return String::from("nop");
+    }
+
+    let spec = match isa::decode(instruction) {
+        Some(spec) => spec,
+        None => return format!(".word {:#010x}", instruction)
+    };
+    let Fields { rs, rt, rd, shamt, immediate, address: jump, .. } = Fields::extract(instruction);
+    let m = spec.mnemonic;
+
+    let imm = match spec.immediate {
+        Immediate::Unsigned => format!("{:#x}", immediate as u16),
+        _ => immediate.to_string()
+    };
+    // branches and jumps are relative to the instruction after them
+    let next = address.wrapping_add(4);
+
+    match spec.syntax {
+        Syntax::RdRsRt => format!("{m} {}, {}, {}", reg(rd), reg(rs), reg(rt)),
+        Syntax::RdRtShamt => format!("{m} {}, {}, {}", reg(rd), reg(rt), shamt),
+        Syntax::RdRtRs => format!("{m} {}, {}, {}", reg(rd), reg(rt), reg(rs)),
+        Syntax::RsRt => format!("{m} {}, {}", reg(rs), reg(rt)),
+        // jalr links $ra unless told otherwise
+        Syntax::RdRs if rd == 31 => format!("{m} {}", reg(rs)),
+        Syntax::RdRs => format!("{m} {}, {}", reg(rd), reg(rs)),
+        Syntax::Rs => format!("{m} {}", reg(rs)),
+        Syntax::Rd => format!("{m} {}", reg(rd)),
+        // coprocessor registers go by number
+        Syntax::RtRd => format!("{m} {}, ${rd}", reg(rt)),
+        Syntax::RtFs => format!("{m} {}, {}", reg(rt), freg(rd)),
+        // FPU operations keep ft in rt, fs in rd and fd in shamt
+        Syntax::FdFsFt => format!("{m} {}, {}, {}", freg(shamt), freg(rd), freg(rt)),
+        Syntax::FdFs => format!("{m} {}, {}", freg(shamt), freg(rd)),
+        Syntax::FsFt => format!("{m} {}, {}", freg(rd), freg(rt)),
+        Syntax::RtRsImm => format!("{m} {}, {}, {}", reg(rt), reg(rs), imm),
+        Syntax::RtImm => format!("{m} {}, {}", reg(rt), imm),
+        Syntax::RsRtLabel => {
+            let branch_target = next.wrapping_add((immediate as i32 * 4) as u32);
+            format!("{m} {}, {}, {}", reg(rs), reg(rt), target(symbols, branch_target))
+        }
+        Syntax::RsLabel => {
+            let branch_target = next.wrapping_add((immediate as i32 * 4) as u32);
+            format!("{m} {}, {}", reg(rs), target(symbols, branch_target))
+        }
+        Syntax::Offset => {
+            let branch_target = next.wrapping_add((immediate as i32 * 4) as u32);
+            format!("{m} {}", target(symbols, branch_target))
+        }
+        Syntax::RtOffsetRs => format!("{m} {}, {}({})", reg(rt), imm, reg(rs)),
+        Syntax::FtOffsetRs => format!("{m} {}, {}({})", freg(rt), imm, reg(rs)),
+        Syntax::Label => {
+            let jump_target = (next & 0xF000_0000) | (jump << 2);
+            format!("{m} {}", target(symbols, jump_target))
+        }
+        Syntax::None => m.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::software::assemble::assemble_source;
+
+    #[test]
+    fn programs_disassemble_to_their_source() {
+        let source = "\
+main:
+addiu $t0, $zero, -3
+ori $t1, $t0, 0xff00
+loop:
+addu $t2, $t2, $t0
+lw $a0, 8($sp)
+bne $t2, $t1, loop
+jal done
+jalr $t9
+jalr $s0, $t9
+sll $t3, $t1, 4
+bgez $t0, main
+done:
+nop
+halt";
+        let (program, _) = assemble_source("disassemble.s", source.to_string()).unwrap();
+        let listing: Vec<String> = disassemble_program(&program).lines()
+            .map(|line| line.rsplit("  ").next().unwrap().trim_start().to_string())
+            .collect();
+        assert_eq!(listing.join("\n"), source);
+    }
+
+    #[test]
+    fn targets_without_a_label_and_unknown_words_show_as_numbers() {
+        let symbols = HashMap::from([(String::from("here"), 0x48)]);
+        assert_eq!(disassemble(0x1000_0001, 0x40, &symbols), "beq $zero, $zero, here");
+        assert_eq!(disassemble(0x1000_0002, 0x40, &symbols), "beq $zero, $zero, 0x0000004c");
+        assert_eq!(disassemble(0x0800_0400, 0x40, &symbols), "j 0x00001000");
+        assert_eq!(disassemble(0xFC00_0000, 0x40, &symbols), ".word 0xfc000000");
+        assert_eq!(disassemble_program(&Program { instructions: vec![0x1000_ffff], ..Program::new() }),
+            "  0x00000040  0x1000ffff  beq $zero, $zero, 0x00000040\n");
+    }
+}
@@ -0,0 +1,145 @@
+use crate::datatypes::{*};
+use super::diagnostic::Span;
+use crate::hardware::isa;
+
+/*
+ * Rewrite every pseudo-instruction in a protogram into the real instructions it stands for.
+ * $at is the scratch register, and the labels of a pseudo-instruction move to the first
+ * instruction it expands into, so label addresses come out right in the assembler's first pass.
+ */
+
+const ZERO: u8 = 0;
+const AT: u8 = 1;
+
+pub fn expand(protogram: Protogram) -> Protogram {
+    let mut text = Vec::with_capacity(protogram.text.len());
+    for instruction in protogram.text {
+        match instruction {
+            Instruction::Pseudo(pseudo) => {
+                let mut expanded = expand_pseudo(&pseudo);
+                set_labels(&mut expanded[0], pseudo.labels);
+                text.extend(expanded);
+            }
+            real => text.push(real)
+        }
+    }
+    Protogram { text, data: protogram.data }
+}
+
+fn set_labels(instruction: &mut Instruction, labels: Vec<Label>) {
+    match instruction {
+        Instruction::R(r) => r.labels = labels,
+        Instruction::I(i) => i.labels = labels,
+        Instruction::J(j) => j.labels = labels,
+        Instruction::Pseudo(p) => p.labels = labels
+    }
+}
+
+// opcode and func of a real instruction from the instruction table
+fn encoding(mnemonic: &str) -> (u8, u8) {
+    let spec = isa::lookup(mnemonic).expect("pseudo-instructions expand into table instructions");
+    (spec.opcode as u8, spec.func as u8)
+}
+
+fn r_type(span: Span, mnemonic: &str, rs: u8, rt: u8, rd: u8, shamt: u8) -> Instruction {
+    let (opcode, func) = encoding(mnemonic);
+    Instruction::R(RInstruction { labels: Vec::new(), span, opcode, rs, rt, rd, shamt, func })
+}
+
+fn i_type(span: Span, mnemonic: &str, rs: u8, rt: u8, immediate: i16) -> Instruction {
+    let (opcode, _) = encoding(mnemonic);
+    Instruction::I(IInstruction { labels: Vec::new(), span, opcode, rs, rt, immediate, lbl_op: String::new(), lbl_use: LabelUse::Branch })
+}
+
+fn i_type_label(span: Span, mnemonic: &str, rs: u8, rt: u8, lbl_op: &str, lbl_use: LabelUse) -> Instruction {
+    let (opcode, _) = encoding(mnemonic);
+    Instruction::I(IInstruction { labels: Vec::new(), span, opcode, rs, rt, immediate: 0, lbl_op: lbl_op.to_string(), lbl_use })
+}
+
+fn branch(span: Span, mnemonic: &str, rs: u8, rt: u8, label: &str) -> Instruction {
+    i_type_label(span, mnemonic, rs, rt, label, LabelUse::Branch)
+}
+
+// Real instructions for one pseudo-instruction, never empty
+fn expand_pseudo(pseudo: &PseudoInstruction) -> Vec<Instruction> {
+    let reg = |n: usize| pseudo.registers[n];
+    let label = pseudo.lbl_op.as_str();
+    let span = pseudo.span;
+
+    match pseudo.mnemonic.as_str() {
+        "li" => {
+            let value = pseudo.immediate;
+            if (i16::MIN as i32..=i16::MAX as i32).contains(&value) {
+                vec![i_type(span, "addiu", ZERO, reg(0), value as i16)]
+            } else if (0..=u16::MAX as i32).contains(&value) {
+                vec![i_type(span, "ori", ZERO, reg(0), value as u16 as i16)]
+            } else {
+                vec![
+                    i_type(span, "lui", ZERO, AT, (value >> 16) as i16),
+                    i_type(span, "ori", AT, reg(0), value as u16 as i16)
+                ]
+            }
+        }
+        "la" => vec![
+            i_type_label(span, "lui", ZERO, AT, label, LabelUse::Upper),
+            i_type_label(span, "ori", AT, reg(0), label, LabelUse::Lower)
+        ],
+        "move" => vec![r_type(span, "addu", ZERO, reg(1), reg(0), 0)],
+        "not" => vec![r_type(span, "nor", reg(1), ZERO, reg(0), 0)],
+        "neg" => vec![r_type(span, "sub", ZERO, reg(1), reg(0), 0)],
+        "blt" => vec![r_type(span, "slt", reg(0), reg(1), AT, 0), branch(span, "bne", AT, ZERO, label)],
+        "bgt" => vec![r_type(span, "slt", reg(1), reg(0), AT, 0), branch(span, "bne", AT, ZERO, label)],
+        "ble" => vec![r_type(span, "slt", reg(1), reg(0), AT, 0), branch(span, "beq", AT, ZERO, label)],
+        "bge" => vec![r_type(span, "slt", reg(0), reg(1), AT, 0), branch(span, "beq", AT, ZERO, label)],
+        "beqz" => vec![branch(span, "beq", reg(0), ZERO, label)],
+        "bnez" => vec![branch(span, "bne", reg(0), ZERO, label)],
+        "b" => vec![branch(span, "beq", ZERO, ZERO, label)],
+        "nop" => vec![r_type(span, "sll", ZERO, ZERO, ZERO, 0)],
+        // the CPU stops when it fetches 0xFFFFFFFF
+        "halt" => vec![Instruction::R(RInstruction { labels: Vec::new(), span, opcode: 0x3f, rs: 0x1f, rt: 0x1f, rd: 0x1f, shamt: 0x1f, func: 0x3f })],
+        other => unreachable!("parser accepted unknown pseudo-instruction '{other}'")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::software::assemble::assemble_source;
+
+    fn words(source: &str) -> Vec<u32> {
+        assemble_source("expand.s", source.to_string()).expect("test program assembles").0.instructions
+    }
+
+    #[test]
+    fn pseudo_instructions_assemble_as_what_they_stand_for() {
+        let cases = [
+            ("li $t0, -5", "addiu $t0, $zero, -5"),
+            ("li $t0, 0xbeef", "ori $t0, $zero, 0xbeef"),
+            ("li $t0, 0x12345678", "lui $at, 0x1234\nori $t0, $at, 0x5678"),
+            ("li $t0, -65536", "lui $at, 0xffff\nori $t0, $at, 0"),
+            ("la $a0, value", "lui $at, 0\nori $a0, $at, 0x1000"),
+            ("move $t0, $t1", "addu $t0, $zero, $t1"),
+            ("not $t0, $t1", "nor $t0, $t1, $zero"),
+            ("neg $t0, $t1", "sub $t0, $zero, $t1"),
+            ("nop", "sll $zero, $zero, 0"),
+            ("l: blt $t0, $t1, l", "l: slt $at, $t0, $t1\nbne $at, $zero, l"),
+            ("l: bge $t0, $t1, l", "l: slt $at, $t0, $t1\nbeq $at, $zero, l"),
+            ("l: bgt $t0, $t1, l", "l: slt $at, $t1, $t0\nbne $at, $zero, l"),
+            ("l: ble $t0, $t1, l", "l: slt $at, $t1, $t0\nbeq $at, $zero, l")
+        ];
+        for (pseudo, real) in cases {
+            let data = ".data\nvalue: .word 1\n.text\n";
+            assert_eq!(words(&format!("{data}{pseudo}")), words(&format!("{data}{real}")), "{pseudo}");
+        }
+    }
+
+    #[test]
+    fn labels_after_an_expansion_move_down_with_it() {
+        let source = "li $t0, 0x12345678\nfirst: la $t1, first\nsecond: bge $t0, $t1, first\nthird: halt";
+        let (program, _) = assemble_source("expand.s", source.to_string()).unwrap();
+        let address = |label: &str| program.symbols[label];
+        assert_eq!((address("first"), address("second"), address("third")), (0x48, 0x50, 0x58));
+        // la loads first's address, and the beq of bge, at 0x54, branches 4 words back to it
+        assert_eq!(program.instructions[3] & 0xFFFF, 0x48);
+        assert_eq!(program.instructions[5] & 0xFFFF, 0xFFFC);
+    }
+}
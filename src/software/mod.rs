@@ -1,4 +1,7 @@
 pub mod assemble;
+pub mod diagnostic;
+pub mod disassemble;
+pub mod expand;
 pub mod tokenize;
 pub mod validate;
 pub mod parse;
\ No newline at end of file
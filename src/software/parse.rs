@@ -1,32 +1,63 @@
-use super::tokenize::Token;
+use super::tokenize::{Token, TokenType};
+use super::diagnostic::{Diagnostic, Span};
 use crate::datatypes::{*};
+use crate::hardware::arch;
+use crate::hardware::isa::{self, Format, Immediate, Syntax};
 
 /*
- * Take in a Vector of tokens, and return a protogram
+ * Take in a Vector of tokens, and return a protogram along with any errors and warnings.
+ * A statement with an error is skipped up to the end of its line so later problems are still reported
  */
 
-pub fn parse(tokens: Vec<Token>) -> Option<Protogram> {
-
-
-
-
-
-
+pub fn parse(tokens: Vec<Token>) -> (Protogram, Vec<Diagnostic>) {
+    let mut parser = Parser::new(tokens);
+    let protogram = parser.parse_program();
+    (protogram, parser.diagnostics)
+}
 
+// Operands of a pseudo-instruction, read in order
+#[derive(Clone, Copy)]
+enum Operand {
+    Register, Integer, Label
+}
 
+// pseudo-instruction mnemonic -> operands, see expand.rs for what each becomes
+fn lookup_pseudo(mnemonic: &str) -> Option<&'static [Operand]> {
+    use Operand::*;
+    let operands: &[Operand] = match mnemonic {
+        "li" => &[Register, Integer],
+        "la" => &[Register, Label],
+        "move" | "not" | "neg" => &[Register, Register],
+        "blt" | "bgt" | "ble" | "bge" => &[Register, Register, Label],
+        "beqz" | "bnez" => &[Register, Label],
+        "b" => &[Label],
+        "nop" | "halt" => &[],
+        _ => return None
+    };
+    Some(operands)
+}
 
-    None
+// parse a decimal or 0x prefixed hex integer
+fn parse_integer(value: &str) -> Option<i64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse::<i64>().ok()
+    }
 }
 
+type ParseResult<T> = Result<T, Diagnostic>;
 
+#[derive(PartialEq)]
+enum Section {
+    Text, Data
+}
 
 struct Parser {
     tokens: Vec<Token>,
-    cursor: usize
-}
-
-enum Line {
-    Instruction, StaticData
+    cursor: usize,
+    section: Section,
+    pending_labels: Vec<Label>,
+    diagnostics: Vec<Diagnostic>
 }
 
 impl Parser {
@@ -34,13 +65,616 @@ impl Parser {
     fn new(tks: Vec<Token>) -> Self {
         Parser {
             tokens: tks,
-            cursor: 0
+            cursor: 0,
+            section: Section::Text,
+            pending_labels: Vec::new(),
+            diagnostics: Vec::new()
         }
         
     }
 
-    fn read_line() -> Line {
-        
+    fn parse_program(&mut self) -> Protogram {
+        let mut protogram = Protogram { text: Vec::new(), data: Vec::new() };
+
+        while let Some(token) = self.peek(0) {
+            let line = token.span.line;
+            if let Err(e) = self.read_statement(&mut protogram) {
+                self.diagnostics.push(e);
+                self.skip_line(line);
+            }
+        }
+
+        if let Err(e) = self.flush_labels(&mut protogram) {
+            self.diagnostics.push(e);
+        }
+        protogram
+    }
+
+    // a label definition, a directive or an instruction
+    fn read_statement(&mut self, protogram: &mut Protogram) -> ParseResult<()> {
+        let token = self.peek(0).unwrap().clone();
+        // label definition
+        if token.token_type == TokenType::Identifier && self.is_punctuation(1, ":") {
+            self.cursor += 2;
+            self.pending_labels.push(Label { name: token.value, span: token.span });
+        } else if token.token_type == TokenType::Directive {
+            self.read_directive(protogram)?;
+        } else if token.token_type == TokenType::Identifier {
+            if self.section != Section::Text {
+                return Err(Diagnostic::error(format!("instruction '{}' outside of .text", token.value), token.span));
+            }
+            let instruction = self.read_instruction()?;
+            protogram.text.push(instruction);
+        } else {
+            return Err(Diagnostic::error(format!("unexpected '{}'", token.value), token.span));
+        }
+        Ok(())
+    }
+
+    // error recovery: drop the rest of the line the failed statement started on
+    fn skip_line(&mut self, line: usize) {
+        while self.peek(0).is_some_and(|t| t.span.line <= line) {
+            self.cursor += 1;
+        }
+    }
+
+    // labels left dangling at the end of a section
+    fn flush_labels(&mut self, protogram: &mut Protogram) -> ParseResult<()> {
+        if self.pending_labels.is_empty() {
+            return Ok(());
+        }
+        let labels = std::mem::take(&mut self.pending_labels);
+        match self.section {
+            Section::Data => {
+                let span = labels[0].span;
+                protogram.data.push(StaticData { labels, span, align: 1, value: Vec::new() });
+                Ok(())
+            }
+            Section::Text => Err(Diagnostic::error(format!("label '{}' does not precede an instruction", labels[0].name), labels[0].span))
+        }
+    }
+
+    fn read_directive(&mut self, protogram: &mut Protogram) -> ParseResult<()> {
+        let token = self.advance().unwrap();
+        let directive = token.value.as_str();
+        match directive {
+            ".text" => {
+                self.flush_labels(protogram)?;
+                self.section = Section::Text;
+            }
+            ".data" => {
+                self.flush_labels(protogram)?;
+                self.section = Section::Data;
+            }
+            ".globl" | ".global" => {
+                self.expect_identifier()?;
+            }
+            ".word" | ".half" | ".byte" | ".float" | ".double" | ".ascii" | ".asciiz" | ".space" | ".align" => {
+                if self.section != Section::Data {
+                    return Err(Diagnostic::error(format!("{directive} outside of .data"), token.span));
+                }
+                let data = self.read_static_data(&token)?;
+                protogram.data.push(data);
+            }
+            _ => return Err(Diagnostic::error(format!("unsupported directive '{directive}'"), token.span))
+        }
+        Ok(())
+    }
+
+    // the operands of a data directive, laid out as the big endian bytes the CPU will read
+    fn read_static_data(&mut self, directive: &Token) -> ParseResult<StaticData> {
+        let line = directive.span.line;
+        let mut align = 1;
+        let mut value = Vec::new();
+        match directive.value.as_str() {
+            ".word" => {
+                align = 4;
+                for v in self.read_integer_list(line, i32::MIN as i64, u32::MAX as i64)? {
+                    value.extend((v as u32).to_be_bytes());
+                }
+            }
+            ".half" => {
+                align = 2;
+                for v in self.read_integer_list(line, i16::MIN as i64, u16::MAX as i64)? {
+                    value.extend((v as u16).to_be_bytes());
+                }
+            }
+            ".byte" => {
+                for v in self.read_integer_list(line, i8::MIN as i64, u8::MAX as i64)? {
+                    value.push(v as u8);
+                }
+            }
+            ".float" => {
+                align = 4;
+                for v in self.read_float_list(line)? {
+                    value.extend((v as f32).to_be_bytes());
+                }
+            }
+            ".double" => {
+                align = 8;
+                for v in self.read_float_list(line)? {
+                    value.extend(v.to_be_bytes());
+                }
+            }
+            ".ascii" | ".asciiz" => {
+                loop {
+                    value.extend(self.expect_string()?.bytes());
+                    if directive.value == ".asciiz" {
+                        value.push(0);
+                    }
+                    if !(self.on_line(0, line) && self.is_punctuation(0, ",")) {
+                        break;
+                    }
+                }
+            }
+            ".space" => {
+                let size = self.expect_integer()?;
+                if !(0..=(arch::DYNAMIC_DATA - arch::STATIC_DATA) as i64).contains(&size) {
+                    return Err(self.error_at_previous(format!(".space size {size} out of range")));
+                }
+                value = vec![0; size as usize];
+            }
+            ".align" => {
+                // aligns the next item to 2^n bytes, the label stays with that item
+                let n = self.expect_integer()?;
+                if !(0..=3).contains(&n) {
+                    return Err(self.error_at_previous(format!(".align {n} out of range, expected 0 to 3")));
+                }
+                return Ok(StaticData { labels: Vec::new(), span: directive.span, align: 1 << n, value });
+            }
+            _ => unreachable!()
+        }
+        let labels = std::mem::take(&mut self.pending_labels);
+        Ok(StaticData { labels, span: directive.span, align, value })
+    }
+
+    // one or more integers on the line separated by commas or whitespace, each within min..=max
+    fn read_integer_list(&mut self, line: usize, min: i64, max: i64) -> ParseResult<Vec<i64>> {
+        let mut values = Vec::new();
+        loop {
+            let v = self.expect_integer()?;
+            if !(min..=max).contains(&v) {
+                return Err(self.error_at_previous(format!("value {v} out of range {min} to {max}")));
+            }
+            values.push(v);
+            // more values follow a comma or another number
+            if !(self.on_line(0, line) && (self.is_punctuation(0, ",") || self.starts_integer(0))) {
+                return Ok(values);
+            }
+        }
+    }
+
+    // one or more numbers on the line, integers or floats, separated like read_integer_list
+    fn read_float_list(&mut self, line: usize) -> ParseResult<Vec<f64>> {
+        let mut values = Vec::new();
+        loop {
+            values.push(self.expect_float()?);
+            let more = self.starts_integer(0) || self.peek(0).is_some_and(|t| t.token_type == TokenType::Float);
+            if !(self.on_line(0, line) && (self.is_punctuation(0, ",") || more)) {
+                return Ok(values);
+            }
+        }
+    }
+
+    fn read_instruction(&mut self) -> ParseResult<Instruction> {
+        let token = self.advance().unwrap();
+        let mnemonic = token.value;
+        let span = token.span;
+        if let Some(operands) = lookup_pseudo(&mnemonic) {
+            self.check_operand_count(&mnemonic, span, (operands.len(), operands.len()))?;
+            return self.read_pseudo_instruction(mnemonic, span, operands);
+        }
+        let spec = match isa::lookup(&mnemonic) {
+            Some(spec) => spec,
+            None => return Err(Diagnostic::error(format!("unknown instruction '{mnemonic}'"), span))
+        };
+        let (opcode, func) = (spec.opcode as u8, spec.func as u8);
+        let count = self.check_operand_count(&mnemonic, span, spec.syntax.operand_count())?;
+        let labels = std::mem::take(&mut self.pending_labels);
+
+        // rs value fixed by the format, for coprocessor operations
+        let fmt = spec.format.fixed_rs().unwrap_or_default() as u8;
+        let r = |labels, rs, rt, rd, shamt| Instruction::R(RInstruction { labels, span, opcode, rs, rt, rd, shamt, func });
+        let i = |labels, rs, rt, immediate, lbl_op| Instruction::I(IInstruction { labels, span, opcode, rs, rt, immediate, lbl_op, lbl_use: LabelUse::Branch });
+
+        let instruction = match spec.syntax {
+            Syntax::RdRsRt => {
+                let rd = self.expect_register()?;
+                let rs = self.expect_register()?;
+                let rt = self.expect_register()?;
+                r(labels, rs, rt, rd, 0)
+            }
+            Syntax::RdRtShamt => {
+                let rd = self.expect_register()?;
+                let rt = self.expect_register()?;
+                let shamt = self.expect_integer()?;
+                if !(0..32).contains(&shamt) {
+                    return Err(self.error_at_previous(format!("shift amount {shamt} out of range 0 to 31")));
+                }
+                r(labels, 0, rt, rd, shamt as u8)
+            }
+            Syntax::RdRtRs => {
+                let rd = self.expect_register()?;
+                let rt = self.expect_register()?;
+                let rs = self.expect_register()?;
+                r(labels, rs, rt, rd, 0)
+            }
+            Syntax::RsRt => {
+                let rs = self.expect_register()?;
+                let rt = self.expect_register()?;
+                r(labels, rs, rt, 0, 0)
+            }
+            Syntax::RdRs => {
+                // the link register is $ra when only the target is given
+                let rd = if count == 2 { self.expect_register()? } else { 31 };
+                let rs = self.expect_register()?;
+                r(labels, rs, 0, rd, 0)
+            }
+            Syntax::Rs => {
+                let rs = self.expect_register()?;
+                r(labels, rs, 0, 0, 0)
+            }
+            Syntax::Rd => {
+                let rd = self.expect_register()?;
+                r(labels, 0, 0, rd, 0)
+            }
+            Syntax::RtFs => {
+                let rt = self.expect_register()?;
+                let fs = self.expect_fp_register()?;
+                r(labels, func, rt, fs, 0)
+            }
+            // FPU operations keep ft in rt, fs in rd and fd in shamt, with the fmt in rs
+            Syntax::FdFsFt => {
+                let fd = self.expect_fp_register()?;
+                let fs = self.expect_fp_register()?;
+                let ft = self.expect_fp_register()?;
+                r(labels, fmt, ft, fs, fd)
+            }
+            Syntax::FdFs => {
+                let fd = self.expect_fp_register()?;
+                let fs = self.expect_fp_register()?;
+                r(labels, fmt, 0, fs, fd)
+            }
+            Syntax::FsFt => {
+                let fs = self.expect_fp_register()?;
+                let ft = self.expect_fp_register()?;
+                r(labels, fmt, ft, fs, 0)
+            }
+            Syntax::RtRd => {
+                let rt = self.expect_register()?;
+                let rd = self.expect_register()?;
+                // coprocessor moves keep the sub-opcode in rs
+                let rs = if spec.format == Format::Cop { func } else { 0 };
+                r(labels, rs, rt, rd, 0)
+            }
+            Syntax::None => r(labels, fmt, 0, 0, 0),
+            Syntax::RtRsImm => {
+                let rt = self.expect_register()?;
+                let rs = self.expect_register()?;
+                let immediate = self.expect_immediate()?;
+                // sign extended immediates from 0x8000 up turn negative
+                if spec.immediate == Immediate::Signed && immediate.1 > i16::MAX as i64 {
+                    self.diagnostics.push(Diagnostic::warning(
+                        format!("immediate {:#x} is sign extended to {}", immediate.1, immediate.0), self.previous_span()));
+                }
+                i(labels, rs, rt, immediate.0, String::new())
+            }
+            Syntax::RtImm => {
+                let rt = self.expect_register()?;
+                let immediate = self.expect_immediate()?;
+                i(labels, 0, rt, immediate.0, String::new())
+            }
+            Syntax::RsRtLabel => {
+                let rs = self.expect_register()?;
+                let rt = self.expect_register()?;
+                let (immediate, label) = self.expect_branch_target()?;
+                i(labels, rs, rt, immediate, label)
+            }
+            Syntax::RsLabel => {
+                let rs = self.expect_register()?;
+                // REGIMM branches are told apart by their rt field
+                let rt = if spec.format == Format::Regimm { func } else { 0 };
+                let (immediate, label) = self.expect_branch_target()?;
+                i(labels, rs, rt, immediate, label)
+            }
+            Syntax::Offset => {
+                let (immediate, label) = self.expect_branch_target()?;
+                i(labels, fmt, func, immediate, label)
+            }
+            Syntax::FtOffsetRs | Syntax::RtOffsetRs => {
+                let rt = if spec.syntax == Syntax::FtOffsetRs { self.expect_fp_register()? } else { self.expect_register()? };
+                self.skip_comma();
+                let immediate = if self.starts_integer(0) { self.expect_immediate()?.0 } else { 0 };
+                self.expect_punctuation("(")?;
+                let rs = self.expect_register()?;
+                self.expect_punctuation(")")?;
+                i(labels, rs, rt, immediate, String::new())
+            }
+            Syntax::Label => {
+                self.skip_comma();
+                if self.starts_integer(0) {
+                    let address = self.expect_integer()?;
+                    Instruction::J(JInstruction { labels, span, opcode, address: (address as u32) >> 2, lbl_op: String::new() })
+                } else {
+                    let label = self.expect_identifier()?;
+                    Instruction::J(JInstruction { labels, span, opcode, address: 0, lbl_op: label })
+                }
+            }
+        };
+        Ok(instruction)
+    }
+
+    fn read_pseudo_instruction(&mut self, mnemonic: String, span: Span, operands: &[Operand]) -> ParseResult<Instruction> {
+        let mut pseudo = PseudoInstruction {
+            labels: std::mem::take(&mut self.pending_labels),
+            span,
+            mnemonic,
+            registers: Vec::new(),
+            immediate: 0,
+            lbl_op: String::new()
+        };
+        for operand in operands {
+            match operand {
+                Operand::Register => pseudo.registers.push(self.expect_register()?),
+                Operand::Integer => {
+                    let value = self.expect_integer()?;
+                    if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                        return Err(self.error_at_previous(format!("immediate {value} does not fit in 32 bits")));
+                    }
+                    pseudo.immediate = value as u32 as i32;
+                }
+                Operand::Label => pseudo.lbl_op = self.expect_identifier()?
+            }
+        }
+        Ok(Instruction::Pseudo(pseudo))
+    }
+
+    // a branch offset written as a number, or a label resolved later
+    fn expect_branch_target(&mut self) -> ParseResult<(i16, String)> {
+        self.skip_comma();
+        if self.starts_integer(0) {
+            Ok((self.expect_immediate()?.0, String::new()))
+        } else {
+            Ok((0, self.expect_identifier()?))
+        }
+    }
+
+    // count the operands written on the mnemonic's line before trying to read them
+    fn check_operand_count(&self, mnemonic: &str, span: Span, expected: (usize, usize)) -> ParseResult<usize> {
+        let mut found = 0;
+        let mut offset = 0;
+        let mut after_integer = false;
+        while let Some(t) = self.peek(offset) {
+            if t.span.line != span.line || t.token_type == TokenType::Directive || self.is_punctuation(offset + 1, ":") {
+                break;
+            }
+            match (t.token_type, t.value.as_str()) {
+                (TokenType::Punctuation, ",") | (TokenType::Operator, "-") => (),
+                // the base register of imm($rs), which counts on its own only without the imm
+                (TokenType::Punctuation, "(") => {
+                    if !after_integer {
+                        found += 1;
+                    }
+                    while self.peek(offset).is_some_and(|t| t.span.line == span.line && t.value != ")") {
+                        offset += 1;
+                    }
+                }
+                _ => found += 1
+            }
+            after_integer = t.token_type == TokenType::Integer;
+            offset += 1;
+        }
+
+        let (min, max) = expected;
+        if !(min..=max).contains(&found) {
+            let plural = if max == 1 { "" } else { "s" };
+            let expected = if min == max { min.to_string() } else { format!("{min} or {max}") };
+            return Err(Diagnostic::error(format!("'{mnemonic}' expects {expected} operand{plural}, found {found}"), span));
+        }
+        Ok(found)
+    }
+
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.cursor + offset)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.cursor).cloned();
+        self.cursor += 1;
+        token
+    }
+
+    fn on_line(&self, offset: usize, line: usize) -> bool {
+        self.peek(offset).is_some_and(|t| t.span.line == line)
+    }
+
+    fn is_punctuation(&self, offset: usize, value: &str) -> bool {
+        self.peek(offset).is_some_and(|t| t.token_type == TokenType::Punctuation && t.value == value)
+    }
+
+    // true if the upcoming tokens are an integer, optionally negated
+    fn starts_integer(&self, offset: usize) -> bool {
+        match self.peek(offset) {
+            Some(t) if t.token_type == TokenType::Integer => true,
+            Some(t) if t.token_type == TokenType::Operator && t.value == "-" => {
+                self.peek(offset + 1).is_some_and(|t| t.token_type == TokenType::Integer)
+            }
+            _ => false
+        }
+    }
+
+    // operands may be separated by commas or just whitespace
+    fn skip_comma(&mut self) {
+        if self.is_punctuation(0, ",") {
+            self.cursor += 1;
+        }
+    }
+
+    fn expect_punctuation(&mut self, value: &str) -> ParseResult<()> {
+        if !self.is_punctuation(0, value) {
+            return Err(self.error_here(format!("expected '{value}', found {}", self.describe_next())));
+        }
+        self.cursor += 1;
+        Ok(())
+    }
+
+    fn expect_identifier(&mut self) -> ParseResult<String> {
+        self.skip_comma();
+        match self.peek(0) {
+            Some(t) if t.token_type == TokenType::Identifier => Ok(self.advance().unwrap().value),
+            _ => Err(self.error_here(format!("expected a label, found {}", self.describe_next())))
+        }
     }
 
-}
\ No newline at end of file
+    fn expect_string(&mut self) -> ParseResult<String> {
+        self.skip_comma();
+        match self.peek(0) {
+            Some(t) if t.token_type == TokenType::String => Ok(self.advance().unwrap().value),
+            _ => Err(self.error_here(format!("expected a string, found {}", self.describe_next())))
+        }
+    }
+
+    fn expect_register(&mut self) -> ParseResult<u8> {
+        self.skip_comma();
+        let register = match self.peek(0) {
+            Some(t) if t.token_type == TokenType::Register => arch::register_number(&t.value[1..]),
+            _ => return Err(self.error_here(format!("expected a register, found {}", self.describe_next())))
+        };
+        match register {
+            Some(n) => {
+                self.cursor += 1;
+                Ok(n as u8)
+            }
+            None => Err(self.error_here(format!("unknown register {}", self.describe_next())))
+        }
+    }
+
+    fn expect_fp_register(&mut self) -> ParseResult<u8> {
+        self.skip_comma();
+        let register = match self.peek(0) {
+            Some(t) if t.token_type == TokenType::Register => arch::fp_register_number(&t.value[1..]),
+            _ => None
+        };
+        match register {
+            Some(n) => {
+                self.cursor += 1;
+                Ok(n as u8)
+            }
+            None => Err(self.error_here(format!("expected a floating point register, found {}", self.describe_next())))
+        }
+    }
+
+    fn expect_float(&mut self) -> ParseResult<f64> {
+        self.skip_comma();
+        let negative = self.peek(0).is_some_and(|t| t.token_type == TokenType::Operator && t.value == "-");
+        let offset = if negative { 1 } else { 0 };
+        let value = match self.peek(offset) {
+            Some(t) if t.token_type == TokenType::Float => t.value.parse::<f64>().ok(),
+            Some(t) if t.token_type == TokenType::Integer => parse_integer(&t.value).map(|v| v as f64),
+            _ => return Err(self.error_here(format!("expected a number, found {}", self.describe_next())))
+        };
+        match value {
+            Some(v) => {
+                self.cursor += offset + 1;
+                Ok(if negative { -v } else { v })
+            }
+            None => Err(self.error_here(format!("invalid number {}", self.describe_next())))
+        }
+    }
+
+    fn expect_integer(&mut self) -> ParseResult<i64> {
+        self.skip_comma();
+        let negative = self.peek(0).is_some_and(|t| t.token_type == TokenType::Operator && t.value == "-");
+        let offset = if negative { 1 } else { 0 };
+        let value = match self.peek(offset) {
+            Some(t) if t.token_type == TokenType::Integer => parse_integer(&t.value),
+            _ => return Err(self.error_here(format!("expected an integer, found {}", self.describe_next())))
+        };
+        match value {
+            Some(v) => {
+                self.cursor += offset + 1;
+                Ok(if negative { -v } else { v })
+            }
+            None => Err(self.error_here(format!("malformed integer {}", self.describe_next())))
+        }
+    }
+
+    // 16 bit immediate, accepting both the signed and unsigned spelling, along with the value as written
+    fn expect_immediate(&mut self) -> ParseResult<(i16, i64)> {
+        let value = self.expect_integer()?;
+        if !(i16::MIN as i64..=u16::MAX as i64).contains(&value) {
+            return Err(self.error_at_previous(format!("immediate {value} does not fit in 16 bits")));
+        }
+        Ok((value as u16 as i16, value))
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek(0) {
+            Some(t) => format!("'{}'", t.value),
+            None => String::from("end of file")
+        }
+    }
+
+    // span of the next token, or just past the last one at the end of the file
+    fn error_here(&self, message: String) -> Diagnostic {
+        let span = match (self.peek(0), self.tokens.last()) {
+            (Some(t), _) => t.span,
+            (None, Some(last)) => Span { column: last.span.column + last.span.length, length: 1, ..last.span },
+            (None, None) => Span { line: 1, column: 1, length: 1 }
+        };
+        Diagnostic::error(message, span)
+    }
+
+    fn previous_span(&self) -> Span {
+        self.tokens[self.cursor - 1].span
+    }
+
+    fn error_at_previous(&self, message: String) -> Diagnostic {
+        Diagnostic::error(message, self.previous_span())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::software::assemble::assemble_source;
+
+    #[test]
+    fn data_directives_pack_big_endian_bytes_on_their_alignment() {
+        let source = r#"
+            .data
+            a: .byte 1, -1, 0x7f
+            b: .half 0x1234, -2
+            c: .asciiz "a\tb\"", "\\"
+            .align 3
+            d: .byte 9
+            .space 3
+            e: .word 0xdeadbeef
+            .text
+            halt"#;
+        let (program, _) = assemble_source("parse.s", source.to_string()).unwrap();
+        assert_eq!(program.data, [
+            0x01, 0xff, 0x7f, 0,                    // a, then padding for the halfwords
+            0x12, 0x34, 0xff, 0xfe,                 // b
+            b'a', b'\t', b'b', b'"', 0, b'\\', 0,   // c, each string ending in a NUL
+            0,                                      // .align 3
+            0x09, 0, 0, 0,                          // d and .space 3
+            0xde, 0xad, 0xbe, 0xef                  // e
+        ]);
+        let address = |label: &str| program.symbols[label];
+        assert_eq!([address("a"), address("b"), address("c"), address("d"), address("e")], [0x1000, 0x1004, 0x1008, 0x1010, 0x1014]);
+    }
+
+    #[test]
+    fn data_out_of_range_is_an_error() {
+        let errors = |source: &str| match assemble_source("parse.s", format!(".data\n{source}")) {
+            Err(diagnostics) => diagnostics[0].message.clone(),
+            Ok(_) => panic!("{source} assembles")
+        };
+        assert_eq!(errors(".byte 256"), "value 256 out of range -128 to 255");
+        assert_eq!(errors(".align 4"), ".align 4 out of range, expected 0 to 3");
+        assert_eq!(errors(".ascii \"\\q\""), "unknown escape sequence '\\q'");
+        let Err(diagnostics) = assemble_source("parse.s", String::from(".data\n.word 12ab")) else { panic!("12ab assembles") };
+        assert_eq!(diagnostics.len(), 1, "the tokenizer and the parser report it once");
+    }
+}
@@ -1,154 +1,294 @@
+use crate::hardware::arch;
+use super::diagnostic::{Diagnostic, Span};
+
 const OPERATORS: &str = "+-*/=";
 const PUNCTUATION: &str = ",;:()[]{}";
+const COMMENT: char = '#';
 
-// Given an input string, returns a vector of tokens in that string
-pub fn tokenize(input: String) -> Vec<Token> {
-    let mut tkn = Tokenizer::new(input.clone());
+// Given an input string, returns a vector of tokens in that string and every problem found in it
+pub fn tokenize(input: String) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut tkn = Tokenizer::new(input);
     let mut res = Vec::<Token>::new();
-    let mut i = 0;
     // extract all the tokens
-    while tkn.has_next() {
-        let token = tkn.next().unwrap();
-
-        println!("TOKEN #{i}:[{}]", token.value);
+    for token in tkn.by_ref() {
         res.push(token);
-        i += 1;
     };
 
-    res
+    (res, tkn.diagnostics)
 }
 
 // Possible types of tokens for easier parsing later
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
-    Integer, Identifier, Operator, Punctuation, Other
+    Integer, Float, Identifier, Directive, Register, String, Operator, Punctuation, Other
 }
 
 // Output of the tokenizer
+#[derive(Debug, Clone)]
 pub struct Token {
-    token_type: TokenType,
-    value: String
+    pub token_type: TokenType,
+    pub value: String,
+    pub span: Span
 }
 
 // Object to parse a String for tokens
 pub struct Tokenizer {
-    input_string: String,
-    index: usize
+    input: Vec<char>,
+    index: usize,
+    line: usize,
+    line_start: usize,      // index of the first character of the current line
+    diagnostics: Vec<Diagnostic>
 }
 impl Tokenizer {
 
     // Constructor
     fn new(input: String) -> Tokenizer{
         Tokenizer {
-            input_string: input,
-            index: 0
+            input: input.chars().collect(),
+            index: 0,
+            line: 1,
+            line_start: 0,
+            diagnostics: Vec::new()
+        }
+    }
+
+    // returns true if there is more text to parse
+    fn has_next(&self) -> bool {
+        self.index < self.input.len()
+    }
+
+    // look ahead without consuming
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.input.get(self.index + offset).copied()
+    }
+
+    // span from start up to the current index, tokens never cross lines
+    fn span_from(&self, start: usize) -> Span {
+        Span { line: self.line, column: start - self.line_start + 1, length: self.index - start }
+    }
+
+    // advance past whitespace and # comments
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(current_char) = self.peek(0) {
+            if current_char == COMMENT {
+                while self.peek(0).is_some_and(|c| c != '\n') {
+                    self.index += 1;
+                }
+            } else if current_char == '\n' {
+                self.index += 1;
+                self.line += 1;
+                self.line_start = self.index;
+            } else if current_char.is_whitespace() {
+                self.index += 1;
+            } else {
+                break
+            }
+        }
+    }
+   
+    // call when you see letter: advance to the next non-word character and get the word
+    fn read_identifier(&mut self) -> String {
+        let start = self.index;
+        while let Some(current_char) = self.peek(0) {
+            if !(current_char.is_alphanumeric() || current_char == '_' || current_char == '.') {
+                break
+            }
+            self.index += 1;
+        }
+        self.input[start..self.index].iter().collect()
+    }
+
+    // call after an opening quote: advance past the closing quote and get the unescaped contents
+    fn read_string(&mut self) -> String {
+        let start = self.index - 1;
+        let mut res = String::new();
+        loop {
+            let current_char = match self.peek(0) {
+                Some(c) if c != '\n' => c,
+                _ => {
+                    self.diagnostics.push(Diagnostic::error(String::from("unterminated string literal"), self.span_from(start)));
+                    return res;
+                }
+            };
+            self.index += 1;
+
+            match current_char {
+                '"' => return res,
+                '\\' => {
+                    let escaped = self.peek(0);
+                    self.index += 1;
+                    match escaped {
+                        Some('n') => res.push('\n'),
+                        Some('t') => res.push('\t'),
+                        Some('r') => res.push('\r'),
+                        Some('0') => res.push('\0'),
+                        Some('\\') => res.push('\\'),
+                        Some('"') => res.push('"'),
+                        Some('\'') => res.push('\''),
+                        None | Some('\n') => self.index -= 1,
+                        Some(c) => {
+                            let span = Span { line: self.line, column: self.index - 1 - self.line_start, length: 2 };
+                            self.diagnostics.push(Diagnostic::error(format!("unknown escape sequence '\\{c}'"), span));
+                        }
+                    }
+                }
+                c => res.push(c)
+            }
+        }
+    }
+
+    // call when you see number: advance past a decimal or 0x prefixed hex number and get it.
+    // Letters running on from it make it malformed, so 12ab isn't taken for hex
+    fn read_number(&mut self) -> String {
+        let start = self.index;
+        let hex = self.peek(0) == Some('0') && self.peek(1).is_some_and(|c| c == 'x' || c == 'X');
+        if hex {
+            self.index += 2;
+        }
+        while self.peek(0).is_some_and(|c| if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() }) {
+            self.index += 1;
+        }
+        let malformed = (hex && self.index == start + 2) || self.peek(0).is_some_and(|c| c.is_alphanumeric() || c == '_');
+        while self.peek(0).is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.index += 1;
+        }
+        let number: String = self.input[start..self.index].iter().collect();
+        if malformed {
+            // worded as the parser has it, so the two reports come out as one
+            self.diagnostics.push(Diagnostic::error(format!("malformed integer '{number}'"), self.span_from(start)));
+        }
+        number
+    }
+
+    // call on the '.' after the integer part of a float like 1.5 or 2.0e-3
+    fn read_fraction(&mut self, integer: String) -> String {
+        let start = self.index;
+        self.index += 1;
+        while self.peek(0).is_some_and(|c| c.is_ascii_digit()) {
+            self.index += 1;
         }
+        // exponent, only taken if digits follow
+        if self.peek(0).is_some_and(|c| c == 'e' || c == 'E') {
+            let sign = if self.peek(1).is_some_and(|c| c == '-' || c == '+') { 1 } else { 0 };
+            if self.peek(1 + sign).is_some_and(|c| c.is_ascii_digit()) {
+                self.index += 1 + sign;
+                while self.peek(0).is_some_and(|c| c.is_ascii_digit()) {
+                    self.index += 1;
+                }
+            }
+        }
+        integer + &self.input[start..self.index].iter().collect::<String>()
     }
 
-    // Return an Option of Token that contains the next bit of text in the input_string
-    pub fn next(&mut self) -> Option<Token> {
-        println!("Finding next @ {}", self.index);
+}
+
+impl Iterator for Tokenizer {
+    type Item = Token;
+
+    // Return an Option of Token that contains the next bit of text in the input
+    fn next(&mut self) -> Option<Token> {
+        self.skip_whitespace_and_comments();
         if !self.has_next() {
             return None
         }
 
-        self.input_string = String::from(self.input_string.split_off(self.index).trim());
-        self.index = 0;
-        println!("remaining: [{}]", self.input_string);
-
-        let current_char = self.input_string.chars().nth(self.index).unwrap();
+        let start = self.index;
+        let current_char = self.input[self.index];
 
+        let (token_type, value) =
         // it's an identifier
-        if current_char.is_alphabetic() {
-            let identifier = self.read_identifier();
-            return Some ( Token { token_type: TokenType::Identifier, value: identifier })
+        if current_char.is_alphabetic() || current_char == '_' {
+            (TokenType::Identifier, self.read_identifier())
+
+        // it's a directive like .text or .word
+        } else if current_char == '.' && self.peek(1).is_some_and(|c| c.is_alphabetic()) {
+            self.index += 1;
+            (TokenType::Directive, format!(".{}", self.read_identifier()))
+
+        // it's a register like $t0, $8 or $f2
+        } else if current_char == '$' && self.peek(1).is_some_and(|c| c.is_alphanumeric()) {
+            self.index += 1;
+            let name = self.read_identifier();
+            match arch::register_number(&name) {
+                // $f0 to $f31 are the FPU's
+                None if arch::fp_register_number(&name).is_some() => (),
+                None => self.diagnostics.push(Diagnostic::error(format!("unknown register '${name}'"), self.span_from(start))),
+                Some(1) => self.diagnostics.push(Diagnostic::warning(
+                    String::from("$at is reserved for pseudo-instruction expansion"), self.span_from(start))),
+                Some(_) => ()
+            }
+            (TokenType::Register, format!("${name}"))
+
+        // it's a string literal, stored with its escapes already applied
+        } else if current_char == '"' {
+            self.index += 1;
+            (TokenType::String, self.read_string())
+
         // it's a number
         } else if current_char.is_ascii_digit() {
             let number = self.read_number();
-            self.index += number.len();
-            return Some ( Token { token_type: TokenType::Integer, value: number})
-       
-        // it's white space
-        } else if current_char.is_whitespace() {
-            let whitespace = self.read_whitespace();
-            self.index += whitespace.len();
-            return self.next()
+            if self.peek(0) == Some('.') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+                (TokenType::Float, self.read_fraction(number))
+            } else {
+                (TokenType::Integer, number)
+            }
 
         // it's an operator
         } else if OPERATORS.contains(current_char) {
             self.index += 1;
-            return Some ( Token { token_type: TokenType::Operator, value: current_char.to_string()})
+            (TokenType::Operator, current_char.to_string())
 
         // it's punctuation
         } else if PUNCTUATION.contains(current_char) {
             self.index += 1;
+            (TokenType::Punctuation, current_char.to_string())
 
-            return Some ( Token { token_type: TokenType::Punctuation, value: current_char.to_string()})
-
-        // it's some other shit
+        // it's something else
         } else {
             self.index += 1;
+            (TokenType::Other, current_char.to_string())
+        };
 
-            return Some ( Token { token_type: TokenType::Other, value: current_char.to_string()})
-        }
-
+        Some ( Token { token_type, value, span: self.span_from(start) })
     }
+}
 
-    // returns true if there is more text to parse
-    fn has_next(&self) -> bool {
-        return self.index < self.input_string.len()
-    }
-   
-    // call when you see letter: advance to the next non-letter character and get the word
-    fn read_identifier(&mut self) -> String {
-        let start = self.index;
-        while self.index < self.input_string.len() {
-            let current_char = self.input_string.chars().nth(self.index).unwrap();
-            println!("Reading identifier [{current_char}]");
-
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            if !current_char.is_alphabetic() {
-                println!("BREAK!");
-                break
-            }
-
-            
-            self.index += 1;
-        }
-        println!("Start:{}, End:{}", start, self.index);
-        return String::from(&self.input_string[start..self.index]);
+    #[test]
+    fn hex_digits_need_the_0x_prefix() {
+        let (tokens, diagnostics) = tokenize(String::from("li $t0, 0xBEEF\nli $t1, 0X1f\nli $t2, 12ab\nli $t3, 0x"));
+        let integers: Vec<&str> = tokens.iter().filter(|t| t.token_type == TokenType::Integer).map(|t| t.value.as_str()).collect();
+        assert_eq!(integers, ["0xBEEF", "0X1f", "12ab", "0x"]);
+        let found: Vec<(&str, Span)> = diagnostics.iter().map(|d| (d.message.as_str(), d.span)).collect();
+        assert_eq!(found, [
+            ("malformed integer '12ab'", Span { line: 3, column: 9, length: 4 }),
+            ("malformed integer '0x'", Span { line: 4, column: 9, length: 2 })
+        ]);
     }
 
-    // call when you see number: advance to the next non-number character and get the number
-    fn read_number(&self) -> String {
-        let mut i = 0;
-        while self.index + i < self.input_string.len() {
-            let current_char = self.input_string.chars().nth(self.index + i).unwrap();
-            println!("Reading number [{current_char}]");
-
-            if !(current_char.is_ascii_hexdigit() || (current_char == 'x' && i == 1)) {
-                break
-            }
-            
-            i += 1;
-        }
-        return String::from(&self.input_string[self.index..(self.index + i)]);
+    #[test]
+    fn registers_go_by_abi_name_or_number() {
+        let (tokens, diagnostics) = tokenize(String::from("addu $t0, $8, $zero\nmove $31, $ra\nmov.s $f2, $f31"));
+        assert!(diagnostics.is_empty());
+        let registers: Vec<&str> = tokens.iter().filter(|t| t.token_type == TokenType::Register).map(|t| t.value.as_str()).collect();
+        assert_eq!(registers, ["$t0", "$8", "$zero", "$31", "$ra", "$f2", "$f31"]);
+        let numbers: Vec<Option<u32>> = registers[..5].iter().map(|name| arch::register_number(&name[1..])).collect();
+        assert_eq!(numbers, [Some(8), Some(8), Some(0), Some(31), Some(31)]);
+        assert_eq!(arch::fp_register_number("f31"), Some(31));
     }
 
-    // call when you see whitespace: advance to the next non-whitespace character and get the whitespace
-    fn read_whitespace(&self) -> String {
-        let mut i = 0;
-        while self.index + i < self.input_string.len() {
-            let current_char = self.input_string.chars().nth(self.index + i).unwrap();
-            println!("Reading whitespace [{current_char}]");
-            if !current_char.is_alphabetic() {
-                break
-            }
-            i += 1;
-        }
-        return String::from(&self.input_string[self.index..(self.index + i)]);
+    #[test]
+    fn unknown_registers_are_reported_where_they_are() {
+        let (_, diagnostics) = tokenize(String::from("addu $t0, $t10, $32\naddu $at, $f32, $0"));
+        let found: Vec<(&str, Span, bool)> = diagnostics.iter().map(|d| (d.message.as_str(), d.span, d.is_error())).collect();
+        assert_eq!(found, [
+            ("unknown register '$t10'", Span { line: 1, column: 11, length: 4 }, true),
+            ("unknown register '$32'", Span { line: 1, column: 17, length: 3 }, true),
+            ("$at is reserved for pseudo-instruction expansion", Span { line: 2, column: 6, length: 3 }, false),
+            ("unknown register '$f32'", Span { line: 2, column: 11, length: 4 }, true)
+        ]);
     }
-
 }
-
-
-
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use super::assemble;
+use super::diagnostic::{Diagnostic, Span};
+use crate::datatypes::{*};
+use crate::hardware::arch;
+
+/*
+ * Semantic checks on an expanded protogram, everything the encoder relies on:
+ * labels defined once, every label operand defined and in reach, and the
+ * program fitting in its memory segments
+ */
+
+pub fn validate(protogram: &Protogram) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    check_duplicate_labels(protogram, &mut diagnostics);
+    check_label_operands(protogram, &mut diagnostics);
+    check_segment_sizes(protogram, &mut diagnostics);
+    diagnostics
+}
+
+fn check_duplicate_labels(protogram: &Protogram, diagnostics: &mut Vec<Diagnostic>) {
+    let text_labels = protogram.text.iter().flat_map(|i| i.labels());
+    let data_labels = protogram.data.iter().flat_map(|d| &d.labels);
+
+    let mut defined: HashMap<&str, Span> = HashMap::new();
+    for label in text_labels.chain(data_labels) {
+        match defined.get(label.name.as_str()) {
+            Some(first) => diagnostics.push(Diagnostic::error(
+                format!("label '{}' already defined on line {}", label.name, first.line), label.span)),
+            None => {
+                defined.insert(&label.name, label.span);
+            }
+        }
+    }
+}
+
+fn check_label_operands(protogram: &Protogram, diagnostics: &mut Vec<Diagnostic>) {
+    let symbols = assemble::collect_symbols(protogram);
+    let undefined = |label: &str, span| Diagnostic::error(format!("undefined label '{label}'"), span);
+
+    let mut address = arch::PC_START;
+    for instruction in &protogram.text {
+        match instruction {
+            Instruction::I(i) if !i.lbl_op.is_empty() => match (symbols.get(&i.lbl_op), &i.lbl_use) {
+                (None, _) => diagnostics.push(undefined(&i.lbl_op, i.span)),
+                (Some(target), LabelUse::Branch) => {
+                    let offset = assemble::branch_offset(*target, address);
+                    if offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+                        diagnostics.push(Diagnostic::error(
+                            format!("branch to '{}' is {offset} instructions away, out of range", i.lbl_op), i.span));
+                    }
+                }
+                (Some(_), _) => ()
+            },
+            Instruction::J(j) if !j.lbl_op.is_empty() => match symbols.get(&j.lbl_op) {
+                None => diagnostics.push(undefined(&j.lbl_op, j.span)),
+                Some(target) if (target >> 2) & !arch::JUMP_ADDRESS_MASK != 0 => diagnostics.push(Diagnostic::error(
+                    format!("jump target '{}' at {:#010x} does not fit in the address field", j.lbl_op, target), j.span)),
+                Some(_) => ()
+            },
+            _ => ()
+        }
+        address += 4;
+    }
+}
+
+fn check_segment_sizes(protogram: &Protogram, diagnostics: &mut Vec<Diagnostic>) {
+    let text_size = protogram.text.len() as u32 * 4;
+    if text_size > arch::STATIC_DATA - arch::PC_START {
+        let span = protogram.text[((arch::STATIC_DATA - arch::PC_START) / 4) as usize].span();
+        diagnostics.push(Diagnostic::error(
+            format!("program is {text_size} bytes, more than the {} bytes of text segment", arch::STATIC_DATA - arch::PC_START), span));
+    }
+
+    let data_size = assemble::data_size(protogram);
+    if data_size > arch::DYNAMIC_DATA - arch::STATIC_DATA {
+        let span = protogram.data.last().map(|d| d.span).unwrap_or_default();
+        diagnostics.push(Diagnostic::error(
+            format!("static data is {data_size} bytes, more than the {} bytes of data segment", arch::DYNAMIC_DATA - arch::STATIC_DATA), span));
+    }
+}